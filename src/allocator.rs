@@ -11,6 +11,8 @@ static ALLOCATOR: LockedHeap = LockedHeap::empty();
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
 pub const HEAP_SIZE: usize = 8 * 1024 * 1024; // 8 MiB
+/// The heap is considered under memory pressure once less than this much is free.
+pub const LOW_MEMORY_THRESHOLD: usize = HEAP_SIZE / 8;
 
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
@@ -40,3 +42,13 @@ pub fn init_heap(
 
     Ok(())
 }
+
+/// Bytes of kernel heap currently free.
+pub fn heap_free() -> usize {
+    ALLOCATOR.lock().free()
+}
+
+/// True when free heap has dropped below `LOW_MEMORY_THRESHOLD`.
+pub fn under_memory_pressure() -> bool {
+    heap_free() < LOW_MEMORY_THRESHOLD
+}
@@ -0,0 +1,102 @@
+//! Machine-level power control: reboot and shutdown.
+
+use x86_64::instructions::port::Port;
+
+const KBC_STATUS: u16 = 0x64;
+const KBC_COMMAND: u16 = 0x64;
+/// Status bit set while the 8042's input buffer still holds an unread byte.
+const KBC_INPUT_FULL: u8 = 1 << 1;
+/// 8042 command that pulses the CPU reset line.
+const KBC_PULSE_RESET: u8 = 0xfe;
+
+/// QEMU's `isa-debug-exit` device (`-device isa-debug-exit,iobase=0xf4,iosize=0x04`).
+/// Writing `code` exits QEMU with status `(code << 1) | 1`.
+pub const DEBUG_EXIT_PORT: u16 = 0xf4;
+/// ACPI PM1a control port on QEMU's PIIX4/Q35 machines, and the value that selects
+/// the soft-off sleep state.
+const ACPI_PM1A_CONTROL: u16 = 0x604;
+const ACPI_SOFT_OFF: u16 = 0x2000;
+
+/// Reset the machine: ask the keyboard controller first, then fall back to a triple
+/// fault.
+pub fn reboot() -> ! {
+    x86_64::instructions::interrupts::disable();
+
+    let mut status: Port<u8> = Port::new(KBC_STATUS);
+    let mut command: Port<u8> = Port::new(KBC_COMMAND);
+    unsafe {
+        while status.read() & KBC_INPUT_FULL != 0 {
+            core::hint::spin_loop();
+        }
+        command.write(KBC_PULSE_RESET);
+    }
+
+    triple_fault()
+}
+
+/// A write to an I/O port, by width.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PortWrite {
+    U16(u16, u16),
+    U32(u16, u32),
+}
+
+/// The port writes that power off with `code`, in order: QEMU's debug-exit device
+/// first, then ACPI soft-off.
+fn shutdown_sequence(code: u32) -> [PortWrite; 2] {
+    [
+        PortWrite::U32(DEBUG_EXIT_PORT, code),
+        PortWrite::U16(ACPI_PM1A_CONTROL, ACPI_SOFT_OFF),
+    ]
+}
+
+/// Power off with `code` (see `shutdown_sequence`). Halts forever if neither device
+/// is present.
+pub fn shutdown(code: u32) -> ! {
+    x86_64::instructions::interrupts::disable();
+
+    for write in shutdown_sequence(code) {
+        unsafe {
+            match write {
+                PortWrite::U16(port, value) => Port::<u16>::new(port).write(value),
+                PortWrite::U32(port, value) => Port::<u32>::new(port).write(value),
+            }
+        }
+    }
+
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+fn triple_fault() -> ! {
+    use x86_64::structures::DescriptorTablePointer;
+    use x86_64::VirtAddr;
+
+    // With an empty IDT the breakpoint cannot be delivered, escalating to a reset.
+    let empty = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::new(0),
+    };
+    unsafe {
+        x86_64::instructions::tables::lidt(&empty);
+    }
+    x86_64::instructions::interrupts::int3();
+
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn shutdown_writes_the_code_to_the_debug_exit_port_first() {
+        assert_eq!(
+            shutdown_sequence(0x31),
+            [PortWrite::U32(0xf4, 0x31), PortWrite::U16(0x604, 0x2000),]
+        );
+    }
+}
@@ -0,0 +1,163 @@
+//! Kernel audit log: a bounded, in-memory record of security-relevant events.
+//! Oldest records are dropped once `MAX_RECORDS` is reached.
+
+use crate::capability::CapabilityId;
+use crate::serial_println;
+use crate::time::uptime_ms;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use spin::Mutex;
+
+const MAX_RECORDS: usize = 256;
+
+/// While an agent keeps repeating the same denial, how often its count is reported.
+const DENIAL_REPORT_MS: u64 = 1000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuditEvent {
+    /// A capability was delegated from `sender` to `recipient` over IPC.
+    CapabilityTransfer {
+        cap: CapabilityId,
+        sender: u64,
+        recipient: u64,
+    },
+    /// `inspector` asked to peek at `target`'s IPC queue; `allowed` is the outcome.
+    IpcInspect {
+        inspector: u64,
+        target: u64,
+        allowed: bool,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct AuditRecord {
+    /// Milliseconds since boot when the event was recorded.
+    pub timestamp_ms: u64,
+    pub event: AuditEvent,
+}
+
+static AUDIT_LOG: Mutex<VecDeque<AuditRecord>> = Mutex::new(VecDeque::new());
+
+/// Append an event to the audit log.
+pub fn record(event: AuditEvent) {
+    let mut log = AUDIT_LOG.lock();
+    if log.len() >= MAX_RECORDS {
+        log.pop_front();
+    }
+    log.push_back(AuditRecord {
+        timestamp_ms: uptime_ms(),
+        event,
+    });
+}
+
+/// The most recent `n` records, oldest first.
+pub fn recent(n: usize) -> Vec<AuditRecord> {
+    let log = AUDIT_LOG.lock();
+    log.iter()
+        .skip(log.len().saturating_sub(n))
+        .cloned()
+        .collect()
+}
+
+/// The last denial logged for an agent and how often it has recurred since.
+struct LastDenial {
+    message: String,
+    /// Repeats not yet reported.
+    repeats: u64,
+    reported_ms: u64,
+}
+
+impl LastDenial {
+    fn flush(&mut self, now_ms: u64) {
+        if self.repeats > 0 {
+            serial_println!(
+                "[SECURITY] {} (repeated {} times)",
+                self.message,
+                self.repeats
+            );
+            self.repeats = 0;
+        }
+        self.reported_ms = now_ms;
+    }
+}
+
+static LAST_DENIALS: Mutex<BTreeMap<u64, LastDenial>> = Mutex::new(BTreeMap::new());
+
+/// Log a `[SECURITY]` denial for `agent_pid`. A denial identical to the agent's previous
+/// one is counted instead of printed, and the count is reported as `(repeated N times)`
+/// at most every `DENIAL_REPORT_MS`, or as soon as a different denial comes in. This
+/// keeps an agent retrying a forbidden call in a loop from flooding the serial log.
+pub fn log_denial(agent_pid: u64, message: fmt::Arguments) {
+    let mut text = String::new();
+    let _ = text.write_fmt(message);
+    let now = uptime_ms();
+
+    let mut denials = LAST_DENIALS.lock();
+    if let Some(last) = denials.get_mut(&agent_pid) {
+        if last.message == text {
+            last.repeats += 1;
+            if now.saturating_sub(last.reported_ms) >= DENIAL_REPORT_MS {
+                last.flush(now);
+            }
+            return;
+        }
+        last.flush(now);
+    }
+    serial_println!("[SECURITY] {}", text);
+    denials.insert(
+        agent_pid,
+        LastDenial {
+            message: text,
+            repeats: 0,
+            reported_ms: now,
+        },
+    );
+}
+
+/// Report any repeats still pending for `agent_pid` and forget its last denial.
+pub fn forget_denials(agent_pid: u64) {
+    if let Some(mut last) = LAST_DENIALS.lock().remove(&agent_pid) {
+        last.flush(uptime_ms());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test_case]
+    fn repeated_denials_are_coalesced_into_one_line() {
+        let pid = 944_000;
+        for _ in 0..1000 {
+            log_denial(pid, format_args!("Agent {pid} denied file read: /x"));
+        }
+        log_denial(pid, format_args!("Agent {pid} denied file read: /y"));
+
+        let tail = testing::log_tail();
+        let line = alloc::format!("[SECURITY] Agent {pid} denied file read: /x\n");
+        assert_eq!(tail.matches(&line).count(), 1);
+        assert!(tail.contains(&alloc::format!(
+            "[SECURITY] Agent {pid} denied file read: /x (repeated 999 times)\n"
+        )));
+        assert!(tail.contains(&alloc::format!(
+            "[SECURITY] Agent {pid} denied file read: /y\n"
+        )));
+        forget_denials(pid);
+    }
+
+    #[test_case]
+    fn forgetting_an_agent_reports_its_pending_repeats() {
+        let pid = 944_001;
+        for _ in 0..3 {
+            log_denial(pid, format_args!("Agent {pid} denied network access"));
+        }
+        forget_denials(pid);
+        assert!(testing::logged(&alloc::format!(
+            "[SECURITY] Agent {pid} denied network access (repeated 2 times)\n"
+        )));
+        assert!(!LAST_DENIALS.lock().contains_key(&pid));
+    }
+}
@@ -0,0 +1,136 @@
+//! Boot report: the outcome of each subsystem's initialization, so a failure is
+//! reported once in a summary instead of panicking mid-boot or passing unnoticed.
+//! Stages are recorded before the heap exists, so nothing here allocates.
+
+use core::fmt;
+
+/// Most stages a report holds; later ones are counted but not kept.
+pub const MAX_STAGES: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Ok,
+    /// The subsystem is unavailable but the kernel can run without it.
+    Degraded(&'static str),
+    /// The kernel cannot continue.
+    Failed(&'static str),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Stage {
+    pub name: &'static str,
+    pub outcome: Outcome,
+}
+
+#[derive(Debug)]
+pub struct BootReport {
+    stages: [Option<Stage>; MAX_STAGES],
+    len: usize,
+}
+
+impl BootReport {
+    pub const fn new() -> Self {
+        BootReport {
+            stages: [None; MAX_STAGES],
+            len: 0,
+        }
+    }
+
+    pub fn record(&mut self, name: &'static str, outcome: Outcome) {
+        if let Some(slot) = self.stages.get_mut(self.len) {
+            *slot = Some(Stage { name, outcome });
+        }
+        self.len += 1;
+    }
+
+    /// Record a stage from a `Result`, treating an error as `Degraded`.
+    pub fn record_result(&mut self, name: &'static str, result: Result<(), &'static str>) {
+        let outcome = match result {
+            Ok(()) => Outcome::Ok,
+            Err(why) => Outcome::Degraded(why),
+        };
+        self.record(name, outcome);
+    }
+
+    pub fn stages(&self) -> impl Iterator<Item = &Stage> {
+        self.stages.iter().flatten()
+    }
+
+    fn count(&self, matches: impl Fn(&Outcome) -> bool) -> usize {
+        self.stages().filter(|s| matches(&s.outcome)).count()
+    }
+
+    /// Whether every stage succeeded.
+    pub fn is_clean(&self) -> bool {
+        self.count(|o| *o != Outcome::Ok) == 0
+    }
+
+    /// Whether a stage failed in a way the kernel cannot continue from.
+    pub fn is_fatal(&self) -> bool {
+        self.count(|o| matches!(o, Outcome::Failed(_))) > 0
+    }
+}
+
+impl Default for BootReport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// One line per stage, then a totals line.
+impl fmt::Display for BootReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for stage in self.stages() {
+            match stage.outcome {
+                Outcome::Ok => writeln!(f, "  [ OK ] {}", stage.name)?,
+                Outcome::Degraded(why) => writeln!(f, "  [WARN] {}: {}", stage.name, why)?,
+                Outcome::Failed(why) => writeln!(f, "  [FAIL] {}: {}", stage.name, why)?,
+            }
+        }
+        write!(
+            f,
+            "  {} ok, {} degraded, {} failed",
+            self.count(|o| *o == Outcome::Ok),
+            self.count(|o| matches!(o, Outcome::Degraded(_))),
+            self.count(|o| matches!(o, Outcome::Failed(_)))
+        )?;
+        if self.len > MAX_STAGES {
+            write!(f, " ({} stages not shown)", self.len - MAX_STAGES)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn mixed_outcomes_are_summarised_per_stage() {
+        let mut report = BootReport::new();
+        report.record("gdt", Outcome::Ok);
+        report.record_result("network", Err("no RTL8139 found"));
+        report.record_result("initramfs", Ok(()));
+        assert!(!report.is_clean());
+        assert!(!report.is_fatal());
+
+        report.record("heap", Outcome::Failed("could not map the kernel heap"));
+        assert!(report.is_fatal());
+        assert_eq!(
+            alloc::format!("{report}"),
+            "  [ OK ] gdt\n  [WARN] network: no RTL8139 found\n  [ OK ] initramfs\n  \
+             [FAIL] heap: could not map the kernel heap\n  2 ok, 1 degraded, 1 failed"
+        );
+    }
+
+    #[test_case]
+    fn stages_past_the_limit_are_counted_but_not_kept() {
+        let mut report = BootReport::new();
+        for _ in 0..MAX_STAGES + 2 {
+            report.record("stage", Outcome::Ok);
+        }
+        assert!(report.is_clean());
+        assert_eq!(report.stages().count(), MAX_STAGES);
+        assert!(alloc::format!("{report}").ends_with("(2 stages not shown)"));
+    }
+}
@@ -0,0 +1,60 @@
+//! Bounds-checked byte-order helpers for parsing wire formats.
+//! Every reader returns `None` instead of panicking when the slice is too short.
+
+/// Borrow `len` bytes starting at `offset`.
+pub fn read_slice(data: &[u8], offset: usize, len: usize) -> Option<&[u8]> {
+    data.get(offset..offset.checked_add(len)?)
+}
+
+fn read_array<const N: usize>(data: &[u8], offset: usize) -> Option<[u8; N]> {
+    read_slice(data, offset, N)?.try_into().ok()
+}
+
+pub fn read_u8(data: &[u8], offset: usize) -> Option<u8> {
+    data.get(offset).copied()
+}
+
+pub fn read_u16_be(data: &[u8], offset: usize) -> Option<u16> {
+    read_array(data, offset).map(u16::from_be_bytes)
+}
+
+pub fn read_u16_le(data: &[u8], offset: usize) -> Option<u16> {
+    read_array(data, offset).map(u16::from_le_bytes)
+}
+
+pub fn read_u32_be(data: &[u8], offset: usize) -> Option<u32> {
+    read_array(data, offset).map(u32::from_be_bytes)
+}
+
+pub fn read_u32_le(data: &[u8], offset: usize) -> Option<u32> {
+    read_array(data, offset).map(u32::from_le_bytes)
+}
+
+pub fn read_u64_le(data: &[u8], offset: usize) -> Option<u64> {
+    read_array(data, offset).map(u64::from_le_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn readers_honour_byte_order() {
+        let data = [0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xF0];
+        assert_eq!(read_u16_be(&data, 0), Some(0x1234));
+        assert_eq!(read_u16_le(&data, 0), Some(0x3412));
+        assert_eq!(read_u32_be(&data, 1), Some(0x3456_789A));
+        assert_eq!(read_u32_le(&data, 1), Some(0x9A78_5634));
+        assert_eq!(read_u64_le(&data, 0), Some(0xF0DE_BC9A_7856_3412));
+    }
+
+    #[test_case]
+    fn readers_return_none_past_the_end() {
+        let data = [1, 2, 3];
+        assert_eq!(read_u8(&data, 3), None);
+        assert_eq!(read_u16_be(&data, 2), None);
+        assert_eq!(read_u32_le(&data, 0), None);
+        assert_eq!(read_slice(&data, 1, 2), Some(&data[1..]));
+        assert_eq!(read_slice(&data, usize::MAX, 2), None);
+    }
+}
@@ -1,13 +1,16 @@
+use crate::bytes::{read_slice, read_u16_le, read_u32_le, read_u64_le, read_u8};
 use crate::println;
+use crate::task::AgentId;
 use alloc::collections::BTreeMap;
 use alloc::string::String;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
 use spin::Mutex;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct CapabilityId(pub u64);
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Capability {
     Memory {
         base: usize,
@@ -27,10 +30,28 @@ pub enum Capability {
         can_send: bool,
         can_receive: bool,
     },
+    /// Read-only view of `target_pid`'s IPC queue, for debugging and monitoring.
+    IpcInspect {
+        target_pid: u64,
+    },
     Spawn {
         max_children: u32,
+        /// Deepest level of the agent tree the holder may spawn into. Children sit one
+        /// level below their parent; the kernel's `MAX_SPAWN_DEPTH` caps this further.
+        max_depth: u32,
+        /// If set, the holder may only spawn modules stored in the VFS under this
+        /// prefix (via `spawn_from_file`), never raw bytes.
+        allowed_prefix: Option<String>,
     },
     Network,
+    /// Precise wall-clock and uptime readings. Only enforced in strict mode.
+    Clock,
+    /// Reboot or power off the machine. Meant for the management agent only.
+    Power,
+    /// Serve dynamic files under `/agent/<pid>/` from the holder's own exports.
+    ServeFiles,
+    /// Send and receive raw Ethernet frames, bypassing the IP stack.
+    RawNetwork,
     FileSystem {
         path_prefix: String,
         read: bool,
@@ -38,9 +59,229 @@ pub enum Capability {
     },
 }
 
-static CAPABILITY_STORE: Mutex<BTreeMap<CapabilityId, Capability>> = Mutex::new(BTreeMap::new());
+// Wire tags for `Capability::encode`. Multi-byte fields follow the tag little-endian.
+const TAG_MEMORY: u8 = 0; // base u64, size u64, flags u8 (1=read, 2=write, 4=execute)
+const TAG_INTERRUPT: u8 = 1; // irq u8
+const TAG_PORT: u8 = 2; // port u16
+const TAG_PROCESS: u8 = 3; // pid u64, flags u8 (1=send, 2=receive)
+const TAG_SPAWN: u8 = 4; // max_children u32, max_depth u32, has_prefix u8, [prefix length u16, prefix]
+const TAG_NETWORK: u8 = 5;
+const TAG_CLOCK: u8 = 6;
+const TAG_FILESYSTEM: u8 = 7; // flags u8 (1=read, 2=write), prefix length u16, prefix
+const TAG_IPC_INSPECT: u8 = 8; // target_pid u64
+const TAG_POWER: u8 = 9;
+const TAG_SERVE_FILES: u8 = 10;
+const TAG_RAW_NETWORK: u8 = 11;
+
+impl Capability {
+    /// Append the capability's wire encoding (a tag byte, then its fields) to `out`.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Capability::Memory {
+                base,
+                size,
+                read,
+                write,
+                execute,
+            } => {
+                out.push(TAG_MEMORY);
+                out.extend_from_slice(&(*base as u64).to_le_bytes());
+                out.extend_from_slice(&(*size as u64).to_le_bytes());
+                out.push(flags(&[*read, *write, *execute]));
+            }
+            Capability::Interrupt { irq } => out.extend_from_slice(&[TAG_INTERRUPT, *irq]),
+            Capability::Port { port } => {
+                out.push(TAG_PORT);
+                out.extend_from_slice(&port.to_le_bytes());
+            }
+            Capability::Process {
+                pid,
+                can_send,
+                can_receive,
+            } => {
+                out.push(TAG_PROCESS);
+                out.extend_from_slice(&pid.to_le_bytes());
+                out.push(flags(&[*can_send, *can_receive]));
+            }
+            Capability::IpcInspect { target_pid } => {
+                out.push(TAG_IPC_INSPECT);
+                out.extend_from_slice(&target_pid.to_le_bytes());
+            }
+            Capability::Spawn {
+                max_children,
+                max_depth,
+                allowed_prefix,
+            } => {
+                out.push(TAG_SPAWN);
+                out.extend_from_slice(&max_children.to_le_bytes());
+                out.extend_from_slice(&max_depth.to_le_bytes());
+                match allowed_prefix {
+                    Some(prefix) => {
+                        out.push(1);
+                        out.extend_from_slice(&(prefix.len() as u16).to_le_bytes());
+                        out.extend_from_slice(prefix.as_bytes());
+                    }
+                    None => out.push(0),
+                }
+            }
+            Capability::Network => out.push(TAG_NETWORK),
+            Capability::Clock => out.push(TAG_CLOCK),
+            Capability::Power => out.push(TAG_POWER),
+            Capability::ServeFiles => out.push(TAG_SERVE_FILES),
+            Capability::RawNetwork => out.push(TAG_RAW_NETWORK),
+            Capability::FileSystem {
+                path_prefix,
+                read,
+                write,
+            } => {
+                out.push(TAG_FILESYSTEM);
+                out.push(flags(&[*read, *write]));
+                out.extend_from_slice(&(path_prefix.len() as u16).to_le_bytes());
+                out.extend_from_slice(path_prefix.as_bytes());
+            }
+        }
+    }
+
+    /// Decode one capability from the start of `data`, returning it and the number of
+    /// bytes consumed. `None` if the encoding is truncated or unknown.
+    pub fn decode(data: &[u8]) -> Option<(Capability, usize)> {
+        let bit = |byte: u8, n: u8| byte & (1 << n) != 0;
+        Some(match read_u8(data, 0)? {
+            TAG_MEMORY => {
+                let f = read_u8(data, 17)?;
+                let cap = Capability::Memory {
+                    base: read_u64_le(data, 1)? as usize,
+                    size: read_u64_le(data, 9)? as usize,
+                    read: bit(f, 0),
+                    write: bit(f, 1),
+                    execute: bit(f, 2),
+                };
+                (cap, 18)
+            }
+            TAG_INTERRUPT => (
+                Capability::Interrupt {
+                    irq: read_u8(data, 1)?,
+                },
+                2,
+            ),
+            TAG_PORT => (
+                Capability::Port {
+                    port: read_u16_le(data, 1)?,
+                },
+                3,
+            ),
+            TAG_PROCESS => {
+                let f = read_u8(data, 9)?;
+                let cap = Capability::Process {
+                    pid: read_u64_le(data, 1)?,
+                    can_send: bit(f, 0),
+                    can_receive: bit(f, 1),
+                };
+                (cap, 10)
+            }
+            TAG_SPAWN => {
+                let (allowed_prefix, len) = match read_u8(data, 9)? {
+                    0 => (None, 10),
+                    1 => {
+                        let len = read_u16_le(data, 10)? as usize;
+                        let prefix = core::str::from_utf8(read_slice(data, 12, len)?).ok()?;
+                        (Some(String::from(prefix)), 12 + len)
+                    }
+                    _ => return None,
+                };
+                let cap = Capability::Spawn {
+                    max_children: read_u32_le(data, 1)?,
+                    max_depth: read_u32_le(data, 5)?,
+                    allowed_prefix,
+                };
+                (cap, len)
+            }
+            TAG_NETWORK => (Capability::Network, 1),
+            TAG_CLOCK => (Capability::Clock, 1),
+            TAG_POWER => (Capability::Power, 1),
+            TAG_SERVE_FILES => (Capability::ServeFiles, 1),
+            TAG_RAW_NETWORK => (Capability::RawNetwork, 1),
+            TAG_FILESYSTEM => {
+                let f = read_u8(data, 1)?;
+                let len = read_u16_le(data, 2)? as usize;
+                let prefix = core::str::from_utf8(read_slice(data, 4, len)?).ok()?;
+                let cap = Capability::FileSystem {
+                    path_prefix: String::from(prefix),
+                    read: bit(f, 0),
+                    write: bit(f, 1),
+                };
+                (cap, 4 + len)
+            }
+            TAG_IPC_INSPECT => {
+                let cap = Capability::IpcInspect {
+                    target_pid: read_u64_le(data, 1)?,
+                };
+                (cap, 9)
+            }
+            _ => return None,
+        })
+    }
+}
+
+/// Pack boolean permissions into a flag byte, first element in bit 0.
+fn flags(bits: &[bool]) -> u8 {
+    bits.iter()
+        .enumerate()
+        .fold(0, |acc, (i, &set)| acc | (u8::from(set) << i))
+}
+
+/// Encode a capability list: a u32le count followed by each `Capability::encode`.
+pub fn encode_list(caps: &[Capability]) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(&(caps.len() as u32).to_le_bytes());
+    for cap in caps {
+        cap.encode(&mut out);
+    }
+    out
+}
+
+/// Decode a list produced by `encode_list`. `None` if it is malformed or has
+/// trailing bytes.
+pub fn decode_list(data: &[u8]) -> Option<Vec<Capability>> {
+    let count = read_u32_le(data, 0)?;
+    let mut offset = 4;
+    let mut caps = Vec::new();
+    for _ in 0..count {
+        let (cap, used) = Capability::decode(data.get(offset..)?)?;
+        caps.push(cap);
+        offset += used;
+    }
+    (offset == data.len()).then_some(caps)
+}
+
+/// A stored capability plus the number of holders sharing it.
+/// Delegation adds a reference; revocation drops one, and the capability is only
+/// destroyed once the last reference is gone.
+struct CapabilityEntry {
+    cap: Capability,
+    refs: u32,
+}
+
+static CAPABILITY_STORE: Mutex<BTreeMap<CapabilityId, CapabilityEntry>> =
+    Mutex::new(BTreeMap::new());
 static NEXT_CAP_ID: Mutex<u64> = Mutex::new(1);
 
+/// When set, `request_capability` denies requests without an explicit `Allow` policy.
+static STRICT_MODE: AtomicBool = AtomicBool::new(false);
+/// Explicit decisions for (agent pid, requested capability type) pairs.
+static REQUEST_POLICY: Mutex<BTreeMap<(u64, u32), RequestDecision>> = Mutex::new(BTreeMap::new());
+
+/// How the kernel answers a runtime capability request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestDecision {
+    /// Grant immediately.
+    Allow,
+    /// Refuse.
+    Deny,
+    /// Leave the decision to the Kernel Supervisor; the agent is told it is pending.
+    Prompt,
+}
+
 pub fn init() {
     println!("Capability system initialized");
 }
@@ -50,16 +291,92 @@ pub fn create_capability(cap: Capability) -> CapabilityId {
     let mut next_id = NEXT_CAP_ID.lock();
     let cap_id = CapabilityId(*next_id);
     *next_id += 1;
-    store.insert(cap_id, cap);
+    store.insert(cap_id, CapabilityEntry { cap, refs: 1 });
     cap_id
 }
 
 pub fn validate_capability(cap_id: CapabilityId) -> Option<Capability> {
-    CAPABILITY_STORE.lock().get(&cap_id).cloned()
+    CAPABILITY_STORE
+        .lock()
+        .get(&cap_id)
+        .map(|entry| entry.cap.clone())
 }
 
+/// Share `cap_id` with another holder (e.g. when delegated over IPC).
+/// Returns false if the capability no longer exists.
+pub fn delegate_capability(cap_id: CapabilityId) -> bool {
+    match CAPABILITY_STORE.lock().get_mut(&cap_id) {
+        Some(entry) => {
+            entry.refs += 1;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Drop one reference to `cap_id`. The capability is destroyed when the last
+/// holder revokes it. Returns false if the capability does not exist. An agent
+/// giving up its own copy goes through `revoke_from_agent`, which also takes the id
+/// out of its capability list.
 pub fn revoke_capability(cap_id: CapabilityId) -> bool {
-    CAPABILITY_STORE.lock().remove(&cap_id).is_some()
+    let mut store = CAPABILITY_STORE.lock();
+    let Some(entry) = store.get_mut(&cap_id) else {
+        return false;
+    };
+    entry.refs -= 1;
+    if entry.refs == 0 {
+        store.remove(&cap_id);
+    }
+    true
+}
+
+/// Revoke `holder`'s share of `cap_id`: the id leaves the agent's capability list and
+/// its reference is dropped. Returns false if the agent does not hold it.
+pub fn revoke_from_agent(holder: AgentId, cap_id: CapabilityId) -> bool {
+    crate::task::remove_capability_from_agent(holder, cap_id) && revoke_capability(cap_id)
+}
+
+/// Number of live references to `cap_id` (0 once destroyed).
+pub fn refcount(cap_id: CapabilityId) -> u32 {
+    CAPABILITY_STORE
+        .lock()
+        .get(&cap_id)
+        .map_or(0, |entry| entry.refs)
+}
+
+/// Enable or disable strict mode. In strict mode capability requests from agents
+/// are denied unless allow-listed with `allow_request`.
+pub fn set_strict_mode(enabled: bool) {
+    STRICT_MODE.store(enabled, Ordering::Relaxed);
+}
+
+pub fn strict_mode() -> bool {
+    STRICT_MODE.load(Ordering::Relaxed)
+}
+
+/// Set the decision for requests of `cap_type` from `pid`, overriding the mode default.
+pub fn set_request_policy(pid: u64, cap_type: u32, decision: RequestDecision) {
+    REQUEST_POLICY.lock().insert((pid, cap_type), decision);
+}
+
+/// Allow `pid` to be granted capability type `cap_type`, including in strict mode.
+pub fn allow_request(pid: u64, cap_type: u32) {
+    set_request_policy(pid, cap_type, RequestDecision::Allow);
+}
+
+/// Remove an explicit policy entry, falling back to the mode default.
+pub fn disallow_request(pid: u64, cap_type: u32) {
+    REQUEST_POLICY.lock().remove(&(pid, cap_type));
+}
+
+/// Policy decision for a runtime capability request. An explicit entry wins;
+/// otherwise requests are allowed, or denied in strict mode.
+pub fn request_decision(pid: u64, cap_type: u32) -> RequestDecision {
+    match REQUEST_POLICY.lock().get(&(pid, cap_type)) {
+        Some(&decision) => decision,
+        None if strict_mode() => RequestDecision::Deny,
+        None => RequestDecision::Allow,
+    }
 }
 
 /// Returns true if any capability in `caps` satisfies `predicate`.
@@ -69,7 +386,9 @@ where
     F: Fn(&Capability) -> bool,
 {
     let store = CAPABILITY_STORE.lock();
-    caps.iter().filter_map(|id| store.get(id)).any(predicate)
+    caps.iter()
+        .filter_map(|id| store.get(id))
+        .any(|entry| predicate(&entry.cap))
 }
 
 /// Convenience: check if a cap set grants readable memory access to `addr`.
@@ -102,39 +421,276 @@ pub fn can_send_to(caps: &[CapabilityId], target_pid: u64) -> bool {
     })
 }
 
+/// Convenience: check if a cap set allows peeking at `target_pid`'s IPC queue.
+pub fn can_inspect_ipc(caps: &[CapabilityId], target_pid: u64) -> bool {
+    find_capability(
+        caps,
+        |c| matches!(c, Capability::IpcInspect { target_pid: pid } if *pid == target_pid),
+    )
+}
+
 pub fn can_spawn(caps: &[CapabilityId]) -> bool {
     find_capability(caps, |c| matches!(c, Capability::Spawn { .. }))
 }
 
+/// Convenience: check if a cap set may spawn children from raw module bytes, i.e.
+/// holds a `Spawn` capability with no `allowed_prefix`.
+pub fn can_spawn_bytes(caps: &[CapabilityId]) -> bool {
+    find_capability(caps, |c| {
+        matches!(
+            c,
+            Capability::Spawn {
+                allowed_prefix: None,
+                ..
+            }
+        )
+    })
+}
+
+/// Convenience: check if a cap set may spawn the module stored at VFS `path`. `path`
+/// must be canonical (see `path_under`).
+pub fn can_spawn_from(caps: &[CapabilityId], path: &str) -> bool {
+    find_capability(caps, |c| match c {
+        Capability::Spawn { allowed_prefix, .. } => allowed_prefix
+            .as_deref()
+            .is_none_or(|prefix| path_under(path, prefix)),
+        _ => false,
+    })
+}
+
+/// Returns the `allowed_prefix` of the first prefix-limited `Spawn` capability in the
+/// cap set, if any.
+pub fn spawn_prefix(caps: &[CapabilityId]) -> Option<String> {
+    let store = CAPABILITY_STORE.lock();
+    caps.iter()
+        .filter_map(|id| store.get(id))
+        .find_map(|entry| match &entry.cap {
+            Capability::Spawn {
+                allowed_prefix: Some(prefix),
+                ..
+            } => Some(prefix.clone()),
+            _ => None,
+        })
+}
+
+/// Returns the most permissive `(max_children, max_depth)` across the cap set's
+/// `Spawn` capabilities, or `None` if it has none.
+pub fn spawn_limits(caps: &[CapabilityId]) -> Option<(u32, u32)> {
+    let store = CAPABILITY_STORE.lock();
+    caps.iter()
+        .filter_map(|id| store.get(id))
+        .filter_map(|entry| match entry.cap {
+            Capability::Spawn {
+                max_children,
+                max_depth,
+                ..
+            } => Some((max_children, max_depth)),
+            _ => None,
+        })
+        .reduce(|(c1, d1), (c2, d2)| (c1.max(c2), d1.max(d2)))
+}
+
 /// Convenience: check if a cap set allows networking layer access.
 pub fn can_access_network(caps: &[CapabilityId]) -> bool {
     find_capability(caps, |c| matches!(c, Capability::Network))
 }
 
-/// Convenience: check if a cap set allows reading a file at `path`.
+/// Convenience: check if a cap set grants direct access to I/O port `port`.
+pub fn can_access_port(caps: &[CapabilityId], port: u16) -> bool {
+    find_capability(
+        caps,
+        |c| matches!(c, Capability::Port { port: p } if *p == port),
+    )
+}
+
+/// Convenience: check if a cap set may read the precise clock. Outside strict mode
+/// every agent may.
+pub fn can_read_clock(caps: &[CapabilityId]) -> bool {
+    !strict_mode() || find_capability(caps, |c| matches!(c, Capability::Clock))
+}
+
+/// Convenience: check if a cap set may reboot or shut down the machine.
+pub fn can_control_power(caps: &[CapabilityId]) -> bool {
+    find_capability(caps, |c| matches!(c, Capability::Power))
+}
+
+/// Convenience: check if a cap set may register dynamic files served by the holder.
+pub fn can_serve_files(caps: &[CapabilityId]) -> bool {
+    find_capability(caps, |c| matches!(c, Capability::ServeFiles))
+}
+
+/// Convenience: check if a cap set grants raw Ethernet access.
+/// `Capability::Network` alone is not enough.
+pub fn can_use_raw_network(caps: &[CapabilityId]) -> bool {
+    find_capability(caps, |c| matches!(c, Capability::RawNetwork))
+}
+
+/// Convenience: check if a cap set allows reading a file at `path`. See `path_under`
+/// for how the path is matched.
 pub fn can_read_file(caps: &[CapabilityId], path: &str) -> bool {
     find_capability(caps, |c| {
         matches!(c,
             Capability::FileSystem { path_prefix, read: true, .. }
-            if path.starts_with(path_prefix.as_str())
+            if path_under(path, path_prefix)
         )
     })
 }
 
-/// Convenience: check if a cap set allows writing a file at `path`.
+/// Convenience: check if a cap set allows writing a file at `path`. See `path_under`
+/// for how the path is matched.
 pub fn can_write_file(caps: &[CapabilityId], path: &str) -> bool {
     find_capability(caps, |c| {
         matches!(c,
             Capability::FileSystem { path_prefix, write: true, .. }
-            if path.starts_with(path_prefix.as_str())
+            if path_under(path, path_prefix)
         )
     })
 }
 
+/// Whether `path` lies under a granted `prefix`. Only canonical paths (as returned by
+/// `vfs::normalize_path`) match: one with `.` or `..` segments could pass a plain
+/// prefix test yet name a file elsewhere (`/agent/../system/x`), so callers must
+/// canonicalize first and hand the same canonical path to the VFS. A prefix without a
+/// trailing `/` matches whole segments only, so `/agent` does not cover `/agents`.
+pub(crate) fn path_under(path: &str, prefix: &str) -> bool {
+    if crate::vfs::normalize_path(path).as_deref() != Some(path) {
+        return false;
+    }
+    match path.strip_prefix(prefix) {
+        Some(rest) => prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+/// Returns every path prefix the cap set grants read access to.
+pub fn readable_prefixes(caps: &[CapabilityId]) -> Vec<String> {
+    let store = CAPABILITY_STORE.lock();
+    caps.iter()
+        .filter_map(|id| store.get(id))
+        .filter_map(|entry| match &entry.cap {
+            Capability::FileSystem {
+                path_prefix,
+                read: true,
+                ..
+            } => Some(path_prefix.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
 /// Returns all resolved capabilities for debugging / display.
 pub fn dump_capabilities(caps: &[CapabilityId]) -> Vec<Capability> {
     let store = CAPABILITY_STORE.lock();
     caps.iter()
-        .filter_map(|id| store.get(id).cloned())
+        .filter_map(|id| store.get(id).map(|entry| entry.cap.clone()))
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::{agent_capabilities, grant_capability_to_agent};
+    use crate::testing;
+
+    #[test_case]
+    fn delegate_then_revoke_leaves_capability_for_delegate() {
+        let cap = create_capability(Capability::Network);
+        assert!(delegate_capability(cap));
+        assert_eq!(refcount(cap), 2);
+
+        assert!(revoke_capability(cap));
+        assert_eq!(refcount(cap), 1);
+        assert!(matches!(
+            validate_capability(cap),
+            Some(Capability::Network)
+        ));
+
+        assert!(revoke_capability(cap));
+        assert_eq!(refcount(cap), 0);
+        assert!(validate_capability(cap).is_none());
+        assert!(!revoke_capability(cap));
+    }
+
+    #[test_case]
+    fn revoke_from_agent_removes_the_id_from_its_list() {
+        let owner = testing::spawn_agent("cap-owner", alloc::vec![Capability::Network]);
+        let delegate = testing::spawn_agent("cap-delegate", Vec::new());
+        let cap = agent_capabilities(owner)[0];
+        delegate_capability(cap);
+        grant_capability_to_agent(delegate, cap);
+
+        assert!(revoke_from_agent(owner, cap));
+        assert!(!agent_capabilities(owner).contains(&cap));
+        assert_eq!(agent_capabilities(delegate), alloc::vec![cap]);
+        assert_eq!(refcount(cap), 1);
+        assert!(!revoke_from_agent(owner, cap));
+
+        assert!(revoke_from_agent(delegate, cap));
+        assert_eq!(refcount(cap), 0);
+    }
+
+    fn files(prefix: &str) -> Vec<CapabilityId> {
+        alloc::vec![create_capability(Capability::FileSystem {
+            path_prefix: String::from(prefix),
+            read: true,
+            write: true,
+        })]
+    }
+
+    #[test_case]
+    fn dot_dot_paths_do_not_pass_the_prefix_check() {
+        let caps = files("/agent/");
+        assert!(!can_read_file(&caps, "/agent/../system/x"));
+        assert!(!can_write_file(&caps, "/agent/../system/x"));
+        assert!(!can_read_file(&caps, "/agent/./x"));
+        assert!(!can_read_file(&caps, "/system/x"));
+    }
+
+    #[test_case]
+    fn nested_paths_under_the_prefix_are_allowed() {
+        let caps = files("/agent/");
+        assert!(can_read_file(&caps, "/agent/sub/x"));
+        assert!(can_write_file(&caps, "/agent/sub/x"));
+
+        let caps = files("/agent");
+        assert!(can_read_file(&caps, "/agent"));
+        assert!(can_read_file(&caps, "/agent/sub/x"));
+        assert!(!can_read_file(&caps, "/agents/x"));
+    }
+
+    #[test_case]
+    fn spawn_prefixes_survive_encoding() {
+        let caps = [
+            Capability::Spawn {
+                max_children: 3,
+                max_depth: 2,
+                allowed_prefix: Some(String::from("/trusted/")),
+            },
+            Capability::Spawn {
+                max_children: 1,
+                max_depth: 1,
+                allowed_prefix: None,
+            },
+        ];
+        let encoded = encode_list(&caps);
+        assert_eq!(decode_list(&encoded), Some(caps.to_vec()));
+        // A prefix flag other than 0 or 1 is malformed.
+        let mut bad = encoded.clone();
+        bad[4 + 9] = 2;
+        assert_eq!(decode_list(&bad), None);
+    }
+
+    #[test_case]
+    fn prefixed_spawn_allows_only_paths_under_the_prefix() {
+        let limited = create_capability(Capability::Spawn {
+            max_children: 1,
+            max_depth: 1,
+            allowed_prefix: Some(String::from("/trusted/")),
+        });
+        assert!(can_spawn(&[limited]));
+        assert!(!can_spawn_bytes(&[limited]));
+        assert!(can_spawn_from(&[limited], "/trusted/tool.wasm"));
+        assert!(!can_spawn_from(&[limited], "/trustedx/tool.wasm"));
+        assert_eq!(spawn_prefix(&[limited]).as_deref(), Some("/trusted/"));
+    }
+}
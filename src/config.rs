@@ -0,0 +1,172 @@
+//! Kernel configuration: `key=value` lines, or a JSON object, read from `CONFIG_PATH`
+//! in the VFS.
+//! The file is parsed on first lookup and cached; any VFS write, delete or rename
+//! touching it drops the cache, so the next lookup sees the new contents.
+
+use crate::json::Value;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+pub const CONFIG_PATH: &str = "/etc/kernel.conf";
+
+/// Parsed `CONFIG_PATH`, or `None` until the next lookup re-reads it.
+static CACHE: Mutex<Option<BTreeMap<String, String>>> = Mutex::new(None);
+
+/// Bumped by every invalidation, so a lookup that read the file before a concurrent
+/// write does not cache the stale contents.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Parse `key=value` lines. Keys and values are trimmed; blank lines, `#` comments and
+/// lines without `=` are skipped. A key given twice keeps its last value.
+pub fn parse(text: &str) -> BTreeMap<String, String> {
+    let mut entries = BTreeMap::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some((key, value)) = line.split_once('=') {
+            let key = key.trim();
+            if !key.is_empty() {
+                entries.insert(String::from(key), String::from(value.trim()));
+            }
+        }
+    }
+    entries
+}
+
+/// Flatten a JSON object into `parse`'s form: nested keys are joined with `.`, so
+/// `{"dns": {"server": "10.0.2.3"}}` sets `dns.server`. Numbers and booleans become
+/// their JSON text; nulls and arrays are skipped. `None` if the text is not valid JSON
+/// or not an object.
+pub fn parse_json(text: &str) -> Option<BTreeMap<String, String>> {
+    let value = crate::json::parse(text).ok()?;
+    if !matches!(value, Value::Object(_)) {
+        return None;
+    }
+    let mut entries = BTreeMap::new();
+    flatten(&mut String::new(), &value, &mut entries);
+    Some(entries)
+}
+
+fn flatten(prefix: &mut String, value: &Value, entries: &mut BTreeMap<String, String>) {
+    let text = match value {
+        Value::Object(members) => {
+            for (key, member) in members {
+                let len = prefix.len();
+                if !prefix.is_empty() {
+                    prefix.push('.');
+                }
+                prefix.push_str(key);
+                flatten(prefix, member, entries);
+                prefix.truncate(len);
+            }
+            return;
+        }
+        Value::Null | Value::Array(_) => return,
+        Value::Bool(b) => b.to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::String(s) => s.clone(),
+    };
+    entries.insert(prefix.clone(), text);
+}
+
+/// Parse the config file's contents: JSON if it starts with `{`, `key=value` lines
+/// otherwise. Malformed JSON yields no entries.
+fn parse_any(text: &str) -> BTreeMap<String, String> {
+    if text.trim_start().starts_with('{') {
+        parse_json(text).unwrap_or_default()
+    } else {
+        parse(text)
+    }
+}
+
+/// The value of `key`, or `None` if it is unset or there is no config file.
+pub fn get(key: &str) -> Option<String> {
+    if let Some(entries) = CACHE.lock().as_ref() {
+        return entries.get(key).cloned();
+    }
+
+    let generation = GENERATION.load(Ordering::Acquire);
+    let entries = crate::vfs::open_file(CONFIG_PATH)
+        .map(|data| parse_any(&String::from_utf8_lossy(&data)))
+        .unwrap_or_default();
+    let value = entries.get(key).cloned();
+    let mut cache = CACHE.lock();
+    if GENERATION.load(Ordering::Acquire) == generation {
+        *cache = Some(entries);
+    }
+    value
+}
+
+/// The value of `key` as an integer, in decimal or `0x` hex. `None` if it is unset or
+/// does not parse.
+pub fn get_int(key: &str) -> Option<i64> {
+    let value = get(key)?;
+    let (digits, negative) = match value.strip_prefix('-') {
+        Some(rest) => (rest, true),
+        None => (value.as_str(), false),
+    };
+    let magnitude = match digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        Some(hex) => i64::from_str_radix(hex, 16).ok()?,
+        None => digits.parse::<i64>().ok()?,
+    };
+    Some(if negative { -magnitude } else { magnitude })
+}
+
+/// Called by the VFS for every mutation, after it has released its own locks; drops
+/// the cache if `path` is the config file.
+pub fn invalidate_path(path: &str) {
+    if path == CONFIG_PATH {
+        let mut cache = CACHE.lock();
+        GENERATION.fetch_add(1, Ordering::Release);
+        *cache = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn key_value_lines_skip_comments_and_keep_the_last_value() {
+        let entries = parse("# comment\n dns.server = 10.0.2.3 \n\nnot a pair\nx=1\nx=2\n=7\n");
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries["dns.server"], "10.0.2.3");
+        assert_eq!(entries["x"], "2");
+    }
+
+    #[test_case]
+    fn json_objects_flatten_to_dotted_keys() {
+        let entries = parse_json(
+            r#"{"dns": {"server": "1.1.1.1"}, "net": {"rate": 5}, "on": true, "skip": null}"#,
+        )
+        .unwrap();
+        assert_eq!(entries["dns.server"], "1.1.1.1");
+        assert_eq!(entries["net.rate"], "5");
+        assert_eq!(entries["on"], "true");
+        assert!(!entries.contains_key("skip"));
+        assert!(parse_json("[1, 2]").is_none());
+    }
+
+    #[test_case]
+    fn writing_the_config_file_invalidates_the_cache() {
+        crate::vfs::write_file(CONFIG_PATH, b"test.limit = 0x10\ntest.offset=-3\n", 0);
+        assert_eq!(get_int("test.limit"), Some(16));
+        assert_eq!(get_int("test.offset"), Some(-3));
+        assert_eq!(get_int("test.missing"), None);
+
+        crate::vfs::write_file(CONFIG_PATH, b"test.limit = 32\ntest.name = kernel\n", 0);
+        assert_eq!(get_int("test.limit"), Some(32));
+        assert_eq!(get("test.name").as_deref(), Some("kernel"));
+        assert_eq!(get_int("test.name"), None);
+
+        crate::vfs::delete_file(CONFIG_PATH, 0);
+        assert_eq!(get("test.limit"), None);
+    }
+}
@@ -0,0 +1,242 @@
+//! Crash diagnostics written to serial by the panic handler: uptime, control and
+//! stack registers, a frame-pointer backtrace and the tail of the serial log.
+//! Nothing here allocates, so it is safe to use after an allocation failure.
+
+use core::arch::asm;
+use core::fmt::{self, Write};
+use spin::Mutex;
+
+/// Bytes of recent serial output kept for crash dumps.
+const LOG_TAIL_SIZE: usize = 2048;
+/// Frames printed in a backtrace.
+pub const MAX_FRAMES: usize = 16;
+/// Largest gap accepted between consecutive frame pointers; anything larger means the
+/// chain is corrupt or frame pointers are not in use.
+const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+/// Ring buffer of the most recent serial output.
+struct LogTail {
+    buf: [u8; LOG_TAIL_SIZE],
+    /// Next write position.
+    head: usize,
+    /// Whether the buffer has wrapped at least once.
+    full: bool,
+}
+
+impl Write for LogTail {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            self.buf[self.head] = byte;
+            self.head = (self.head + 1) % LOG_TAIL_SIZE;
+            self.full |= self.head == 0;
+        }
+        Ok(())
+    }
+}
+
+static LOG_TAIL: Mutex<LogTail> = Mutex::new(LogTail {
+    buf: [0; LOG_TAIL_SIZE],
+    head: 0,
+    full: false,
+});
+
+/// Append formatted output to the log tail. Called for everything printed to serial.
+pub fn record(args: fmt::Arguments) {
+    if let Some(mut tail) = LOG_TAIL.try_lock() {
+        let _ = tail.write_fmt(args);
+    }
+}
+
+/// Registers captured at the point of the crash dump.
+#[derive(Debug, Clone, Copy)]
+pub struct Registers {
+    pub rsp: u64,
+    pub rbp: u64,
+    pub rflags: u64,
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+}
+
+/// Capture the current stack, flag and control registers.
+#[inline(always)]
+pub fn capture_registers() -> Registers {
+    let (rsp, rbp, rflags, cr0, cr2, cr3, cr4): (u64, u64, u64, u64, u64, u64, u64);
+    unsafe {
+        asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags));
+        asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+        asm!("pushfq", "pop {}", out(reg) rflags, options(nomem, preserves_flags));
+        asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack, preserves_flags));
+        asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack, preserves_flags));
+        asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+        asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack, preserves_flags));
+    }
+    Registers {
+        rsp,
+        rbp,
+        rflags,
+        cr0,
+        cr2,
+        cr3,
+        cr4,
+    }
+}
+
+/// Follow a frame-pointer chain starting at `rbp`, storing return addresses in
+/// `frames`. Each frame holds the caller's `rbp` at `[rbp]` and the return address at
+/// `[rbp + 8]`; `read_word` fetches a word or returns `None` if it is unreadable.
+/// Stops at a null, misaligned or non-increasing frame pointer. Returns the number of
+/// frames stored.
+pub fn walk_frames<F>(mut rbp: usize, read_word: F, frames: &mut [usize]) -> usize
+where
+    F: Fn(usize) -> Option<usize>,
+{
+    let mut count = 0;
+    while count < frames.len() && rbp != 0 && rbp % 8 == 0 {
+        let (Some(next), Some(ret)) = (read_word(rbp), read_word(rbp + 8)) else {
+            break;
+        };
+        if ret == 0 {
+            break;
+        }
+        frames[count] = ret;
+        count += 1;
+
+        if next <= rbp || next - rbp > MAX_FRAME_SIZE {
+            break;
+        }
+        rbp = next;
+    }
+    count
+}
+
+/// Write a backtrace as `  #N 0x<addr>` lines.
+pub fn format_backtrace<W: Write>(out: &mut W, frames: &[usize]) -> fmt::Result {
+    if frames.is_empty() {
+        return writeln!(out, "  <no frames; built without frame pointers?>");
+    }
+    for (i, addr) in frames.iter().enumerate() {
+        writeln!(out, "  #{i:<2} {addr:#018x}")?;
+    }
+    Ok(())
+}
+
+/// Write the full crash dump to `out`.
+pub fn dump<W: Write>(out: &mut W, regs: &Registers) -> fmt::Result {
+    writeln!(
+        out,
+        "---- crash dump (uptime {} ms) ----",
+        crate::time::uptime_ms()
+    )?;
+    writeln!(
+        out,
+        "rsp={:#018x} rbp={:#018x} rflags={:#018x}",
+        regs.rsp, regs.rbp, regs.rflags
+    )?;
+    writeln!(
+        out,
+        "cr0={:#018x} cr2={:#018x} cr3={:#018x} cr4={:#018x}",
+        regs.cr0, regs.cr2, regs.cr3, regs.cr4
+    )?;
+
+    writeln!(out, "backtrace:")?;
+    let mut frames = [0usize; MAX_FRAMES];
+    // The chain lives on the current kernel stack, which is mapped while we run on it.
+    let count = walk_frames(
+        regs.rbp as usize,
+        |addr| Some(unsafe { core::ptr::read_volatile(addr as *const usize) }),
+        &mut frames,
+    );
+    format_backtrace(out, &frames[..count])?;
+
+    writeln!(out, "recent log:")?;
+    write_log_tail(out)?;
+    writeln!(out, "---- end of crash dump ----")
+}
+
+/// Write the recent serial output kept for crash dumps, oldest first. Nothing is
+/// written if the tail is busy.
+pub fn write_log_tail<W: Write>(out: &mut W) -> fmt::Result {
+    // The panic may have happened mid-print; don't wait on the lock.
+    if let Some(tail) = LOG_TAIL.try_lock() {
+        let (older, newer) = tail.buf.split_at(tail.head);
+        let (first, second) = if tail.full {
+            // The oldest line was partly overwritten; start at the next full one.
+            let skip = newer
+                .iter()
+                .position(|&b| b == b'\n')
+                .map_or(newer.len(), |i| i + 1);
+            (&newer[skip..], older)
+        } else {
+            (older, &[][..])
+        };
+        for &byte in first.iter().chain(second) {
+            let c = if byte == b'\n' || (0x20..0x7f).contains(&byte) {
+                byte as char
+            } else {
+                '?'
+            };
+            out.write_char(c)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::collections::BTreeMap;
+    use alloc::string::String;
+
+    /// A synthetic stack of `(rbp, saved rbp, return address)` frames.
+    fn stack(frames: &[(usize, usize, usize)]) -> BTreeMap<usize, usize> {
+        let mut words = BTreeMap::new();
+        for &(rbp, next, ret) in frames {
+            words.insert(rbp, next);
+            words.insert(rbp + 8, ret);
+        }
+        words
+    }
+
+    #[test_case]
+    fn backtrace_follows_the_frame_chain() {
+        let words = stack(&[
+            (0x1000, 0x1040, 0xAAA0),
+            (0x1040, 0x10C0, 0xBBB0),
+            (0x10C0, 0, 0xCCC0),
+        ]);
+        let mut frames = [0; MAX_FRAMES];
+        let count = walk_frames(0x1000, |addr| words.get(&addr).copied(), &mut frames);
+        assert_eq!(frames[..count], [0xAAA0, 0xBBB0, 0xCCC0]);
+
+        let mut out = String::new();
+        format_backtrace(&mut out, &frames[..count]).unwrap();
+        assert_eq!(
+            out,
+            "  #0  0x000000000000aaa0\n  #1  0x000000000000bbb0\n  #2  0x000000000000ccc0\n"
+        );
+    }
+
+    #[test_case]
+    fn backtrace_stops_at_a_corrupt_chain() {
+        // The second frame points back down the stack.
+        let words = stack(&[(0x2000, 0x2040, 0x1111), (0x2040, 0x2000, 0x2222)]);
+        let mut frames = [0; MAX_FRAMES];
+        let count = walk_frames(0x2000, |addr| words.get(&addr).copied(), &mut frames);
+        assert_eq!(frames[..count], [0x1111, 0x2222]);
+
+        assert_eq!(
+            walk_frames(0x2004, |addr| words.get(&addr).copied(), &mut frames),
+            0
+        );
+        assert_eq!(
+            walk_frames(0x3000, |addr| words.get(&addr).copied(), &mut frames),
+            0
+        );
+
+        let mut out = String::new();
+        format_backtrace(&mut out, &[]).unwrap();
+        assert_eq!(out, "  <no frames; built without frame pointers?>\n");
+    }
+}
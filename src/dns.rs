@@ -1,64 +1,286 @@
-use crate::net::NETWORK;
+use crate::bytes::{read_slice, read_u16_be, read_u32_be, read_u8};
+use crate::net::{alloc_ephemeral_port, free_ephemeral_port, with_network, NetError, NetworkStack};
 use crate::serial_println;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
 use alloc::vec;
 use alloc::vec::Vec;
+use core::ops::Range;
+use core::sync::atomic::{AtomicU64, Ordering};
+use smoltcp::iface::SocketHandle;
 use smoltcp::socket::udp::{PacketBuffer, PacketMetadata, Socket as UdpSocket};
 use smoltcp::time::Instant;
 use smoltcp::wire::{IpAddress, IpEndpoint, Ipv4Address};
+use spin::Mutex;
 
-/// QEMU SLIRP default DNS server
+/// QEMU SLIRP default DNS server, used unless `dns.server` is configured.
 const DNS_SERVER: Ipv4Address = Ipv4Address::new(10, 0, 2, 3);
 const DNS_PORT: u16 = 53;
-const LOCAL_PORT: u16 = 41234;
 
-/// Resolve a domain name to an IPv4 address using a minimal DNS stub resolver.
-/// Constructs a raw DNS query packet, sends it over UDP, polls for a response,
-/// and parses the first A record from the answer section.
-pub fn resolve(domain: &str) -> Option<[u8; 4]> {
-    let query = build_dns_query(domain);
+const QTYPE_A: u16 = 1;
+const QTYPE_MX: u16 = 15;
+const QTYPE_TXT: u16 = 16;
+const QTYPE_SOA: u16 = 6;
 
-    let mut net_guard = NETWORK.lock();
-    let net = net_guard.as_mut()?;
+const RCODE_NOERROR: u8 = 0;
+const RCODE_NXDOMAIN: u8 = 3;
 
-    // Create UDP socket with small buffers
-    let rx_buffer = PacketBuffer::new(vec![PacketMetadata::EMPTY; 4], vec![0u8; 1024]);
-    let tx_buffer = PacketBuffer::new(vec![PacketMetadata::EMPTY; 4], vec![0u8; 1024]);
-    let mut socket = UdpSocket::new(rx_buffer, tx_buffer);
-    socket.bind(LOCAL_PORT).ok()?;
+/// Maximum compression pointers followed while decoding a single name.
+const MAX_NAME_POINTERS: usize = 16;
 
-    let handle = net.sockets.add(socket);
+/// VFS path of the hosts file loaded at boot; the initramfs mounts the archive's
+/// `etc/hosts` here.
+pub const HOSTS_PATH: &str = "/etc/hosts";
 
-    // Send the DNS query
-    {
-        let socket = net.sockets.get_mut::<UdpSocket>(handle);
-        let endpoint = IpEndpoint::new(IpAddress::Ipv4(DNS_SERVER), DNS_PORT);
-        socket.send_slice(&query, endpoint).ok()?;
+/// Pinned name -> address mappings. Consulted before the network and never expire.
+static OVERRIDES: Mutex<BTreeMap<String, [u8; 4]>> = Mutex::new(BTreeMap::new());
+
+/// Pin `domain` to `ip`; `resolve` returns it without touching the network.
+pub fn set_override(domain: &str, ip: [u8; 4]) {
+    OVERRIDES.lock().insert(domain.to_ascii_lowercase(), ip);
+}
+
+/// Remove a pinned mapping. Returns true if one existed.
+pub fn clear_override(domain: &str) -> bool {
+    OVERRIDES
+        .lock()
+        .remove(&domain.to_ascii_lowercase())
+        .is_some()
+}
+
+/// Load `/etc/hosts`-style entries (`<ipv4> <name> [aliases...]`, `#` comments)
+/// from the VFS as overrides. Non-IPv4 lines are skipped. Returns the number of
+/// names pinned.
+pub fn load_hosts_file() -> usize {
+    let Some(contents) = crate::vfs::open_file(HOSTS_PATH) else {
+        return 0;
+    };
+    let count = pin_hosts(core::str::from_utf8(&contents).unwrap_or(""));
+    serial_println!(
+        "[DNS] Loaded {} host override(s) from {}",
+        count,
+        HOSTS_PATH
+    );
+    count
+}
+
+/// Pin every name in hosts-file `text`; returns how many were pinned.
+fn pin_hosts(text: &str) -> usize {
+    let mut count = 0;
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or("");
+        let mut fields = line.split_whitespace();
+        let Some(ip) = fields.next().and_then(parse_ipv4) else {
+            continue;
+        };
+        for name in fields {
+            set_override(name, ip);
+            count += 1;
+        }
     }
+    count
+}
 
-    // Poll to push the packet out and wait for a response
-    let mut result: Option<[u8; 4]> = None;
-    for tick in 0..200 {
-        net.iface.poll(
-            Instant::from_millis((tick * 10) as i64),
-            &mut net.device,
-            &mut net.sockets,
-        );
+/// Parse a dotted-quad IPv4 address.
+fn parse_ipv4(text: &str) -> Option<[u8; 4]> {
+    let mut ip = [0u8; 4];
+    let mut octets = text.split('.');
+    for slot in ip.iter_mut() {
+        *slot = octets.next()?.parse().ok()?;
+    }
+    octets.next().is_none().then_some(ip)
+}
 
-        let socket = net.sockets.get_mut::<UdpSocket>(handle);
-        if socket.can_recv() {
-            let mut buf = vec![0u8; 512];
-            if let Ok((size, _)) = socket.recv_slice(&mut buf) {
-                if size > 12 {
-                    result = parse_dns_response(&buf[..size]);
-                    break;
-                }
-            }
+/// Upper bound on how long a cached answer is trusted, whatever its TTL.
+pub const MAX_CACHE_TTL_SECS: u32 = 3600;
+/// How long a failed lookup is cached when the response carries no SOA record.
+pub const DEFAULT_NEGATIVE_TTL_SECS: u32 = 30;
+/// Upper bound on how long a failed lookup is cached.
+pub const MAX_NEGATIVE_TTL_SECS: u32 = 300;
+
+/// How long a lookup waits on another caller's query for the same name before giving
+/// up on it and querying itself. Longer than any query's `QUERY_ATTEMPTS`, so only an
+/// abandoned marker is ever taken over.
+pub const IN_FLIGHT_TIMEOUT_MS: u64 = 5000;
+
+/// Times a query is sent before a silent server counts as no answer.
+pub const QUERY_ATTEMPTS: u32 = 3;
+/// How long each attempt waits for the reply, before jitter.
+pub const QUERY_TIMEOUT_MS: u64 = 700;
+/// Up to this much is added at random to each attempt's wait, so that lookups whose
+/// queries went unanswered together do not all resend at the same moment.
+pub const QUERY_JITTER_MS: u64 = 300;
+
+struct CacheEntry {
+    /// `None` records that the name has no A record (a negative entry).
+    ip: Option<[u8; 4]>,
+    expires_ms: u64,
+}
+
+enum CacheSlot {
+    Answer(CacheEntry),
+    /// A `resolve` started a query for the name at `started_ms`; later callers wait
+    /// for its result instead of sending their own.
+    InFlight {
+        started_ms: u64,
+    },
+    /// The last query failed without a cacheable answer (e.g. a timeout). Kept so the
+    /// callers that waited on it get the same error; the next lookup queries again.
+    Failed(DnsError),
+}
+
+/// A-record answers, keyed by lowercased name, kept for the record's TTL. Failed
+/// lookups are kept too, for the negative TTL (see `negative_ttl`), as are markers
+/// for queries still in flight.
+static CACHE: Mutex<BTreeMap<String, CacheSlot>> = Mutex::new(BTreeMap::new());
+static CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+static CACHE_MISSES: AtomicU64 = AtomicU64::new(0);
+static COALESCED: AtomicU64 = AtomicU64::new(0);
+
+/// Resolver cache counters. Override lookups count as neither.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Lookups answered by waiting on another caller's query for the same name.
+    pub coalesced: u64,
+    /// Entries currently held, including expired ones not yet replaced.
+    pub entries: usize,
+}
+
+pub fn cache_stats() -> CacheStats {
+    CacheStats {
+        hits: CACHE_HITS.load(Ordering::Relaxed),
+        misses: CACHE_MISSES.load(Ordering::Relaxed),
+        coalesced: COALESCED.load(Ordering::Relaxed),
+        entries: CACHE
+            .lock()
+            .values()
+            .filter(|slot| matches!(slot, CacheSlot::Answer(_)))
+            .count(),
+    }
+}
+
+/// Drop every cached answer. Counters are kept.
+pub fn flush_cache() {
+    CACHE.lock().clear();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnsError {
+    /// The network stack was busy for longer than `net::LOCK_TIMEOUT_MS`.
+    Timeout,
+    /// No network, no response, or no usable answer.
+    NotFound,
+    /// The name has an empty or over-long label, or is too long to encode.
+    InvalidName,
+}
+
+/// What a `resolve` should do next, after one look at the cache.
+enum Lookup {
+    Done(Result<[u8; 4], DnsError>),
+    /// Another caller's query for the name is in flight; look again shortly.
+    Wait,
+    /// No usable entry: this caller now holds the in-flight marker and must query,
+    /// then hand the response to `finish_query`.
+    Query {
+        now: u64,
+    },
+}
+
+/// Check the cache for `key`. `waited` says whether this caller has already waited on
+/// another's query, so its answer counts as coalesced rather than a hit.
+fn check_cache(key: &str, waited: bool) -> Lookup {
+    let now = crate::time::uptime_ms();
+    let mut cache = CACHE.lock();
+    match cache.get(key) {
+        Some(CacheSlot::Answer(entry)) if entry.expires_ms > now => {
+            let counter = if waited { &COALESCED } else { &CACHE_HITS };
+            counter.fetch_add(1, Ordering::Relaxed);
+            Lookup::Done(entry.ip.ok_or(DnsError::NotFound))
+        }
+        Some(CacheSlot::InFlight { started_ms })
+            if now.saturating_sub(*started_ms) < IN_FLIGHT_TIMEOUT_MS =>
+        {
+            Lookup::Wait
+        }
+        Some(CacheSlot::Failed(e)) if waited => {
+            COALESCED.fetch_add(1, Ordering::Relaxed);
+            Lookup::Done(Err(*e))
+        }
+        // Missing, expired, a stale marker or an old failure: query it ourselves.
+        _ => {
+            cache.insert(String::from(key), CacheSlot::InFlight { started_ms: now });
+            CACHE_MISSES.fetch_add(1, Ordering::Relaxed);
+            Lookup::Query { now }
         }
     }
+}
 
-    net.sockets.remove(handle);
+/// Cache the outcome of the query `check_cache` asked for at `now`. Replacing the
+/// in-flight marker releases the callers waiting on it.
+fn finish_query(
+    key: String,
+    now: u64,
+    response: Result<Vec<u8>, DnsError>,
+) -> Result<[u8; 4], DnsError> {
+    let answer = response
+        .as_ref()
+        .ok()
+        .and_then(|response| match parse_dns_response(response) {
+            Some((ip, ttl)) => Some((Some(ip), ttl.min(MAX_CACHE_TTL_SECS))),
+            None => negative_ttl(response).map(|ttl| (None, ttl)),
+        });
+    match answer {
+        Some((ip, ttl)) => {
+            let entry = CacheEntry {
+                ip,
+                expires_ms: now + u64::from(ttl) * 1000,
+            };
+            CACHE.lock().insert(key, CacheSlot::Answer(entry));
+            ip.ok_or(DnsError::NotFound)
+        }
+        None => {
+            let e = response.err().unwrap_or(DnsError::NotFound);
+            CACHE.lock().insert(key, CacheSlot::Failed(e));
+            Err(e)
+        }
+    }
+}
 
-    if let Some(ip) = result {
+/// Resolve a domain name to an IPv4 address using a minimal DNS stub resolver.
+/// Pinned overrides (see `set_override`) are returned directly, then unexpired cache
+/// entries, including cached failures. Otherwise constructs a raw DNS query packet,
+/// sends it over UDP (resending up to `QUERY_ATTEMPTS` times), polls for a response,
+/// and parses the first A record from the answer section, caching it for its TTL
+/// (capped at `MAX_CACHE_TTL_SECS`). NXDOMAIN and empty answers are cached as
+/// failures; timeouts are only passed on to the callers waiting on this query.
+///
+/// Blocks until the answer arrives. A query already in flight for the name belongs
+/// to an agent parked in the executor, which cannot finish while this blocks, so it
+/// is not waited on; agents use `start_resolve` to share it instead.
+pub fn resolve(domain: &str) -> Result<[u8; 4], DnsError> {
+    let key = domain.to_ascii_lowercase();
+    if let Some(&ip) = OVERRIDES.lock().get(&key) {
+        return Ok(ip);
+    }
+
+    let packet = build_dns_query(domain, QTYPE_A).ok_or(DnsError::InvalidName)?;
+    let now = match check_cache(&key, false) {
+        Lookup::Done(result) => return result,
+        Lookup::Wait => {
+            CACHE_MISSES.fetch_add(1, Ordering::Relaxed);
+            crate::time::uptime_ms()
+        }
+        Lookup::Query { now } => now,
+    };
+    let result = finish_query(key, now, send(&packet));
+    log_resolution(domain, &result);
+    result
+}
+
+fn log_resolution(domain: &str, result: &Result<[u8; 4], DnsError>) {
+    if let Ok(ip) = result {
         serial_println!(
             "[DNS] Resolved {} -> {}.{}.{}.{}",
             domain,
@@ -70,17 +292,317 @@ pub fn resolve(domain: &str) -> Option<[u8; 4]> {
     } else {
         serial_println!("[DNS] Failed to resolve {}", domain);
     }
+}
+
+/// The first step of a lookup that must not block its caller.
+pub enum Resolve {
+    Done(Result<[u8; 4], DnsError>),
+    /// The answer is not known yet; `poll` the lookup until it is.
+    Pending(PendingResolve),
+}
+
+/// Look `domain` up as `resolve` does, but without blocking: the lookup either
+/// completes from an override or the cache, or sends its query (or finds another
+/// caller's in flight) and is handed back for the executor to `poll` between slices.
+/// Concurrent lookups of one name thus share a single query.
+pub fn start_resolve(domain: &str) -> Resolve {
+    let key = domain.to_ascii_lowercase();
+    if let Some(&ip) = OVERRIDES.lock().get(&key) {
+        return Resolve::Done(Ok(ip));
+    }
+    let Some(query) = build_dns_query(domain, QTYPE_A) else {
+        return Resolve::Done(Err(DnsError::InvalidName));
+    };
+
+    let mut lookup = PendingResolve {
+        domain: String::from(domain),
+        key,
+        query,
+        started_ms: 0,
+        waited: false,
+        exchange: None,
+    };
+    match lookup.poll() {
+        Some(result) => Resolve::Done(result),
+        None => Resolve::Pending(lookup),
+    }
+}
+
+/// A lookup started by `start_resolve` that has no answer yet. Dropping it abandons
+/// the lookup, handing the name to the next caller.
+pub struct PendingResolve {
+    domain: String,
+    key: String,
+    query: Vec<u8>,
+    /// When this lookup took the in-flight marker.
+    started_ms: u64,
+    /// Whether it has waited on another caller's query.
+    waited: bool,
+    /// This lookup's own query, once it holds the in-flight marker.
+    exchange: Option<Exchange>,
+}
+
+impl PendingResolve {
+    /// Advance the lookup without blocking; `Some` once it has its result. While
+    /// another caller's query is in flight this only looks at the cache; once this
+    /// lookup holds the marker it sends its own query and polls for the reply.
+    pub fn poll(&mut self) -> Option<Result<[u8; 4], DnsError>> {
+        let Some(exchange) = self.exchange.as_mut() else {
+            return match check_cache(&self.key, self.waited) {
+                Lookup::Done(result) => Some(result),
+                Lookup::Wait => {
+                    self.waited = true;
+                    None
+                }
+                Lookup::Query { now } => {
+                    self.started_ms = now;
+                    let opened = with_network(|net| {
+                        Exchange::open(net, self.query.clone(), crate::time::uptime_ms())
+                    });
+                    match opened {
+                        Ok(Some(exchange)) => {
+                            self.exchange = Some(exchange);
+                            None
+                        }
+                        Ok(None) | Err(NetError::Unavailable) => {
+                            Some(self.finish(Err(DnsError::NotFound)))
+                        }
+                        Err(NetError::Timeout) => Some(self.finish(Err(DnsError::Timeout))),
+                    }
+                }
+            };
+        };
+
+        let progress = match with_network(|net| exchange.poll(net, crate::time::uptime_ms())) {
+            Ok(progress) => progress,
+            // Busy now; look again on the next slice.
+            Err(NetError::Timeout) => return None,
+            Err(NetError::Unavailable) => Progress::GaveUp,
+        };
+        let response = match progress {
+            Progress::Waiting => return None,
+            Progress::Reply(reply) => Ok(reply),
+            Progress::GaveUp => Err(DnsError::NotFound),
+        };
+        if let Some(exchange) = self.exchange.take() {
+            exchange.close();
+        }
+        Some(self.finish(response))
+    }
+
+    fn finish(&self, response: Result<Vec<u8>, DnsError>) -> Result<[u8; 4], DnsError> {
+        let result = finish_query(self.key.clone(), self.started_ms, response);
+        log_resolution(&self.domain, &result);
+        result
+    }
+}
+
+impl Drop for PendingResolve {
+    fn drop(&mut self) {
+        let Some(exchange) = self.exchange.take() else {
+            return;
+        };
+        exchange.close();
+        // Let the callers waiting on this query take the name over now rather than
+        // after `IN_FLIGHT_TIMEOUT_MS`.
+        let mut cache = CACHE.lock();
+        if matches!(cache.get(&self.key), Some(CacheSlot::InFlight { started_ms }) if *started_ms == self.started_ms)
+        {
+            cache.remove(&self.key);
+        }
+    }
+}
+
+/// Look up the mail exchangers for `domain` as `(preference, exchange)` pairs,
+/// in the order the server returned them.
+pub fn resolve_mx(domain: &str) -> Result<Vec<(u16, String)>, DnsError> {
+    let records = parse_mx_response(&query(domain, QTYPE_MX)?);
+    serial_println!("[DNS] {} MX record(s) for {}", records.len(), domain);
+    Ok(records)
+}
+
+/// Look up the TXT records for `domain`. Each record's character-strings are
+/// concatenated into one string.
+pub fn resolve_txt(domain: &str) -> Result<Vec<String>, DnsError> {
+    let records = parse_txt_response(&query(domain, QTYPE_TXT)?);
+    serial_println!("[DNS] {} TXT record(s) for {}", records.len(), domain);
+    Ok(records)
+}
+
+/// Send a single DNS query of type `qtype` and return the raw response packet.
+fn query(domain: &str, qtype: u16) -> Result<Vec<u8>, DnsError> {
+    send(&build_dns_query(domain, qtype).ok_or(DnsError::InvalidName)?)
+}
+
+/// Send an encoded query and return the raw response packet.
+fn send(query: &[u8]) -> Result<Vec<u8>, DnsError> {
+    match with_network(|net| exchange(net, query)) {
+        Ok(response) => response.ok_or(DnsError::NotFound),
+        Err(NetError::Timeout) => Err(DnsError::Timeout),
+        Err(NetError::Unavailable) => Err(DnsError::NotFound),
+    }
+}
 
+/// Send `query` to the DNS server and poll for its response, blocking the network
+/// stack meanwhile.
+fn exchange(net: &mut NetworkStack, query: &[u8]) -> Option<Vec<u8>> {
+    // The stack is held throughout, so time is counted in polls rather than read from
+    // the clock.
+    let mut now_ms = 0;
+    let mut exchange = Exchange::open(net, query.to_vec(), now_ms)?;
+    let result = loop {
+        match exchange.poll(net, now_ms) {
+            Progress::Waiting => now_ms += 10,
+            Progress::Reply(reply) => break Some(reply),
+            Progress::GaveUp => break None,
+        }
+    };
+    exchange.close_in(net);
     result
 }
 
-/// Build a minimal DNS A-record query packet for the given domain.
-fn build_dns_query(domain: &str) -> Vec<u8> {
+/// How long one attempt waits: `QUERY_TIMEOUT_MS` plus random jitter.
+fn attempt_timeout_ms() -> u64 {
+    QUERY_TIMEOUT_MS + u64::from(crate::rng::next_u16()) % QUERY_JITTER_MS
+}
+
+/// A query sent from its own UDP socket, waiting for the server's reply.
+struct Exchange {
+    handle: SocketHandle,
+    local_port: u16,
+    query: Vec<u8>,
+    server: IpEndpoint,
+    attempts: u32,
+    /// When the current attempt stops waiting, on the caller's clock.
+    deadline_ms: u64,
+}
+
+/// What one `Exchange::poll` found.
+enum Progress {
+    Waiting,
+    Reply(Vec<u8>),
+    /// Every attempt went unanswered, or the query could not be resent.
+    GaveUp,
+}
+
+impl Exchange {
+    /// Open a socket on a random port and send the first attempt of `query`. `None`
+    /// if no port or socket is free or the send fails.
+    fn open(net: &mut NetworkStack, query: Vec<u8>, now_ms: u64) -> Option<Exchange> {
+        // Create UDP socket with small buffers
+        let rx_buffer = PacketBuffer::new(vec![PacketMetadata::EMPTY; 4], vec![0u8; 1024]);
+        let tx_buffer = PacketBuffer::new(vec![PacketMetadata::EMPTY; 4], vec![0u8; 1024]);
+        let mut socket = UdpSocket::new(rx_buffer, tx_buffer);
+        let local_port = alloc_ephemeral_port()?;
+        if socket.bind(local_port).is_err() {
+            free_ephemeral_port(local_port);
+            return None;
+        }
+
+        let Ok(handle) = net.add_socket(socket) else {
+            free_ephemeral_port(local_port);
+            return None;
+        };
+
+        let mut exchange = Exchange {
+            handle,
+            local_port,
+            query,
+            server: IpEndpoint::new(IpAddress::Ipv4(dns_server()), DNS_PORT),
+            attempts: 0,
+            deadline_ms: 0,
+        };
+        if !exchange.send(net, now_ms) {
+            exchange.close_in(net);
+            return None;
+        }
+        // Push the packet out now rather than on the first poll.
+        net.iface.poll(
+            Instant::from_millis(now_ms as i64),
+            &mut net.device,
+            &mut net.sockets,
+        );
+        Some(exchange)
+    }
+
+    /// Send the query again as a new attempt, with a fresh jittered deadline.
+    fn send(&mut self, net: &mut NetworkStack, now_ms: u64) -> bool {
+        self.attempts += 1;
+        self.deadline_ms = now_ms + attempt_timeout_ms();
+        net.sockets
+            .get_mut::<UdpSocket>(self.handle)
+            .send_slice(&self.query, self.server)
+            .is_ok()
+    }
+
+    /// Poll the interface once at `now_ms` and take the reply if it has arrived. An
+    /// attempt that has waited past its deadline is followed by a resend, up to
+    /// `QUERY_ATTEMPTS` in all.
+    fn poll(&mut self, net: &mut NetworkStack, now_ms: u64) -> Progress {
+        net.iface.poll(
+            Instant::from_millis(now_ms as i64),
+            &mut net.device,
+            &mut net.sockets,
+        );
+
+        let socket = net.sockets.get_mut::<UdpSocket>(self.handle);
+        while socket.can_recv() {
+            let mut buf = vec![0u8; 512];
+            let Ok((size, meta)) = socket.recv_slice(&mut buf) else {
+                break;
+            };
+            // Only the socket bound to this query's random port sees the reply; on
+            // top of that, drop stray or spoofed datagrams.
+            if is_reply(&self.query, self.server, meta.endpoint, &buf[..size]) {
+                buf.truncate(size);
+                return Progress::Reply(buf);
+            }
+        }
+
+        let waiting = now_ms < self.deadline_ms;
+        if waiting || (self.attempts < QUERY_ATTEMPTS && self.send(net, now_ms)) {
+            Progress::Waiting
+        } else {
+            Progress::GaveUp
+        }
+    }
+
+    /// Remove the socket and free its port, with the stack already locked.
+    fn close_in(self, net: &mut NetworkStack) {
+        net.sockets.remove(self.handle);
+        free_ephemeral_port(self.local_port);
+    }
+
+    /// `close_in`, locking the stack. The port is freed even if the stack is gone.
+    fn close(self) {
+        let port = self.local_port;
+        if with_network(|net| self.close_in(net)).is_err() {
+            free_ephemeral_port(port);
+        }
+    }
+}
+
+/// The server to query: `dns.server` from the kernel config, or `DNS_SERVER`.
+fn dns_server() -> Ipv4Address {
+    crate::config::get("dns.server")
+        .and_then(|addr| addr.parse().ok())
+        .unwrap_or(DNS_SERVER)
+}
+
+/// Whether `reply`, received from `from`, answers `query` sent to `server`: it must
+/// come from the server's DNS port and echo the query's transaction ID.
+fn is_reply(query: &[u8], server: IpEndpoint, from: IpEndpoint, reply: &[u8]) -> bool {
+    from == server && reply.len() > 12 && reply[..2] == query[..2]
+}
+
+/// Build a minimal DNS query packet of type `qtype` for the given domain, or `None`
+/// if `write_name` cannot encode it.
+fn build_dns_query(domain: &str, qtype: u16) -> Option<Vec<u8>> {
     let mut pkt = Vec::with_capacity(64);
 
     // Header (12 bytes)
     // Transaction ID
-    pkt.extend_from_slice(&[0xAB, 0xCD]);
+    pkt.extend_from_slice(&crate::rng::next_u16().to_be_bytes());
     // Flags: standard query, recursion desired
     pkt.extend_from_slice(&[0x01, 0x00]);
     // QDCOUNT = 1
@@ -93,78 +615,754 @@ fn build_dns_query(domain: &str) -> Vec<u8> {
     pkt.extend_from_slice(&[0x00, 0x00]);
 
     // Question section: encode domain as DNS labels
-    for label in domain.split('.') {
-        pkt.push(label.len() as u8);
-        pkt.extend_from_slice(label.as_bytes());
+    if !write_name(&mut pkt, domain, &mut CompressionTable::new()) {
+        return None;
     }
-    pkt.push(0x00); // Root label terminator
 
-    // QTYPE = A (1)
-    pkt.extend_from_slice(&[0x00, 0x01]);
+    pkt.extend_from_slice(&qtype.to_be_bytes());
     // QCLASS = IN (1)
     pkt.extend_from_slice(&[0x00, 0x01]);
 
-    pkt
+    Some(pkt)
 }
 
-/// Parse a DNS response and extract the first A record's IPv4 address.
-fn parse_dns_response(data: &[u8]) -> Option<[u8; 4]> {
-    if data.len() < 12 {
-        return None;
+/// Parse a DNS response and extract the first A record's IPv4 address and TTL in seconds.
+/// Truncated or malformed packets yield `None` rather than panicking.
+fn parse_dns_response(data: &[u8]) -> Option<([u8; 4], u32)> {
+    answer_records(data)
+        .into_iter()
+        .find(|(rtype, _, rdata)| *rtype == QTYPE_A && rdata.len() == 4)
+        .and_then(|(_, ttl, rdata)| Some((data.get(rdata)?.try_into().ok()?, ttl)))
+}
+
+/// A resource record's type, TTL and the byte range of its RDATA within the packet.
+type Record = (u16, u32, Range<usize>);
+
+/// Walk the answer section of a response.
+fn answer_records(data: &[u8]) -> Vec<Record> {
+    let (mut records, ancount) = resource_records(data);
+    records.truncate(ancount);
+    records
+}
+
+/// Walk the authority section of a response.
+fn authority_records(data: &[u8]) -> Vec<Record> {
+    let (records, ancount) = resource_records(data);
+    records.into_iter().skip(ancount).collect()
+}
+
+/// Parse the answer and authority sections, in that order, stopping at the first
+/// malformed record. Returns the records and the answer count from the header.
+fn resource_records(data: &[u8]) -> (Vec<Record>, usize) {
+    let mut records = Vec::new();
+    let (Some(qdcount), Some(ancount), Some(nscount)) = (
+        read_u16_be(data, 4),
+        read_u16_be(data, 6),
+        read_u16_be(data, 8),
+    ) else {
+        return (records, 0);
+    };
+    let ancount = ancount as usize;
+
+    let mut offset = 12;
+    for _ in 0..qdcount {
+        match read_name(data, offset) {
+            Some((_, next)) => offset = next + 4, // QTYPE (2) + QCLASS (2)
+            None => return (records, ancount),
+        }
+    }
+
+    for _ in 0..ancount + nscount as usize {
+        let Some((_, next)) = read_name(data, offset) else {
+            break;
+        };
+        // TYPE (2) + CLASS (2) + TTL (4) + RDLENGTH (2)
+        let (Some(rtype), Some(ttl), Some(rdlength)) = (
+            read_u16_be(data, next),
+            read_u32_be(data, next + 4),
+            read_u16_be(data, next + 8),
+        ) else {
+            break;
+        };
+        let start = next + 10;
+        if read_slice(data, start, rdlength as usize).is_none() {
+            break;
+        }
+
+        records.push((rtype, ttl, start..start + rdlength as usize));
+        offset = start + rdlength as usize;
     }
 
-    let ancount = u16::from_be_bytes([data[6], data[7]]) as usize;
-    if ancount == 0 {
+    (records, ancount)
+}
+
+/// If `data` is a definitive negative answer for an A query (NXDOMAIN, or success with
+/// no A record), how long it may be cached in seconds: the authority SOA's TTL capped
+/// by its MINIMUM field (RFC 2308), else `DEFAULT_NEGATIVE_TTL_SECS`. `None` for
+/// positive answers and other errors (e.g. SERVFAIL), which are not cached.
+fn negative_ttl(data: &[u8]) -> Option<u32> {
+    let rcode = read_u8(data, 3)? & 0x0F;
+    let negative = match rcode {
+        RCODE_NXDOMAIN => true,
+        RCODE_NOERROR => parse_dns_response(data).is_none(),
+        _ => false,
+    };
+    if !negative {
         return None;
     }
 
-    // Skip the header (12 bytes) and the question section
-    let mut offset = 12;
+    let soa_ttl = authority_records(data)
+        .into_iter()
+        .find(|(rtype, _, _)| *rtype == QTYPE_SOA)
+        .and_then(|(_, ttl, rdata)| {
+            // MNAME, RNAME, then SERIAL, REFRESH, RETRY, EXPIRE, MINIMUM (u32 each).
+            let (_, after_mname) = read_name(data, rdata.start)?;
+            let (_, after_rname) = read_name(data, after_mname)?;
+            let minimum = read_u32_be(data, after_rname + 16)?;
+            (after_rname + 20 <= rdata.end).then_some(ttl.min(minimum))
+        });
+    Some(
+        soa_ttl
+            .unwrap_or(DEFAULT_NEGATIVE_TTL_SECS)
+            .min(MAX_NEGATIVE_TTL_SECS),
+    )
+}
 
-    // Skip question: walk labels until null terminator
-    while offset < data.len() && data[offset] != 0 {
-        let len = data[offset] as usize;
-        offset += 1 + len;
+/// Longest label a name may contain, in bytes.
+const MAX_LABEL_LEN: usize = 63;
+/// Longest name `read_name` decodes and `write_name` encodes, in wire bytes.
+const MAX_NAME_LEN: usize = 255;
+/// Highest message offset a compression pointer can hold (14 bits).
+const MAX_POINTER_OFFSET: usize = 0x3FFF;
+
+/// Offsets of names already written to a message, keyed by their lowercased suffix.
+pub type CompressionTable = BTreeMap<String, u16>;
+
+/// Append `name` to the DNS message in `buf` (which must start at the message header),
+/// pointing at an earlier copy of its longest suffix found in `table` instead of
+/// repeating it. Suffixes written in full are added to `table` for later names.
+/// Returns false, leaving `buf` and `table` unchanged, if a label is empty or longer
+/// than 63 bytes, or the whole name would take more than 255 bytes uncompressed.
+pub fn write_name(buf: &mut Vec<u8>, name: &str, table: &mut CompressionTable) -> bool {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    let labels: Vec<&str> = if name.is_empty() {
+        Vec::new()
+    } else {
+        name.split('.').collect()
+    };
+    if labels
+        .iter()
+        .any(|label| label.is_empty() || label.len() > MAX_LABEL_LEN)
+    {
+        return false;
+    }
+    // Each label plus its length byte, then the root label.
+    let wire_len: usize = labels.iter().map(|label| label.len() + 1).sum::<usize>() + 1;
+    if wire_len > MAX_NAME_LEN {
+        return false;
     }
-    offset += 1; // null terminator
-    offset += 4; // QTYPE (2) + QCLASS (2)
 
-    // Parse answer records
-    for _ in 0..ancount {
-        if offset + 12 > data.len() {
+    for i in 0..labels.len() {
+        let suffix = labels[i..].join(".").to_ascii_lowercase();
+        if let Some(&target) = table.get(&suffix) {
+            buf.extend_from_slice(&(0xC000 | target).to_be_bytes());
+            return true;
+        }
+        if buf.len() <= MAX_POINTER_OFFSET {
+            table.insert(suffix, buf.len() as u16);
+        }
+        buf.push(labels[i].len() as u8);
+        buf.extend_from_slice(labels[i].as_bytes());
+    }
+    buf.push(0x00); // Root label terminator
+    true
+}
+
+/// Decode a (possibly compressed) domain name starting at `offset`.
+/// Returns the dotted name and the offset just past the name at its original position.
+/// Every pointer must lead strictly backwards, as compression only refers to earlier
+/// names, so pointer loops are rejected; so are names over 255 bytes.
+fn read_name(data: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut name = String::new();
+    let mut end = None;
+    let mut pointers = 0;
+    let mut wire_len = 0;
+
+    loop {
+        let len = read_u8(data, offset)? as usize;
+        if len & 0xC0 == 0xC0 {
+            let target = ((len & 0x3F) << 8) | read_u8(data, offset + 1)? as usize;
+            end.get_or_insert(offset + 2);
+            pointers += 1;
+            if pointers > MAX_NAME_POINTERS || target >= offset {
+                return None;
+            }
+            offset = target;
+            continue;
+        }
+
+        wire_len += 1 + len;
+        if wire_len > MAX_NAME_LEN {
             return None;
         }
+        if len == 0 {
+            return Some((name, end.unwrap_or(offset + 1)));
+        }
 
-        // Skip name (handle compression pointers)
-        if data[offset] & 0xC0 == 0xC0 {
-            offset += 2; // Compressed pointer
-        } else {
-            while offset < data.len() && data[offset] != 0 {
-                let len = data[offset] as usize;
+        let label = read_slice(data, offset + 1, len)?;
+        if !name.is_empty() {
+            name.push('.');
+        }
+        name.push_str(core::str::from_utf8(label).ok()?);
+        offset += 1 + len;
+    }
+}
+
+fn parse_mx_response(data: &[u8]) -> Vec<(u16, String)> {
+    answer_records(data)
+        .into_iter()
+        .filter(|(rtype, _, rdata)| *rtype == QTYPE_MX && rdata.len() >= 3)
+        .filter_map(|(_, _, rdata)| {
+            let preference = read_u16_be(data, rdata.start)?;
+            let (exchange, _) = read_name(data, rdata.start + 2)?;
+            Some((preference, exchange))
+        })
+        .collect()
+}
+
+/// Parse TXT answers, joining each record's length-prefixed character-strings.
+fn parse_txt_response(data: &[u8]) -> Vec<String> {
+    answer_records(data)
+        .into_iter()
+        .filter(|(rtype, _, _)| *rtype == QTYPE_TXT)
+        .filter_map(|(_, _, rdata)| {
+            let rdata = &data[rdata];
+            let mut text = String::new();
+            let mut offset = 0;
+            while offset < rdata.len() {
+                let len = rdata[offset] as usize;
+                let chunk = read_slice(rdata, offset + 1, len)?;
+                text.push_str(core::str::from_utf8(chunk).ok()?);
                 offset += 1 + len;
             }
-            offset += 1;
+            Some(text)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smoltcp::wire::EthernetAddress;
+
+    /// A response to a `qtype` query for `domain` carrying `answers` as
+    /// (type, TTL, RDATA), each owned by the question name via a pointer.
+    fn response(domain: &str, qtype: u16, answers: &[(u16, u32, Vec<u8>)]) -> Vec<u8> {
+        let mut pkt = build_dns_query(domain, qtype).unwrap();
+        pkt[2] = 0x81;
+        pkt[3] = 0x80;
+        pkt[6..8].copy_from_slice(&(answers.len() as u16).to_be_bytes());
+        for (rtype, ttl, rdata) in answers {
+            pkt.extend_from_slice(&[0xC0, 12]);
+            pkt.extend_from_slice(&rtype.to_be_bytes());
+            pkt.extend_from_slice(&[0x00, 0x01]);
+            pkt.extend_from_slice(&ttl.to_be_bytes());
+            pkt.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            pkt.extend_from_slice(rdata);
         }
+        pkt
+    }
 
-        if offset + 10 > data.len() {
-            return None;
+    #[test_case]
+    fn mx_answers_keep_preference_and_decompress_the_exchange() {
+        // "mail" followed by a pointer to "example.com" in the question.
+        let mut compressed = alloc::vec![0x00, 0x0A, 4];
+        compressed.extend_from_slice(b"mail");
+        compressed.extend_from_slice(&[0xC0, 12]);
+        let mut plain = alloc::vec![0x00, 0x14, 6];
+        plain.extend_from_slice(b"backup\x07example\x03com\x00");
+
+        let pkt = response(
+            "example.com",
+            QTYPE_MX,
+            &[(QTYPE_MX, 60, compressed), (QTYPE_MX, 60, plain)],
+        );
+        assert_eq!(
+            parse_mx_response(&pkt),
+            alloc::vec![
+                (10, String::from("mail.example.com")),
+                (20, String::from("backup.example.com")),
+            ]
+        );
+    }
+
+    #[test_case]
+    fn txt_answers_join_their_character_strings() {
+        let mut rdata = alloc::vec![7];
+        rdata.extend_from_slice(b"v=spf1 ");
+        rdata.push(4);
+        rdata.extend_from_slice(b"-all");
+        let pkt = response("example.com", QTYPE_TXT, &[(QTYPE_TXT, 60, rdata)]);
+        assert_eq!(
+            parse_txt_response(&pkt),
+            alloc::vec![String::from("v=spf1 -all")]
+        );
+
+        // A character-string running past its RDATA is dropped, not read out of bounds.
+        let pkt = response(
+            "example.com",
+            QTYPE_TXT,
+            &[(QTYPE_TXT, 60, alloc::vec![9, b'x'])],
+        );
+        assert!(parse_txt_response(&pkt).is_empty());
+    }
+    #[test_case]
+    fn truncated_responses_parse_to_nothing() {
+        let pkt = response(
+            "example.com",
+            QTYPE_A,
+            &[(QTYPE_A, 60, alloc::vec![10, 0, 2, 15])],
+        );
+        assert_eq!(parse_dns_response(&pkt), Some(([10, 0, 2, 15], 60)));
+        for len in 0..pkt.len() {
+            assert_eq!(parse_dns_response(&pkt[..len]), None);
+        }
+    }
+    #[test_case]
+    fn overrides_short_circuit_resolution() {
+        let misses = cache_stats().misses;
+        set_override("Pinned.Example", [192, 0, 2, 7]);
+        assert_eq!(resolve("pinned.example"), Ok([192, 0, 2, 7]));
+        assert_eq!(cache_stats().misses, misses);
+        assert!(clear_override("PINNED.example"));
+        assert!(!clear_override("pinned.example"));
+    }
+
+    #[test_case]
+    fn hosts_file_entries_become_overrides() {
+        // The file itself is loaded end to end in the initramfs tests.
+        let text =
+            "# pinned for tests\n192.0.2.10 db.internal db # primary\n::1 v6only\nbogus line\n";
+        assert_eq!(pin_hosts(text), 2);
+        assert_eq!(resolve("db.internal"), Ok([192, 0, 2, 10]));
+        assert_eq!(resolve("db"), Ok([192, 0, 2, 10]));
+        assert!(!clear_override("v6only"));
+    }
+
+    #[test_case]
+    fn a_fixed_seed_repeats_transaction_ids_and_ports() {
+        let run = || {
+            crate::rng::seed(0x5EED);
+            let ids: Vec<[u8; 2]> = (0..4)
+                .map(|_| {
+                    build_dns_query("example.com", QTYPE_A).unwrap()[..2]
+                        .try_into()
+                        .unwrap()
+                })
+                .collect();
+            let port = crate::net::alloc_ephemeral_port().unwrap();
+            crate::net::free_ephemeral_port(port);
+            (ids, port)
+        };
+
+        let first = run();
+        assert!(crate::rng::is_deterministic());
+        assert_eq!(run(), first);
+    }
+
+    #[test_case]
+    fn cached_lookups_count_as_hits_in_the_network_stats() {
+        let entry = CacheEntry {
+            ip: Some([192, 0, 2, 7]),
+            expires_ms: u64::MAX,
+        };
+        CACHE
+            .lock()
+            .insert(String::from("stats.test"), CacheSlot::Answer(entry));
+        let before = crate::net::stats();
+
+        assert_eq!(resolve("STATS.test"), Ok([192, 0, 2, 7]));
+        let after = crate::net::stats();
+        assert_eq!(after.dns_cache_hits, before.dns_cache_hits + 1);
+        assert_eq!(after.dns_cache_misses, before.dns_cache_misses);
+        let file = String::from_utf8(crate::net::stats_file()).unwrap();
+        assert!(file.contains(&alloc::format!("dns_cache_hits {}\n", after.dns_cache_hits)));
+    }
+
+    /// An A-query response for `domain` with rcode `rcode`, no answers and, if `soa`
+    /// is given as (TTL, MINIMUM), an SOA record in the authority section.
+    fn negative_response(domain: &str, rcode: u8, soa: Option<(u32, u32)>) -> Vec<u8> {
+        let mut pkt = response(domain, QTYPE_A, &[]);
+        pkt[3] = 0x80 | rcode;
+        if let Some((ttl, minimum)) = soa {
+            pkt[8..10].copy_from_slice(&1u16.to_be_bytes());
+            let mut rdata = Vec::from(&b"\x02ns\x00\x04host\x00"[..]);
+            for field in [1, 7200, 3600, 86400, minimum] {
+                rdata.extend_from_slice(&u32::to_be_bytes(field));
+            }
+            pkt.extend_from_slice(&[0xC0, 12]);
+            pkt.extend_from_slice(&QTYPE_SOA.to_be_bytes());
+            pkt.extend_from_slice(&[0x00, 0x01]);
+            pkt.extend_from_slice(&ttl.to_be_bytes());
+            pkt.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            pkt.extend_from_slice(&rdata);
         }
+        pkt
+    }
 
-        let rtype = u16::from_be_bytes([data[offset], data[offset + 1]]);
-        let rdlength = u16::from_be_bytes([data[offset + 8], data[offset + 9]]) as usize;
-        offset += 10;
+    #[test_case]
+    fn negative_answers_are_cached_for_the_soa_minimum() {
+        let nxdomain = |soa| negative_response("gone.example", RCODE_NXDOMAIN, soa);
+        assert_eq!(negative_ttl(&nxdomain(Some((900, 60)))), Some(60));
+        assert_eq!(negative_ttl(&nxdomain(Some((45, 600)))), Some(45));
+        assert_eq!(
+            negative_ttl(&nxdomain(Some((86400, 86400)))),
+            Some(MAX_NEGATIVE_TTL_SECS)
+        );
+        assert_eq!(
+            negative_ttl(&nxdomain(None)),
+            Some(DEFAULT_NEGATIVE_TTL_SECS)
+        );
+        // No A record in a successful response is negative too; SERVFAIL is not.
+        let empty = negative_response("empty.example", RCODE_NOERROR, None);
+        assert_eq!(negative_ttl(&empty), Some(DEFAULT_NEGATIVE_TTL_SECS));
+        assert_eq!(negative_ttl(&negative_response("x.example", 2, None)), None);
+        let positive = response(
+            "example.com",
+            QTYPE_A,
+            &[(QTYPE_A, 60, alloc::vec![10, 0, 2, 15])],
+        );
+        assert_eq!(negative_ttl(&positive), None);
+    }
+
+    #[test_case]
+    fn cached_failures_short_circuit_until_they_expire() {
+        let negative = |expires_ms| {
+            CacheSlot::Answer(CacheEntry {
+                ip: None,
+                expires_ms,
+            })
+        };
+        let window = crate::time::uptime_ms() + 60_000;
+        CACHE
+            .lock()
+            .insert(String::from("nx.test"), negative(window));
+        let before = cache_stats();
+        assert_eq!(resolve("nx.test"), Err(DnsError::NotFound));
+        assert_eq!(resolve("NX.test"), Err(DnsError::NotFound));
+        let after = cache_stats();
+        assert_eq!(after.hits, before.hits + 2);
+        assert_eq!(after.misses, before.misses);
+
+        // Once expired the name is queried again.
+        CACHE.lock().insert(String::from("nx.test"), negative(0));
+        assert!(resolve("nx.test").is_err());
+        assert_eq!(cache_stats().misses, after.misses + 1);
+        assert!(!matches!(
+            CACHE.lock().get("nx.test"),
+            Some(CacheSlot::Answer(CacheEntry { expires_ms: 0, .. }))
+        ));
+    }
+
+    #[test_case]
+    fn successive_queries_draw_different_source_ports() {
+        crate::rng::seed(0xD45);
+        let first = alloc_ephemeral_port().unwrap();
+        free_ephemeral_port(first);
+        let second = alloc_ephemeral_port().unwrap();
+        free_ephemeral_port(second);
+        assert_ne!(first, second);
+        assert!(first >= crate::rng::EPHEMERAL_PORT_START);
+    }
+
+    #[test_case]
+    fn replies_must_come_from_the_server_port_with_the_query_id() {
+        let query = build_dns_query("reply.test", QTYPE_A).unwrap();
+        let reply = response("reply.test", QTYPE_A, &[(QTYPE_A, 60, vec![192, 0, 2, 1])]);
+        let server = IpEndpoint::new(IpAddress::Ipv4(DNS_SERVER), DNS_PORT);
+        assert!(is_reply(&query, server, server, &reply));
+
+        let other_port = IpEndpoint::new(IpAddress::Ipv4(DNS_SERVER), 5353);
+        assert!(!is_reply(&query, server, other_port, &reply));
+        let mut wrong_id = reply.clone();
+        wrong_id[0] ^= 0xFF;
+        assert!(!is_reply(&query, server, server, &wrong_id));
+    }
 
-        if rtype == 1 && rdlength == 4 && offset + 4 <= data.len() {
-            return Some([
-                data[offset],
-                data[offset + 1],
-                data[offset + 2],
-                data[offset + 3],
-            ]);
+    #[test_case]
+    fn queries_for_unencodable_names_are_rejected() {
+        let long_label = "a".repeat(64);
+        // Four 63-byte labels take 257 wire bytes; two fewer fit exactly.
+        let long_name = vec!["b".repeat(63); 4].join(".");
+        for name in [
+            "bad..name",
+            ".lead",
+            long_label.as_str(),
+            long_name.as_str(),
+        ] {
+            assert_eq!(build_dns_query(name, QTYPE_A), None, "{name}");
+            assert_eq!(resolve(name), Err(DnsError::InvalidName), "{name}");
         }
 
-        offset += rdlength;
+        let query = build_dns_query("ok.test.", QTYPE_A).unwrap();
+        assert_eq!(&query[12..], b"\x02ok\x04test\x00\x00\x01\x00\x01");
+        assert!(build_dns_query(&long_name[2..], QTYPE_A).is_some());
     }
 
-    None
+    #[test_case]
+    fn written_names_share_suffixes_and_round_trip() {
+        let mut buf = vec![0u8; 12];
+        let mut table = CompressionTable::new();
+        assert!(write_name(&mut buf, "mail.example.com", &mut table));
+        let second = buf.len();
+        assert!(write_name(&mut buf, "WWW.Example.com.", &mut table));
+
+        assert_eq!(&buf[12..18], b"\x04mail\x07");
+        // "www" then a pointer to "example.com" inside the first name.
+        assert_eq!(&buf[second..], b"\x03WWW\xC0\x11");
+        assert_eq!(
+            read_name(&buf, 12),
+            Some((String::from("mail.example.com"), second))
+        );
+        assert_eq!(
+            read_name(&buf, second),
+            Some((String::from("WWW.example.com"), buf.len()))
+        );
+        assert!(!write_name(&mut buf, "bad..name", &mut table));
+        assert_eq!(read_name(&buf, buf.len()), None);
+    }
+
+    #[test_case]
+    fn pointer_loops_are_rejected() {
+        // A pointer to itself, and two names pointing at each other: following them
+        // from either end needs a forward pointer at some point.
+        assert_eq!(read_name(&[0xC0, 0x00], 0), None);
+        let data = [0x01, b'a', 0xC0, 0x04, 0x01, b'b', 0xC0, 0x00];
+        assert_eq!(read_name(&data, 0), None);
+        assert_eq!(read_name(&data, 4), None);
+    }
+
+    /// A module whose `_start` resolves `name` into offset 64 and traps unless that
+    /// returns OK with `ip`.
+    fn resolving_module(name: &[u8], ip: [u8; 4]) -> Vec<u8> {
+        use crate::testing::{Code, ModuleBuilder, I32, I32_DIV_U, I32_EQZ, I32_SUB};
+
+        let mut m = ModuleBuilder::new();
+        let resolve = m.import("resolve_dns", &[I32, I32, I32], &[I32]);
+        // `1 / (status == OK)`, then `1 / (address == ip)`.
+        let body = Code::new()
+            .i32(1)
+            .i32(0)
+            .i32(name.len() as i32)
+            .i32(64)
+            .call(resolve)
+            .op(I32_EQZ)
+            .op(I32_DIV_U)
+            .drop()
+            .i32(1)
+            .i32(64)
+            .load32(0)
+            .i32(i32::from_le_bytes(ip))
+            .op(I32_SUB)
+            .op(I32_EQZ)
+            .op(I32_DIV_U)
+            .drop();
+        let start = m.func(&[], &[], &[], body);
+        m.export("_start", start).data(0, name);
+        m.build()
+    }
+
+    /// The frame a server at `server_mac` sends back for the DNS query frame `sent`,
+    /// answering it with `ip`.
+    fn reply_frame(sent: &[u8], server_mac: EthernetAddress, ip: [u8; 4]) -> Vec<u8> {
+        use smoltcp::phy::ChecksumCapabilities;
+        use smoltcp::wire::{
+            EthernetFrame, EthernetProtocol, EthernetRepr, IpProtocol, Ipv4Packet, Ipv4Repr,
+            UdpPacket, UdpRepr,
+        };
+
+        let eth = EthernetFrame::new_checked(sent).unwrap();
+        let ipv4 = Ipv4Packet::new_checked(eth.payload()).unwrap();
+        let udp = UdpPacket::new_checked(ipv4.payload()).unwrap();
+        assert_eq!(udp.dst_port(), DNS_PORT);
+        let mut dns = udp.payload().to_vec();
+        dns[2] = 0x81;
+        dns[3] = 0x80;
+        dns[6..8].copy_from_slice(&1u16.to_be_bytes());
+        dns.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+        dns.extend_from_slice(&ip);
+
+        let eth_repr = EthernetRepr {
+            src_addr: server_mac,
+            dst_addr: eth.src_addr(),
+            ethertype: EthernetProtocol::Ipv4,
+        };
+        let ip_repr = Ipv4Repr {
+            src_addr: ipv4.dst_addr(),
+            dst_addr: ipv4.src_addr(),
+            next_header: IpProtocol::Udp,
+            payload_len: 8 + dns.len(),
+            hop_limit: 64,
+        };
+        let udp_repr = UdpRepr {
+            src_port: DNS_PORT,
+            dst_port: udp.src_port(),
+        };
+        let checksums = ChecksumCapabilities::default();
+        let mut frame = vec![0u8; eth_repr.buffer_len() + ip_repr.buffer_len() + 8 + dns.len()];
+        let mut eth_out = EthernetFrame::new_unchecked(&mut frame[..]);
+        eth_repr.emit(&mut eth_out);
+        let mut ip_out = Ipv4Packet::new_unchecked(eth_out.payload_mut());
+        ip_repr.emit(&mut ip_out, &checksums);
+        let mut udp_out = UdpPacket::new_unchecked(ip_out.payload_mut());
+        udp_repr.emit(
+            &mut udp_out,
+            &IpAddress::Ipv4(ip_repr.src_addr),
+            &IpAddress::Ipv4(ip_repr.dst_addr),
+            dns.len(),
+            |payload| payload.copy_from_slice(&dns),
+            &checksums,
+        );
+        frame
+    }
+
+    /// DNS queries among the frames the NIC sent since its `tx_packets` read `before`
+    /// (only the last four are kept).
+    fn queries_sent_since(before: u64) -> usize {
+        use smoltcp::wire::{EthernetFrame, Ipv4Packet, UdpPacket};
+
+        with_network(|net| {
+            let sent = (net.device.stats().tx_packets - before) as usize;
+            (0..sent.min(4))
+                .filter(|&back| {
+                    let frame = net.device.sent_frame(back);
+                    let eth = EthernetFrame::new_checked(frame).unwrap();
+                    Ipv4Packet::new_checked(eth.payload())
+                        .ok()
+                        .and_then(|ip| Some(UdpPacket::new_checked(ip.payload()).ok()?.dst_port()))
+                        == Some(DNS_PORT)
+                })
+                .count()
+        })
+        .unwrap()
+    }
+
+    #[test_case]
+    fn agents_resolving_one_name_together_share_one_query() {
+        use crate::capability::Capability;
+
+        crate::testing::network();
+        let server_mac = EthernetAddress([0x02, 0, 0, 0, 0, 0x53]);
+        crate::net::add_static_arp(DNS_SERVER, server_mac).unwrap();
+        let ip = [192, 0, 2, 73];
+        let module = resolving_module(b"together.test", ip);
+        let runtime = crate::wasm::WasmRuntime::new();
+        let a = crate::testing::spawn_agent("resolver-a", vec![Capability::Network]);
+        let b = crate::testing::spawn_agent("resolver-b", vec![Capability::Network]);
+        runtime.spawn_module(&module, a.0).unwrap();
+        runtime.spawn_module(&module, b.0).unwrap();
+        let before = cache_stats();
+        let sent = crate::net::stats().nic.tx_packets;
+
+        // A sends the query and is suspended; B finds it in flight and is suspended
+        // too, and keeps being resumed to look again while the reply is out.
+        for _ in 0..4 {
+            crate::task::run_executor_step();
+        }
+        assert_eq!(queries_sent_since(sent), 1);
+        let query = with_network(|net| net.device.sent_frame(0).to_vec()).unwrap();
+        crate::rtl8139::script_rx(reply_frame(&query, server_mac, ip));
+        crate::task::run_executor();
+
+        for agent in [a, b] {
+            assert!(crate::testing::logged(&alloc::format!(
+                "[EXEC] Agent {} finished",
+                agent.0
+            )));
+        }
+        assert_eq!(queries_sent_since(sent), 1);
+        let after = cache_stats();
+        assert_eq!(after.misses, before.misses + 1);
+        assert_eq!(after.coalesced, before.coalesced + 1);
+        assert_eq!(resolve("together.test"), Ok(ip));
+    }
+
+    #[test_case]
+    fn silent_servers_get_a_bounded_number_of_jittered_resends() {
+        crate::testing::network();
+        let query = build_dns_query("silent.test", QTYPE_A).unwrap();
+        with_network(|net| {
+            let mut exchange = Exchange::open(net, query, 0).unwrap();
+            let mut now_ms = 0;
+            let mut attempts = 0;
+            loop {
+                attempts += 1;
+                let wait = exchange.deadline_ms - now_ms;
+                assert!((QUERY_TIMEOUT_MS..QUERY_TIMEOUT_MS + QUERY_JITTER_MS).contains(&wait));
+                assert!(matches!(
+                    exchange.poll(net, now_ms + wait - 1),
+                    Progress::Waiting
+                ));
+                now_ms = exchange.deadline_ms;
+                match exchange.poll(net, now_ms) {
+                    Progress::Waiting => assert_eq!(exchange.attempts, attempts + 1),
+                    Progress::GaveUp => break,
+                    Progress::Reply(_) => panic!("nothing should answer"),
+                }
+            }
+            assert_eq!(attempts, QUERY_ATTEMPTS);
+            exchange.close_in(net);
+        })
+        .unwrap();
+    }
+
+    #[test_case]
+    fn waiters_get_an_uncached_failure_and_the_next_lookup_retries() {
+        crate::testing::network();
+        let before = cache_stats();
+        let Lookup::Query { now } = check_cache("unanswered.test", false) else {
+            panic!("first lookup should query");
+        };
+        assert!(matches!(
+            check_cache("unanswered.test", false),
+            Lookup::Wait
+        ));
+
+        // The mock device never answers.
+        let sent = crate::net::stats().nic.tx_packets;
+        let response = query("unanswered.test", QTYPE_A);
+        assert!(crate::net::stats().nic.tx_packets > sent);
+        let first = finish_query(String::from("unanswered.test"), now, response);
+        assert!(first.is_err());
+        let Lookup::Done(second) = check_cache("unanswered.test", true) else {
+            panic!("the waiter should see the failure");
+        };
+        assert_eq!(second, first);
+        assert_eq!(cache_stats().coalesced, before.coalesced + 1);
+        assert_eq!(cache_stats().misses, before.misses + 1);
+
+        // The failure was only for the waiters: a fresh lookup queries again.
+        assert!(matches!(
+            check_cache("unanswered.test", false),
+            Lookup::Query { .. }
+        ));
+        assert_eq!(cache_stats().misses, before.misses + 2);
+        CACHE.lock().remove("unanswered.test");
+    }
+
+    #[test_case]
+    fn abandoned_in_flight_markers_are_taken_over() {
+        let started_ms = crate::time::uptime_ms();
+        CACHE.lock().insert(
+            String::from("abandoned.test"),
+            CacheSlot::InFlight { started_ms },
+        );
+        assert!(matches!(check_cache("abandoned.test", false), Lookup::Wait));
+        crate::time::tick(IN_FLIGHT_TIMEOUT_MS);
+        assert!(matches!(
+            check_cache("abandoned.test", true),
+            Lookup::Query { .. }
+        ));
+        CACHE.lock().remove("abandoned.test");
+    }
 }
@@ -1,72 +1,439 @@
-use crate::vfs::register_file;
-use crate::{serial_println, serial_print};
+use crate::vfs::{register_file, set_digest};
+use crate::serial_println;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
 use core::str;
 
+/// Bytes of each file shown in a verbose hex dump.
+const HEX_DUMP_BYTES: usize = 120;
+
+/// Options for `init_opts`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InitOpts {
+    /// Hex-dump the first `HEX_DUMP_BYTES` of every mounted file to serial.
+    pub verbose: bool,
+}
+
+/// A problem found while parsing the archive. Only `Empty` stops loading; the others
+/// are collected in `InitramfsSummary::errors` while the rest of the archive mounts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InitramfsError {
+    /// The archive has no bytes at all.
+    Empty,
+    /// The header at `offset` fails its checksum, so its fields cannot be trusted.
+    BadChecksum { offset: usize },
+    /// The contents of `name` run past the end of the archive. Parsing stops here.
+    FileBeyondBounds { name: String },
+    /// The name in the header at `offset` is not valid UTF-8, or climbs above the root.
+    InvalidName { offset: usize },
+}
+
+impl fmt::Display for InitramfsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InitramfsError::Empty => write!(f, "archive is empty"),
+            InitramfsError::BadChecksum { offset } => write!(f, "bad header checksum at offset {offset}"),
+            InitramfsError::FileBeyondBounds { name } => write!(f, "file {name} extends beyond archive boundaries"),
+            InitramfsError::InvalidName { offset } => write!(f, "invalid name in header at offset {offset}"),
+        }
+    }
+}
+
+/// What `init` mounted and what it had to skip.
+#[derive(Debug, Default)]
+pub struct InitramfsSummary {
+    pub mounted: usize,
+    /// Entries that could not be mounted, each with a matching entry in `errors`.
+    pub skipped: usize,
+    pub errors: Vec<InitramfsError>,
+    /// The archive ended with the two all-zero blocks USTAR requires. False if it
+    /// stopped at a lone zero block, a short trailing block or the end of the data.
+    pub clean: bool,
+}
+
 /// Parses a USTAR format tarball loaded into memory and mounts its contents into the VFS.
-/// Returns the number of files successfully mounted.
-pub fn init(archive: &'static [u8]) -> Result<usize, &'static str> {
+/// Fails only if the archive is empty; damaged entries are skipped and reported in the
+/// summary.
+pub fn init(archive: &'static [u8]) -> Result<InitramfsSummary, InitramfsError> {
+    init_opts(archive, InitOpts::default())
+}
+
+/// Format up to `HEX_DUMP_BYTES` of `data` as space-separated hex into `out`,
+/// replacing its previous contents.
+pub fn hex_dump(out: &mut String, data: &[u8]) {
+    out.clear();
+    for b in &data[..data.len().min(HEX_DUMP_BYTES)] {
+        let _ = write!(out, "{b:02x} ");
+    }
+}
+
+/// Bytes in a USTAR block: headers and padded file contents are whole blocks.
+const BLOCK_SIZE: usize = 512;
+
+/// Parse a numeric header field: octal digits, optionally padded with leading spaces
+/// and terminated by a null byte or space. A malformed field reads as 0.
+fn octal_field(field: &[u8]) -> u64 {
+    let start = field.iter().position(|&c| c != b' ').unwrap_or(field.len());
+    let digits = &field[start..];
+    let end = digits.iter().position(|&c| c == 0 || c == b' ').unwrap_or(digits.len());
+    str::from_utf8(&digits[..end]).ok().and_then(|s| u64::from_str_radix(s, 8).ok()).unwrap_or(0)
+}
+
+/// Whether the archive holds the two-zero-block end marker at `offset`.
+fn end_marker_at(archive: &[u8], offset: usize) -> bool {
+    archive
+        .get(offset..offset + 2 * BLOCK_SIZE)
+        .is_some_and(|blocks| blocks.iter().all(|&b| b == 0))
+}
+
+/// Whether a header's checksum field matches its contents: the sum of all 512 bytes,
+/// with the 8-byte checksum field itself counted as spaces.
+fn checksum_ok(header: &[u8]) -> bool {
+    let stored = str::from_utf8(&header[148..156])
+        .ok()
+        .map(|field| field.trim_matches(|c| c == '\0' || c == ' '))
+        .and_then(|field| u32::from_str_radix(field, 8).ok());
+    let sum: u32 = header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| if (148..156).contains(&i) { b' ' as u32 } else { b as u32 })
+        .sum();
+    stored == Some(sum)
+}
+
+/// The absolute VFS path for the archive member `name`, or `None` if it is empty or
+/// `..` would take it above the root.
+fn mount_path(name: &str) -> Option<String> {
+    let path = crate::vfs::normalize_path(&alloc::format!("/{name}"))?;
+    (path != "/").then_some(path)
+}
+
+/// Like `init`, with a hex dump of every mounted file when `opts.verbose` is set.
+pub fn init_opts(archive: &'static [u8], opts: InitOpts) -> Result<InitramfsSummary, InitramfsError> {
     if archive.is_empty() {
-        return Err("Archive is empty");
+        return Err(InitramfsError::Empty);
     }
 
-    let mut count = 0;
+    let mut summary = InitramfsSummary::default();
     let mut offset = 0;
+    // `<name>.sha256` sidecars, applied once every file has been mounted.
+    let mut sidecars: Vec<(String, &[u8])> = Vec::new();
+    // Reused for every file's dump so verbose mounting doesn't allocate per file.
+    let mut dump = String::with_capacity(if opts.verbose { HEX_DUMP_BYTES * 3 } else { 0 });
+
+    // A trailing block shorter than 512 bytes can't hold a header, so it ends the archive.
+    while offset + BLOCK_SIZE <= archive.len() {
+        let header = &archive[offset..offset + BLOCK_SIZE];
 
-    while offset + 512 <= archive.len() {
-        let header = &archive[offset..offset + 512];
-        
         // The end of a tar archive is indicated by two consecutive 512-byte blocks of null bytes.
-        // We'll just check if the first byte of the filename is null to detect the end.
+        // Any header with an empty name ends parsing, but only the full marker is clean.
         if header[0] == 0 {
+            summary.clean = end_marker_at(archive, offset);
             break;
         }
 
+        // A corrupt header's size can't be trusted either, so step to the next block.
+        if !checksum_ok(header) {
+            serial_println!("[INITRAMFS] Skipped header with bad checksum at offset {}", offset);
+            summary.skip(InitramfsError::BadChecksum { offset });
+            offset += BLOCK_SIZE;
+            continue;
+        }
+
+        // Parse Size and Mtime (12 bytes each, octal)
+        let size = octal_field(&header[124..136]) as usize;
+        let mtime = octal_field(&header[136..148]);
+        let aligned_size = (size + 511) & !511;
+
         // Parse Name (100 bytes)
         let name_end = header[0..100].iter().position(|&c| c == 0).unwrap_or(100);
-        let name = match str::from_utf8(&header[0..name_end]) {
-            Ok(n) => n,
-            Err(_) => {
-                serial_println!("[INITRAMFS] Skipped file with invalid UTF-8 name");
-                offset += 512;
+        // Archive names are relative to its root (`agent1.wasm`, `./etc/hosts`); mount
+        // each file at the matching absolute VFS path.
+        let name = match str::from_utf8(&header[0..name_end]).ok().and_then(mount_path) {
+            Some(n) => n,
+            None => {
+                serial_println!("[INITRAMFS] Skipped file with invalid name");
+                summary.skip(InitramfsError::InvalidName { offset });
+                offset += BLOCK_SIZE + aligned_size;
                 continue;
             }
         };
 
-        // Parse Size (12 bytes, octal, null or space terminated)
-        let size_str_end = header[124..136].iter().position(|&c| c == 0 || c == b' ').unwrap_or(12);
-        let size_str = str::from_utf8(&header[124..124 + size_str_end]).unwrap_or("0");
-        let size = usize::from_str_radix(size_str, 8).unwrap_or(0);
-
         // Parse Type flag (1 byte)
         let type_flag = header[156];
         
         // Move offset past header
-        offset += 512;
+        offset += BLOCK_SIZE;
 
         // Regular file ('0' or null byte)
         if type_flag == b'0' || type_flag == 0 {
             if offset + size > archive.len() {
                 serial_println!("[INITRAMFS] Warning: File {} extends beyond archive boundaries", name);
+                summary.skip(InitramfsError::FileBeyondBounds { name: name.clone() });
                 break;
             }
 
             let file_data = &archive[offset..offset + size];
-            register_file(name, file_data);
-            count += 1;
+            register_file(&name, file_data, mtime);
+            summary.mounted += 1;
+
+            if let Some(target) = name.strip_suffix(".sha256") {
+                sidecars.push((String::from(target), file_data));
+            }
             
             serial_println!("[INITRAMFS] Mounted: {} ({} bytes)", name, size);
-            serial_print!("  [HEX] ");
-            let dump_len = core::cmp::min(size, 120);
-            for b in &file_data[0..dump_len] {
-                serial_print!("{:02x} ", b);
+            if opts.verbose {
+                hex_dump(&mut dump, file_data);
+                serial_println!("  [HEX] {}", dump);
             }
-            serial_println!("");
         }
 
         // Move offset past file contents. Blocks are always exactly 512 bytes aligned.
-        let aligned_size = (size + 511) & !511;
         offset += aligned_size;
     }
 
-    Ok(count)
+    if !summary.clean {
+        serial_println!("[INITRAMFS] Warning: Archive has no end-of-archive marker (stopped at offset {})", offset);
+    }
+
+    for (target, contents) in sidecars {
+        let digest = str::from_utf8(contents).ok().and_then(crate::crypto::digest_from_hex);
+        match digest {
+            Some(digest) if set_digest(&target, digest) => {
+                serial_println!("[INITRAMFS] Recorded SHA-256 digest for {}", target);
+            }
+            _ => serial_println!("[INITRAMFS] Warning: Ignoring invalid sidecar {}.sha256", target),
+        }
+    }
+
+    Ok(summary)
+}
+
+impl InitramfsSummary {
+    fn skip(&mut self, error: InitramfsError) {
+        self.skipped += 1;
+        self.errors.push(error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    /// A USTAR archive of regular files, ending with the two-block end marker.
+    fn archive(files: &[(&str, &[u8])]) -> &'static [u8] {
+        tar(files).leak()
+    }
+
+    /// `archive`, left unleaked so tests can damage it first.
+    fn tar(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut tar = Vec::new();
+        for (name, data) in files {
+            let mut header = [0u8; BLOCK_SIZE];
+            header[..name.len()].copy_from_slice(name.as_bytes());
+            header[124..135].copy_from_slice(alloc::format!("{:011o}", data.len()).as_bytes());
+            header[136..147].copy_from_slice(b"00000000000");
+            header[156] = b'0';
+            header[148..156].fill(b' ');
+            let sum: u32 = header.iter().map(|&b| b as u32).sum();
+            header[148..155].copy_from_slice(alloc::format!("{sum:06o}\0").as_bytes());
+            tar.extend_from_slice(&header);
+            tar.extend_from_slice(data);
+            tar.resize(tar.len().next_multiple_of(BLOCK_SIZE), 0);
+        }
+        tar.resize(tar.len() + 2 * BLOCK_SIZE, 0);
+        tar
+    }
+
+    /// Recompute the checksum of the header at `offset` after editing it.
+    fn reseal(tar: &mut [u8], offset: usize) {
+        let header = &mut tar[offset..offset + BLOCK_SIZE];
+        header[148..156].fill(b' ');
+        let sum: u32 = header.iter().map(|&b| b as u32).sum();
+        header[148..155].copy_from_slice(alloc::format!("{sum:06o}\0").as_bytes());
+    }
+
+    /// The serial output logged after `marker`.
+    fn logged_since(marker: &str) -> String {
+        let tail = testing::log_tail();
+        let start = tail.rfind(marker).expect("marker not in the log tail");
+        String::from(&tail[start..])
+    }
+
+    #[test_case]
+    fn quiet_mounting_prints_no_hex_dump() {
+        let tar = archive(&[("/test/initramfs-quiet.txt", b"quiet")]);
+        serial_println!("-- initramfs quiet --");
+        let summary = init(tar).unwrap();
+
+        assert_eq!((summary.mounted, summary.clean), (1, true));
+        let log = logged_since("-- initramfs quiet --");
+        assert!(log.contains("[INITRAMFS] Mounted: /test/initramfs-quiet.txt (5 bytes)"));
+        assert!(!log.contains("[HEX]"));
+    }
+
+    #[test_case]
+    fn verbose_mounting_dumps_each_file() {
+        let tar = archive(&[("/test/initramfs-a.txt", b"abc"), ("/test/initramfs-b.txt", b"\x00\xff")]);
+        serial_println!("-- initramfs verbose --");
+        init_opts(tar, InitOpts { verbose: true }).unwrap();
+
+        let log = logged_since("-- initramfs verbose --");
+        assert!(log.contains("  [HEX] 61 62 63 \n"));
+        assert!(log.contains("  [HEX] 00 ff \n"));
+    }
+
+    #[test_case]
+    fn hex_dump_reuses_its_buffer_and_stops_at_the_limit() {
+        let mut out = String::new();
+        hex_dump(&mut out, &[0xAB; 200]);
+        assert_eq!(out.len(), HEX_DUMP_BYTES * 3);
+        hex_dump(&mut out, b"\x01");
+        assert_eq!(out, "01 ");
+    }
+
+    #[test_case]
+    fn empty_archive_is_an_error() {
+        assert_eq!(init(&[]).unwrap_err(), InitramfsError::Empty);
+    }
+
+    #[test_case]
+    fn bad_checksums_are_reported_with_their_offset() {
+        let mut tar = tar(&[("/test/initramfs-bad.txt", b""), ("/test/initramfs-good.txt", b"ok")]);
+        tar[0] = b'X';
+        let summary = init(tar.leak()).unwrap();
+
+        assert_eq!(summary.errors, [InitramfsError::BadChecksum { offset: 0 }]);
+        assert_eq!((summary.mounted, summary.skipped, summary.clean), (1, 1, true));
+        assert!(crate::vfs::open_file("/test/initramfs-good.txt").is_some());
+    }
+
+    #[test_case]
+    fn invalid_names_are_reported_with_their_offset() {
+        let mut tar = tar(&[("/test/initramfs-ok.txt", b"ok"), ("/test/initramfs-name.txt", b"x")]);
+        tar[2 * BLOCK_SIZE + 6] = 0xFF;
+        reseal(&mut tar, 2 * BLOCK_SIZE);
+        let summary = init(tar.leak()).unwrap();
+
+        assert_eq!(summary.errors, [InitramfsError::InvalidName { offset: 2 * BLOCK_SIZE }]);
+        assert_eq!((summary.mounted, summary.skipped, summary.clean), (1, 1, true));
+    }
+
+    #[test_case]
+    fn archive_relative_names_mount_at_absolute_paths() {
+        use crate::capability::Capability;
+        use crate::testing::{Code, ModuleBuilder, I32};
+
+        let tar = archive(&[("test/initramfs-rel.txt", b"rel"), ("./test/initramfs-dot.txt", b"dot")]);
+        assert_eq!(init(tar).unwrap().mounted, 2);
+        assert_eq!(crate::vfs::open_file("/test/initramfs-rel.txt").as_deref(), Some(&b"rel"[..]));
+        assert_eq!(crate::vfs::open_file("/test/initramfs-dot.txt").as_deref(), Some(&b"dot"[..]));
+        assert!(crate::vfs::open_file("test/initramfs-rel.txt").is_none());
+
+        // An agent reads the mounted file by its absolute path.
+        let path = b"/test/initramfs-rel.txt";
+        let mut m = ModuleBuilder::new();
+        let file_read = m.import("file_read", &[I32, I32, I32, I32], &[I32]);
+        let body = Code::new().i32(0).i32(path.len() as i32).i32(64).i32(60).call(file_read);
+        let run = m.func(&[], &[I32], &[], body);
+        m.export("run", run).data(0, path);
+        let agent = testing::spawn_agent(
+            "initramfs-reader",
+            alloc::vec![Capability::FileSystem { path_prefix: String::from("/test/"), read: true, write: false }],
+        );
+        let runtime = crate::wasm::WasmRuntime::new();
+        assert_eq!(testing::call_status(&runtime, &m.build(), agent, "run"), crate::syscall_errors::OK);
+    }
+
+    #[test_case]
+    fn archived_hosts_file_is_loaded_from_etc_hosts() {
+        assert_eq!(init(archive(&[("./etc/hosts", b"192.0.2.54 hosts.initramfs.test\n")])).unwrap().mounted, 1);
+        assert!(crate::vfs::exists(crate::dns::HOSTS_PATH));
+
+        assert_eq!(crate::dns::load_hosts_file(), 1);
+        assert_eq!(crate::dns::resolve("hosts.initramfs.test"), Ok([192, 0, 2, 54]));
+        assert!(crate::dns::clear_override("hosts.initramfs.test"));
+    }
+
+    #[test_case]
+    fn names_climbing_above_the_root_are_invalid() {
+        let summary = init(archive(&[("../initramfs-escape.txt", b"x")])).unwrap();
+        assert_eq!(summary.errors, [InitramfsError::InvalidName { offset: 0 }]);
+        assert_eq!(summary.mounted, 0);
+    }
+
+    #[test_case]
+    fn files_past_the_end_are_reported_by_name() {
+        let mut tar = tar(&[("/test/initramfs-cut.txt", &[7; 600])]);
+        tar.truncate(BLOCK_SIZE + 100);
+        let summary = init(tar.leak()).unwrap();
+
+        assert_eq!(
+            summary.errors,
+            [InitramfsError::FileBeyondBounds { name: String::from("/test/initramfs-cut.txt") }]
+        );
+        assert_eq!((summary.mounted, summary.skipped, summary.clean), (0, 1, false));
+    }
+
+    #[test_case]
+    fn archives_ending_in_two_zero_blocks_are_clean() {
+        let summary = init(archive(&[("/test/initramfs-clean.txt", b"clean")])).unwrap();
+        assert!(summary.clean);
+        assert!(summary.errors.is_empty());
+    }
+
+    #[test_case]
+    fn archives_without_the_terminator_mount_but_are_not_clean() {
+        let mut tar = tar(&[("/test/initramfs-open.txt", b"open")]);
+        tar.truncate(2 * BLOCK_SIZE);
+        let summary = init(tar.clone().leak()).unwrap();
+        assert_eq!((summary.mounted, summary.clean), (1, false));
+        assert!(summary.errors.is_empty());
+
+        // A single zero block stops parsing too, but is only half the marker.
+        tar.resize(3 * BLOCK_SIZE, 0);
+        let summary = init(tar.leak()).unwrap();
+        assert_eq!((summary.mounted, summary.clean), (1, false));
+    }
+
+    #[test_case]
+    fn partial_trailing_blocks_end_the_archive_quietly() {
+        let mut tar = tar(&[("/test/initramfs-tail.txt", b"tail")]);
+        tar.truncate(2 * BLOCK_SIZE);
+        tar.extend_from_slice(&[b'x'; 100]);
+        serial_println!("-- initramfs partial --");
+        let summary = init(tar.leak()).unwrap();
+
+        assert_eq!((summary.mounted, summary.skipped, summary.clean), (1, 0, false));
+        assert!(summary.errors.is_empty());
+        assert!(logged_since("-- initramfs partial --")
+            .contains("Archive has no end-of-archive marker (stopped at offset 1024)"));
+    }
+
+    #[test_case]
+    fn archived_mtimes_are_reported_by_stat() {
+        let mut tar = tar(&[("/test/initramfs-mtime.txt", b"dated"), ("/test/initramfs-padded.txt", b"")]);
+        // 2024-02-29T13:45:00Z, zero-padded as tar writes it...
+        tar[136..147].copy_from_slice(alloc::format!("{:011o}", 1_709_214_300u64).as_bytes());
+        reseal(&mut tar, 0);
+        // ...and space-padded with a space terminator, as some older tools do.
+        tar[2 * BLOCK_SIZE + 136..2 * BLOCK_SIZE + 148].copy_from_slice(b"     177777 ");
+        reseal(&mut tar, 2 * BLOCK_SIZE);
+        init(tar.leak()).unwrap();
+
+        let stat = crate::vfs::stat_as("/test/initramfs-mtime.txt", 0).unwrap();
+        assert_eq!(stat, crate::vfs::FileStat { size: 5, read_only: true, mtime: 1_709_214_300 });
+        assert_eq!(crate::vfs::stat_as("/test/initramfs-padded.txt", 0).unwrap().mtime, 0o177777);
+    }
+
+    #[test_case]
+    fn malformed_numeric_fields_read_as_zero() {
+        assert_eq!(octal_field(b"00000000017\0"), 15);
+        assert_eq!(octal_field(b"0000000001 8"), 1);
+        assert_eq!(octal_field(b"00000000089\0"), 0);
+        assert_eq!(octal_field(b"            "), 0);
+        assert_eq!(octal_field(b"\0\0\0\0\0\0\0\0\0\0\0\0"), 0);
+    }
 }
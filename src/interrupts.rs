@@ -1,6 +1,9 @@
 use crate::gdt;
 use crate::print;
 use crate::println;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin::Mutex;
@@ -12,6 +15,94 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 pub static PICS: Mutex<ChainedPics> =
     Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
+/// Lines on the chained 8259 pair.
+pub const IRQ_LINES: usize = 16;
+/// Where `/proc/interrupts` is served from.
+pub const STATS_PATH: &str = "/proc/interrupts";
+
+/// The lowest-priority line on each PIC, where spurious interrupts are delivered.
+const MASTER_SPURIOUS_IRQ: u8 = 7;
+const SLAVE_SPURIOUS_IRQ: u8 = 15;
+/// The master line the slave PIC is cascaded through.
+const CASCADE_IRQ: u8 = 2;
+/// OCW3 command that makes the next command-port read return the in-service register.
+const PIC_READ_ISR: u8 = 0x0b;
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_2_COMMAND: u16 = 0xa0;
+
+static IRQ_COUNTS: [AtomicU64; IRQ_LINES] = [const { AtomicU64::new(0) }; IRQ_LINES];
+static SPURIOUS_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// Snapshot of interrupt activity since boot.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IrqStats {
+    /// Handled interrupts per IRQ line (spurious ones are not included).
+    pub counts: [u64; IRQ_LINES],
+    pub spurious: u64,
+}
+
+/// Handlers currently running; nonzero while in interrupt context.
+static ISR_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// Marks interrupt context for as long as it is held. Every handler that may log takes
+/// one first, so the print paths know not to block on a lock the interrupted code holds.
+struct IsrContext;
+
+impl IsrContext {
+    fn enter() -> Self {
+        ISR_DEPTH.fetch_add(1, Ordering::Relaxed);
+        IsrContext
+    }
+}
+
+impl Drop for IsrContext {
+    fn drop(&mut self) {
+        ISR_DEPTH.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Whether the caller is running inside an interrupt handler.
+pub fn in_interrupt() -> bool {
+    ISR_DEPTH.load(Ordering::Relaxed) > 0
+}
+
+/// Count one interrupt on `irq`. Called at the top of every IRQ handler.
+pub fn record_irq(irq: u8) {
+    if let Some(counter) = IRQ_COUNTS.get(usize::from(irq)) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Count one spurious interrupt (IRQ 7/15 with no in-service bit set).
+pub fn record_spurious() {
+    SPURIOUS_COUNT.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn stats() -> IrqStats {
+    let mut stats = IrqStats {
+        spurious: SPURIOUS_COUNT.load(Ordering::Relaxed),
+        ..IrqStats::default()
+    };
+    for (slot, counter) in stats.counts.iter_mut().zip(IRQ_COUNTS.iter()) {
+        *slot = counter.load(Ordering::Relaxed);
+    }
+    stats
+}
+
+/// Generator for `STATS_PATH`: one `irq count` line per line that has fired, then the
+/// spurious count.
+pub fn stats_file() -> Vec<u8> {
+    let stats = stats();
+    let mut out = alloc::string::String::new();
+    for (irq, count) in stats.counts.iter().enumerate() {
+        if *count > 0 {
+            let _ = writeln!(out, "{irq:>3}: {count}");
+        }
+    }
+    let _ = writeln!(out, "spurious: {}", stats.spurious);
+    out.into_bytes()
+}
+
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
@@ -23,6 +114,9 @@ lazy_static! {
         }
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        idt[usize::from(PIC_1_OFFSET + MASTER_SPURIOUS_IRQ)]
+            .set_handler_fn(master_spurious_handler);
+        idt[usize::from(PIC_1_OFFSET + SLAVE_SPURIOUS_IRQ)].set_handler_fn(slave_spurious_handler);
         idt
     };
 }
@@ -32,6 +126,7 @@ pub fn init_idt() {
 }
 
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    let _isr = IsrContext::enter();
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
@@ -43,6 +138,8 @@ extern "x86-interrupt" fn double_fault_handler(
 }
 
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _isr = IsrContext::enter();
+    record_irq(InterruptIndex::Timer.irq());
     crate::time::tick(18); // ~18ms per PIT tick at default frequency
     unsafe {
         PICS.lock()
@@ -54,6 +151,9 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
     use x86_64::instructions::port::Port;
 
+    let _isr = IsrContext::enter();
+    record_irq(InterruptIndex::Keyboard.irq());
+
     lazy_static! {
         static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> = Mutex::new(
             Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore)
@@ -79,6 +179,53 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     }
 }
 
+/// Whether `irq` is actually in service on its PIC, as opposed to a spurious delivery.
+fn in_service(irq: u8) -> bool {
+    use x86_64::instructions::port::Port;
+
+    let (port, bit) = if irq < 8 {
+        (PIC_1_COMMAND, irq)
+    } else {
+        (PIC_2_COMMAND, irq - 8)
+    };
+    let mut command: Port<u8> = Port::new(port);
+    let isr = unsafe {
+        command.write(PIC_READ_ISR);
+        command.read()
+    };
+    isr & (1 << bit) != 0
+}
+
+extern "x86-interrupt" fn master_spurious_handler(_stack_frame: InterruptStackFrame) {
+    let _isr = IsrContext::enter();
+    if !in_service(MASTER_SPURIOUS_IRQ) {
+        // No EOI: the master never raised this interrupt.
+        record_spurious();
+        return;
+    }
+    record_irq(MASTER_SPURIOUS_IRQ);
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(PIC_1_OFFSET + MASTER_SPURIOUS_IRQ);
+    }
+}
+
+extern "x86-interrupt" fn slave_spurious_handler(_stack_frame: InterruptStackFrame) {
+    let _isr = IsrContext::enter();
+    let vector = if in_service(SLAVE_SPURIOUS_IRQ) {
+        record_irq(SLAVE_SPURIOUS_IRQ);
+        PIC_1_OFFSET + SLAVE_SPURIOUS_IRQ
+    } else {
+        // The master still saw a real interrupt on the cascade line, so only it gets
+        // an EOI.
+        record_spurious();
+        PIC_1_OFFSET + CASCADE_IRQ
+    };
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(vector);
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
@@ -94,4 +241,92 @@ impl InterruptIndex {
     fn as_usize(self) -> usize {
         usize::from(self.as_u8())
     }
+
+    fn irq(self) -> u8 {
+        self.as_u8() - PIC_1_OFFSET
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x86_64::instructions::interrupts::without_interrupts;
+
+    #[test_case]
+    fn recorded_irqs_are_counted_per_line() {
+        // With interrupts off the timer cannot add to IRQ 0 behind our back.
+        let (before, after) = without_interrupts(|| {
+            let before = stats();
+            for _ in 0..5 {
+                record_irq(0);
+            }
+            record_irq(IRQ_LINES as u8);
+            (before, stats())
+        });
+        assert_eq!(after.counts[0], before.counts[0] + 5);
+        assert_eq!(after.counts[1..], before.counts[1..]);
+        let file = alloc::string::String::from_utf8(stats_file()).unwrap();
+        assert!(file.contains("  0: "));
+    }
+
+    #[test_case]
+    fn spurious_interrupts_are_counted_apart() {
+        let before = stats();
+        record_spurious();
+        record_spurious();
+        let after = stats();
+        assert_eq!(after.spurious, before.spurious + 2);
+        let file = alloc::string::String::from_utf8(stats_file()).unwrap();
+        assert!(file.ends_with(&alloc::format!("spurious: {}\n", after.spurious)));
+    }
+
+    /// Logs `len` bytes from interrupt context while the interrupted code holds the
+    /// serial port, returning how many messages were dropped.
+    fn log_with_port_held(len: usize) -> usize {
+        let message = "x".repeat(len);
+        without_interrupts(|| {
+            let before = crate::serial::dropped_from_interrupts();
+            let _port = crate::serial::SERIAL1.lock();
+            let _isr = IsrContext::enter();
+            crate::serial_println!("{}", message);
+            crate::serial::dropped_from_interrupts() - before
+        })
+    }
+
+    #[test_case]
+    fn handlers_defer_output_while_the_port_is_held() {
+        // Returning at all shows the handler did not spin on the held port.
+        assert_eq!(log_with_port_held(16), 0);
+        // The next ordinary print writes the deferred bytes out and empties the buffer.
+        crate::serial_println!();
+    }
+
+    #[test_case]
+    fn deferred_output_that_overflows_the_buffer_is_dropped() {
+        assert_eq!(log_with_port_held(2048), 1);
+        crate::serial_println!();
+        // The flush above made room again.
+        assert_eq!(log_with_port_held(1000), 0);
+        crate::serial_println!();
+    }
+
+    #[test_case]
+    fn handlers_skip_the_console_while_it_is_busy() {
+        without_interrupts(|| {
+            let _console = crate::vga_buffer::WRITER.lock();
+            let _isr = IsrContext::enter();
+            crate::println!("dropped keystroke echo");
+        });
+    }
+
+    #[test_case]
+    fn nested_contexts_leave_interrupt_context_only_when_all_are_gone() {
+        assert!(!in_interrupt());
+        let outer = IsrContext::enter();
+        let inner = IsrContext::enter();
+        drop(inner);
+        assert!(in_interrupt());
+        drop(outer);
+        assert!(!in_interrupt());
+    }
 }
@@ -1,6 +1,16 @@
-use crate::capability::{validate_capability, CapabilityId};
+use crate::audit::{self, AuditEvent};
+use crate::capability::{
+    delegate_capability, revoke_capability, validate_capability, CapabilityId,
+};
 use crate::println;
-use alloc::{collections::BTreeMap, vec::Vec};
+use crate::serial_println;
+use crate::task::{agent_capabilities, agent_name, AgentId};
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    vec::Vec,
+};
+use core::cmp::Reverse;
+use core::sync::atomic::{AtomicU64, Ordering};
 use spin::Mutex;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -14,6 +24,64 @@ pub struct Message {
     pub sender: ProcessId,
     pub data: Vec<u8>,
     pub capabilities: Vec<CapabilityId>,
+    /// Set when the sender asked for a delivery acknowledgement.
+    pub ack_id: Option<u64>,
+    /// Set on acknowledgements: the id of the message that was received.
+    pub acked: Option<u64>,
+    /// Higher priorities are received first; see `receive_message`.
+    pub priority: u8,
+    /// Kernel-wide enqueue order, strictly increasing across all endpoints.
+    pub seq: u64,
+}
+
+pub const PRIORITY_LOW: u8 = 0;
+/// Priority of `send_message` and of acknowledgements.
+pub const PRIORITY_NORMAL: u8 = 1;
+pub const PRIORITY_HIGH: u8 = 2;
+
+/// Sequence number given to the next queued message.
+static NEXT_SEQ: AtomicU64 = AtomicU64::new(1);
+
+fn next_seq() -> u64 {
+    NEXT_SEQ.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Prefix of the capability escalation requests agents send to the supervisor.
+pub const CAP_REQUEST_PREFIX: &[u8] = b"CAP_REQUEST:";
+
+/// What a message carries, for receivers that handle one kind at a time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    /// Free-form payload.
+    Text,
+    /// A `CAP_REQUEST:<pid>:<type>:<detail>` escalation request.
+    CapRequest,
+    /// A delivery acknowledgement queued by `send_message_acked`.
+    Ack,
+}
+
+impl MessageKind {
+    /// Decode the numeric kind agents pass to `receive_ipc_kind`.
+    pub fn from_u32(kind: u32) -> Option<Self> {
+        match kind {
+            0 => Some(MessageKind::Text),
+            1 => Some(MessageKind::CapRequest),
+            2 => Some(MessageKind::Ack),
+            _ => None,
+        }
+    }
+}
+
+impl Message {
+    pub fn kind(&self) -> MessageKind {
+        if self.acked.is_some() {
+            MessageKind::Ack
+        } else if self.data.starts_with(CAP_REQUEST_PREFIX) {
+            MessageKind::CapRequest
+        } else {
+            MessageKind::Text
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -22,8 +90,80 @@ pub struct IpcEndpoint {
     pub max_messages: usize,
 }
 
+/// Queue depth of endpoints created by `create_endpoint` or lazily on first send.
+pub const DEFAULT_QUEUE_DEPTH: usize = 32;
+
 static IPC_ENDPOINTS: Mutex<BTreeMap<ProcessId, IpcEndpoint>> = Mutex::new(BTreeMap::new());
 
+/// How long an acknowledged message may sit unreceived before its ack times out.
+pub const ACK_TIMEOUT_MS: u64 = 5_000;
+
+/// Delivery state of a message sent with `send_message_acked`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AckStatus {
+    /// Queued but not yet received.
+    Pending,
+    /// The recipient received it.
+    Delivered,
+    /// Not received within `ACK_TIMEOUT_MS`; a later receive no longer acks it.
+    TimedOut,
+}
+
+struct PendingAck {
+    sender: ProcessId,
+    sent_ms: u64,
+    status: AckStatus,
+}
+
+/// Outstanding acknowledgements by message id, until the sender polls a final status.
+static ACKS: Mutex<BTreeMap<u64, PendingAck>> = Mutex::new(BTreeMap::new());
+static NEXT_ACK_ID: Mutex<u64> = Mutex::new(1);
+
+/// Undeliverable messages kept per sender; the oldest are dropped first.
+pub const MAX_DEAD_LETTERS: usize = 16;
+
+/// A message that could not be delivered because the recipient had no endpoint or a
+/// full queue. Its capabilities were never delegated and still belong to the sender.
+#[derive(Debug, Clone)]
+pub struct DeadLetter {
+    pub recipient: ProcessId,
+    pub data: Vec<u8>,
+    pub capabilities: Vec<CapabilityId>,
+}
+
+/// Dead-letter queues of the senders that enabled one.
+static DEAD_LETTERS: Mutex<BTreeMap<ProcessId, VecDeque<DeadLetter>>> = Mutex::new(BTreeMap::new());
+
+/// Turn `sender`'s dead-letter queue on or off. Turning it off discards its contents.
+pub fn set_dead_letters(sender: ProcessId, enabled: bool) {
+    let mut queues = DEAD_LETTERS.lock();
+    if enabled {
+        queues.entry(sender).or_default();
+    } else {
+        queues.remove(&sender);
+    }
+}
+
+/// Take every dead letter queued for `sender`, oldest first.
+pub fn drain_dead_letters(sender: ProcessId) -> Vec<DeadLetter> {
+    DEAD_LETTERS
+        .lock()
+        .get_mut(&sender)
+        .map(|queue| queue.drain(..).collect())
+        .unwrap_or_default()
+}
+
+fn dead_letter(sender: ProcessId, letter: DeadLetter) {
+    let mut queues = DEAD_LETTERS.lock();
+    let Some(queue) = queues.get_mut(&sender) else {
+        return;
+    };
+    if queue.len() >= MAX_DEAD_LETTERS {
+        queue.pop_front();
+    }
+    queue.push_back(letter);
+}
+
 pub fn init() {
     // Reserve PID 0 as the Kernel Supervisor endpoint
     let mut endpoints = IPC_ENDPOINTS.lock();
@@ -38,6 +178,14 @@ pub fn init() {
 }
 
 pub fn create_endpoint(process_id: ProcessId) -> Result<(), &'static str> {
+    create_endpoint_with_depth(process_id, DEFAULT_QUEUE_DEPTH)
+}
+
+/// Create an endpoint holding at most `max_messages` queued messages.
+pub fn create_endpoint_with_depth(
+    process_id: ProcessId,
+    max_messages: usize,
+) -> Result<(), &'static str> {
     let mut endpoints = IPC_ENDPOINTS.lock();
 
     if endpoints.contains_key(&process_id) {
@@ -48,48 +196,599 @@ pub fn create_endpoint(process_id: ProcessId) -> Result<(), &'static str> {
         process_id,
         IpcEndpoint {
             messages: Vec::new(),
-            max_messages: 32,
+            max_messages,
         },
     );
 
     Ok(())
 }
 
+/// Deepest queue an agent may give itself with `set_queue_depth` unless
+/// `ipc.max_queue_depth` is configured.
+pub const DEFAULT_MAX_QUEUE_DEPTH: usize = 256;
+
+/// The cap on `set_queue_depth`: `ipc.max_queue_depth` if configured, otherwise
+/// `DEFAULT_MAX_QUEUE_DEPTH`.
+pub fn max_queue_depth() -> usize {
+    crate::config::get_int("ipc.max_queue_depth")
+        .and_then(|depth| usize::try_from(depth).ok())
+        .unwrap_or(DEFAULT_MAX_QUEUE_DEPTH)
+}
+
+/// Why `set_queue_depth` refused a depth.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueDepthError {
+    /// Zero, or above `max_queue_depth()`.
+    OutOfRange,
+    /// Fewer than the messages already queued.
+    BelowQueued,
+}
+
+/// Resize `process_id`'s queue to hold `depth` messages, creating its endpoint if it
+/// has none yet. Queued messages are kept, so `depth` may not be below their count.
+pub fn set_queue_depth(process_id: ProcessId, depth: usize) -> Result<(), QueueDepthError> {
+    if depth == 0 || depth > max_queue_depth() {
+        return Err(QueueDepthError::OutOfRange);
+    }
+    let mut endpoints = IPC_ENDPOINTS.lock();
+    let endpoint = endpoints.entry(process_id).or_insert_with(|| IpcEndpoint {
+        messages: Vec::new(),
+        max_messages: DEFAULT_QUEUE_DEPTH,
+    });
+    if depth < endpoint.messages.len() {
+        return Err(QueueDepthError::BelowQueued);
+    }
+    endpoint.max_messages = depth;
+    Ok(())
+}
+
 pub fn send_message(
     sender: ProcessId,
     recipient: ProcessId,
     data: Vec<u8>,
     capabilities: Vec<CapabilityId>,
 ) -> Result<(), &'static str> {
-    // Validate capabilities
+    deliver(sender, recipient, data, capabilities, None, PRIORITY_NORMAL)
+}
+
+/// Like `send_message`, queued at `priority` (`PRIORITY_*`, higher is received first).
+pub fn send_message_with_priority(
+    sender: ProcessId,
+    recipient: ProcessId,
+    data: Vec<u8>,
+    capabilities: Vec<CapabilityId>,
+    priority: u8,
+) -> Result<(), &'static str> {
+    deliver(sender, recipient, data, capabilities, None, priority)
+}
+
+/// Like `send_message`, but the sender is acknowledged when the recipient receives the
+/// message: an ack message lands on the sender's endpoint and `ack_status` reports
+/// `Delivered`. Returns the message id to poll with.
+pub fn send_message_acked(
+    sender: ProcessId,
+    recipient: ProcessId,
+    data: Vec<u8>,
+    capabilities: Vec<CapabilityId>,
+) -> Result<u64, &'static str> {
+    let id = {
+        let mut next = NEXT_ACK_ID.lock();
+        let id = *next;
+        *next += 1;
+        id
+    };
+    ACKS.lock().insert(
+        id,
+        PendingAck {
+            sender,
+            sent_ms: crate::time::uptime_ms(),
+            status: AckStatus::Pending,
+        },
+    );
+    if let Err(e) = deliver(
+        sender,
+        recipient,
+        data,
+        capabilities,
+        Some(id),
+        PRIORITY_NORMAL,
+    ) {
+        ACKS.lock().remove(&id);
+        return Err(e);
+    }
+    Ok(id)
+}
+
+/// Delivery state of acked message `id` sent by `sender`, or `None` if there is no such
+/// message. `Delivered` and `TimedOut` are final and reported once; the entry is then
+/// forgotten.
+pub fn ack_status(sender: ProcessId, id: u64) -> Option<AckStatus> {
+    let mut acks = ACKS.lock();
+    let ack = acks.get_mut(&id).filter(|ack| ack.sender == sender)?;
+    if ack.status == AckStatus::Pending
+        && crate::time::uptime_ms().saturating_sub(ack.sent_ms) >= ACK_TIMEOUT_MS
+    {
+        ack.status = AckStatus::TimedOut;
+    }
+    let status = ack.status;
+    if status != AckStatus::Pending {
+        acks.remove(&id);
+    }
+    Some(status)
+}
+
+fn deliver(
+    sender: ProcessId,
+    recipient: ProcessId,
+    data: Vec<u8>,
+    capabilities: Vec<CapabilityId>,
+    ack_id: Option<u64>,
+    priority: u8,
+) -> Result<(), &'static str> {
+    // Validate capabilities: each must exist and be held by the sender. The kernel
+    // supervisor may hand out any capability.
+    let sender_caps = if sender == KERNEL_SUPERVISOR_PID {
+        Vec::new()
+    } else {
+        agent_capabilities(AgentId(sender.0))
+    };
     for &cap_id in &capabilities {
         if validate_capability(cap_id).is_none() {
             return Err("Invalid capability");
         }
+        if sender != KERNEL_SUPERVISOR_PID && !sender_caps.contains(&cap_id) {
+            serial_println!(
+                "[SECURITY] PID {} tried to transfer capability {} it does not hold",
+                sender.0,
+                cap_id.0
+            );
+            return Err("Capability not owned by sender");
+        }
     }
 
+    // Agents don't coordinate endpoint creation, so a message to a registered agent
+    // that hasn't created its endpoint yet lazily creates one with the default depth.
     let mut endpoints = IPC_ENDPOINTS.lock();
+    if !endpoints.contains_key(&recipient) && agent_name(AgentId(recipient.0)).is_some() {
+        endpoints.insert(
+            recipient,
+            IpcEndpoint {
+                messages: Vec::new(),
+                max_messages: DEFAULT_QUEUE_DEPTH,
+            },
+        );
+    }
+    let undeliverable = match endpoints.get(&recipient) {
+        None => Some("No such endpoint"),
+        Some(endpoint) if endpoint.messages.len() >= endpoint.max_messages => {
+            Some("Message queue full")
+        }
+        Some(_) => None,
+    };
+    if let Some(reason) = undeliverable {
+        drop(endpoints);
+        dead_letter(
+            sender,
+            DeadLetter {
+                recipient,
+                data,
+                capabilities,
+            },
+        );
+        return Err(reason);
+    }
     let endpoint = endpoints.get_mut(&recipient).ok_or("No such endpoint")?;
 
-    if endpoint.messages.len() >= endpoint.max_messages {
-        return Err("Message queue full");
+    // Each delegated capability gains a reference held by the recipient, so a later
+    // revoke by the sender does not pull it out from under the delegate.
+    for &cap_id in &capabilities {
+        delegate_capability(cap_id);
+        audit::record(AuditEvent::CapabilityTransfer {
+            cap: cap_id,
+            sender: sender.0,
+            recipient: recipient.0,
+        });
     }
 
     endpoint.messages.push(Message {
         sender,
         data,
         capabilities,
+        ack_id,
+        acked: None,
+        priority,
+        seq: next_seq(),
     });
 
     Ok(())
 }
 
+/// Remove `process_id`'s endpoint, zeroing queued payloads and dropping the capability
+/// references they carried. The endpoint is recreated empty on next use. Its
+/// dead-letter queue is zeroed and disabled as well.
+pub fn destroy_endpoint(process_id: ProcessId) {
+    if let Some(mut queue) = DEAD_LETTERS.lock().remove(&process_id) {
+        for letter in &mut queue {
+            letter.data.fill(0);
+        }
+    }
+    let Some(mut endpoint) = IPC_ENDPOINTS.lock().remove(&process_id) else {
+        return;
+    };
+    for message in &mut endpoint.messages {
+        message.data.fill(0);
+        for &cap_id in &message.capabilities {
+            revoke_capability(cap_id);
+        }
+    }
+}
+
+/// Depth and capacity of `process_id`'s queue, without exposing its contents. An agent
+/// that has no endpoint yet reports the empty default queue it would get on first
+/// send; `None` means there is no such endpoint or agent.
+pub fn queue_stats(process_id: ProcessId) -> Option<(usize, usize)> {
+    if let Some(endpoint) = IPC_ENDPOINTS.lock().get(&process_id) {
+        return Some((endpoint.messages.len(), endpoint.max_messages));
+    }
+    agent_name(AgentId(process_id.0)).map(|_| (0, DEFAULT_QUEUE_DEPTH))
+}
+
+/// Copies of the messages queued for `process_id`, in the order they would be
+/// received, without removing them or triggering acknowledgements.
+pub fn peek_messages(process_id: ProcessId) -> Vec<Message> {
+    let mut messages = IPC_ENDPOINTS
+        .lock()
+        .get(&process_id)
+        .map(|endpoint| endpoint.messages.clone())
+        .unwrap_or_default();
+    messages.sort_by_key(receive_order);
+    messages
+}
+
+/// Receive the next queued message. Messages are received in strict priority order,
+/// highest first, and first-in first-out within a priority. Ties are broken by the
+/// enqueue sequence number, so the order is fully determined by the order of sends.
 pub fn receive_message(process_id: ProcessId) -> Option<Message> {
+    take_first(process_id, |_| true)
+}
+
+/// Receive the next queued message of `kind`, in the same order as `receive_message`,
+/// leaving messages of other kinds queued.
+pub fn receive_matching(process_id: ProcessId, kind: MessageKind) -> Option<Message> {
+    take_first(process_id, |message| message.kind() == kind)
+}
+
+/// Sort key giving the receive order: priority descending, then sequence ascending.
+fn receive_order(message: &Message) -> (Reverse<u8>, u64) {
+    (Reverse(message.priority), message.seq)
+}
+
+fn take_first(process_id: ProcessId, matches: impl Fn(&Message) -> bool) -> Option<Message> {
     let mut endpoints = IPC_ENDPOINTS.lock();
-    if let Some(endpoint) = endpoints.get_mut(&process_id) {
-        if !endpoint.messages.is_empty() {
-            return Some(endpoint.messages.remove(0));
+    let endpoint = endpoints.get_mut(&process_id)?;
+    let (index, _) = endpoint
+        .messages
+        .iter()
+        .enumerate()
+        .filter(|(_, message)| matches(message))
+        .min_by_key(|(_, message)| receive_order(message))?;
+    let message = endpoint.messages.remove(index);
+
+    if let Some(id) = message.ack_id {
+        acknowledge(&mut endpoints, process_id, message.sender, id);
+    }
+    Some(message)
+}
+
+/// Mark acked message `id` delivered and queue an ack carrying its id on the sender's
+/// endpoint. Acks that already timed out are left alone. The ack message is dropped if
+/// the sender's queue is full; `ack_status` still reports the delivery.
+fn acknowledge(
+    endpoints: &mut BTreeMap<ProcessId, IpcEndpoint>,
+    recipient: ProcessId,
+    sender: ProcessId,
+    id: u64,
+) {
+    let mut acks = ACKS.lock();
+    let Some(ack) = acks.get_mut(&id).filter(|a| a.status == AckStatus::Pending) else {
+        return;
+    };
+    ack.status = AckStatus::Delivered;
+
+    if let Some(endpoint) = endpoints.get_mut(&sender) {
+        if endpoint.messages.len() < endpoint.max_messages {
+            endpoint.messages.push(Message {
+                sender: recipient,
+                data: id.to_le_bytes().to_vec(),
+                capabilities: Vec::new(),
+                ack_id: None,
+                acked: Some(id),
+                priority: PRIORITY_NORMAL,
+                seq: next_seq(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capability::{refcount, Capability};
+    use crate::testing;
+
+    fn transfers_of(cap: CapabilityId) -> Vec<AuditEvent> {
+        audit::recent(usize::MAX)
+            .into_iter()
+            .map(|record| record.event)
+            .filter(
+                |event| matches!(event, AuditEvent::CapabilityTransfer { cap: c, .. } if *c == cap),
+            )
+            .collect()
+    }
+
+    #[test_case]
+    fn delegated_capabilities_are_audited() {
+        let sender = testing::spawn_agent("ipc-giver", alloc::vec![Capability::Network]);
+        let recipient = testing::spawn_agent("ipc-taker", Vec::new());
+        let cap = agent_capabilities(sender)[0];
+
+        let (from, to) = (ProcessId(sender.0), ProcessId(recipient.0));
+        assert_eq!(
+            send_message(from, to, b"cap".to_vec(), alloc::vec![cap]),
+            Ok(())
+        );
+        assert_eq!(
+            transfers_of(cap),
+            alloc::vec![AuditEvent::CapabilityTransfer {
+                cap,
+                sender: sender.0,
+                recipient: recipient.0,
+            }]
+        );
+        assert_eq!(refcount(cap), 2);
+        assert_eq!(receive_message(to).unwrap().capabilities, alloc::vec![cap]);
+    }
+
+    #[test_case]
+    fn capabilities_the_sender_does_not_hold_are_refused() {
+        let owner = testing::spawn_agent("ipc-owner", alloc::vec![Capability::Network]);
+        let thief = testing::spawn_agent("ipc-thief", Vec::new());
+        let recipient = testing::spawn_agent("ipc-fence", Vec::new());
+        let cap = agent_capabilities(owner)[0];
+
+        let (from, to) = (ProcessId(thief.0), ProcessId(recipient.0));
+        assert_eq!(
+            send_message(from, to, Vec::new(), alloc::vec![cap]),
+            Err("Capability not owned by sender")
+        );
+        assert!(transfers_of(cap).is_empty());
+        assert_eq!(refcount(cap), 1);
+        assert!(receive_message(to).is_none());
+    }
+
+    #[test_case]
+    fn sending_to_an_agent_without_an_endpoint_creates_one() {
+        let sender = testing::spawn_agent("ipc-early", Vec::new());
+        let recipient = testing::spawn_agent("ipc-late", Vec::new());
+        let (from, to) = (ProcessId(sender.0), ProcessId(recipient.0));
+
+        assert_eq!(
+            send_message(from, to, b"early".to_vec(), Vec::new()),
+            Ok(())
+        );
+        assert_eq!(queue_stats(to), Some((1, DEFAULT_QUEUE_DEPTH)));
+        assert_eq!(create_endpoint(to), Err("Endpoint already exists"));
+
+        let message = receive_message(to).unwrap();
+        assert_eq!((message.sender, message.data), (from, b"early".to_vec()));
+    }
+
+    #[test_case]
+    fn sending_to_an_unknown_pid_still_fails() {
+        let sender = testing::spawn_agent("ipc-nowhere", Vec::new());
+        let nobody = ProcessId(u64::MAX - 1);
+        assert_eq!(
+            send_message(ProcessId(sender.0), nobody, Vec::new(), Vec::new()),
+            Err("No such endpoint")
+        );
+        assert_eq!(queue_stats(nobody), None);
+    }
+
+    #[test_case]
+    fn ack_arrives_only_once_the_recipient_receives() {
+        let sender = testing::spawn_agent("ack-sender", Vec::new());
+        let recipient = testing::spawn_agent("ack-recipient", Vec::new());
+        let (from, to) = (ProcessId(sender.0), ProcessId(recipient.0));
+        create_endpoint(from).unwrap();
+
+        let id = send_message_acked(from, to, b"ping".to_vec(), Vec::new()).unwrap();
+        assert_eq!(ack_status(from, id), Some(AckStatus::Pending));
+        assert!(receive_message(from).is_none());
+        assert_eq!(ack_status(ProcessId(recipient.0), id), None);
+
+        assert_eq!(receive_message(to).unwrap().data, b"ping".to_vec());
+        let ack = receive_message(from).unwrap();
+        assert_eq!((ack.sender, ack.acked), (to, Some(id)));
+        assert_eq!(ack.data, id.to_le_bytes().to_vec());
+        assert_eq!(ack_status(from, id), Some(AckStatus::Delivered));
+        assert_eq!(ack_status(from, id), None);
+    }
+
+    #[test_case]
+    fn unreceived_acked_messages_time_out() {
+        let sender = testing::spawn_agent("ack-patient", Vec::new());
+        let recipient = testing::spawn_agent("ack-absent", Vec::new());
+        let (from, to) = (ProcessId(sender.0), ProcessId(recipient.0));
+        create_endpoint(from).unwrap();
+
+        let id = send_message_acked(from, to, b"late".to_vec(), Vec::new()).unwrap();
+        // Backdate the send to boot, once the kernel has been up long enough.
+        crate::time::sleep_ms(ACK_TIMEOUT_MS.saturating_sub(crate::time::uptime_ms()));
+        ACKS.lock().get_mut(&id).unwrap().sent_ms = 0;
+
+        assert_eq!(ack_status(from, id), Some(AckStatus::TimedOut));
+        assert!(receive_message(to).is_some());
+        assert!(receive_message(from).is_none());
+    }
+
+    #[test_case]
+    fn receiving_by_kind_leaves_other_kinds_queued() {
+        let sender = testing::spawn_agent("kind-sender", Vec::new());
+        let recipient = testing::spawn_agent("kind-recipient", Vec::new());
+        let (from, to) = (ProcessId(sender.0), ProcessId(recipient.0));
+        let mut request = CAP_REQUEST_PREFIX.to_vec();
+        request.extend_from_slice(b"7:network:");
+        for data in [&b"first"[..], &request, b"second", b"CAP_REQUEST:8:clock:"] {
+            send_message(from, to, data.to_vec(), Vec::new()).unwrap();
+        }
+
+        let message = receive_matching(to, MessageKind::CapRequest).unwrap();
+        assert_eq!(message.data, request);
+        assert_eq!(message.kind(), MessageKind::CapRequest);
+        assert_eq!(queue_stats(to), Some((3, DEFAULT_QUEUE_DEPTH)));
+        assert!(receive_matching(to, MessageKind::Ack).is_none());
+
+        // Each kind stays in FIFO order.
+        assert_eq!(receive_message(to).unwrap().data, b"first".to_vec());
+        assert_eq!(
+            receive_matching(to, MessageKind::CapRequest).unwrap().data,
+            b"CAP_REQUEST:8:clock:".to_vec()
+        );
+        assert_eq!(
+            receive_matching(to, MessageKind::Text).unwrap().data,
+            b"second".to_vec()
+        );
+        assert!(receive_message(to).is_none());
+    }
+
+    #[test_case]
+    fn sends_to_a_full_queue_become_drainable_dead_letters() {
+        let sender = testing::spawn_agent("dead-sender", Vec::new());
+        let recipient = testing::spawn_agent("dead-recipient", Vec::new());
+        let (from, to) = (ProcessId(sender.0), ProcessId(recipient.0));
+        create_endpoint_with_depth(to, 1).unwrap();
+
+        send_message(from, to, b"fits".to_vec(), Vec::new()).unwrap();
+        assert_eq!(
+            send_message(from, to, b"dropped".to_vec(), Vec::new()),
+            Err("Message queue full")
+        );
+        assert!(drain_dead_letters(from).is_empty());
+
+        set_dead_letters(from, true);
+        assert_eq!(
+            send_message(from, to, b"kept".to_vec(), Vec::new()),
+            Err("Message queue full")
+        );
+        let letters = drain_dead_letters(from);
+        assert_eq!(letters.len(), 1);
+        assert_eq!(
+            (letters[0].recipient, &letters[0].data[..]),
+            (to, &b"kept"[..])
+        );
+        assert!(drain_dead_letters(from).is_empty());
+
+        // A retry succeeds once the recipient has made room.
+        assert!(receive_message(to).is_some());
+        let retry = letters.into_iter().next().unwrap();
+        assert_eq!(
+            send_message(from, retry.recipient, retry.data, Vec::new()),
+            Ok(())
+        );
+        set_dead_letters(from, false);
+    }
+
+    #[test_case]
+    fn dead_letter_queue_keeps_the_newest_messages() {
+        let sender = testing::spawn_agent("dead-flood", Vec::new());
+        let from = ProcessId(sender.0);
+        let nobody = ProcessId(u64::MAX - 2);
+        set_dead_letters(from, true);
+
+        for i in 0..MAX_DEAD_LETTERS + 2 {
+            assert!(send_message(from, nobody, alloc::vec![i as u8], Vec::new()).is_err());
+        }
+        let letters = drain_dead_letters(from);
+        set_dead_letters(from, false);
+
+        assert_eq!(letters.len(), MAX_DEAD_LETTERS);
+        assert_eq!(letters[0].data, alloc::vec![2]);
+        assert_eq!(
+            letters[MAX_DEAD_LETTERS - 1].data,
+            alloc::vec![MAX_DEAD_LETTERS as u8 + 1]
+        );
+    }
+
+    #[test_case]
+    fn priorities_are_received_strictly_and_fifo_within_each() {
+        let sender = testing::spawn_agent("prio-sender", Vec::new());
+        let recipient = testing::spawn_agent("prio-recipient", Vec::new());
+        let (from, to) = (ProcessId(sender.0), ProcessId(recipient.0));
+        let sends = [
+            (PRIORITY_LOW, b"low-1"),
+            (PRIORITY_NORMAL, b"nrm-1"),
+            (PRIORITY_HIGH, b"hig-1"),
+            (PRIORITY_LOW, b"low-2"),
+            (PRIORITY_HIGH, b"hig-2"),
+            (PRIORITY_NORMAL, b"nrm-2"),
+            (PRIORITY_HIGH, b"hig-3"),
+        ];
+        for (priority, data) in sends {
+            send_message_with_priority(from, to, data.to_vec(), Vec::new(), priority).unwrap();
+        }
+
+        let expected: [&[u8]; 7] = [
+            b"hig-1", b"hig-2", b"hig-3", b"nrm-1", b"nrm-2", b"low-1", b"low-2",
+        ];
+        let peeked: Vec<Vec<u8>> = peek_messages(to).into_iter().map(|m| m.data).collect();
+        assert_eq!(peeked, expected);
+
+        let mut last: Option<Message> = None;
+        for data in expected {
+            let message = receive_message(to).unwrap();
+            assert_eq!(message.data, data);
+            if let Some(last) = last.filter(|l| l.priority == message.priority) {
+                assert!(message.seq > last.seq);
+            }
+            last = Some(message);
+        }
+        assert!(receive_message(to).is_none());
+    }
+
+    #[test_case]
+    fn deeper_queues_accept_more_messages_until_the_new_depth() {
+        let agent = testing::spawn_agent("ipc-deep", Vec::new());
+        let queue = ProcessId(agent.0);
+        let send = || send_message(KERNEL_SUPERVISOR_PID, queue, b"x".to_vec(), Vec::new());
+        for _ in 0..DEFAULT_QUEUE_DEPTH {
+            send().unwrap();
+        }
+        assert!(send().is_err());
+
+        assert_eq!(set_queue_depth(queue, DEFAULT_QUEUE_DEPTH + 2), Ok(()));
+        assert_eq!(send(), Ok(()));
+        assert_eq!(send(), Ok(()));
+        assert!(send().is_err());
+        assert_eq!(
+            queue_stats(queue),
+            Some((DEFAULT_QUEUE_DEPTH + 2, DEFAULT_QUEUE_DEPTH + 2))
+        );
+    }
+
+    #[test_case]
+    fn queue_depths_outside_the_cap_or_below_the_backlog_are_refused() {
+        let agent = testing::spawn_agent("ipc-shallow", Vec::new());
+        let queue = ProcessId(agent.0);
+        assert_eq!(set_queue_depth(queue, 0), Err(QueueDepthError::OutOfRange));
+        assert_eq!(
+            set_queue_depth(queue, max_queue_depth() + 1),
+            Err(QueueDepthError::OutOfRange)
+        );
+
+        for _ in 0..3 {
+            send_message(KERNEL_SUPERVISOR_PID, queue, b"x".to_vec(), Vec::new()).unwrap();
         }
+        assert_eq!(set_queue_depth(queue, 2), Err(QueueDepthError::BelowQueued));
+        assert_eq!(set_queue_depth(queue, 3), Ok(()));
     }
-    None
 }
@@ -0,0 +1,462 @@
+//! Minimal JSON parser for configuration: objects, arrays, strings, integers, booleans
+//! and null. Numbers must be integers that fit an `i64`; the kernel does no floating
+//! point. Input length and nesting depth are capped so a hostile document cannot
+//! exhaust the heap or the stack.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Longest document `parse` accepts, in bytes.
+pub const MAX_INPUT_LEN: usize = 64 * 1024;
+/// Deepest nesting of arrays and objects `parse` accepts.
+pub const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(i64),
+    String(String),
+    Array(Vec<Value>),
+    /// Members in document order. A key given twice keeps both; `get` finds the first.
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// The member `key` of an object.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    /// Follow a `.`-separated path of object keys, e.g. `"net.dns.server"`.
+    pub fn path(&self, path: &str) -> Option<&Value> {
+        path.split('.').try_fold(self, |value, key| value.get(key))
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The document is longer than `MAX_INPUT_LEN`.
+    TooLong,
+    /// Arrays and objects nest deeper than `MAX_DEPTH`.
+    TooDeep,
+    /// The document ends inside a value.
+    UnexpectedEnd,
+    /// A byte that cannot start or continue a value here.
+    UnexpectedByte(u8),
+    /// A number with a fraction or exponent, or one outside the `i64` range.
+    InvalidNumber,
+    /// An unknown `\` escape or a bad `\u` sequence.
+    InvalidEscape,
+    /// A raw control character inside a string.
+    ControlCharacter,
+    /// More than whitespace after the top-level value.
+    TrailingData,
+}
+
+/// Why `parse` rejected a document, and the byte offset where it noticed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseError {
+    pub offset: usize,
+    pub kind: ErrorKind,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let offset = self.offset;
+        match self.kind {
+            ErrorKind::TooLong => write!(f, "document longer than {MAX_INPUT_LEN} bytes"),
+            ErrorKind::TooDeep => write!(f, "nesting deeper than {MAX_DEPTH} at offset {offset}"),
+            ErrorKind::UnexpectedEnd => write!(f, "unexpected end of input at offset {offset}"),
+            ErrorKind::UnexpectedByte(b) => {
+                write!(f, "unexpected byte 0x{b:02x} at offset {offset}")
+            }
+            ErrorKind::InvalidNumber => write!(f, "invalid number at offset {offset}"),
+            ErrorKind::InvalidEscape => write!(f, "invalid escape at offset {offset}"),
+            ErrorKind::ControlCharacter => {
+                write!(f, "control character in string at offset {offset}")
+            }
+            ErrorKind::TrailingData => write!(f, "trailing data at offset {offset}"),
+        }
+    }
+}
+
+/// Parse a complete JSON document.
+pub fn parse(text: &str) -> Result<Value, ParseError> {
+    if text.len() > MAX_INPUT_LEN {
+        return Err(ParseError {
+            offset: MAX_INPUT_LEN,
+            kind: ErrorKind::TooLong,
+        });
+    }
+    let mut parser = Parser {
+        bytes: text.as_bytes(),
+        pos: 0,
+    };
+    let value = parser.value(0)?;
+    parser.skip_whitespace();
+    if parser.pos < parser.bytes.len() {
+        return Err(parser.error(ErrorKind::TrailingData));
+    }
+    Ok(value)
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, kind: ErrorKind) -> ParseError {
+        ParseError {
+            offset: self.pos,
+            kind,
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.bytes.get(self.pos) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> Result<u8, ParseError> {
+        self.bytes
+            .get(self.pos)
+            .copied()
+            .ok_or(self.error(ErrorKind::UnexpectedEnd))
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), ParseError> {
+        match self.peek()? {
+            b if b == byte => {
+                self.pos += 1;
+                Ok(())
+            }
+            b => Err(self.error(ErrorKind::UnexpectedByte(b))),
+        }
+    }
+
+    fn literal(&mut self, word: &[u8], value: Value) -> Result<Value, ParseError> {
+        for &b in word {
+            self.expect(b)?;
+        }
+        Ok(value)
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, ParseError> {
+        self.skip_whitespace();
+        match self.peek()? {
+            b'{' => self.object(depth + 1),
+            b'[' => self.array(depth + 1),
+            b'"' => self.string().map(Value::String),
+            b't' => self.literal(b"true", Value::Bool(true)),
+            b'f' => self.literal(b"false", Value::Bool(false)),
+            b'n' => self.literal(b"null", Value::Null),
+            b'-' | b'0'..=b'9' => self.number(),
+            b => Err(self.error(ErrorKind::UnexpectedByte(b))),
+        }
+    }
+
+    fn object(&mut self, depth: usize) -> Result<Value, ParseError> {
+        if depth > MAX_DEPTH {
+            return Err(self.error(ErrorKind::TooDeep));
+        }
+        self.expect(b'{')?;
+        let mut members = Vec::new();
+        self.skip_whitespace();
+        if self.peek()? == b'}' {
+            self.pos += 1;
+            return Ok(Value::Object(members));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.skip_whitespace();
+            self.expect(b':')?;
+            let value = self.value(depth)?;
+            members.push((key, value));
+            self.skip_whitespace();
+            match self.peek()? {
+                b',' => self.pos += 1,
+                b'}' => {
+                    self.pos += 1;
+                    return Ok(Value::Object(members));
+                }
+                b => return Err(self.error(ErrorKind::UnexpectedByte(b))),
+            }
+        }
+    }
+
+    fn array(&mut self, depth: usize) -> Result<Value, ParseError> {
+        if depth > MAX_DEPTH {
+            return Err(self.error(ErrorKind::TooDeep));
+        }
+        self.expect(b'[')?;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.peek()? == b']' {
+            self.pos += 1;
+            return Ok(Value::Array(items));
+        }
+        loop {
+            items.push(self.value(depth)?);
+            self.skip_whitespace();
+            match self.peek()? {
+                b',' => self.pos += 1,
+                b']' => {
+                    self.pos += 1;
+                    return Ok(Value::Array(items));
+                }
+                b => return Err(self.error(ErrorKind::UnexpectedByte(b))),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Value, ParseError> {
+        let start = self.pos;
+        if self.bytes.get(self.pos) == Some(&b'-') {
+            self.pos += 1;
+        }
+        let digits = self.pos;
+        while let Some(b'0'..=b'9') = self.bytes.get(self.pos) {
+            self.pos += 1;
+        }
+        let leading_zero = self.pos - digits > 1 && self.bytes[digits] == b'0';
+        let fraction = matches!(self.bytes.get(self.pos), Some(b'.' | b'e' | b'E'));
+        if self.pos == digits || leading_zero || fraction {
+            return Err(ParseError {
+                offset: start,
+                kind: ErrorKind::InvalidNumber,
+            });
+        }
+        // Only ASCII digits and '-' were consumed, so this slice is valid UTF-8.
+        let text = core::str::from_utf8(&self.bytes[start..self.pos]).unwrap_or("");
+        text.parse::<i64>()
+            .map(Value::Number)
+            .map_err(|_| ParseError {
+                offset: start,
+                kind: ErrorKind::InvalidNumber,
+            })
+    }
+
+    fn string(&mut self) -> Result<String, ParseError> {
+        self.expect(b'"')?;
+        let mut out = Vec::new();
+        loop {
+            let b = self.peek()?;
+            self.pos += 1;
+            match b {
+                b'"' => break,
+                b'\\' => {
+                    let escaped = self.peek()?;
+                    self.pos += 1;
+                    match escaped {
+                        b'"' | b'\\' | b'/' => out.push(escaped),
+                        b'b' => out.push(0x08),
+                        b'f' => out.push(0x0c),
+                        b'n' => out.push(b'\n'),
+                        b'r' => out.push(b'\r'),
+                        b't' => out.push(b'\t'),
+                        b'u' => {
+                            let c = self.unicode_escape()?;
+                            let mut buf = [0; 4];
+                            out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                        }
+                        _ => {
+                            self.pos -= 1;
+                            return Err(self.error(ErrorKind::InvalidEscape));
+                        }
+                    }
+                }
+                0x00..=0x1f => {
+                    self.pos -= 1;
+                    return Err(self.error(ErrorKind::ControlCharacter));
+                }
+                _ => out.push(b),
+            }
+        }
+        // The input is a `str` and escapes are pushed as whole UTF-8 sequences.
+        String::from_utf8(out).map_err(|_| self.error(ErrorKind::InvalidEscape))
+    }
+
+    /// The character after a `\u`, combining a UTF-16 surrogate pair if there is one.
+    fn unicode_escape(&mut self) -> Result<char, ParseError> {
+        let high = self.hex4()?;
+        let code = if (0xd800..0xdc00).contains(&high) {
+            self.expect(b'\\')?;
+            self.expect(b'u')?;
+            let low = self.hex4()?;
+            if !(0xdc00..0xe000).contains(&low) {
+                return Err(self.error(ErrorKind::InvalidEscape));
+            }
+            0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+        } else {
+            high
+        };
+        char::from_u32(code).ok_or(self.error(ErrorKind::InvalidEscape))
+    }
+
+    fn hex4(&mut self) -> Result<u32, ParseError> {
+        let digits = self
+            .bytes
+            .get(self.pos..self.pos + 4)
+            .ok_or(self.error(ErrorKind::UnexpectedEnd))?;
+        if !digits.iter().all(u8::is_ascii_hexdigit) {
+            return Err(self.error(ErrorKind::InvalidEscape));
+        }
+        let value = digits.iter().fold(0, |acc, &d| {
+            (acc << 4) | (d as char).to_digit(16).unwrap_or(0)
+        });
+        self.pos += 4;
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(text: &str) -> ParseError {
+        parse(text).unwrap_err()
+    }
+
+    #[test_case]
+    fn nested_objects_and_arrays_parse() {
+        let value = parse(
+            r#" {"net": {"dns": {"server": "10.0.2.3", "ttl": 300}}, "ok": [true, null, -1]} "#,
+        )
+        .unwrap();
+        assert_eq!(
+            value.path("net.dns.server").and_then(Value::as_str),
+            Some("10.0.2.3")
+        );
+        assert_eq!(value.path("net.dns.ttl").and_then(Value::as_i64), Some(300));
+        assert_eq!(
+            value.get("ok").and_then(Value::as_array),
+            Some(&[Value::Bool(true), Value::Null, Value::Number(-1)][..])
+        );
+        assert_eq!(value.path("net.missing"), None);
+    }
+
+    #[test_case]
+    fn typed_accessors_reject_other_types() {
+        let value = parse(r#"{"port": 8080, "name": "agent", "debug": false}"#).unwrap();
+        assert_eq!(value.get("port").and_then(Value::as_i64), Some(8080));
+        assert_eq!(value.get("port").and_then(Value::as_str), None);
+        assert_eq!(value.get("name").and_then(Value::as_i64), None);
+        assert_eq!(value.get("debug").and_then(Value::as_bool), Some(false));
+    }
+
+    #[test_case]
+    fn escapes_decode_to_utf8() {
+        let value = parse(r#""tab\there é 😀 \"q\"""#).unwrap();
+        assert_eq!(value.as_str(), Some("tab\there \u{e9} \u{1f600} \"q\""));
+    }
+
+    #[test_case]
+    fn malformed_documents_report_where_they_fail() {
+        assert_eq!(
+            error(r#"{"a": 1,}"#),
+            ParseError {
+                offset: 8,
+                kind: ErrorKind::UnexpectedByte(b'}')
+            }
+        );
+        assert_eq!(
+            error(r#"{"a": "#),
+            ParseError {
+                offset: 6,
+                kind: ErrorKind::UnexpectedEnd
+            }
+        );
+        assert_eq!(
+            error("[1.5]"),
+            ParseError {
+                offset: 1,
+                kind: ErrorKind::InvalidNumber
+            }
+        );
+        assert_eq!(
+            error("[01]"),
+            ParseError {
+                offset: 1,
+                kind: ErrorKind::InvalidNumber
+            }
+        );
+        assert_eq!(
+            error(r#""\x""#),
+            ParseError {
+                offset: 2,
+                kind: ErrorKind::InvalidEscape
+            }
+        );
+        assert_eq!(
+            error("\"a\nb\""),
+            ParseError {
+                offset: 2,
+                kind: ErrorKind::ControlCharacter
+            }
+        );
+        assert_eq!(
+            error("{} x"),
+            ParseError {
+                offset: 3,
+                kind: ErrorKind::TrailingData
+            }
+        );
+        assert_eq!(
+            alloc::format!("{}", error("[1, ]")),
+            "unexpected byte 0x5d at offset 4"
+        );
+    }
+
+    #[test_case]
+    fn nesting_and_length_are_capped() {
+        let deep = "[".repeat(MAX_DEPTH + 1) + &"]".repeat(MAX_DEPTH + 1);
+        assert_eq!(
+            error(&deep),
+            ParseError {
+                offset: MAX_DEPTH,
+                kind: ErrorKind::TooDeep
+            }
+        );
+        let shallow = "[".repeat(MAX_DEPTH) + &"]".repeat(MAX_DEPTH);
+        assert!(parse(&shallow).is_ok());
+
+        let long = alloc::format!("\"{}\"", "a".repeat(MAX_INPUT_LEN));
+        assert_eq!(error(&long).kind, ErrorKind::TooLong);
+    }
+}
@@ -0,0 +1,60 @@
+//! Kernel-mediated named locks that let agents serialize access to shared resources.
+//! A lock is free or held by exactly one agent; contenders wait cooperatively in the
+//! executor (see `env.lock_acquire`) rather than spinning inside a host call.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use spin::Mutex;
+
+/// Longest lock name accepted from agents.
+pub const MAX_NAME_LEN: usize = 64;
+
+/// Held locks by name, mapped to the owning agent's PID.
+static LOCKS: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReleaseError {
+    /// Nobody holds the lock.
+    NotHeld,
+    /// Another agent holds the lock.
+    NotOwner,
+}
+
+/// Take `name` for `pid` if it is free. Re-acquiring a lock `pid` already holds
+/// succeeds without nesting.
+pub fn try_acquire(name: &str, pid: u64) -> bool {
+    let mut locks = LOCKS.lock();
+    match locks.get(name) {
+        Some(&owner) => owner == pid,
+        None => {
+            locks.insert(String::from(name), pid);
+            true
+        }
+    }
+}
+
+/// Release `name` if `pid` holds it.
+pub fn release(name: &str, pid: u64) -> Result<(), ReleaseError> {
+    let mut locks = LOCKS.lock();
+    match locks.get(name) {
+        None => Err(ReleaseError::NotHeld),
+        Some(&owner) if owner != pid => Err(ReleaseError::NotOwner),
+        Some(_) => {
+            locks.remove(name);
+            Ok(())
+        }
+    }
+}
+
+/// The agent holding `name`, if any.
+pub fn owner(name: &str) -> Option<u64> {
+    LOCKS.lock().get(name).copied()
+}
+
+/// Release every lock `pid` holds, e.g. when its agent exits. Returns how many.
+pub fn release_all(pid: u64) -> usize {
+    let mut locks = LOCKS.lock();
+    let before = locks.len();
+    locks.retain(|_, owner| *owner != pid);
+    before - locks.len()
+}
@@ -3,6 +3,8 @@
 #![feature(custom_test_frameworks)]
 #![feature(abi_x86_interrupt)]
 #![feature(alloc_error_handler)]
+#![test_runner(crate::testing::run_tests)]
+#![reexport_test_harness_main = "test_main"]
 #![allow(dead_code)]
 
 extern crate alloc;
@@ -11,19 +13,36 @@ use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 
 mod allocator;
+pub mod arch;
+mod audit;
+mod boot;
+pub mod bytes;
 mod capability;
+pub mod checksum;
+pub mod config;
+mod crashdump;
+pub mod crypto;
 pub mod dns;
 mod gdt;
 pub mod initramfs;
 mod interrupts;
 mod ipc;
+pub mod json;
+pub mod locks;
 mod memory;
 pub mod net;
 pub mod pci;
+mod preempt;
+pub mod ratelimit;
+pub mod rng;
 pub mod rtl8139;
+mod sandbox;
 mod serial;
+pub mod sockets;
 pub mod syscall_errors;
 mod task;
+#[cfg(test)]
+mod testing;
 pub mod time;
 pub mod vfs;
 mod vga_buffer;
@@ -42,26 +61,42 @@ macro_rules! log {
 }
 
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    use boot::{BootReport, Outcome};
     use memory::BootInfoFrameAllocator;
     use x86_64::VirtAddr;
 
+    let mut report = BootReport::new();
+
     // Initialize core systems
     gdt::init();
+    report.record("gdt", Outcome::Ok);
     interrupts::init_idt();
     unsafe { interrupts::PICS.lock().initialize() };
     x86_64::instructions::interrupts::enable();
+    report.record("interrupts", Outcome::Ok);
+    time::calibrate_tsc();
+    rng::init();
 
     // Initialize memory
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
     let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    match allocator::init_heap(&mut mapper, &mut frame_allocator) {
+        Ok(()) => report.record("heap", Outcome::Ok),
+        Err(_) => report.record("heap", Outcome::Failed("could not map the kernel heap")),
+    }
+    if report.is_fatal() {
+        halt_with_report(&report);
+    }
 
     // Initialize microkernel subsystems
     capability::init();
+    report.record("capabilities", Outcome::Ok);
     ipc::init();
+    report.record("ipc", Outcome::Ok);
 
     log!("[SETUP] Scanning PCI buses...");
+    let mut network = Err("no RTL8139 found; networking disabled");
     let devices = pci::scan_buses();
     for dev in devices {
         log!(
@@ -79,13 +114,46 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
             let io_base = (dev.bar0 & !3) as u16; // Port I/O addresses have lowest bits set as flags
             let mut rtl = rtl8139::Rtl8139::new(io_base, boot_info.physical_memory_offset);
             rtl.init();
-            net::init(rtl);
+            network = net::init(rtl);
         }
     }
+    report.record_result("network", network);
 
+    log!("[SETUP] Parsing Initramfs...");
+    let archive_bytes = include_bytes!("archive.tar");
+    let mounted = match initramfs::init(archive_bytes) {
+        Ok(summary) => {
+            log!("  Successfully mounted {} files from Initramfs.", summary.mounted);
+            for error in &summary.errors {
+                log!("  [INITRAMFS] Skipped entry: {}", error);
+            }
+            if !summary.clean {
+                log!("  [INITRAMFS] Archive is missing its end-of-archive marker.");
+            }
+            if summary.errors.is_empty() { Ok(()) } else { Err("some entries were skipped") }
+        }
+        Err(_) => Err("archive is empty"),
+    };
+    report.record_result("initramfs", mounted);
+    // Agents see the initramfs files (mounted at /) under /shared/ as well, each
+    // writing its own copies there.
+    vfs::mount_overlay(vfs::SHARED_PREFIX, "/");
+
+    #[cfg(test)]
+    test_main();
+
+    log!("[BOOT] Boot report:\n{}", report);
     run_wasm_demo();
 }
 
+/// Log the boot report and stop: a stage failed that the kernel cannot run without.
+fn halt_with_report(report: &boot::BootReport) -> ! {
+    log!("[BOOT] Boot failed:\n{}", report);
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
 // ── Wasm Microvisor demo ───────────────────────────────────────────────────
 
 fn run_wasm_demo() -> ! {
@@ -102,23 +170,24 @@ fn run_wasm_demo() -> ! {
     log!("[SETUP] Initializing Wasm Runtime...");
     let runtime = wasm::WasmRuntime::new();
 
-    log!("[SETUP] Parsing Initramfs...");
-    let archive_bytes = include_bytes!("archive.tar");
-    match initramfs::init(archive_bytes) {
-        Ok(count) => log!("  Successfully mounted {} files from Initramfs.", count),
-        Err(e) => {
-            log!("[ERROR] Failed to parse Initramfs: {}", e);
-            panic!("Critical Boot Failure: VFS Initialization Failed.");
-        }
-    }
+    dns::load_hosts_file();
+    vfs::register_dynamic_file(net::STATS_PATH, net::stats_file);
+    vfs::register_dynamic_file(interrupts::STATS_PATH, interrupts::stats_file);
+    vfs::register_dynamic_file(vfs::EVENTS_PATH, vfs::events_file);
 
     log!("[SETUP] Spawning OpenClaw Core Agent...");
 
     // Give the core agent capability to spawn other agents (skills) and use the network
-    let cap_spawn = create_capability(Capability::Spawn { max_children: 10 });
+    let cap_spawn = create_capability(Capability::Spawn {
+        max_children: 10,
+        max_depth: task::MAX_SPAWN_DEPTH,
+        allowed_prefix: None,
+    });
     let cap_net = create_capability(Capability::Network);
-    let core_agent = spawn_agent("openclaw_core", vec![cap_spawn, cap_net]);
+    let core_agent = spawn_agent("openclaw_core", vec![cap_spawn, cap_net], None)
+        .expect("kernel-spawned agents are not limited");
     let pid = task::agent_pid(core_agent);
+    let _ = task::register_name(core_agent, "openclaw_core");
 
     log!("  Agent 'openclaw_core' created with PID: {}", pid);
 
@@ -128,18 +197,21 @@ fn run_wasm_demo() -> ! {
     for filename in files {
         if filename.ends_with(".wasm") {
             log!("[EXEC] Found Wasm Agent: {}", filename);
-            if let Some(wasm_bytes) = vfs::open_file(&filename) {
-                log!("  Executing {}...", filename);
-                match runtime.execute_module(&wasm_bytes, pid) {
-                    Ok(_) => {
-                        log!("  [SUCCESS] {} executed successfully.", filename);
-                    }
-                    Err(e) => {
-                        log!("  [ERROR] {} execution failed: {}", filename, e);
-                    }
+            task::set_supervision(
+                core_agent,
+                &filename,
+                task::RestartPolicy::OnFailure { max_restarts: 3 },
+            );
+            log!("  Executing {}...", filename);
+            match runtime.supervise(core_agent) {
+                Ok(_) => {
+                    log!("  [SUCCESS] {} executed successfully.", filename);
+                }
+                Err(e) => {
+                    log!("  [ERROR] {} execution failed: {}", filename, e);
                 }
-                executed_count += 1;
             }
+            executed_count += 1;
         }
     }
 
@@ -147,6 +219,9 @@ fn run_wasm_demo() -> ! {
         log!("[WARN] No .wasm files found in the VFS.");
     }
 
+    // Agents spawned by the supervised modules may still be queued or mid-run.
+    task::run_executor();
+
     log!("");
     log!("============================================================");
     log!("  Microvisor Halted.");
@@ -160,15 +235,27 @@ fn run_wasm_demo() -> ! {
 
 // ── Required handlers ─────────────────────────────────────────────────────────
 
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    x86_64::instructions::interrupts::disable();
+    let regs = crashdump::capture_registers();
+    // The panic may have fired while the port was held; nothing else runs from here on.
+    unsafe { serial::SERIAL1.force_unlock() };
     serial_println!("KERNEL PANIC: {}", info);
+    let _ = crashdump::dump(&mut *serial::SERIAL1.lock(), &regs);
     println!("KERNEL PANIC: {}", info);
     loop {
         x86_64::instructions::hlt();
     }
 }
 
+#[cfg(test)]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    testing::fail(info)
+}
+
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
     panic!("allocation error: {:?}", layout)
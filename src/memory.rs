@@ -1,10 +1,33 @@
 use x86_64::{
     PhysAddr, VirtAddr,
-    structures::paging::{PageTable, OffsetPageTable, PhysFrame, Size4KiB, FrameAllocator}
+    structures::paging::{
+        PageTable, OffsetPageTable, PhysFrame, Size4KiB, FrameAllocator, Mapper, Page,
+        PageTableFlags,
+        mapper::{MapToError, UnmapError},
+    }
 };
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Virtual window device memory is mapped into, well clear of the heap.
+pub const MMIO_START: u64 = 0x_5555_0000_0000;
+pub const MMIO_SIZE: u64 = 1024 * 1024 * 1024; // 1 GiB
+
+/// Next free address in the MMIO window. Unmapped ranges are not reused.
+static NEXT_MMIO: AtomicU64 = AtomicU64::new(MMIO_START);
+
+/// Device registers must not be cached or executed.
+pub const MMIO_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::NO_CACHE)
+    .union(PageTableFlags::WRITE_THROUGH)
+    .union(PageTableFlags::NO_EXECUTE);
+
+/// Where the bootloader mapped all of physical memory; set by `init`.
+static PHYS_MEM_OFFSET: AtomicU64 = AtomicU64::new(0);
 
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYS_MEM_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
@@ -20,6 +43,53 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
     &mut *page_table_ptr
 }
 
+/// Map `size` bytes of device memory at `phys_addr` (e.g. a PCI memory BAR) into the
+/// MMIO window with `MMIO_FLAGS`. Returns the virtual address of `phys_addr`; the
+/// region need not be page aligned. The frames are the device's own, so only page
+/// table frames come from `frame_allocator`. A full MMIO window is reported as
+/// `FrameAllocationFailed`.
+pub fn map_mmio(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    phys_addr: PhysAddr,
+    size: u64,
+) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let first_frame = PhysFrame::<Size4KiB>::containing_address(phys_addr);
+    let last_frame = PhysFrame::containing_address(phys_addr + size.max(1) - 1u64);
+    let frames = PhysFrame::range_inclusive(first_frame, last_frame);
+    let span = (last_frame.start_address() - first_frame.start_address()) + 4096;
+
+    let base = NEXT_MMIO.fetch_add(span, Ordering::Relaxed);
+    if base + span > MMIO_START + MMIO_SIZE {
+        NEXT_MMIO.fetch_sub(span, Ordering::Relaxed);
+        return Err(MapToError::FrameAllocationFailed);
+    }
+
+    let first_page = Page::<Size4KiB>::containing_address(VirtAddr::new(base));
+    for (i, frame) in frames.enumerate() {
+        let page = first_page + i as u64;
+        unsafe {
+            mapper.map_to(page, frame, MMIO_FLAGS, frame_allocator)?.flush();
+        }
+    }
+
+    Ok(VirtAddr::new(base) + (phys_addr - first_frame.start_address()))
+}
+
+/// Undo `map_mmio` for the region of `size` bytes mapped at `virt_addr`.
+pub fn unmap_mmio(
+    mapper: &mut impl Mapper<Size4KiB>,
+    virt_addr: VirtAddr,
+    size: u64,
+) -> Result<(), UnmapError> {
+    let first_page = Page::<Size4KiB>::containing_address(virt_addr);
+    let last_page = Page::containing_address(virt_addr + size.max(1) - 1u64);
+    for page in Page::range_inclusive(first_page, last_page) {
+        mapper.unmap(page)?.1.flush();
+    }
+    Ok(())
+}
+
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     next: usize,
@@ -52,3 +122,59 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
         frame
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+    use x86_64::structures::paging::mapper::{TranslateResult, Translate};
+
+    /// Hands out page-table frames set aside beforehand, so the mapper can be
+    /// borrowed while they are allocated.
+    struct Frames(Vec<PhysFrame>);
+
+    unsafe impl FrameAllocator<Size4KiB> for Frames {
+        fn allocate_frame(&mut self) -> Option<PhysFrame> {
+            self.0.pop()
+        }
+    }
+
+    #[repr(align(4096))]
+    struct DevicePage([u8; 4096]);
+
+    #[test_case]
+    fn mmio_region_is_readable_uncached_and_unmappable() {
+        let offset = VirtAddr::new(PHYS_MEM_OFFSET.load(Ordering::Relaxed));
+        let mut mapper = unsafe { init(offset) };
+        let phys_of = |mapper: &OffsetPageTable, ptr: *const u8| {
+            mapper.translate_addr(VirtAddr::from_ptr(ptr)).unwrap()
+        };
+
+        // Empty tables from the heap, for the levels the MMIO window still lacks.
+        let mut frames = Frames((0..3)
+            .map(|_| Box::leak(Box::new(PageTable::new())) as *const PageTable as *const u8)
+            .map(|table| PhysFrame::containing_address(phys_of(&mapper, table)))
+            .collect());
+
+        // Stand-in device registers: a heap page with a marker at offset 8.
+        let device = Box::leak(Box::new(DevicePage([0; 4096])));
+        device.0[8] = 0xAB;
+        let phys = phys_of(&mapper, device.0.as_ptr()) + 8u64;
+
+        let virt = map_mmio(&mut mapper, &mut frames, phys, 1).unwrap();
+        assert!(virt.as_u64() >= MMIO_START && virt.as_u64() < MMIO_START + MMIO_SIZE);
+        assert_eq!(virt.as_u64() % 4096, 8);
+        assert_eq!(unsafe { core::ptr::read_volatile(virt.as_ptr::<u8>()) }, 0xAB);
+        match mapper.translate(virt) {
+            TranslateResult::Mapped { frame, flags, .. } => {
+                assert_eq!(frame.start_address() + 8u64, phys);
+                assert!(flags.contains(MMIO_FLAGS));
+            }
+            _ => panic!("MMIO page is not mapped"),
+        }
+
+        unmap_mmio(&mut mapper, virt, 1).unwrap();
+        assert!(matches!(mapper.translate(virt), TranslateResult::NotMapped));
+    }
+}
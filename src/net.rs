@@ -1,11 +1,18 @@
-use crate::rtl8139::Rtl8139;
+use crate::rtl8139::{NicStats, Rtl8139, MAX_FRAME_SIZE};
 use crate::serial_println;
+use crate::time::uptime_ms;
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
 use alloc::vec;
 use alloc::vec::Vec;
-use smoltcp::iface::{Config, Interface, SocketSet};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet};
 use smoltcp::phy::{Device, DeviceCapabilities, Medium, RxToken, TxToken};
+use smoltcp::socket::AnySocket;
 use smoltcp::time::Instant;
-use smoltcp::wire::{EthernetAddress, HardwareAddress, IpAddress, IpCidr, Ipv4Address};
+use smoltcp::wire::{
+    ArpOperation, ArpPacket, ArpRepr, EthernetAddress, EthernetFrame, EthernetProtocol,
+    EthernetRepr, HardwareAddress, IpAddress, IpCidr, Ipv4Address,
+};
 use spin::Mutex;
 
 pub struct RxTokenWrapper(pub Vec<u8>);
@@ -30,7 +37,18 @@ impl<'a> TxToken for TxTokenWrapper<'a> {
     {
         let mut buffer = vec![0; len];
         let result = f(&mut buffer);
-        self.device.tx_raw(&buffer);
+        if let Some(reply) = static_arp_reply(&buffer) {
+            INJECTED_FRAMES.lock().push_back(reply);
+            return result;
+        }
+        // The card never receives its own transmissions; loop frames for it back.
+        if buffer.get(..6) == Some(&self.device.mac[..]) {
+            INJECTED_FRAMES.lock().push_back(buffer);
+            return result;
+        }
+        if let Err(e) = self.device.tx_raw(&buffer) {
+            serial_println!("[NET] Dropped outgoing frame: {e:?}");
+        }
         result
     }
 }
@@ -43,7 +61,13 @@ impl Device for Rtl8139 {
         &'a mut self,
         _timestamp: Instant,
     ) -> Option<(Self::RxToken<'a>, Self::TxToken<'a>)> {
-        match self.rx_poll() {
+        let injected = INJECTED_FRAMES.lock().pop_front();
+        let received = injected.or_else(|| {
+            let frame = self.rx_poll()?;
+            capture_raw(&frame);
+            Some(frame)
+        });
+        match received {
             Some(payload) => {
                 let rx = RxTokenWrapper(payload);
                 let tx = TxTokenWrapper { device: self };
@@ -66,39 +90,507 @@ impl Device for Rtl8139 {
     }
 }
 
+/// Neighbors whose MAC is fixed by the operator instead of learned over ARP.
+static STATIC_ARP: Mutex<BTreeMap<Ipv4Address, EthernetAddress>> = Mutex::new(BTreeMap::new());
+
+/// Frames handed to smoltcp as if the NIC had received them, ahead of real traffic:
+/// static ARP replies and frames the stack addressed to its own MAC.
+static INJECTED_FRAMES: Mutex<VecDeque<Vec<u8>>> = Mutex::new(VecDeque::new());
+
+/// Pin `ip` to `mac` for setups with no ARP responder (e.g. point-to-point links).
+/// smoltcp's neighbor cache is not public, so the entry is fed to it as an ARP reply,
+/// and the ARP requests smoltcp sends for `ip` when the entry ages out are answered
+/// locally instead of going on the wire. The first packet to `ip` therefore never
+/// waits on resolution.
+pub fn add_static_arp(ip: Ipv4Address, mac: EthernetAddress) -> Result<(), NetError> {
+    STATIC_ARP.lock().insert(ip, mac);
+    with_network(|net| {
+        let Some(local_ip) = net.iface.ipv4_addr() else {
+            return;
+        };
+        let local_mac = EthernetAddress(net.device.mac);
+        INJECTED_FRAMES
+            .lock()
+            .push_back(arp_reply_frame(mac, ip, local_mac, local_ip));
+        let now = Instant::from_millis(uptime_ms() as i64);
+        net.iface.poll(now, &mut net.device, &mut net.sockets);
+    })
+}
+
+/// Stop answering ARP for `ip` locally. smoltcp keeps the learned MAC until its cache
+/// entry expires, after which `ip` is resolved over the wire again. Returns false if
+/// there was no static entry.
+pub fn remove_static_arp(ip: Ipv4Address) -> bool {
+    STATIC_ARP.lock().remove(&ip).is_some()
+}
+
+/// The static ARP entries, by IP.
+pub fn static_arp_entries() -> Vec<(Ipv4Address, EthernetAddress)> {
+    STATIC_ARP
+        .lock()
+        .iter()
+        .map(|(ip, mac)| (*ip, *mac))
+        .collect()
+}
+
+/// If `frame` is an ARP request for a statically pinned IP, the reply to feed back.
+fn static_arp_reply(frame: &[u8]) -> Option<Vec<u8>> {
+    let frame = EthernetFrame::new_checked(frame).ok()?;
+    if frame.ethertype() != EthernetProtocol::Arp {
+        return None;
+    }
+    let packet = ArpPacket::new_checked(frame.payload()).ok()?;
+    let ArpRepr::EthernetIpv4 {
+        operation: ArpOperation::Request,
+        source_hardware_addr,
+        source_protocol_addr,
+        target_protocol_addr,
+        ..
+    } = ArpRepr::parse(&packet).ok()?
+    else {
+        return None;
+    };
+    let mac = *STATIC_ARP.lock().get(&target_protocol_addr)?;
+    Some(arp_reply_frame(
+        mac,
+        target_protocol_addr,
+        source_hardware_addr,
+        source_protocol_addr,
+    ))
+}
+
+/// An Ethernet frame carrying an ARP reply from `mac`/`ip` to `target_mac`/`target_ip`.
+fn arp_reply_frame(
+    mac: EthernetAddress,
+    ip: Ipv4Address,
+    target_mac: EthernetAddress,
+    target_ip: Ipv4Address,
+) -> Vec<u8> {
+    let eth = EthernetRepr {
+        src_addr: mac,
+        dst_addr: target_mac,
+        ethertype: EthernetProtocol::Arp,
+    };
+    let arp = ArpRepr::EthernetIpv4 {
+        operation: ArpOperation::Reply,
+        source_hardware_addr: mac,
+        source_protocol_addr: ip,
+        target_hardware_addr: target_mac,
+        target_protocol_addr: target_ip,
+    };
+    let mut buffer = vec![0; eth.buffer_len() + arp.buffer_len()];
+    let mut frame = EthernetFrame::new_unchecked(&mut buffer[..]);
+    eth.emit(&mut frame);
+    arp.emit(&mut ArpPacket::new_unchecked(frame.payload_mut()));
+    buffer
+}
+
 pub struct NetworkStack {
     pub iface: Interface,
     pub sockets: SocketSet<'static>,
     pub device: Rtl8139,
 }
 
+impl NetworkStack {
+    /// Add `socket` to the socket set, or hand it back if the set already holds
+    /// `max_sockets()` sockets.
+    pub fn add_socket<T: AnySocket<'static>>(&mut self, socket: T) -> Result<SocketHandle, T> {
+        if self.socket_count() >= max_sockets() {
+            return Err(socket);
+        }
+        Ok(self.sockets.add(socket))
+    }
+
+    /// Sockets currently in the socket set, agent-owned or the kernel's own.
+    pub fn socket_count(&self) -> usize {
+        self.sockets.iter().count()
+    }
+}
+
+/// Default size of the smoltcp socket set: every agent socket the socket registry
+/// allows, plus room for the kernel's own DNS and probe sockets.
+pub const DEFAULT_MAX_SOCKETS: usize = crate::sockets::DEFAULT_MAX_SOCKETS + 8;
+
+static MAX_SOCKETS: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_SOCKETS);
+
+/// Cap the socket set at `max` sockets. The set is allocated at this size when the
+/// stack is (re)initialized; a lower cap applies to new sockets at once.
+pub fn set_max_sockets(max: usize) {
+    MAX_SOCKETS.store(max, Ordering::Relaxed);
+}
+
+/// Most sockets the socket set will hold.
+pub fn max_sockets() -> usize {
+    MAX_SOCKETS.load(Ordering::Relaxed)
+}
+
+/// Sockets currently in the socket set; 0 if there is no stack.
+pub fn socket_count() -> usize {
+    with_network(|net| net.socket_count()).unwrap_or(0)
+}
+
+/// An empty socket set with room for `max_sockets()`, so it never reallocates.
+fn new_socket_set() -> SocketSet<'static> {
+    SocketSet::new(Vec::with_capacity(max_sockets()))
+}
+
 lazy_static::lazy_static! {
     pub static ref NETWORK: Mutex<Option<NetworkStack>> = Mutex::new(None);
 }
 
-pub fn init(mut device: Rtl8139) {
+/// Local ports currently handed out by `alloc_ephemeral_port`.
+static EPHEMERAL_PORTS: Mutex<BTreeSet<u16>> = Mutex::new(BTreeSet::new());
+
+/// Reserve an unused local port in the dynamic range (49152–65535) for an outgoing
+/// connection. The search starts at a random port so successive connections don't
+/// reuse the same one. Returns `None` only if every port is in use.
+pub fn alloc_ephemeral_port() -> Option<u16> {
+    let mut in_use = EPHEMERAL_PORTS.lock();
+    let start = crate::rng::ephemeral_port();
+    let port = (start..=u16::MAX)
+        .chain(crate::rng::EPHEMERAL_PORT_START..start)
+        .find(|port| !in_use.contains(port))?;
+    in_use.insert(port);
+    Some(port)
+}
+
+/// Return a port obtained from `alloc_ephemeral_port` to the pool.
+pub fn free_ephemeral_port(port: u16) {
+    EPHEMERAL_PORTS.lock().remove(&port);
+}
+
+/// How long a caller waits for the network stack lock before giving up.
+pub const LOCK_TIMEOUT_MS: u64 = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetError {
+    /// No NIC was found at boot, so there is no stack.
+    Unavailable,
+    /// The stack lock stayed held for `LOCK_TIMEOUT_MS` (e.g. its holder died).
+    Timeout,
+}
+
+/// Run `f` against the network stack, waiting at most `LOCK_TIMEOUT_MS` for the lock
+/// instead of spinning forever. The guard is dropped as soon as `f` returns, so a
+/// host call that traps afterwards cannot leave the stack locked.
+pub fn with_network<R>(f: impl FnOnce(&mut NetworkStack) -> R) -> Result<R, NetError> {
+    let start = uptime_ms();
+    loop {
+        if let Some(mut guard) = NETWORK.try_lock() {
+            return guard.as_mut().map(f).ok_or(NetError::Unavailable);
+        }
+        if uptime_ms().saturating_sub(start) >= LOCK_TIMEOUT_MS {
+            serial_println!(
+                "[NET] Network stack lock held for {}ms, giving up",
+                LOCK_TIMEOUT_MS
+            );
+            return Err(NetError::Timeout);
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Recover a wedged NIC without rebooting: reset the card, re-read its MAC and rebuild
+/// the smoltcp interface with the current addresses and routes. Every agent socket is
+/// dropped, so handles agents still hold stop resolving. Returns the number of sockets
+/// dropped.
+pub fn reinit() -> Result<usize, NetError> {
+    with_network(|net| {
+        let dropped = crate::sockets::drop_all(net);
+
+        let ip_addrs: Vec<IpCidr> = net.iface.ip_addrs().to_vec();
+        let mut routes = None;
+        net.iface
+            .routes_mut()
+            .update(|table| routes = Some(table.clone()));
+
+        net.device.reset();
+        let hardware_addr = HardwareAddress::Ethernet(EthernetAddress(net.device.mac));
+        let mut config = Config::new(hardware_addr);
+        config.random_seed = crate::rng::next_u64();
+        let now = Instant::from_millis(uptime_ms() as i64);
+        let mut iface = Interface::new(config, &mut net.device, now);
+
+        iface.update_ip_addrs(|addrs| {
+            for cidr in ip_addrs {
+                let _ = addrs.push(cidr);
+            }
+        });
+        if let Some(routes) = routes {
+            iface.routes_mut().update(|table| *table = routes);
+        }
+
+        net.iface = iface;
+        net.sockets = new_socket_set();
+        serial_println!(
+            "[NET] Network stack reinitialized ({} socket(s) dropped)",
+            dropped
+        );
+        dropped
+    })
+}
+
+/// Longest a `flush` keeps polling, so a busy link cannot hold the caller indefinitely.
+pub const MAX_FLUSH_MS: u64 = 10;
+
+/// Poll the interface until it has nothing left to send or process, pushing frames
+/// queued by sockets out to the NIC now rather than on the next poll. Gives up after
+/// `MAX_FLUSH_MS`. Returns the number of polls that made progress.
+pub fn flush() -> Result<usize, NetError> {
+    with_network(|net| {
+        let start = uptime_ms();
+        let mut progressed = 0;
+        loop {
+            let now = Instant::from_millis(uptime_ms() as i64);
+            if !net.iface.poll(now, &mut net.device, &mut net.sockets) {
+                break;
+            }
+            progressed += 1;
+            if uptime_ms().saturating_sub(start) >= MAX_FLUSH_MS {
+                break;
+            }
+        }
+        progressed
+    })
+}
+
+/// Received frames kept for `recv_raw`; the oldest are dropped first.
+pub const MAX_RAW_FRAMES: usize = 32;
+
+/// Destination MAC, source MAC and EtherType.
+const ETHERNET_HEADER_LEN: usize = 14;
+
+/// Agents that have called `recv_raw` and not yet been stopped by `stop_raw_capture`.
+/// Received frames are only copied while this is non-empty.
+static RAW_READERS: Mutex<BTreeSet<u64>> = Mutex::new(BTreeSet::new());
+
+/// Copies of frames received from the NIC, for L2 agents. smoltcp still processes the
+/// originals, so raw readers see IP traffic too.
+static RAW_FRAMES: Mutex<VecDeque<Vec<u8>>> = Mutex::new(VecDeque::new());
+
+fn capture_raw(frame: &[u8]) {
+    if RAW_READERS.lock().is_empty() {
+        return;
+    }
+    let mut frames = RAW_FRAMES.lock();
+    if frames.len() >= MAX_RAW_FRAMES {
+        frames.pop_front();
+    }
+    frames.push_back(frame.to_vec());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RawSendError {
+    /// Shorter than an Ethernet header or longer than `rtl8139::MAX_FRAME_SIZE`.
+    BadLength,
+    /// The source MAC is not the NIC's own.
+    ForeignSource,
+    Net(NetError),
+}
+
+/// Transmit a complete Ethernet frame (without CRC) as-is, bypassing smoltcp. The
+/// source address must be the NIC's MAC, so agents cannot spoof other hosts.
+pub fn send_raw(frame: &[u8]) -> Result<(), RawSendError> {
+    if !(ETHERNET_HEADER_LEN..=MAX_FRAME_SIZE).contains(&frame.len()) {
+        return Err(RawSendError::BadLength);
+    }
+    with_network(|net| {
+        if frame[6..12] != net.device.mac {
+            return Err(RawSendError::ForeignSource);
+        }
+        net.device
+            .tx_raw(frame)
+            .map_err(|_| RawSendError::BadLength)
+    })
+    .map_err(RawSendError::Net)?
+}
+
+/// Take the oldest captured frame for agent `pid`, polling the NIC first so new
+/// arrivals are seen. Capture starts with a reader's first call and lasts until
+/// `stop_raw_capture`, so frames received while nobody reads are not kept. All raw
+/// readers share one ring: each frame goes to whichever reads it first.
+pub fn recv_raw(pid: u64) -> Result<Option<Vec<u8>>, NetError> {
+    RAW_READERS.lock().insert(pid);
+    with_network(|net| {
+        let now = Instant::from_millis(uptime_ms() as i64);
+        net.iface.poll(now, &mut net.device, &mut net.sockets);
+    })?;
+    Ok(RAW_FRAMES.lock().pop_front())
+}
+
+/// Stop capturing for agent `pid`, e.g. when it exits or loses `RawNetwork`. Once the
+/// last reader stops, capture is turned off and frames still queued are dropped.
+/// Returns whether `pid` was reading.
+pub fn stop_raw_capture(pid: u64) -> bool {
+    let mut readers = RAW_READERS.lock();
+    let was_reading = readers.remove(&pid);
+    if readers.is_empty() {
+        RAW_FRAMES.lock().clear();
+    }
+    was_reading
+}
+
+/// Longest `tcp_probe` wait; the stack is locked for the whole probe.
+pub const MAX_PROBE_TIMEOUT_MS: u64 = 2_000;
+
+/// What a TCP handshake to a port revealed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortState {
+    /// The host answered the SYN with a SYN-ACK.
+    Open,
+    /// The host answered with a RST.
+    Closed,
+    /// No answer before the timeout.
+    Filtered,
+}
+
+/// Probe `addr:port` by starting a TCP handshake and classifying the reply. An open
+/// connection is aborted at once and no data is exchanged. `timeout_ms` is capped at
+/// `MAX_PROBE_TIMEOUT_MS`.
+pub fn tcp_probe(addr: Ipv4Address, port: u16, timeout_ms: u64) -> Result<PortState, NetError> {
+    use smoltcp::socket::tcp::{Socket, SocketBuffer, State};
+
+    let timeout_ms = timeout_ms.min(MAX_PROBE_TIMEOUT_MS);
+    with_network(|net| {
+        let Some(local_port) = alloc_ephemeral_port() else {
+            return PortState::Filtered;
+        };
+        let mut socket = Socket::new(
+            SocketBuffer::new(vec![0; 64]),
+            SocketBuffer::new(vec![0; 64]),
+        );
+        if socket
+            .connect(
+                net.iface.context(),
+                (IpAddress::Ipv4(addr), port),
+                local_port,
+            )
+            .is_err()
+        {
+            free_ephemeral_port(local_port);
+            return PortState::Filtered;
+        }
+        let Ok(handle) = net.add_socket(socket) else {
+            free_ephemeral_port(local_port);
+            return PortState::Filtered;
+        };
+
+        let start = uptime_ms();
+        let state = loop {
+            let now = Instant::from_millis(uptime_ms() as i64);
+            net.iface.poll(now, &mut net.device, &mut net.sockets);
+
+            let socket = net.sockets.get_mut::<Socket>(handle);
+            match socket.state() {
+                State::Established => {
+                    socket.abort();
+                    net.iface.poll(now, &mut net.device, &mut net.sockets);
+                    break PortState::Open;
+                }
+                State::Closed => break PortState::Closed,
+                _ if uptime_ms().saturating_sub(start) >= timeout_ms => break PortState::Filtered,
+                _ => core::hint::spin_loop(),
+            }
+        };
+
+        net.sockets.remove(handle);
+        free_ephemeral_port(local_port);
+        state
+    })
+}
+
+/// VFS path of the network statistics file.
+pub const STATS_PATH: &str = "/proc/net/stats";
+
+/// Network health snapshot: NIC counters, open agent sockets and DNS cache counters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NetStats {
+    pub nic: NicStats,
+    pub open_sockets: u32,
+    pub dns_cache_hits: u64,
+    pub dns_cache_misses: u64,
+}
+
+impl NetStats {
+    /// Size of the `to_bytes` encoding.
+    pub const ENCODED_LEN: usize = 8 * 4 + 4 + 8 * 2;
+
+    /// Little-endian encoding: rx_packets, tx_packets, rx_errors, tx_errors (u64 each),
+    /// open_sockets (u32), dns_cache_hits, dns_cache_misses (u64 each).
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(Self::ENCODED_LEN);
+        for counter in [
+            self.nic.rx_packets,
+            self.nic.tx_packets,
+            self.nic.rx_errors,
+            self.nic.tx_errors,
+        ] {
+            out.extend_from_slice(&counter.to_le_bytes());
+        }
+        out.extend_from_slice(&self.open_sockets.to_le_bytes());
+        out.extend_from_slice(&self.dns_cache_hits.to_le_bytes());
+        out.extend_from_slice(&self.dns_cache_misses.to_le_bytes());
+        out
+    }
+}
+
+/// Collect current network statistics. NIC counters are zero when there is no NIC
+/// or the stack is busy.
+pub fn stats() -> NetStats {
+    let dns = crate::dns::cache_stats();
+    NetStats {
+        nic: with_network(|net| net.device.stats()).unwrap_or_default(),
+        open_sockets: crate::sockets::total_open() as u32,
+        dns_cache_hits: dns.hits,
+        dns_cache_misses: dns.misses,
+    }
+}
+
+/// Generator for `STATS_PATH`: one `name value` pair per line.
+pub fn stats_file() -> Vec<u8> {
+    let stats = stats();
+    alloc::format!(
+        "rx_packets {}\ntx_packets {}\nrx_errors {}\ntx_errors {}\nrx_overflows {}\n\
+         open_sockets {}\ndns_cache_hits {}\ndns_cache_misses {}\n",
+        stats.nic.rx_packets,
+        stats.nic.tx_packets,
+        stats.nic.rx_errors,
+        stats.nic.tx_errors,
+        stats.nic.rx_overflows,
+        stats.open_sockets,
+        stats.dns_cache_hits,
+        stats.dns_cache_misses
+    )
+    .into_bytes()
+}
+
+/// Bring up the smoltcp interface on `device` with the static QEMU user-network
+/// addresses.
+pub fn init(mut device: Rtl8139) -> Result<(), &'static str> {
     let mac = device.mac;
     let hardware_addr = HardwareAddress::Ethernet(EthernetAddress(mac));
 
     let mut config = Config::new(hardware_addr);
-    config.random_seed = 0x12345678; // Minimal hack for no_std PRNG randomness
+    config.random_seed = crate::rng::next_u64();
 
     let mut iface = Interface::new(config, &mut device, Instant::from_millis(0));
 
     // QEMU user networking assigns 10.0.2.15 to the guest by default in typical SLIRP,
     // but just assigning a static IP directly is fastest.
+    let mut pushed = Ok(());
     iface.update_ip_addrs(|ip_addrs| {
-        ip_addrs
+        pushed = ip_addrs
             .push(IpCidr::new(IpAddress::v4(10, 0, 2, 15), 24))
-            .unwrap();
+            .map_err(|_| "no room for the interface address");
     });
+    pushed?;
 
     iface
         .routes_mut()
         .add_default_ipv4_route(Ipv4Address::new(10, 0, 2, 2))
-        .unwrap();
+        .map_err(|_| "no room for the default route")?;
 
-    let sockets = SocketSet::new(vec![]);
+    let sockets = new_socket_set();
 
     serial_println!("[NET] IP Stack Configured: 10.0.2.15/24 (Gateway 10.0.2.2)");
 
@@ -107,4 +599,305 @@ pub fn init(mut device: Rtl8139) {
         sockets,
         device,
     });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn ephemeral_ports_are_unique_until_freed() {
+        crate::rng::seed(0x0904);
+        let first = alloc_ephemeral_port().unwrap();
+        // The same random starting point skips the port while it is in use...
+        crate::rng::seed(0x0904);
+        let second = alloc_ephemeral_port().unwrap();
+        assert_ne!(first, second);
+        assert!(first >= crate::rng::EPHEMERAL_PORT_START);
+        assert!(second >= crate::rng::EPHEMERAL_PORT_START);
+
+        // ...and hands it out again once it has been freed.
+        free_ephemeral_port(first);
+        crate::rng::seed(0x0904);
+        assert_eq!(alloc_ephemeral_port(), Some(first));
+
+        free_ephemeral_port(first);
+        free_ephemeral_port(second);
+    }
+
+    #[test_case]
+    fn reinit_drops_sockets_and_keeps_the_interface_usable() {
+        use smoltcp::socket::tcp::{Socket, SocketBuffer};
+
+        crate::testing::network();
+        let owner = crate::testing::spawn_agent("net-reinit", Vec::new()).0;
+        let socket = Socket::new(
+            SocketBuffer::new(alloc::vec![0; 64]),
+            SocketBuffer::new(alloc::vec![0; 64]),
+        );
+        let id = with_network(|net| crate::sockets::open(net, owner, socket, None))
+            .unwrap()
+            .unwrap();
+
+        assert!(reinit().unwrap() >= 1);
+        assert!(crate::sockets::handle(owner, id).is_none());
+        assert_eq!(crate::sockets::open_count(owner), 0);
+        let guest_addr = IpCidr::new(IpAddress::v4(10, 0, 2, 15), 24);
+        assert!(with_network(|net| net.iface.ip_addrs().contains(&guest_addr)).unwrap());
+
+        // A query after the rebuild reaches the card, though no server answers it.
+        let sent_before = with_network(|net| net.device.stats().tx_packets).unwrap();
+        assert!(crate::dns::resolve_txt("reinit.test").is_err());
+        let sent_after = with_network(|net| net.device.stats().tx_packets).unwrap();
+        assert!(sent_after > sent_before);
+    }
+
+    /// The value of `name` in `stats_file()`.
+    fn stats_line(name: &str) -> u64 {
+        let file = alloc::string::String::from_utf8(stats_file()).unwrap();
+        let line = file.lines().find(|l| l.starts_with(name)).unwrap();
+        line[name.len() + 1..].parse().unwrap()
+    }
+
+    #[test_case]
+    fn stats_count_transmitted_frames() {
+        crate::testing::network();
+        let before = stats();
+        assert_eq!(stats_line("tx_packets"), before.nic.tx_packets);
+
+        with_network(|net| net.device.tx_raw(&[0; 60]))
+            .unwrap()
+            .unwrap();
+        let after = stats();
+        assert_eq!(after.nic.tx_packets, before.nic.tx_packets + 1);
+        assert_eq!(stats_line("tx_packets"), after.nic.tx_packets);
+
+        let bytes = after.to_bytes();
+        assert_eq!(bytes.len(), NetStats::ENCODED_LEN);
+        assert_eq!(bytes[8..16], after.nic.tx_packets.to_le_bytes());
+    }
+
+    #[test_case]
+    fn probe_of_a_listening_port_is_open() {
+        crate::testing::loopback();
+        let listener = crate::testing::listen(7101);
+        let state = tcp_probe(crate::testing::GUEST_IP, 7101, 200);
+        with_network(|net| net.sockets.remove(listener)).unwrap();
+        assert_eq!(state, Ok(PortState::Open));
+    }
+
+    #[test_case]
+    fn probe_answered_with_a_reset_is_closed() {
+        crate::testing::loopback();
+        assert_eq!(
+            tcp_probe(crate::testing::GUEST_IP, 7102, 200),
+            Ok(PortState::Closed)
+        );
+    }
+
+    #[test_case]
+    fn unanswered_probe_is_filtered() {
+        crate::testing::network();
+        let silent = Ipv4Address::new(10, 0, 2, 99);
+        add_static_arp(silent, EthernetAddress([0x02, 0, 0, 0, 0, 0x99])).unwrap();
+        let started = uptime_ms();
+        assert_eq!(tcp_probe(silent, 80, 20), Ok(PortState::Filtered));
+        assert!(uptime_ms() - started >= 20);
+        remove_static_arp(silent);
+    }
+
+    /// Add a TCP socket connecting to `remote:port`, its SYN queued but not yet sent.
+    fn queue_syn(net: &mut NetworkStack, remote: Ipv4Address, port: u16) -> (SocketHandle, u16) {
+        use smoltcp::socket::tcp::{Socket, SocketBuffer};
+
+        let local_port = alloc_ephemeral_port().unwrap();
+        let mut socket = Socket::new(
+            SocketBuffer::new(vec![0; 64]),
+            SocketBuffer::new(vec![0; 64]),
+        );
+        socket
+            .connect(
+                net.iface.context(),
+                (IpAddress::Ipv4(remote), port),
+                local_port,
+            )
+            .unwrap();
+        (net.add_socket(socket).ok().unwrap(), local_port)
+    }
+
+    /// The frames the NIC sent since its `tx_packets` read `before`, newest first; only
+    /// the last four are kept.
+    fn sent_since(net: &NetworkStack, before: u64) -> Vec<Vec<u8>> {
+        let sent = (net.device.stats().tx_packets - before) as usize;
+        (0..sent.min(4))
+            .map(|back| net.device.sent_frame(back).to_vec())
+            .collect()
+    }
+
+    /// Whether `frame` is a TCP SYN for `ip` sent to `mac`.
+    fn is_syn_to(frame: &[u8], mac: EthernetAddress, ip: Ipv4Address) -> bool {
+        use smoltcp::wire::{Ipv4Packet, TcpPacket};
+
+        let eth = EthernetFrame::new_checked(frame).unwrap();
+        let Ok(packet) = Ipv4Packet::new_checked(eth.payload()) else {
+            return false;
+        };
+        if eth.dst_addr() != mac || packet.dst_addr() != ip {
+            return false;
+        }
+        let tcp = TcpPacket::new_checked(packet.payload()).unwrap();
+        tcp.syn() && !tcp.ack()
+    }
+
+    #[test_case]
+    fn first_syn_through_a_static_gateway_needs_no_arp() {
+        crate::testing::network();
+        let gateway = Ipv4Address::new(10, 0, 2, 2);
+        let gateway_mac = EthernetAddress([0x02, 0, 0, 0, 0, 0x02]);
+        add_static_arp(gateway, gateway_mac).unwrap();
+        let remote = Ipv4Address::new(198, 51, 100, 7);
+
+        let frames = with_network(|net| {
+            let (handle, local_port) = queue_syn(net, remote, 80);
+            let before = net.device.stats().tx_packets;
+            let now = Instant::from_millis(uptime_ms() as i64);
+            net.iface.poll(now, &mut net.device, &mut net.sockets);
+            let frames = sent_since(net, before);
+            net.sockets.remove(handle);
+            free_ephemeral_port(local_port);
+            frames
+        })
+        .unwrap();
+        remove_static_arp(gateway);
+
+        assert!(frames.iter().all(|f| {
+            EthernetFrame::new_checked(&f[..]).unwrap().ethertype() != EthernetProtocol::Arp
+        }));
+        assert!(frames.iter().any(|f| is_syn_to(f, gateway_mac, remote)));
+    }
+
+    #[test_case]
+    fn flush_transmits_a_queued_segment() {
+        crate::testing::network();
+        let peer = Ipv4Address::new(10, 0, 2, 53);
+        let peer_mac = EthernetAddress([0x02, 0, 0, 0, 0, 0x53]);
+        add_static_arp(peer, peer_mac).unwrap();
+
+        let (handle, local_port, before) = with_network(|net| {
+            let (handle, local_port) = queue_syn(net, peer, 953);
+            (handle, local_port, net.device.stats().tx_packets)
+        })
+        .unwrap();
+        assert!(flush().unwrap() >= 1);
+        let frames = with_network(|net| {
+            let frames = sent_since(net, before);
+            net.sockets.remove(handle);
+            frames
+        })
+        .unwrap();
+        free_ephemeral_port(local_port);
+        remove_static_arp(peer);
+
+        assert!(frames.iter().any(|f| is_syn_to(f, peer_mac, peer)));
+    }
+
+    /// An Ethernet frame from `src` to `dst` with the LLDP EtherType.
+    fn raw_frame(dst: [u8; 6], src: [u8; 6], payload: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::from(dst);
+        frame.extend_from_slice(&src);
+        frame.extend_from_slice(&[0x88, 0xcc]);
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test_case]
+    fn raw_frames_reach_the_nic_unchanged() {
+        crate::testing::network();
+        let mac = with_network(|net| net.device.mac).unwrap();
+        let frame = raw_frame([0xff; 6], mac, b"raw out");
+        send_raw(&frame).unwrap();
+        let sent = with_network(|net| net.device.sent_frame(0)[..frame.len()].to_vec()).unwrap();
+        assert_eq!(sent, frame);
+
+        let spoofed = raw_frame([0xff; 6], [0x02, 0, 0, 0, 0, 0x60], b"raw out");
+        assert_eq!(send_raw(&spoofed), Err(RawSendError::ForeignSource));
+        assert_eq!(send_raw(&frame[..10]), Err(RawSendError::BadLength));
+        assert_eq!(
+            send_raw(&vec![0; MAX_FRAME_SIZE + 1]),
+            Err(RawSendError::BadLength)
+        );
+    }
+
+    #[test_case]
+    fn received_frames_are_captured_for_raw_readers() {
+        crate::testing::network();
+        while recv_raw(960_001).unwrap().is_some() {}
+        let mac = with_network(|net| net.device.mac).unwrap();
+        let frame = raw_frame(mac, [0x02, 0, 0, 0, 0, 0x60], b"raw in");
+        crate::rtl8139::script_rx(frame.clone());
+
+        assert_eq!(recv_raw(960_001).unwrap(), Some(frame));
+        assert_eq!(recv_raw(960_001).unwrap(), None);
+        assert!(stop_raw_capture(960_001));
+    }
+
+    #[test_case]
+    fn capture_stops_with_the_last_reader() {
+        crate::testing::network();
+        let mac = with_network(|net| net.device.mac).unwrap();
+        let frame = raw_frame(mac, [0x02, 0, 0, 0, 0, 0x61], b"raw in");
+        recv_raw(960_002).unwrap();
+        while recv_raw(960_003).unwrap().is_some() {}
+
+        // Reaping one reader keeps capture on for the other.
+        crate::task::reclaim_resources(960_002);
+        assert!(!stop_raw_capture(960_002));
+        crate::rtl8139::script_rx(frame.clone());
+        assert_eq!(recv_raw(960_003).unwrap(), Some(frame.clone()));
+
+        // With nobody left, arrivals are dropped instead of queued.
+        assert!(stop_raw_capture(960_003));
+        assert!(!stop_raw_capture(960_003));
+        crate::rtl8139::script_rx(frame);
+        with_network(|net| {
+            net.iface
+                .poll(Instant::from_millis(0), &mut net.device, &mut net.sockets)
+        })
+        .unwrap();
+        assert!(RAW_FRAMES.lock().is_empty());
+    }
+
+    fn udp_socket() -> smoltcp::socket::udp::Socket<'static> {
+        use smoltcp::socket::udp::{PacketBuffer, PacketMetadata, Socket};
+
+        Socket::new(
+            PacketBuffer::new(vec![PacketMetadata::EMPTY; 1], vec![0; 64]),
+            PacketBuffer::new(vec![PacketMetadata::EMPTY; 1], vec![0; 64]),
+        )
+    }
+
+    #[test_case]
+    fn socket_set_rejects_sockets_past_the_cap() {
+        crate::testing::network();
+        let base = socket_count();
+        set_max_sockets(base + 2);
+        let add = || with_network(|net| net.add_socket(udp_socket()).ok()).unwrap();
+
+        let first = add().unwrap();
+        let second = add().unwrap();
+        assert_eq!(socket_count(), base + 2);
+        assert!(add().is_none());
+
+        with_network(|net| net.sockets.remove(first)).unwrap();
+        assert_eq!(socket_count(), base + 1);
+        let third = add().unwrap();
+        with_network(|net| {
+            net.sockets.remove(second);
+            net.sockets.remove(third);
+        })
+        .unwrap();
+        assert_eq!(socket_count(), base);
+        set_max_sockets(DEFAULT_MAX_SOCKETS);
+    }
 }
@@ -4,6 +4,35 @@ use alloc::vec::Vec;
 const CONFIG_ADDRESS: u16 = 0xCF8;
 const CONFIG_DATA: u16 = 0xCFC;
 
+// Configuration space offsets (type 0 header unless noted)
+const REG_STATUS_COMMAND: u8 = 0x04;
+const REG_CLASS: u8 = 0x08; // class code in the upper 16 bits
+const REG_HEADER_TYPE: u8 = 0x0C; // header type in bits 16..24
+const REG_BAR0: u8 = 0x10;
+const REG_EXPANSION_ROM: u8 = 0x30;
+const REG_BRIDGE_EXPANSION_ROM: u8 = 0x38; // type 1 (PCI-to-PCI bridge) header
+const REG_BRIDGE_BUS_NUMBERS: u8 = 0x18; // type 1: primary, secondary, subordinate bus
+const REG_CAPABILITIES_PTR: u8 = 0x34;
+
+const HEADER_TYPE_BRIDGE: u8 = 0x01;
+const CLASS_PCI_TO_PCI_BRIDGE: u32 = 0x0604; // base class 0x06, subclass 0x04
+
+const STATUS_CAPABILITIES_LIST: u32 = 1 << 20; // bit 4 of the status register
+/// Upper bound on capability entries walked, guarding against malformed (looping) lists.
+const MAX_CAPABILITIES: usize = 48;
+
+pub const CAP_ID_MSI: u8 = 0x05;
+pub const CAP_ID_PCIE: u8 = 0x10;
+pub const CAP_ID_MSIX: u8 = 0x11;
+
+/// An entry in a device's capability list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciCapability {
+    pub id: u8,
+    /// Offset of the capability header in configuration space.
+    pub offset: u8,
+}
+
 #[derive(Debug, Clone)]
 pub struct PciDevice {
     pub bus: u8,
@@ -12,6 +41,30 @@ pub struct PciDevice {
     pub vendor_id: u16,
     pub device_id: u16,
     pub bar0: u32,
+    /// Raw BAR registers. Bridges only implement the first two; the rest are 0.
+    pub bars: [u32; 6],
+    /// Raw expansion-ROM base address register.
+    pub expansion_rom: u32,
+    pub capabilities: Vec<PciCapability>,
+}
+
+impl PciDevice {
+    /// Configuration-space offset of the capability with `id`, if the device has one.
+    pub fn find_capability(&self, id: u8) -> Option<u8> {
+        self.capabilities.iter().find(|c| c.id == id).map(|c| c.offset)
+    }
+
+    pub fn msi_offset(&self) -> Option<u8> {
+        self.find_capability(CAP_ID_MSI)
+    }
+
+    pub fn msix_offset(&self) -> Option<u8> {
+        self.find_capability(CAP_ID_MSIX)
+    }
+
+    pub fn is_pcie(&self) -> bool {
+        self.find_capability(CAP_ID_PCIE).is_some()
+    }
 }
 
 /// Reads a 32-bit dword from the PCI configuration space.
@@ -29,43 +82,264 @@ pub fn pci_read_config(bus: u8, slot: u8, func: u8, offset: u8) -> u32 {
     }
 }
 
+/// Walks the capability list of a device whose configuration space is read through
+/// `read_config` (dword-aligned offset -> dword). Returns no entries if the status
+/// register says the list is absent.
+pub fn walk_capabilities<F>(read_config: F) -> Vec<PciCapability>
+where
+    F: Fn(u8) -> u32,
+{
+    let mut caps = Vec::new();
+    if read_config(REG_STATUS_COMMAND) & STATUS_CAPABILITIES_LIST == 0 {
+        return caps;
+    }
+
+    // The low two bits of every pointer are reserved.
+    let mut offset = (read_config(REG_CAPABILITIES_PTR) & 0xFC) as u8;
+    while offset != 0 && caps.len() < MAX_CAPABILITIES {
+        let header = read_config(offset);
+        caps.push(PciCapability {
+            id: (header & 0xFF) as u8,
+            offset,
+        });
+        offset = ((header >> 8) & 0xFC) as u8;
+    }
+    caps
+}
+
+/// Reads the BARs, expansion ROM and capability list of a present function.
+fn read_device<F>(
+    read_config: &F,
+    bus: u8,
+    slot: u8,
+    func: u8,
+    vendor_id: u16,
+    device_id: u16,
+) -> PciDevice
+where
+    F: Fn(u8, u8, u8, u8) -> u32,
+{
+    let header_type = ((read_config(bus, slot, func, REG_HEADER_TYPE) >> 16) & 0x7F) as u8;
+    let (bar_count, rom_reg) = if header_type == HEADER_TYPE_BRIDGE {
+        (2, REG_BRIDGE_EXPANSION_ROM)
+    } else {
+        (6, REG_EXPANSION_ROM)
+    };
+
+    let mut bars = [0u32; 6];
+    for (i, bar) in bars.iter_mut().enumerate().take(bar_count) {
+        *bar = read_config(bus, slot, func, REG_BAR0 + i as u8 * 4);
+    }
+
+    PciDevice {
+        bus,
+        device: slot,
+        function: func,
+        vendor_id,
+        device_id,
+        bar0: bars[0],
+        bars,
+        expansion_rom: read_config(bus, slot, func, rom_reg),
+        capabilities: walk_capabilities(|offset| read_config(bus, slot, func, offset)),
+    }
+}
+
 /// Scans the PCI buses for connected devices.
 pub fn scan_buses() -> Vec<PciDevice> {
+    enumerate(pci_read_config)
+}
+
+/// Enumerates the devices reachable from the host bridges, following PCI-to-PCI bridges
+/// to their secondary buses. Configuration space is read through `read_config`
+/// (bus, slot, function, dword-aligned offset -> dword). Each bus is scanned at most
+/// once, so bridges that point back at a bus already seen cannot loop.
+pub fn enumerate<F>(read_config: F) -> Vec<PciDevice>
+where
+    F: Fn(u8, u8, u8, u8) -> u32,
+{
     let mut devices = Vec::new();
-    
-    // Scan all possible buses, slots, and functions
-    for bus in 0..=255 {
+    let mut visited = [false; 256];
+    let mut pending: Vec<u8> = Vec::new();
+
+    // A multi-function host bridge at 0:0 means one host controller per function,
+    // each owning the bus numbered after it. An absent one reads as all ones, which
+    // must not pass for the multi-function bit.
+    let host_present = read_config(0, 0, 0, 0) & 0xFFFF != 0xFFFF;
+    if host_present && (read_config(0, 0, 0, REG_HEADER_TYPE) >> 16) & 0x80 != 0 {
+        for func in (0..8).rev() {
+            if read_config(0, 0, func, 0) & 0xFFFF != 0xFFFF {
+                pending.push(func);
+            }
+        }
+    } else {
+        pending.push(0);
+    }
+
+    while let Some(bus) = pending.pop() {
+        if core::mem::replace(&mut visited[bus as usize], true) {
+            continue;
+        }
+
         for slot in 0..32 {
             // Check Function 0 to see if device exists
-            let vendor_id = (pci_read_config(bus, slot, 0, 0) & 0xFFFF) as u16;
-            
+            let vendor_id = (read_config(bus, slot, 0, 0) & 0xFFFF) as u16;
+
             if vendor_id == 0xFFFF {
                 continue; // Device doesn't exist
             }
 
             // Read the header type to see if it's a multi-function device
-            let header_type = ((pci_read_config(bus, slot, 0, 0x0C) >> 16) & 0xFF) as u8;
+            let header_type = ((read_config(bus, slot, 0, REG_HEADER_TYPE) >> 16) & 0xFF) as u8;
             let functions = if (header_type & 0x80) != 0 { 8 } else { 1 };
 
             for func in 0..functions {
-                let id_reg = pci_read_config(bus, slot, func, 0);
+                let id_reg = read_config(bus, slot, func, 0);
                 let vend = (id_reg & 0xFFFF) as u16;
                 let dev_id = (id_reg >> 16) as u16;
 
-                if vend != 0xFFFF {
-                    let bar0 = pci_read_config(bus, slot, func, 0x10);
-                    devices.push(PciDevice {
-                        bus,
-                        device: slot,
-                        function: func,
-                        vendor_id: vend,
-                        device_id: dev_id,
-                        bar0,
-                    });
+                if vend == 0xFFFF {
+                    continue;
+                }
+                devices.push(read_device(&read_config, bus, slot, func, vend, dev_id));
+
+                if let Some(secondary) = secondary_bus(&read_config, bus, slot, func) {
+                    if !visited[secondary as usize] {
+                        pending.push(secondary);
+                    }
                 }
             }
         }
     }
-    
+
     devices
 }
+
+/// The secondary bus number of a PCI-to-PCI bridge, or `None` for other functions.
+/// A secondary bus of 0 means the bridge has not been configured.
+fn secondary_bus<F>(read_config: &F, bus: u8, slot: u8, func: u8) -> Option<u8>
+where
+    F: Fn(u8, u8, u8, u8) -> u32,
+{
+    let header_type = ((read_config(bus, slot, func, REG_HEADER_TYPE) >> 16) & 0x7F) as u8;
+    let class = read_config(bus, slot, func, REG_CLASS) >> 16;
+    if header_type != HEADER_TYPE_BRIDGE || class != CLASS_PCI_TO_PCI_BRIDGE {
+        return None;
+    }
+    match ((read_config(bus, slot, func, REG_BRIDGE_BUS_NUMBERS) >> 8) & 0xFF) as u8 {
+        0 => None,
+        secondary => Some(secondary),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::collections::BTreeMap;
+
+    /// Configuration spaces of mocked functions, 64 dwords each; absent functions read
+    /// as all ones.
+    #[derive(Default)]
+    struct ConfigSpace(BTreeMap<(u8, u8, u8), [u32; 64]>);
+
+    impl ConfigSpace {
+        fn function(&mut self, bus: u8, slot: u8, func: u8) -> &mut [u32; 64] {
+            self.0.entry((bus, slot, func)).or_insert([0; 64])
+        }
+
+        fn read(&self, bus: u8, slot: u8, func: u8, offset: u8) -> u32 {
+            self.0.get(&(bus, slot, func)).map_or(0xFFFF_FFFF, |regs| regs[offset as usize / 4])
+        }
+    }
+
+    /// A function with an MSI capability at 0x50 followed by a PCIe one at 0x60.
+    fn nic(space: &mut ConfigSpace) {
+        let regs = space.function(0, 3, 0);
+        regs[0] = 0x8139_10EC;
+        regs[REG_STATUS_COMMAND as usize / 4] = STATUS_CAPABILITIES_LIST;
+        regs[REG_BAR0 as usize / 4] = 0xC001;
+        regs[REG_BAR0 as usize / 4 + 1] = 0xFEB0_0000;
+        regs[REG_EXPANSION_ROM as usize / 4] = 0xFEB4_0000;
+        regs[REG_CAPABILITIES_PTR as usize / 4] = 0x50;
+        regs[0x50 / 4] = 0x0000_6000 | CAP_ID_MSI as u32;
+        regs[0x60 / 4] = CAP_ID_PCIE as u32;
+    }
+
+    #[test_case]
+    fn capability_list_reports_msi_and_its_offset() {
+        let mut space = ConfigSpace::default();
+        nic(&mut space);
+        let devices = enumerate(|b, s, f, o| space.read(b, s, f, o));
+        assert_eq!(devices.len(), 1);
+
+        let nic = &devices[0];
+        assert_eq!((nic.vendor_id, nic.device_id), (0x10EC, 0x8139));
+        assert_eq!(nic.bars[..2], [0xC001, 0xFEB0_0000]);
+        assert_eq!(nic.bar0, 0xC001);
+        assert_eq!(nic.expansion_rom, 0xFEB4_0000);
+        assert_eq!(nic.msi_offset(), Some(0x50));
+        assert!(nic.is_pcie());
+        assert_eq!(nic.msix_offset(), None);
+    }
+
+    #[test_case]
+    fn looping_capability_list_is_bounded() {
+        let mut regs = [0u32; 64];
+        regs[REG_STATUS_COMMAND as usize / 4] = STATUS_CAPABILITIES_LIST;
+        regs[REG_CAPABILITIES_PTR as usize / 4] = 0x40;
+        regs[0x40 / 4] = 0x0000_4000 | CAP_ID_MSIX as u32; // points at itself
+        assert_eq!(walk_capabilities(|o| regs[o as usize / 4]).len(), MAX_CAPABILITIES);
+
+        regs[REG_STATUS_COMMAND as usize / 4] = 0;
+        assert!(walk_capabilities(|o| regs[o as usize / 4]).is_empty());
+    }
+
+    /// A PCI-to-PCI bridge at `bus:slot.0` whose secondary bus is `secondary`.
+    fn bridge(space: &mut ConfigSpace, bus: u8, slot: u8, secondary: u8) {
+        let regs = space.function(bus, slot, 0);
+        regs[0] = 0x0001_8086;
+        regs[REG_CLASS as usize / 4] = CLASS_PCI_TO_PCI_BRIDGE << 16;
+        regs[REG_HEADER_TYPE as usize / 4] = (HEADER_TYPE_BRIDGE as u32) << 16;
+        regs[REG_BRIDGE_BUS_NUMBERS as usize / 4] =
+            (secondary as u32) << 16 | (secondary as u32) << 8 | bus as u32;
+    }
+
+    fn host_bridge(space: &mut ConfigSpace) {
+        space.function(0, 0, 0)[0] = 0x1237_8086;
+    }
+
+    fn found(devices: &[PciDevice], bus: u8, slot: u8) -> usize {
+        devices.iter().filter(|d| (d.bus, d.device) == (bus, slot)).count()
+    }
+
+    #[test_case]
+    fn devices_behind_a_bridge_are_found_on_its_secondary_bus() {
+        let mut space = ConfigSpace::default();
+        host_bridge(&mut space);
+        bridge(&mut space, 0, 1, 2);
+        space.function(2, 4, 0)[0] = 0x100E_8086;
+        // No bridge leads to bus 5, so it is never scanned.
+        space.function(5, 0, 0)[0] = 0x100E_8086;
+
+        let devices = enumerate(|b, s, f, o| space.read(b, s, f, o));
+        assert_eq!(devices.len(), 3);
+        assert_eq!(found(&devices, 2, 4), 1);
+        assert_eq!(devices.iter().find(|d| d.bus == 2).unwrap().device_id, 0x100E);
+        assert_eq!(found(&devices, 5, 0), 0);
+    }
+
+    #[test_case]
+    fn bridges_pointing_back_at_a_scanned_bus_do_not_loop() {
+        let mut space = ConfigSpace::default();
+        host_bridge(&mut space);
+        bridge(&mut space, 0, 1, 2);
+        bridge(&mut space, 2, 0, 2);
+        bridge(&mut space, 2, 1, 3);
+        bridge(&mut space, 3, 0, 2);
+        space.function(2, 3, 0)[0] = 0x100E_8086;
+
+        let devices = enumerate(|b, s, f, o| space.read(b, s, f, o));
+        assert_eq!(devices.len(), 6);
+        assert_eq!(found(&devices, 2, 3), 1);
+        assert_eq!(found(&devices, 0, 1), 1);
+    }
+}
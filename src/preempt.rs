@@ -0,0 +1,468 @@
+//! Preemption points for agent modules.
+//!
+//! wasmi cannot resume a call that ran out of fuel inside Wasm code; only a call
+//! suspended by a failing host function can be resumed. `instrument` therefore rewrites
+//! each module to call the host import `kernel.preempt` on entry to every function and
+//! at the head of every loop. The import suspends the module once its fuel slice is
+//! spent, so an agent that never makes a syscall still gives up the CPU.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use wasmparser::{BinaryReader, BinaryReaderError, Import, Operator, TypeRef, ValType};
+
+/// Module and field name of the injected preemption import.
+pub const PREEMPT_MODULE: &str = "kernel";
+pub const PREEMPT_FUNC: &str = "preempt";
+
+const HEADER: &[u8] = b"\0asm\x01\0\0\0";
+
+const CUSTOM: u8 = 0;
+const TYPE: u8 = 1;
+const IMPORT: u8 = 2;
+const GLOBAL: u8 = 6;
+const EXPORT: u8 = 7;
+const START: u8 = 8;
+const ELEMENT: u8 = 9;
+const CODE: u8 = 10;
+
+const OP_CALL: u8 = 0x10;
+const OP_RETURN_CALL: u8 = 0x12;
+const OP_REF_FUNC: u8 = 0xd2;
+const EXTERNAL_FUNC: u8 = 0x00;
+
+struct Malformed(String);
+
+impl From<BinaryReaderError> for Malformed {
+    fn from(err: BinaryReaderError) -> Self {
+        Malformed(alloc::format!("{err}"))
+    }
+}
+
+type Result<T> = core::result::Result<T, Malformed>;
+
+/// Return a copy of `wasm` with a call to `kernel.preempt` at the start of every
+/// function body and every loop. The import is appended after the module's own
+/// imports, so function indices past them shift by one; calls, exports, the start
+/// function, element segments and `ref.func` are renumbered to match, and the `name`
+/// section, which would be stale, is dropped.
+pub fn instrument(wasm: &[u8]) -> core::result::Result<Vec<u8>, String> {
+    rewrite(wasm).map_err(|Malformed(err)| alloc::format!("Failed to instrument module: {err}"))
+}
+
+fn rewrite(wasm: &[u8]) -> Result<Vec<u8>> {
+    if !wasm.starts_with(HEADER) {
+        return Err(Malformed(String::from("bad header")));
+    }
+    let sections = split_sections(wasm)?;
+
+    let mut types = 0;
+    let mut imported_funcs = 0;
+    for section in &sections {
+        match section.id {
+            TYPE => types = section.reader().read_var_u32()?,
+            IMPORT => imported_funcs = count_func_imports(section)?,
+            _ => {}
+        }
+    }
+    let rewriter = Rewriter {
+        preempt: imported_funcs,
+        preempt_type: types,
+    };
+
+    let mut out = Vec::from(HEADER);
+    let (mut typed, mut imported) = (false, false);
+    for section in &sections {
+        if section.id != CUSTOM {
+            if !typed && section.id != TYPE {
+                push_section(&mut out, TYPE, &rewriter.types(None)?);
+                typed = true;
+            }
+            if !imported && section.id != TYPE && section.id != IMPORT {
+                push_section(&mut out, IMPORT, &rewriter.imports(None)?);
+                imported = true;
+            }
+        }
+        let contents = match section.id {
+            CUSTOM if section.reader().read_string()? == "name" => continue,
+            TYPE => {
+                typed = true;
+                rewriter.types(Some(section))?
+            }
+            IMPORT => {
+                imported = true;
+                rewriter.imports(Some(section))?
+            }
+            GLOBAL => rewriter.globals(section)?,
+            EXPORT => rewriter.exports(section)?,
+            START => {
+                let mut contents = Vec::new();
+                uleb(
+                    &mut contents,
+                    rewriter.remap(section.reader().read_var_u32()?),
+                );
+                contents
+            }
+            ELEMENT => rewriter.elements(section)?,
+            CODE => rewriter.code(section)?,
+            _ => Vec::from(section.contents),
+        };
+        push_section(&mut out, section.id, &contents);
+    }
+    if !typed {
+        push_section(&mut out, TYPE, &rewriter.types(None)?);
+    }
+    if !imported {
+        push_section(&mut out, IMPORT, &rewriter.imports(None)?);
+    }
+    Ok(out)
+}
+
+struct Section<'a> {
+    id: u8,
+    contents: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Section<'a> {
+    fn reader(&self) -> BinaryReader<'a> {
+        BinaryReader::new_with_offset(self.contents, self.offset)
+    }
+}
+
+fn split_sections(wasm: &[u8]) -> Result<Vec<Section<'_>>> {
+    let mut reader = BinaryReader::new_with_offset(&wasm[HEADER.len()..], HEADER.len());
+    let mut sections = Vec::new();
+    while !reader.eof() {
+        let id = reader.read_u8()?;
+        let len = reader.read_var_u32()? as usize;
+        let offset = reader.original_position();
+        let contents = reader.read_bytes(len)?;
+        sections.push(Section {
+            id,
+            contents,
+            offset,
+        });
+    }
+    Ok(sections)
+}
+
+fn count_func_imports(section: &Section<'_>) -> Result<u32> {
+    let mut reader = section.reader();
+    let mut funcs = 0;
+    for _ in 0..reader.read_var_u32()? {
+        if let TypeRef::Func(_) = reader.read::<Import>()?.ty {
+            funcs += 1;
+        }
+    }
+    Ok(funcs)
+}
+
+struct Rewriter {
+    /// Function index of the injected import.
+    preempt: u32,
+    /// Type index of its `() -> ()` signature.
+    preempt_type: u32,
+}
+
+impl Rewriter {
+    fn remap(&self, func: u32) -> u32 {
+        if func >= self.preempt {
+            func + 1
+        } else {
+            func
+        }
+    }
+
+    fn call_preempt(&self, out: &mut Vec<u8>) {
+        out.push(OP_CALL);
+        uleb(out, self.preempt);
+    }
+
+    /// The type section with `() -> ()` appended.
+    fn types(&self, section: Option<&Section<'_>>) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        uleb(&mut out, self.preempt_type + 1);
+        if let Some(section) = section {
+            let mut reader = section.reader();
+            reader.read_var_u32()?;
+            out.extend_from_slice(&section.contents[reader.current_position()..]);
+        }
+        out.extend_from_slice(&[0x60, 0, 0]);
+        Ok(out)
+    }
+
+    /// The import section with `kernel.preempt` appended.
+    fn imports(&self, section: Option<&Section<'_>>) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        let mut count = 1;
+        let mut entries: &[u8] = &[];
+        if let Some(section) = section {
+            let mut reader = section.reader();
+            count += reader.read_var_u32()?;
+            entries = &section.contents[reader.current_position()..];
+        }
+        uleb(&mut out, count);
+        out.extend_from_slice(entries);
+        name(&mut out, PREEMPT_MODULE);
+        name(&mut out, PREEMPT_FUNC);
+        out.push(EXTERNAL_FUNC);
+        uleb(&mut out, self.preempt_type);
+        Ok(out)
+    }
+
+    fn globals(&self, section: &Section<'_>) -> Result<Vec<u8>> {
+        let mut reader = section.reader();
+        let mut out = Vec::new();
+        let count = reader.read_var_u32()?;
+        uleb(&mut out, count);
+        for _ in 0..count {
+            let start = reader.current_position();
+            reader.read::<ValType>()?;
+            reader.read_u8()?;
+            out.extend_from_slice(&section.contents[start..reader.current_position()]);
+            self.const_expr(&mut reader, section.contents, &mut out)?;
+        }
+        Ok(out)
+    }
+
+    fn exports(&self, section: &Section<'_>) -> Result<Vec<u8>> {
+        let mut reader = section.reader();
+        let mut out = Vec::new();
+        let count = reader.read_var_u32()?;
+        uleb(&mut out, count);
+        for _ in 0..count {
+            name(&mut out, reader.read_string()?);
+            let kind = reader.read_u8()?;
+            let index = reader.read_var_u32()?;
+            out.push(kind);
+            uleb(
+                &mut out,
+                if kind == EXTERNAL_FUNC {
+                    self.remap(index)
+                } else {
+                    index
+                },
+            );
+        }
+        Ok(out)
+    }
+
+    fn elements(&self, section: &Section<'_>) -> Result<Vec<u8>> {
+        let mut reader = section.reader();
+        let mut out = Vec::new();
+        let count = reader.read_var_u32()?;
+        uleb(&mut out, count);
+        for _ in 0..count {
+            let flags = reader.read_var_u32()?;
+            uleb(&mut out, flags);
+            if flags > 7 {
+                return Err(Malformed(alloc::format!(
+                    "unknown element segment kind {flags}"
+                )));
+            }
+            // Bit 0: passive or declared; bit 1: explicit table index (when active) or
+            // explicit element kind; bit 2: items are expressions rather than indices.
+            let active = flags & 1 == 0;
+            if active && flags & 2 != 0 {
+                uleb(&mut out, reader.read_var_u32()?);
+            }
+            if active {
+                self.const_expr(&mut reader, section.contents, &mut out)?;
+            }
+            if !active || flags & 2 != 0 {
+                out.push(reader.read_u8()?);
+            }
+            let items = reader.read_var_u32()?;
+            uleb(&mut out, items);
+            for _ in 0..items {
+                if flags & 4 != 0 {
+                    self.const_expr(&mut reader, section.contents, &mut out)?;
+                } else {
+                    uleb(&mut out, self.remap(reader.read_var_u32()?));
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    /// Copy a constant expression up to and including its `end`, renumbering `ref.func`.
+    fn const_expr(
+        &self,
+        reader: &mut BinaryReader<'_>,
+        contents: &[u8],
+        out: &mut Vec<u8>,
+    ) -> Result<()> {
+        loop {
+            let start = reader.current_position();
+            match reader.read_operator()? {
+                Operator::End => {
+                    out.extend_from_slice(&contents[start..reader.current_position()]);
+                    return Ok(());
+                }
+                Operator::RefFunc { function_index } => {
+                    out.push(OP_REF_FUNC);
+                    uleb(out, self.remap(function_index));
+                }
+                _ => out.extend_from_slice(&contents[start..reader.current_position()]),
+            }
+        }
+    }
+
+    fn code(&self, section: &Section<'_>) -> Result<Vec<u8>> {
+        let mut reader = section.reader();
+        let mut out = Vec::new();
+        let count = reader.read_var_u32()?;
+        uleb(&mut out, count);
+        for _ in 0..count {
+            let len = reader.read_var_u32()? as usize;
+            let offset = reader.original_position();
+            let body = self.function_body(reader.read_bytes(len)?, offset)?;
+            uleb(&mut out, body.len() as u32);
+            out.extend_from_slice(&body);
+        }
+        Ok(out)
+    }
+
+    fn function_body(&self, body: &[u8], offset: usize) -> Result<Vec<u8>> {
+        let mut reader = BinaryReader::new_with_offset(body, offset);
+        for _ in 0..reader.read_var_u32()? {
+            reader.read_var_u32()?;
+            reader.read::<ValType>()?;
+        }
+        let mut out = Vec::from(&body[..reader.current_position()]);
+        self.call_preempt(&mut out);
+
+        while !reader.eof() {
+            let start = reader.current_position();
+            let op = reader.read_operator()?;
+            let raw = &body[start..reader.current_position()];
+            match op {
+                Operator::Call { function_index } => {
+                    out.push(OP_CALL);
+                    uleb(&mut out, self.remap(function_index));
+                }
+                Operator::ReturnCall { function_index } => {
+                    out.push(OP_RETURN_CALL);
+                    uleb(&mut out, self.remap(function_index));
+                }
+                Operator::RefFunc { function_index } => {
+                    out.push(OP_REF_FUNC);
+                    uleb(&mut out, self.remap(function_index));
+                }
+                Operator::Loop { .. } => {
+                    out.extend_from_slice(raw);
+                    self.call_preempt(&mut out);
+                }
+                _ => out.extend_from_slice(raw),
+            }
+        }
+        Ok(out)
+    }
+}
+
+fn push_section(out: &mut Vec<u8>, id: u8, contents: &[u8]) {
+    out.push(id);
+    uleb(out, contents.len() as u32);
+    out.extend_from_slice(contents);
+}
+
+fn name(out: &mut Vec<u8>, name: &str) {
+    uleb(out, name.len() as u32);
+    out.extend_from_slice(name.as_bytes());
+}
+
+fn uleb(out: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Code, ModuleBuilder, I32, I32_SUB};
+    use wasmi::{Caller, Engine, Linker, Module, Store};
+
+    /// Counts of (preemption checks, `env.log` calls) made by a run.
+    type Counts = (u32, u32);
+
+    /// Run `_start` of `wasm` after instrumenting it, counting the injected checks and
+    /// the module's own `env.log` calls.
+    fn run_instrumented(wasm: &[u8]) -> Counts {
+        let engine = Engine::default();
+        let module = Module::new(&engine, &instrument(wasm).unwrap()[..]).unwrap();
+        let mut store = Store::new(&engine, (0, 0));
+        let mut linker = <Linker<Counts>>::new(&engine);
+        linker
+            .func_wrap(
+                PREEMPT_MODULE,
+                PREEMPT_FUNC,
+                |mut caller: Caller<'_, Counts>| {
+                    caller.data_mut().0 += 1;
+                },
+            )
+            .unwrap();
+        linker
+            .func_wrap("env", "log", |mut caller: Caller<'_, Counts>| {
+                caller.data_mut().1 += 1;
+            })
+            .unwrap();
+        let instance = linker
+            .instantiate(&mut store, &module)
+            .unwrap()
+            .start(&mut store)
+            .unwrap();
+        instance
+            .get_typed_func::<(), ()>(&store, "_start")
+            .unwrap()
+            .call(&mut store, ())
+            .unwrap();
+        *store.data()
+    }
+
+    #[test_case]
+    fn checks_run_at_function_entry_and_every_loop_iteration() {
+        let mut m = ModuleBuilder::new();
+        let log = m.import("log", &[], &[]);
+        let helper = m.func(&[], &[], &[], Code::new().call(log));
+        // Call helper three times from a loop counting local 0 down from 3.
+        let body = Code::new()
+            .i32(3)
+            .local_set(0)
+            .loop_()
+            .call(helper)
+            .local_get(0)
+            .i32(1)
+            .op(I32_SUB)
+            .local_set(0)
+            .local_get(0)
+            .br_if(0)
+            .end();
+        let main = m.func(&[], &[], &[I32], body);
+        m.export("_start", main);
+
+        // One check on entering _start, then per iteration one at the loop head and
+        // one on entering helper; helper's calls still reach env.log.
+        assert_eq!(run_instrumented(&m.build()), (7, 3));
+    }
+
+    #[test_case]
+    fn module_without_imports_gets_both_sections() {
+        let mut m = ModuleBuilder::new();
+        let main = m.func(&[], &[], &[], Code::new());
+        m.export("_start", main);
+        assert_eq!(run_instrumented(&m.build()), (1, 0));
+    }
+
+    #[test_case]
+    fn garbage_is_rejected() {
+        assert!(instrument(b"\0asm garbage").is_err());
+        assert!(instrument(b"not wasm").is_err());
+    }
+}
@@ -0,0 +1,103 @@
+use crate::time::uptime_ms;
+use alloc::collections::BTreeMap;
+use spin::Mutex;
+
+/// Network requests per second an agent may make unless the supervisor sets a limit
+/// or `net.requests_per_sec` is configured.
+pub const DEFAULT_REQUESTS_PER_SEC: u32 = 20;
+
+fn default_rate() -> u32 {
+    crate::config::get_int("net.requests_per_sec")
+        .and_then(|rate| u32::try_from(rate).ok())
+        .unwrap_or(DEFAULT_REQUESTS_PER_SEC)
+}
+
+/// A token bucket holding up to `rate` tokens, refilled at `rate` tokens per second.
+/// Tokens are tracked in thousandths so refills stay exact at millisecond granularity.
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    rate: u32,
+    milli_tokens: u64,
+    last_refill_ms: u64,
+}
+
+impl TokenBucket {
+    fn new(rate: u32, now_ms: u64) -> Self {
+        TokenBucket {
+            rate,
+            milli_tokens: rate as u64 * 1000,
+            last_refill_ms: now_ms,
+        }
+    }
+
+    fn try_take(&mut self, now_ms: u64) -> bool {
+        let capacity = self.rate as u64 * 1000;
+        let elapsed = now_ms.saturating_sub(self.last_refill_ms);
+        // `rate` tokens per second is exactly `rate` milli-tokens per millisecond.
+        self.milli_tokens = (self.milli_tokens + elapsed * self.rate as u64).min(capacity);
+        self.last_refill_ms = now_ms;
+
+        if self.milli_tokens >= 1000 {
+            self.milli_tokens -= 1000;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+static BUCKETS: Mutex<BTreeMap<u64, TokenBucket>> = Mutex::new(BTreeMap::new());
+
+/// Set the network request budget for `agent_pid`, refilling its bucket.
+/// Used by the Kernel Supervisor to throttle or relax individual agents.
+pub fn set_limit(agent_pid: u64, requests_per_sec: u32) {
+    BUCKETS
+        .lock()
+        .insert(agent_pid, TokenBucket::new(requests_per_sec, uptime_ms()));
+}
+
+/// Consume one request token for `agent_pid`. Returns false if the agent has
+/// exhausted its budget for the current window.
+pub fn check(agent_pid: u64) -> bool {
+    let now = uptime_ms();
+    BUCKETS
+        .lock()
+        .entry(agent_pid)
+        .or_insert_with(|| TokenBucket::new(default_rate(), now))
+        .try_take(now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn bucket_refuses_the_request_past_its_rate_then_refills() {
+        let mut bucket = TokenBucket::new(3, 1_000);
+        assert!(bucket.try_take(1_000));
+        assert!(bucket.try_take(1_000));
+        assert!(bucket.try_take(1_000));
+        assert!(!bucket.try_take(1_000));
+
+        // A third of a second later one token (3 per second) is back, and only one.
+        assert!(bucket.try_take(1_334));
+        assert!(!bucket.try_take(1_334));
+    }
+
+    #[test_case]
+    fn bucket_never_holds_more_than_one_second_of_tokens() {
+        let mut bucket = TokenBucket::new(2, 0);
+        assert!(bucket.try_take(60_000));
+        assert!(bucket.try_take(60_000));
+        assert!(!bucket.try_take(60_000));
+    }
+
+    #[test_case]
+    fn supervisor_limit_applies_to_check() {
+        let pid = 880_001;
+        set_limit(pid, 2);
+        assert!(check(pid));
+        assert!(check(pid));
+        assert!(!check(pid));
+    }
+}
@@ -0,0 +1,63 @@
+//! Kernel pseudo-random number generator (SplitMix64).
+//! Not cryptographically secure. Used for DNS transaction IDs, ephemeral ports and the
+//! smoltcp seed. Seeded from the TSC at boot unless a fixed seed is supplied, either at
+//! build time via the `KERNEL_RNG_SEED` environment variable or at runtime via `seed`,
+//! which makes every consumer deterministic for reproducible runs.
+
+use spin::Mutex;
+
+/// First port handed out by `ephemeral_port` (IANA dynamic range).
+pub const EPHEMERAL_PORT_START: u16 = 49152;
+
+struct Rng {
+    state: u64,
+    deterministic: bool,
+}
+
+static RNG: Mutex<Rng> = Mutex::new(Rng {
+    state: 0,
+    deterministic: false,
+});
+
+/// Seed the generator at boot: from `KERNEL_RNG_SEED` if it was set at build time,
+/// otherwise from the TSC. Does nothing if `seed` was already called.
+pub fn init() {
+    if RNG.lock().deterministic {
+        return;
+    }
+    match option_env!("KERNEL_RNG_SEED").and_then(|s| s.parse().ok()) {
+        Some(fixed) => seed(fixed),
+        None => RNG.lock().state = crate::time::rdtsc(),
+    }
+}
+
+/// Reset the generator to a fixed seed. All later randomness is a pure function of it.
+pub fn seed(value: u64) {
+    let mut rng = RNG.lock();
+    rng.state = value;
+    rng.deterministic = true;
+}
+
+/// True once a fixed seed is in effect.
+pub fn is_deterministic() -> bool {
+    RNG.lock().deterministic
+}
+
+pub fn next_u64() -> u64 {
+    let mut rng = RNG.lock();
+    rng.state = rng.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = rng.state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+pub fn next_u16() -> u16 {
+    (next_u64() >> 48) as u16
+}
+
+/// A local port in the dynamic range for an outgoing connection.
+pub fn ephemeral_port() -> u16 {
+    let span = (u16::MAX - EPHEMERAL_PORT_START) as u64 + 1;
+    EPHEMERAL_PORT_START + (next_u64() % span) as u16
+}
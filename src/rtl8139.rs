@@ -1,5 +1,7 @@
 use alloc::vec::Vec;
 use x86_64::instructions::port::Port;
+use crate::bytes::{read_u16_le, read_u32_le};
+use crate::checksum::crc32;
 use crate::serial_println;
 
 const RTL8139_VENDOR_ID: u16 = 0x10EC;
@@ -12,6 +14,8 @@ const REG_TSD0: u16 = 0x10;
 const REG_TSAD0: u16 = 0x20;
 const REG_RBSTART: u16 = 0x30;
 const REG_CMD: u16 = 0x37;
+const REG_CAPR: u16 = 0x38;
+const REG_CBR: u16 = 0x3A;
 const REG_IMR: u16 = 0x3C;
 const REG_ISR: u16 = 0x3E;
 const REG_RCR: u16 = 0x44;
@@ -19,6 +23,58 @@ const REG_CONFIG1: u16 = 0x52;
 
 const RX_BUFFER_SIZE: usize = 8192 + 16 + 1500;
 const TX_BUFFER_SIZE: usize = 2048;
+/// Largest Ethernet frame the card is handed: 1500-byte MTU + 14-byte header (the NIC appends the CRC).
+pub const MAX_FRAME_SIZE: usize = 1514;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxError {
+    /// The frame exceeds `MAX_FRAME_SIZE`; carries the rejected length.
+    FrameTooLarge(usize),
+}
+
+// Per-packet RX header status bits
+/// The frame arrived intact.
+const RX_STATUS_ROK: u16 = 1 << 0;
+/// Frame alignment error.
+const RX_STATUS_FAE: u16 = 1 << 1;
+/// CRC error.
+const RX_STATUS_CRC: u16 = 1 << 2;
+/// Frame longer than 4K.
+const RX_STATUS_LONG: u16 = 1 << 3;
+/// Runt: frame shorter than 64 bytes.
+const RX_STATUS_RUNT: u16 = 1 << 4;
+/// Invalid symbol error.
+const RX_STATUS_ISE: u16 = 1 << 5;
+const RX_STATUS_ERRORS: u16 =
+    RX_STATUS_FAE | RX_STATUS_CRC | RX_STATUS_LONG | RX_STATUS_RUNT | RX_STATUS_ISE;
+
+// Interrupt status bits (write 1 to clear)
+/// The RX ring filled up and the card dropped incoming frames.
+const ISR_RX_OVERFLOW: u16 = 1 << 4;
+/// The card's internal RX FIFO overflowed.
+const ISR_FIFO_OVERFLOW: u16 = 1 << 6;
+
+/// Smallest frame passed up the stack: a bare Ethernet header.
+const MIN_RX_FRAME_SIZE: usize = 14;
+/// CRC the card leaves at the end of every received frame.
+const CRC_LEN: usize = 4;
+/// Reads of the command register to wait for a software reset to finish. A card that
+/// never clears the reset bit is wedged or absent, and waiting longer will not help.
+const RESET_POLL_LIMIT: u32 = 100_000;
+
+/// Frame counters since the driver was created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NicStats {
+    pub rx_packets: u64,
+    pub tx_packets: u64,
+    /// Frames dropped as malformed: error status bits (CRC, alignment, runt...), an
+    /// implausible length or, with FCS checking on, a CRC mismatch.
+    pub rx_errors: u64,
+    /// Frames refused before transmission (e.g. oversized).
+    pub tx_errors: u64,
+    /// Times the RX ring overflowed because it was not drained fast enough.
+    pub rx_overflows: u64,
+}
 
 #[derive(Debug)]
 pub struct Rtl8139 {
@@ -29,6 +85,21 @@ pub struct Rtl8139 {
     tx_buffers: [Vec<u8>; 4],
     tx_index: usize,
     rx_offset: usize,
+    stats: NicStats,
+    /// Recompute each received frame's CRC instead of trusting the card's status bits.
+    verify_fcs: bool,
+    /// Registers to serve in place of the card's I/O ports, for tests.
+    #[cfg(test)]
+    mock_ports: Option<MockPorts>,
+}
+
+/// Stand-in for the card's registers: reads come from `regs` (0 if unset) and writes
+/// are recorded in order.
+#[cfg(test)]
+#[derive(Debug, Default)]
+struct MockPorts {
+    regs: alloc::collections::BTreeMap<u16, u16>,
+    writes: Vec<(u16, u16)>,
 }
 
 impl Rtl8139 {
@@ -51,6 +122,10 @@ impl Rtl8139 {
             tx_buffers,
             tx_index: 0,
             rx_offset: 0,
+            stats: NicStats::default(),
+            verify_fcs: false,
+            #[cfg(test)]
+            mock_ports: None,
         };
         dev.read_mac();
         dev
@@ -78,7 +153,14 @@ impl Rtl8139 {
             
             // 2. Software Reset
             Port::<u8>::new(self.io_base + REG_CMD).write(0x10);
-            while (Port::<u8>::new(self.io_base + REG_CMD).read() & 0x10) != 0 {}
+            let mut polls = 0;
+            while (Port::<u8>::new(self.io_base + REG_CMD).read() & 0x10) != 0 {
+                polls += 1;
+                if polls == RESET_POLL_LIMIT {
+                    serial_println!("[RTL8139] Warning: software reset did not complete");
+                    break;
+                }
+            }
             
             // 3. Setup RX Ring Buffer pointing to our physical translated memory address
             let rx_phys = self.virt_to_phys(self.rx_buffer.as_ptr());
@@ -93,8 +175,44 @@ impl Rtl8139 {
         serial_println!("[RTL8139] Initialized. RX buffer physically mapped at {:#X}", self.virt_to_phys(self.rx_buffer.as_ptr()));
     }
 
-    /// Transmit a raw ethernet payload
-    pub fn tx_raw(&mut self, payload: &[u8]) {
+    /// Packet and error counters.
+    pub fn stats(&self) -> NicStats {
+        self.stats
+    }
+
+    /// The transmit buffer holding the frame sent `back` frames before the latest, for
+    /// tests to inspect what went on the wire. The card rotates through four buffers.
+    #[cfg(test)]
+    pub fn sent_frame(&self, back: usize) -> &[u8] {
+        &self.tx_buffers[(self.tx_index + 3 - back % 4) % 4]
+    }
+
+    /// Check every received frame against its trailing FCS, dropping mismatches.
+    pub fn set_verify_fcs(&mut self, enabled: bool) {
+        self.verify_fcs = enabled;
+    }
+
+    /// I/O port base the card was found at.
+    pub fn io_base(&self) -> u16 {
+        self.io_base
+    }
+
+    /// Recover a wedged card: re-read the MAC and re-run the software reset and ring setup.
+    /// Frames still in the RX ring are lost.
+    pub fn reset(&mut self) {
+        self.tx_index = 0;
+        self.rx_offset = 0;
+        self.read_mac();
+        self.init();
+    }
+
+    /// Transmit a raw ethernet payload. Frames larger than `MAX_FRAME_SIZE` are rejected.
+    pub fn tx_raw(&mut self, payload: &[u8]) -> Result<(), TxError> {
+        if payload.len() > MAX_FRAME_SIZE {
+            self.stats.tx_errors += 1;
+            return Err(TxError::FrameTooLarge(payload.len()));
+        }
+
         let ptr = self.tx_buffers[self.tx_index].as_ptr();
         let phys = self.virt_to_phys(ptr);
 
@@ -107,31 +225,275 @@ impl Rtl8139 {
         }
         
         self.tx_index = (self.tx_index + 1) % 4;
+        self.stats.tx_packets += 1;
+        Ok(())
+    }
+
+    fn read_reg16(&self, reg: u16) -> u16 {
+        #[cfg(test)]
+        if let Some(mock) = &self.mock_ports {
+            return mock.regs.get(&reg).copied().unwrap_or(0);
+        }
+        unsafe { Port::<u16>::new(self.io_base + reg).read() }
+    }
+
+    fn read_reg8(&self, reg: u16) -> u8 {
+        #[cfg(test)]
+        if self.mock_ports.is_some() {
+            return self.read_reg16(reg) as u8;
+        }
+        unsafe { Port::<u8>::new(self.io_base + reg).read() }
+    }
+
+    fn write_reg16(&mut self, reg: u16, value: u16) {
+        #[cfg(test)]
+        if let Some(mock) = &mut self.mock_ports {
+            mock.writes.push((reg, value));
+            return;
+        }
+        unsafe { Port::<u16>::new(self.io_base + reg).write(value) }
+    }
+
+    fn write_reg8(&mut self, reg: u16, value: u8) {
+        #[cfg(test)]
+        if let Some(mock) = &mut self.mock_ports {
+            mock.writes.push((reg, value as u16));
+            return;
+        }
+        unsafe { Port::<u8>::new(self.io_base + reg).write(value) }
+    }
+
+    /// If the card reports an RX overflow, skip the frames stuck in the ring and resume
+    /// receiving; otherwise it stops delivering frames for good. Returns true if it had
+    /// to recover.
+    fn recover_rx_overflow(&mut self) -> bool {
+        let isr = self.read_reg16(REG_ISR);
+        let overflow = isr & (ISR_RX_OVERFLOW | ISR_FIFO_OVERFLOW);
+        if overflow == 0 {
+            return false;
+        }
+
+        serial_println!("[RTL8139] Warning: RX buffer overflow; discarding queued frames");
+        self.stats.rx_overflows += 1;
+        // Resume reading where the card writes next. CAPR trails the read offset by 16.
+        let cbr = self.read_reg16(REG_CBR);
+        self.rx_offset = cbr as usize % 8192;
+        self.write_reg16(REG_CAPR, cbr.wrapping_sub(16));
+        self.write_reg16(REG_ISR, overflow);
+        // Make sure the receiver is still enabled alongside the transmitter.
+        self.write_reg8(REG_CMD, 0x0C);
+        true
     }
 
     /// Poll for an incoming raw ethernet payload
     pub fn rx_poll(&mut self) -> Option<Vec<u8>> {
-        let cmd = unsafe { Port::<u8>::new(self.io_base + REG_CMD).read() };
+        #[cfg(test)]
+        if let Some(frame) = SCRIPTED_RX.lock().pop_front() {
+            self.stats.rx_packets += 1;
+            return Some(frame);
+        }
+
+        if self.recover_rx_overflow() {
+            return None;
+        }
+
+        let cmd = self.read_reg8(REG_CMD);
         if (cmd & 1) != 0 {
             return None; // Queue Empty
         }
+        self.take_frame()
+    }
 
-        let length = u16::from_le_bytes([self.rx_buffer[self.rx_offset + 2], self.rx_buffer[self.rx_offset + 3]]) as usize;
-        
-        let packet_offset = self.rx_offset + 4;
-        let p_len = length.saturating_sub(4); // Exclude CRC at the tail end
-        
-        let mut packet = Vec::with_capacity(p_len);
-        for i in 0..p_len {
-            packet.push(self.rx_buffer[(packet_offset + i) % 8192]);
+    /// Take the frame at the read offset out of the RX ring, dropping it (and counting an
+    /// RX error) if its header reports an error or an implausible length.
+    fn take_frame(&mut self) -> Option<Vec<u8>> {
+        let status = read_u16_le(&self.rx_buffer, self.rx_offset)?;
+        let length = read_u16_le(&self.rx_buffer, self.rx_offset + 2)? as usize;
+
+        if !length_valid(length) {
+            // The length can't be trusted to find the next packet; resync the ring.
+            serial_println!("[RTL8139] Dropping frame with bad length {}; resetting RX ring", length);
+            self.stats.rx_errors += 1;
+            self.reset();
+            return None;
         }
 
+        let packet = if status_valid(status) {
+            let packet_offset = self.rx_offset + 4;
+            let mut packet = Vec::with_capacity(length);
+            for i in 0..length {
+                packet.push(self.rx_buffer[(packet_offset + i) % 8192]);
+            }
+            if !self.verify_fcs || fcs_valid(&packet) {
+                packet.truncate(length - CRC_LEN); // Exclude CRC at the tail end
+                Some(packet)
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+
         // Align offset
         self.rx_offset = (self.rx_offset + length + 4 + 3) & !3;
         if self.rx_offset >= 8192 {
             self.rx_offset -= 8192;
         }
 
-        Some(packet)
+        match packet {
+            Some(_) => self.stats.rx_packets += 1,
+            None => self.stats.rx_errors += 1,
+        }
+        packet
+    }
+}
+
+/// Frames any card reports as received ahead of its ring, so tests can feed a driver
+/// with no hardware behind it.
+#[cfg(test)]
+static SCRIPTED_RX: spin::Mutex<alloc::collections::VecDeque<Vec<u8>>> =
+    spin::Mutex::new(alloc::collections::VecDeque::new());
+
+/// Have the next `rx_poll` return `frame` (without CRC), as if the card received it.
+#[cfg(test)]
+pub fn script_rx(frame: Vec<u8>) {
+    SCRIPTED_RX.lock().push_back(frame);
+}
+
+/// Whether an RX header's status reports a good frame: ROK set and no error bits.
+pub fn status_valid(status: u16) -> bool {
+    status & RX_STATUS_ROK != 0 && status & RX_STATUS_ERRORS == 0
+}
+
+/// Whether an RX header's length (frame plus CRC) is a plausible Ethernet frame.
+pub fn length_valid(length: usize) -> bool {
+    (MIN_RX_FRAME_SIZE + CRC_LEN..=MAX_FRAME_SIZE + CRC_LEN).contains(&length)
+}
+
+/// Whether a received frame's trailing 4-byte FCS matches the CRC-32 of the rest.
+pub fn fcs_valid(frame: &[u8]) -> bool {
+    let Some(body_len) = frame.len().checked_sub(CRC_LEN) else {
+        return false;
+    };
+    read_u32_le(frame, body_len) == Some(crc32(&frame[..body_len]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::NO_DEVICE;
+
+    #[test_case]
+    fn oversized_frames_are_rejected_before_reaching_the_card() {
+        let mut nic = Rtl8139::new(NO_DEVICE, 0);
+        assert_eq!(nic.tx_raw(&[0u8; 3000]), Err(TxError::FrameTooLarge(3000)));
+        assert_eq!(nic.tx_raw(&[0u8; MAX_FRAME_SIZE + 1]), Err(TxError::FrameTooLarge(MAX_FRAME_SIZE + 1)));
+        assert_eq!(nic.stats().tx_errors, 2);
+        assert_eq!(nic.stats().tx_packets, 0);
+    }
+
+    /// Place a frame with the given RX header at the card's read offset, as the card
+    /// would write it into the ring.
+    fn receive(nic: &mut Rtl8139, status: u16, length: u16) {
+        let at = nic.rx_offset;
+        nic.rx_buffer[at..at + 2].copy_from_slice(&status.to_le_bytes());
+        nic.rx_buffer[at + 2..at + 4].copy_from_slice(&length.to_le_bytes());
+        nic.rx_buffer[at + 4..at + 4 + length as usize].fill(0x5A);
+    }
+
+    #[test_case]
+    fn runt_and_crc_error_frames_are_dropped() {
+        let mut nic = Rtl8139::new(NO_DEVICE, 0);
+        receive(&mut nic, RX_STATUS_ROK | RX_STATUS_RUNT, 20);
+        assert_eq!(nic.take_frame(), None);
+        assert_eq!(nic.rx_offset, 24);
+
+        receive(&mut nic, RX_STATUS_ROK | RX_STATUS_CRC, 64);
+        assert_eq!(nic.take_frame(), None);
+        assert_eq!(nic.rx_offset, 24 + 68);
+        assert_eq!(nic.stats().rx_errors, 2);
+
+        receive(&mut nic, RX_STATUS_ROK, 64);
+        assert_eq!(nic.take_frame(), Some(alloc::vec![0x5A; 60]));
+        assert_eq!(nic.stats().rx_packets, 1);
+    }
+
+    #[test_case]
+    fn implausible_lengths_reset_the_ring() {
+        let mut nic = Rtl8139::new(NO_DEVICE, 0);
+        receive(&mut nic, RX_STATUS_ROK, 64);
+        assert!(nic.take_frame().is_some());
+
+        receive(&mut nic, RX_STATUS_ROK, 0);
+        assert_eq!(nic.take_frame(), None);
+        assert_eq!(nic.rx_offset, 0);
+        assert!(!length_valid(MAX_FRAME_SIZE + CRC_LEN + 1));
+        assert_eq!(nic.stats().rx_errors, 1);
+    }
+
+    /// `receive` a good 64-byte frame whose FCS is XORed with `corruption`.
+    fn receive_with_fcs(nic: &mut Rtl8139, corruption: u32) {
+        let at = nic.rx_offset;
+        receive(nic, RX_STATUS_ROK, 64);
+        let fcs = crc32(&[0x5A; 60]) ^ corruption;
+        nic.rx_buffer[at + 64..at + 68].copy_from_slice(&fcs.to_le_bytes());
+    }
+
+    #[test_case]
+    fn frames_failing_the_fcs_check_are_dropped() {
+        let mut nic = Rtl8139::new(NO_DEVICE, 0);
+        nic.set_verify_fcs(true);
+        receive_with_fcs(&mut nic, 0);
+        assert_eq!(nic.take_frame(), Some(alloc::vec![0x5A; 60]));
+
+        receive_with_fcs(&mut nic, 1);
+        assert_eq!(nic.take_frame(), None);
+        assert_eq!(nic.stats().rx_errors, 1);
+        assert_eq!(nic.stats().rx_packets, 1);
+        assert!(!fcs_valid(&[0x5A; 3]));
+    }
+
+    /// A driver whose registers are mocked, with `isr` pending, the card's write
+    /// pointer at `cbr` and the RX ring empty.
+    fn mocked(isr: u16, cbr: u16) -> Rtl8139 {
+        let mut nic = Rtl8139::new(NO_DEVICE, 0);
+        let mut ports = MockPorts::default();
+        ports.regs.insert(REG_ISR, isr);
+        ports.regs.insert(REG_CBR, cbr);
+        ports.regs.insert(REG_CMD, 1);
+        nic.mock_ports = Some(ports);
+        nic
+    }
+
+    #[test_case]
+    fn rx_overflow_resyncs_the_ring_and_is_counted() {
+        let mut nic = mocked(ISR_RX_OVERFLOW, 0x0400);
+        nic.rx_offset = 0x100;
+        assert_eq!(nic.rx_poll(), None);
+        assert_eq!(nic.rx_offset, 0x0400);
+        assert_eq!(nic.mock_ports.as_ref().unwrap().writes, alloc::vec![
+            (REG_CAPR, 0x03F0),
+            (REG_ISR, ISR_RX_OVERFLOW),
+            (REG_CMD, 0x0C),
+        ]);
+        assert_eq!(nic.stats().rx_overflows, 1);
+
+        // A FIFO overflow recovers the same way; both bits are acknowledged together.
+        let mut nic = mocked(ISR_RX_OVERFLOW | ISR_FIFO_OVERFLOW | 1, 0);
+        assert_eq!(nic.rx_poll(), None);
+        let writes = &nic.mock_ports.as_ref().unwrap().writes;
+        assert_eq!(writes[0], (REG_CAPR, 0xFFF0));
+        assert_eq!(writes[1], (REG_ISR, ISR_RX_OVERFLOW | ISR_FIFO_OVERFLOW));
+        assert_eq!(nic.stats().rx_overflows, 1);
+    }
+
+    #[test_case]
+    fn polls_without_an_overflow_leave_the_registers_alone() {
+        let mut nic = mocked(1, 0x0400);
+        nic.rx_offset = 0x100;
+        assert_eq!(nic.rx_poll(), None);
+        assert_eq!(nic.rx_offset, 0x100);
+        assert!(nic.mock_ports.as_ref().unwrap().writes.is_empty());
+        assert_eq!(nic.stats().rx_overflows, 0);
     }
 }
@@ -0,0 +1,163 @@
+//! Sandbox profiles: the fuel, memory, logging, network, filesystem and rate limits an
+//! agent runs under, bundled so `WasmRuntime::execute_sandboxed` can set them in one
+//! place. Start from a preset and adjust fields as needed.
+
+use crate::capability::{create_capability, Capability};
+use crate::task::{grant_capability_to_agent, AgentId};
+use crate::wasm::{LogSinks, FUEL_LIMIT};
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Where an agent's `debug_log` output goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogLevel {
+    /// Dropped.
+    Off,
+    /// Serial only.
+    Serial,
+    /// Serial and the VGA console.
+    All,
+}
+
+impl LogLevel {
+    pub fn sinks(self) -> LogSinks {
+        match self {
+            LogLevel::Off => LogSinks {
+                serial: false,
+                vga: false,
+            },
+            LogLevel::Serial => LogSinks {
+                serial: true,
+                vga: false,
+            },
+            LogLevel::All => LogSinks {
+                serial: true,
+                vga: true,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SandboxProfile {
+    /// Fuel the module may burn before it traps.
+    pub fuel: u64,
+    /// Cap on the module's linear memory, in 64 KiB Wasm pages.
+    pub max_memory_pages: u32,
+    pub log_level: LogLevel,
+    /// Pre-grant `Capability::Network`.
+    pub network: bool,
+    /// Pre-grant read/write `Capability::FileSystem` for each prefix.
+    pub allowed_fs_prefixes: Vec<String>,
+    /// Network requests per second, or `None` for the rate limiter's default.
+    pub rate_limit: Option<u32>,
+}
+
+impl SandboxProfile {
+    /// For code of unknown origin: a small fuel and memory budget, no network and no
+    /// filesystem access.
+    pub fn untrusted() -> Self {
+        SandboxProfile {
+            fuel: 100_000_000,
+            max_memory_pages: 16,
+            log_level: LogLevel::Serial,
+            network: false,
+            allowed_fs_prefixes: Vec::new(),
+            rate_limit: Some(5),
+        }
+    }
+
+    /// For first-party agents: the full fuel budget, network and the whole filesystem.
+    pub fn trusted() -> Self {
+        SandboxProfile {
+            fuel: FUEL_LIMIT,
+            max_memory_pages: 256,
+            log_level: LogLevel::All,
+            network: true,
+            allowed_fs_prefixes: vec![String::from("/")],
+            rate_limit: None,
+        }
+    }
+
+    /// For long-running services: network, configuration and scratch space, with a
+    /// higher request rate than the default.
+    pub fn service() -> Self {
+        SandboxProfile {
+            fuel: FUEL_LIMIT,
+            max_memory_pages: 64,
+            log_level: LogLevel::Serial,
+            network: true,
+            allowed_fs_prefixes: vec![String::from("/etc/"), String::from("/tmp/")],
+            rate_limit: Some(50),
+        }
+    }
+
+    /// Grant the profile's capabilities to `agent_pid` and set its rate limit. Fuel,
+    /// memory and logging are applied when the module is instantiated.
+    pub fn grant(&self, agent_pid: u64) {
+        let agent = AgentId(agent_pid);
+        if self.network {
+            grant_capability_to_agent(agent, create_capability(Capability::Network));
+        }
+        for prefix in &self.allowed_fs_prefixes {
+            let cap = create_capability(Capability::FileSystem {
+                path_prefix: prefix.clone(),
+                read: true,
+                write: true,
+            });
+            grant_capability_to_agent(agent, cap);
+        }
+        if let Some(rate) = self.rate_limit {
+            crate::ratelimit::set_limit(agent_pid, rate);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Code, ModuleBuilder, I32};
+    use crate::wasm::WasmRuntime;
+
+    /// A module whose `_start` probes a closed port on the guest's own address and
+    /// traps unless the probe reports it refused, i.e. unless it had network access.
+    fn probe_module() -> Vec<u8> {
+        let mut m = ModuleBuilder::new();
+        let probe = m.import("tcp_probe", &[I32, I32], &[I32]);
+        let body = Code::new()
+            .i32(0)
+            .i32(7106)
+            .call(probe)
+            .i32(crate::syscall_errors::ERR_CONNECTION_REFUSED as i32)
+            .op(testing::I32_SUB)
+            .op(testing::I32_EQZ)
+            .local_set(0)
+            .i32(1)
+            .local_get(0)
+            .op(testing::I32_DIV_U)
+            .drop();
+        let start = m.func(&[], &[], &[I32], body);
+        m.export("_start", start).data(0, &testing::GUEST_IP.0);
+        m.build()
+    }
+
+    #[test_case]
+    fn only_the_trusted_profile_reaches_the_network() {
+        testing::loopback();
+        let runtime = WasmRuntime::new();
+        let wasm = probe_module();
+
+        let untrusted = testing::spawn_agent("sandbox-untrusted", Vec::new());
+        let denied = runtime.execute_sandboxed(&wasm, untrusted.0, &SandboxProfile::untrusted());
+        assert!(denied.is_err());
+        assert!(testing::logged(&alloc::format!(
+            "Agent {} denied network access",
+            untrusted.0
+        )));
+
+        let trusted = testing::spawn_agent("sandbox-trusted", Vec::new());
+        let allowed = runtime.execute_sandboxed(&wasm, trusted.0, &SandboxProfile::trusted());
+        assert_eq!(allowed, Ok(()));
+    }
+}
@@ -1,4 +1,5 @@
 use uart_16550::SerialPort;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use spin::Mutex;
 use lazy_static::lazy_static;
 
@@ -10,10 +11,92 @@ lazy_static! {
     };
 }
 
+/// Line status register of COM1 and its "transmitter empty" bit.
+const LINE_STATUS_PORT: u16 = 0x3FD;
+const TRANSMITTER_EMPTY: u8 = 1 << 6;
+
+/// Wait until every byte written so far has left the UART.
+pub fn flush() {
+    let _serial = SERIAL1.lock();
+    let mut status: x86_64::instructions::port::Port<u8> =
+        x86_64::instructions::port::Port::new(LINE_STATUS_PORT);
+    while unsafe { status.read() } & TRANSMITTER_EMPTY == 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Bytes of interrupt-context output held back while the port was busy.
+const DEFERRED_SIZE: usize = 1024;
+
+/// Output logged by interrupt handlers while the interrupted code held `SERIAL1`,
+/// written out by the next `_print` outside interrupt context.
+struct Deferred {
+    buf: [u8; DEFERRED_SIZE],
+    len: usize,
+}
+
+impl Deferred {
+    /// Append a whole message, or nothing: a message that does not fit is rolled back
+    /// rather than left truncated for the next `_print` to write out.
+    fn push(&mut self, args: core::fmt::Arguments) -> bool {
+        use core::fmt::Write;
+        let start = self.len;
+        let fits = self.write_fmt(args).is_ok();
+        if !fits {
+            self.len = start;
+        }
+        fits
+    }
+}
+
+impl core::fmt::Write for Deferred {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let end = self.len + s.len();
+        if end > DEFERRED_SIZE {
+            return Err(core::fmt::Error);
+        }
+        self.buf[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+static DEFERRED: Mutex<Deferred> = Mutex::new(Deferred { buf: [0; DEFERRED_SIZE], len: 0 });
+
+/// Interrupt-context messages lost because both the port and the deferred buffer were
+/// busy, or the buffer was full.
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of messages logged from interrupt handlers that never reached the port.
+pub fn dropped_from_interrupts() -> usize {
+    DROPPED.load(Ordering::Relaxed)
+}
+
 #[doc(hidden)]
 pub fn _print(args: core::fmt::Arguments) {
     use core::fmt::Write;
-    SERIAL1.lock().write_fmt(args).unwrap();
+    // Spinning here from a handler would deadlock if the interrupted code holds the
+    // port, so handlers only ever try the locks.
+    if crate::interrupts::in_interrupt() {
+        let written = match SERIAL1.try_lock() {
+            Some(mut serial) => serial.write_fmt(args).is_ok(),
+            None => DEFERRED.try_lock().is_some_and(|mut deferred| deferred.push(args)),
+        };
+        if !written {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    } else {
+        let mut serial = SERIAL1.lock();
+        if let Some(mut deferred) = DEFERRED.try_lock() {
+            let len = deferred.len;
+            for &byte in &deferred.buf[..len] {
+                serial.send(byte);
+            }
+            deferred.len = 0;
+        }
+        serial.write_fmt(args).unwrap();
+    }
+    crate::crashdump::record(args);
 }
 
 #[macro_export]
@@ -28,3 +111,20 @@ macro_rules! serial_println {
     () => ($crate::serial_print!("\n"));
     ($($arg:tt)*) => ($crate::serial_print!("{}\n", format_args!($($arg)*)));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn deferred_messages_are_kept_whole_or_not_at_all() {
+        let mut deferred = Deferred { buf: [0; DEFERRED_SIZE], len: DEFERRED_SIZE - 8 };
+        // The first piece fits but the message does not; none of it stays.
+        assert!(!deferred.push(format_args!("{}{}", "1234", "56789")));
+        assert_eq!(deferred.len, DEFERRED_SIZE - 8);
+
+        assert!(deferred.push(format_args!("{}{}", "1234", "5678")));
+        assert_eq!(deferred.len, DEFERRED_SIZE);
+        assert_eq!(&deferred.buf[DEFERRED_SIZE - 8..], b"12345678");
+    }
+}
@@ -0,0 +1,553 @@
+//! Registry of agent-owned sockets in the shared smoltcp `SocketSet`.
+//! Agents refer to sockets by small integer handles; the registry records the owner
+//! of each one and enforces global and per-agent limits on open sockets.
+
+use crate::net::{alloc_ephemeral_port, free_ephemeral_port, with_network, NetworkStack};
+use crate::serial_println;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use smoltcp::iface::SocketHandle;
+use smoltcp::socket::{tcp, AnySocket, Socket};
+use smoltcp::time::Duration;
+use smoltcp::wire::IpEndpoint;
+use spin::Mutex;
+
+/// Default cap on sockets open across all agents.
+pub const DEFAULT_MAX_SOCKETS: usize = 64;
+/// Default cap on sockets open by a single agent.
+pub const DEFAULT_MAX_SOCKETS_PER_AGENT: usize = 8;
+/// Size of each of an agent TCP socket's receive and send buffers.
+pub const TCP_BUFFER_SIZE: usize = 1500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketError {
+    /// The system-wide socket limit, or the socket set's capacity, is reached.
+    GlobalLimit,
+    /// The agent already holds its maximum number of sockets.
+    AgentLimit,
+    /// No ephemeral port was free, or the remote endpoint is invalid (e.g. port 0).
+    Connect,
+}
+
+/// Tunables agents may read and set on their sockets, by wire code:
+///
+/// | code | option       | sockets  | value                                     |
+/// |------|--------------|----------|-------------------------------------------|
+/// | 0    | `Timeout`    | TCP      | abort after this many idle ms (0 = never) |
+/// | 1    | `KeepAlive`  | TCP      | keep-alive interval in ms (0 = off)       |
+/// | 2    | `Nagle`      | TCP      | 1 = Nagle's algorithm on, 0 = off         |
+/// | 3    | `AckDelay`   | TCP      | delayed-ACK timeout in ms (0 = off)       |
+/// | 4    | `HopLimit`   | TCP, UDP | IP TTL, 1-255 (0 = stack default)         |
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketOption {
+    Timeout,
+    KeepAlive,
+    Nagle,
+    AckDelay,
+    HopLimit,
+}
+
+impl SocketOption {
+    pub fn from_u32(code: u32) -> Option<Self> {
+        match code {
+            0 => Some(SocketOption::Timeout),
+            1 => Some(SocketOption::KeepAlive),
+            2 => Some(SocketOption::Nagle),
+            3 => Some(SocketOption::AckDelay),
+            4 => Some(SocketOption::HopLimit),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptionError {
+    /// No such socket, or the caller does not own it.
+    NotFound,
+    /// The option does not apply to this kind of socket.
+    Unsupported,
+    /// The value is out of range for the option.
+    InvalidValue,
+}
+
+/// Why `send` or `recv` moved no data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferError {
+    /// No such socket, or the caller does not own it.
+    NotFound,
+    /// Not a TCP socket.
+    Unsupported,
+    /// The connection is not open in that direction: still connecting, or closed.
+    NotConnected,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SocketEntry {
+    owner: u64,
+    handle: SocketHandle,
+    /// Ephemeral port bound by the socket, returned to the pool on close.
+    local_port: Option<u16>,
+    /// Payload bytes the owner sent and received through the socket.
+    bytes_sent: u64,
+    bytes_received: u64,
+}
+
+impl SocketEntry {
+    fn release(&self, net: &mut NetworkStack) {
+        net.sockets.remove(self.handle);
+        if let Some(port) = self.local_port {
+            free_ephemeral_port(port);
+        }
+    }
+}
+
+struct SocketTable {
+    entries: BTreeMap<u32, SocketEntry>,
+    next_id: u32,
+    max_sockets: usize,
+    max_per_agent: usize,
+}
+
+impl SocketTable {
+    const fn new() -> Self {
+        SocketTable {
+            entries: BTreeMap::new(),
+            next_id: 1,
+            max_sockets: DEFAULT_MAX_SOCKETS,
+            max_per_agent: DEFAULT_MAX_SOCKETS_PER_AGENT,
+        }
+    }
+
+    fn owned_by(&self, owner: u64) -> usize {
+        self.entries.values().filter(|e| e.owner == owner).count()
+    }
+}
+
+static SOCKETS: Mutex<SocketTable> = Mutex::new(SocketTable::new());
+
+/// Supervisor configuration: set the global and per-agent open-socket limits.
+/// Sockets already open are not closed if they exceed the new limits.
+pub fn set_limits(max_sockets: usize, max_per_agent: usize) {
+    let mut table = SOCKETS.lock();
+    table.max_sockets = max_sockets;
+    table.max_per_agent = max_per_agent;
+}
+
+/// Current `(global, per_agent)` limits.
+pub fn limits() -> (usize, usize) {
+    let table = SOCKETS.lock();
+    (table.max_sockets, table.max_per_agent)
+}
+
+/// Number of sockets currently open across all agents.
+pub fn total_open() -> usize {
+    SOCKETS.lock().entries.len()
+}
+
+/// Number of sockets currently open by `owner`.
+pub fn open_count(owner: u64) -> usize {
+    SOCKETS.lock().owned_by(owner)
+}
+
+/// Add `socket` to the stack on behalf of `owner` and return its handle. `local_port`
+/// is an ephemeral port the socket is bound to, freed again when the socket closes.
+/// Fails without adding anything if a limit would be exceeded.
+pub fn open<T: AnySocket<'static>>(
+    net: &mut NetworkStack,
+    owner: u64,
+    socket: T,
+    local_port: Option<u16>,
+) -> Result<u32, SocketError> {
+    let mut table = SOCKETS.lock();
+    if table.entries.len() >= table.max_sockets {
+        return Err(SocketError::GlobalLimit);
+    }
+    if table.owned_by(owner) >= table.max_per_agent {
+        return Err(SocketError::AgentLimit);
+    }
+
+    let handle = net
+        .add_socket(socket)
+        .map_err(|_| SocketError::GlobalLimit)?;
+    let id = table.next_id;
+    table.next_id = table.next_id.wrapping_add(1).max(1);
+    table.entries.insert(
+        id,
+        SocketEntry {
+            owner,
+            handle,
+            local_port,
+            bytes_sent: 0,
+            bytes_received: 0,
+        },
+    );
+    Ok(id)
+}
+
+/// Start a TCP connection to `remote` for `owner` from a fresh ephemeral port and
+/// return the socket's handle. Nothing is sent until the stack is next polled; follow
+/// the handshake with `tcp_state`.
+pub fn connect_tcp(
+    net: &mut NetworkStack,
+    owner: u64,
+    remote: IpEndpoint,
+) -> Result<u32, SocketError> {
+    let mut socket = tcp::Socket::new(
+        tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+        tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+    );
+    let local_port = alloc_ephemeral_port().ok_or(SocketError::Connect)?;
+    if socket
+        .connect(net.iface.context(), remote, local_port)
+        .is_err()
+    {
+        free_ephemeral_port(local_port);
+        return Err(SocketError::Connect);
+    }
+    open(net, owner, socket, Some(local_port)).inspect_err(|_| free_ephemeral_port(local_port))
+}
+
+/// Count payload bytes moved through socket `id` by `owner`: `sent` handed to the
+/// socket, `received` taken from it. Host functions that send or receive on an agent's
+/// socket call this after each successful transfer. Ignored if `owner` does not hold it.
+pub fn record_transfer(owner: u64, id: u32, sent: usize, received: usize) {
+    if let Some(entry) = SOCKETS
+        .lock()
+        .entries
+        .get_mut(&id)
+        .filter(|e| e.owner == owner)
+    {
+        entry.bytes_sent += sent as u64;
+        entry.bytes_received += received as u64;
+    }
+}
+
+/// `(bytes_sent, bytes_received)` on socket `id` since it was opened, if `owner` holds
+/// it.
+pub fn transfer_stats(owner: u64, id: u32) -> Option<(u64, u64)> {
+    SOCKETS
+        .lock()
+        .entries
+        .get(&id)
+        .filter(|e| e.owner == owner)
+        .map(|e| (e.bytes_sent, e.bytes_received))
+}
+
+/// Queue as much of `data` as fits in TCP socket `id`'s send buffer and return how
+/// many bytes that was. Nothing goes out until the stack is next polled.
+pub fn send(
+    net: &mut NetworkStack,
+    owner: u64,
+    id: u32,
+    data: &[u8],
+) -> Result<usize, TransferError> {
+    let sent = tcp_mut(net, owner, id)?
+        .send_slice(data)
+        .map_err(|_| TransferError::NotConnected)?;
+    record_transfer(owner, id, sent, 0);
+    Ok(sent)
+}
+
+/// Move bytes received on TCP socket `id` into `out` and return how many; 0 if none
+/// have arrived yet.
+pub fn recv(
+    net: &mut NetworkStack,
+    owner: u64,
+    id: u32,
+    out: &mut [u8],
+) -> Result<usize, TransferError> {
+    let received = tcp_mut(net, owner, id)?
+        .recv_slice(out)
+        .map_err(|_| TransferError::NotConnected)?;
+    record_transfer(owner, id, 0, received);
+    Ok(received)
+}
+
+fn tcp_mut(
+    net: &mut NetworkStack,
+    owner: u64,
+    id: u32,
+) -> Result<&mut tcp::Socket<'static>, TransferError> {
+    match socket_mut(net, owner, id) {
+        Ok(Socket::Tcp(socket)) => Ok(socket),
+        Ok(_) => Err(TransferError::Unsupported),
+        Err(_) => Err(TransferError::NotFound),
+    }
+}
+
+/// The smoltcp handle behind socket `id`, if `owner` holds it.
+pub fn handle(owner: u64, id: u32) -> Option<SocketHandle> {
+    SOCKETS
+        .lock()
+        .entries
+        .get(&id)
+        .filter(|e| e.owner == owner)
+        .map(|e| e.handle)
+}
+
+/// Set `option` to `value` on socket `id`, which `owner` must hold. See `SocketOption`
+/// for the value encoding.
+pub fn set_option(
+    net: &mut NetworkStack,
+    owner: u64,
+    id: u32,
+    option: SocketOption,
+    value: u64,
+) -> Result<(), OptionError> {
+    let millis = || (value != 0).then(|| Duration::from_millis(value));
+    let hop_limit = || match value {
+        0 => Ok(None),
+        1..=255 => Ok(Some(value as u8)),
+        _ => Err(OptionError::InvalidValue),
+    };
+
+    match (socket_mut(net, owner, id)?, option) {
+        (Socket::Tcp(socket), SocketOption::Timeout) => socket.set_timeout(millis()),
+        (Socket::Tcp(socket), SocketOption::KeepAlive) => socket.set_keep_alive(millis()),
+        (Socket::Tcp(socket), SocketOption::Nagle) => match value {
+            0 | 1 => socket.set_nagle_enabled(value == 1),
+            _ => return Err(OptionError::InvalidValue),
+        },
+        (Socket::Tcp(socket), SocketOption::AckDelay) => socket.set_ack_delay(millis()),
+        (Socket::Tcp(socket), SocketOption::HopLimit) => socket.set_hop_limit(hop_limit()?),
+        (Socket::Udp(socket), SocketOption::HopLimit) => socket.set_hop_limit(hop_limit()?),
+        _ => return Err(OptionError::Unsupported),
+    }
+    Ok(())
+}
+
+/// Current value of `option` on socket `id`, encoded as for `set_option`.
+pub fn get_option(
+    net: &mut NetworkStack,
+    owner: u64,
+    id: u32,
+    option: SocketOption,
+) -> Result<u64, OptionError> {
+    let millis = |duration: Option<Duration>| duration.map_or(0, |d| d.total_millis());
+
+    Ok(match (socket_mut(net, owner, id)?, option) {
+        (Socket::Tcp(socket), SocketOption::Timeout) => millis(socket.timeout()),
+        (Socket::Tcp(socket), SocketOption::KeepAlive) => millis(socket.keep_alive()),
+        (Socket::Tcp(socket), SocketOption::Nagle) => u64::from(socket.nagle_enabled()),
+        (Socket::Tcp(socket), SocketOption::AckDelay) => millis(socket.ack_delay()),
+        (Socket::Tcp(socket), SocketOption::HopLimit) => u64::from(socket.hop_limit().unwrap_or(0)),
+        (Socket::Udp(socket), SocketOption::HopLimit) => u64::from(socket.hop_limit().unwrap_or(0)),
+        _ => return Err(OptionError::Unsupported),
+    })
+}
+
+/// Stable wire code for a TCP connection state, as reported by `tcp_state`:
+///
+/// | code | state         | code | state       |
+/// |------|---------------|------|-------------|
+/// | 0    | `Closed`      | 6    | `FinWait2`  |
+/// | 1    | `Listen`      | 7    | `CloseWait` |
+/// | 2    | `SynSent`     | 8    | `Closing`   |
+/// | 3    | `SynReceived` | 9    | `LastAck`   |
+/// | 4    | `Established` | 10   | `TimeWait`  |
+/// | 5    | `FinWait1`    |      |             |
+pub fn tcp_state_code(state: tcp::State) -> u32 {
+    match state {
+        tcp::State::Closed => 0,
+        tcp::State::Listen => 1,
+        tcp::State::SynSent => 2,
+        tcp::State::SynReceived => 3,
+        tcp::State::Established => 4,
+        tcp::State::FinWait1 => 5,
+        tcp::State::FinWait2 => 6,
+        tcp::State::CloseWait => 7,
+        tcp::State::Closing => 8,
+        tcp::State::LastAck => 9,
+        tcp::State::TimeWait => 10,
+    }
+}
+
+/// Connection state of TCP socket `id`, which `owner` must hold, as a
+/// `tcp_state_code`. A connect that failed (refused or timed out) reads as `Closed`.
+pub fn tcp_state(net: &mut NetworkStack, owner: u64, id: u32) -> Result<u32, OptionError> {
+    match socket_mut(net, owner, id)? {
+        Socket::Tcp(socket) => Ok(tcp_state_code(socket.state())),
+        _ => Err(OptionError::Unsupported),
+    }
+}
+
+/// The socket behind `id`, whatever its type, if `owner` holds it.
+fn socket_mut(
+    net: &mut NetworkStack,
+    owner: u64,
+    id: u32,
+) -> Result<&mut Socket<'static>, OptionError> {
+    let handle = handle(owner, id).ok_or(OptionError::NotFound)?;
+    net.sockets
+        .iter_mut()
+        .find(|(h, _)| *h == handle)
+        .map(|(_, socket)| socket)
+        .ok_or(OptionError::NotFound)
+}
+
+/// Close socket `id` if `owner` holds it. Returns false otherwise.
+pub fn close(net: &mut NetworkStack, owner: u64, id: u32) -> bool {
+    let mut table = SOCKETS.lock();
+    match table.entries.get(&id) {
+        Some(entry) if entry.owner == owner => {
+            entry.release(net);
+            table.entries.remove(&id);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Close every agent socket, e.g. before the stack is rebuilt. Handles agents still
+/// hold become invalid. Returns the number of sockets dropped.
+pub fn drop_all(net: &mut NetworkStack) -> usize {
+    let mut table = SOCKETS.lock();
+    for entry in table.entries.values() {
+        entry.release(net);
+    }
+    let count = table.entries.len();
+    table.entries.clear();
+    count
+}
+
+/// Close every socket held by `owner`, e.g. when its agent exits.
+/// Returns the number of sockets reaped.
+pub fn reap(owner: u64) -> usize {
+    let reaped = with_network(|net| {
+        let mut table = SOCKETS.lock();
+        let mut count = 0;
+        table.entries.retain(|_, entry| {
+            if entry.owner != owner {
+                return true;
+            }
+            entry.release(net);
+            count += 1;
+            false
+        });
+        count
+    })
+    .unwrap_or_else(|_| {
+        // No stack (or it is wedged): forget the entries so the slots are reusable.
+        let mut table = SOCKETS.lock();
+        let before = table.entries.len();
+        table.entries.retain(|_, entry| {
+            if entry.owner == owner {
+                if let Some(port) = entry.local_port {
+                    free_ephemeral_port(port);
+                }
+                return false;
+            }
+            true
+        });
+        before - table.entries.len()
+    });
+
+    if reaped > 0 {
+        serial_println!("[NET] Reaped {} socket(s) of Agent {}", reaped, owner);
+    }
+    reaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use alloc::vec::Vec;
+
+    fn tcp_socket() -> tcp::Socket<'static> {
+        tcp::Socket::new(
+            tcp::SocketBuffer::new(vec![0; 64]),
+            tcp::SocketBuffer::new(vec![0; 64]),
+        )
+    }
+
+    fn open_for(owner: u64) -> Result<u32, SocketError> {
+        with_network(|net| open(net, owner, tcp_socket(), None)).unwrap()
+    }
+
+    #[test_case]
+    fn agents_are_held_to_their_socket_limit() {
+        testing::network();
+        let greedy = testing::spawn_agent("sockets-greedy", Vec::new()).0;
+        let other = testing::spawn_agent("sockets-other", Vec::new()).0;
+        let defaults = limits();
+
+        set_limits(DEFAULT_MAX_SOCKETS, 2);
+        let opened = [
+            open_for(greedy),
+            open_for(greedy),
+            open_for(greedy),
+            open_for(other),
+        ];
+        set_limits(defaults.0, defaults.1);
+
+        assert!(opened[0].is_ok() && opened[1].is_ok());
+        assert_eq!(opened[2], Err(SocketError::AgentLimit));
+        assert!(opened[3].is_ok());
+        assert_eq!(open_count(greedy), 2);
+        assert_eq!(reap(greedy), 2);
+        assert_eq!(reap(other), 1);
+    }
+
+    #[test_case]
+    fn reaping_an_agent_frees_its_slots() {
+        testing::network();
+        let exiting = testing::spawn_agent("sockets-exiting", Vec::new()).0;
+        let waiting = testing::spawn_agent("sockets-waiting", Vec::new()).0;
+        let defaults = limits();
+
+        set_limits(total_open() + 2, DEFAULT_MAX_SOCKETS_PER_AGENT);
+        assert!(open_for(exiting).is_ok() && open_for(exiting).is_ok());
+        let while_full = open_for(waiting);
+        assert_eq!(reap(exiting), 2);
+        let after_reap = open_for(waiting);
+        set_limits(defaults.0, defaults.1);
+
+        assert_eq!(while_full, Err(SocketError::GlobalLimit));
+        assert!(after_reap.is_ok());
+        assert_eq!(open_count(exiting), 0);
+        assert_eq!(reap(waiting), 1);
+    }
+
+    #[test_case]
+    fn transfers_are_counted_per_socket() {
+        testing::loopback();
+        let agent = testing::spawn_agent("sockets-transfer", Vec::new()).0;
+        let listener = testing::listen(7164);
+        let remote = (smoltcp::wire::IpAddress::Ipv4(testing::GUEST_IP), 7164);
+        let id = with_network(|net| connect_tcp(net, agent, remote.into()))
+            .unwrap()
+            .unwrap();
+        let early = with_network(|net| send(net, agent, id, b"early")).unwrap();
+        assert_eq!(early, Err(TransferError::NotConnected));
+        crate::net::flush().unwrap();
+
+        let sent = with_network(|net| send(net, agent, id, b"hello")).unwrap();
+        assert_eq!(sent, Ok(5));
+        crate::net::flush().unwrap();
+        with_network(|net| {
+            let peer = net.sockets.get_mut::<tcp::Socket>(listener);
+            let mut buf = [0; 16];
+            assert_eq!(peer.recv_slice(&mut buf), Ok(5));
+            assert_eq!(peer.send_slice(b"welcome"), Ok(7));
+        })
+        .unwrap();
+        crate::net::flush().unwrap();
+
+        let mut buf = [0; 16];
+        let received = with_network(|net| recv(net, agent, id, &mut buf)).unwrap();
+        assert_eq!(received, Ok(7));
+        assert_eq!(&buf[..7], b"welcome");
+        let none_yet = with_network(|net| recv(net, agent, id, &mut buf)).unwrap();
+        assert_eq!(none_yet, Ok(0));
+        assert_eq!(transfer_stats(agent, id), Some((5, 7)));
+
+        let stranger = testing::spawn_agent("sockets-stranger", Vec::new()).0;
+        let foreign = with_network(|net| send(net, stranger, id, b"x")).unwrap();
+        assert_eq!(foreign, Err(TransferError::NotFound));
+        assert_eq!(transfer_stats(stranger, id), None);
+
+        assert_eq!(reap(agent), 1);
+        with_network(|net| net.sockets.remove(listener)).unwrap();
+    }
+}
@@ -8,6 +8,10 @@ pub const ERR_NOT_FOUND: u32 = 3;
 pub const ERR_NETWORK_UNREACHABLE: u32 = 4;
 pub const ERR_TIMEOUT: u32 = 5;
 pub const ERR_INVALID_ARGUMENT: u32 = 6;
+pub const ERR_RATE_LIMITED: u32 = 7;
+pub const ERR_PENDING: u32 = 8;
+pub const ERR_QUOTA_EXCEEDED: u32 = 9;
+pub const ERR_CONNECTION_REFUSED: u32 = 10;
 
 // Capability-specific codes (100+)
 pub const ERR_CAPABILITY_MISSING: u32 = 100;
@@ -26,6 +30,10 @@ pub fn error_message(code: u32) -> &'static str {
         ERR_NETWORK_UNREACHABLE => "Network unreachable",
         ERR_TIMEOUT => "Operation timed out",
         ERR_INVALID_ARGUMENT => "Invalid argument",
+        ERR_RATE_LIMITED => "Rate limit exceeded",
+        ERR_PENDING => "Awaiting supervisor decision",
+        ERR_QUOTA_EXCEEDED => "Quota exceeded",
+        ERR_CONNECTION_REFUSED => "Connection refused",
         ERR_CAPABILITY_MISSING => "Missing required capability",
         ERR_CAPABILITY_NETWORK => "Missing Capability::Network",
         ERR_CAPABILITY_FILESYSTEM => "Missing Capability::FileSystem for this path",
@@ -1,16 +1,47 @@
 use crate::capability::CapabilityId;
-use alloc::collections::BTreeMap;
+use crate::serial_println;
+use crate::syscall_errors::{ERR_CAPABILITY_SPAWN, ERR_PERMISSION_DENIED, ERR_QUOTA_EXCEEDED};
+use crate::wasm::{TaskStatus, WasmTask};
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
 use alloc::string::String;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
 use spin::Mutex;
 
+/// Deepest level of the agent tree. Kernel-spawned agents sit at depth 0.
+pub const MAX_SPAWN_DEPTH: u32 = 3;
+
+// Standard signals, one bit each so pending signals coalesce into a mask.
+/// Re-read configuration.
+pub const SIGNAL_RELOAD: u32 = 1 << 0;
+/// Shut down cleanly.
+pub const SIGNAL_TERMINATE: u32 = 1 << 1;
+/// Stop doing work until signalled again.
+pub const SIGNAL_PAUSE: u32 = 1 << 2;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct AgentId(pub u64);
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum AgentState {
+    /// Spawned, waiting in the spawn queue for the executor to instantiate its module.
+    Pending,
     Running,
     Terminated,
+    /// The agent's module finished (or crashed) and the supervisor will not restart it.
+    Exited,
+}
+
+/// What the supervisor does when an agent's module stops running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartPolicy {
+    /// Leave the agent exited, whatever the outcome.
+    Never,
+    /// Re-run the module after a trap or error, at most `max_restarts` times.
+    OnFailure { max_restarts: u32 },
+    /// Re-run the module after every exit, clean or not.
+    Always,
 }
 
 #[derive(Debug, Clone)]
@@ -19,11 +50,30 @@ pub struct Agent {
     pub name: String,
     pub capabilities: Vec<CapabilityId>,
     pub state: AgentState,
+    /// VFS path the agent's module is loaded from, used for restarts.
+    pub module_path: Option<String>,
+    pub restart_policy: RestartPolicy,
+    pub restarts: u32,
+    /// The agent that spawned this one, or `None` if the kernel did.
+    pub parent: Option<AgentId>,
+    /// Distance from the root of the agent tree.
+    pub depth: u32,
+    /// Signals sent but not yet polled, one bit per signal.
+    pub pending_signals: u32,
+}
+
+impl Agent {
+    /// Running, or about to be: a pending agent already counts against limits.
+    fn is_live(&self) -> bool {
+        matches!(self.state, AgentState::Pending | AgentState::Running)
+    }
 }
 
 struct Registry {
     agents: BTreeMap<AgentId, Agent>,
     next_id: u64,
+    /// Service names agents can be addressed by, each held by at most one agent.
+    names: BTreeMap<String, AgentId>,
 }
 
 impl Registry {
@@ -31,16 +81,70 @@ impl Registry {
         Registry {
             agents: BTreeMap::new(),
             next_id: 1,
+            names: BTreeMap::new(),
         }
     }
 }
 
+/// Longest service name accepted by `register_name`.
+pub const MAX_SERVICE_NAME_LEN: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NameError {
+    /// Empty or longer than `MAX_SERVICE_NAME_LEN`.
+    Invalid,
+    /// No such agent.
+    UnknownAgent,
+    /// Another running agent holds the name.
+    Taken,
+}
+
 static REGISTRY: Mutex<Registry> = Mutex::new(Registry::new());
 
 /// Spawn a new agent with the given name and pre-allocated capability set.
 /// Returns its AgentId.
-pub fn spawn_agent(name: &str, capabilities: Vec<CapabilityId>) -> AgentId {
+///
+/// With a `parent`, the parent must hold `Capability::Spawn`, have fewer than
+/// `max_children` live children, and the child's depth (one below the parent's) must
+/// not exceed the capability's `max_depth` or `MAX_SPAWN_DEPTH`; otherwise the spawn is
+/// rejected with `ERR_CAPABILITY_SPAWN`.
+pub fn spawn_agent(
+    name: &str,
+    capabilities: Vec<CapabilityId>,
+    parent: Option<AgentId>,
+) -> Result<AgentId, u32> {
     let mut reg = REGISTRY.lock();
+    let depth = match parent {
+        Some(parent_id) => {
+            let parent_agent = reg.agents.get(&parent_id).ok_or(ERR_CAPABILITY_SPAWN)?;
+            let (max_children, max_depth) =
+                crate::capability::spawn_limits(&parent_agent.capabilities)
+                    .ok_or(ERR_CAPABILITY_SPAWN)?;
+            let depth = parent_agent.depth + 1;
+            let live_children = reg
+                .agents
+                .values()
+                .filter(|a| a.parent == Some(parent_id) && a.is_live())
+                .count();
+
+            if depth > MAX_SPAWN_DEPTH
+                || depth > max_depth
+                || live_children >= max_children as usize
+            {
+                serial_println!(
+                    "[SPAWN] Agent {} denied child '{}' (depth {}, {} live children)",
+                    parent_id.0,
+                    name,
+                    depth,
+                    live_children
+                );
+                return Err(ERR_CAPABILITY_SPAWN);
+            }
+            depth
+        }
+        None => 0,
+    };
+
     let id = AgentId(reg.next_id);
     reg.next_id += 1;
     reg.agents.insert(
@@ -50,9 +154,114 @@ pub fn spawn_agent(name: &str, capabilities: Vec<CapabilityId>) -> AgentId {
             name: String::from(name),
             capabilities,
             state: AgentState::Running,
+            module_path: None,
+            restart_policy: RestartPolicy::Never,
+            restarts: 0,
+            parent,
+            depth,
+            pending_signals: 0,
         },
     );
-    id
+    Ok(id)
+}
+
+/// Spawn a child of `parent` that starts with `inherited`, a subset of the parent's
+/// own capabilities. Each one gains a reference held by the child, as with IPC
+/// delegation. Fails with `ERR_PERMISSION_DENIED` if the parent does not hold every
+/// capability, or as `spawn_agent` does.
+pub fn spawn_agent_with_caps(
+    name: &str,
+    parent: AgentId,
+    inherited: &[CapabilityId],
+) -> Result<AgentId, u32> {
+    let parent_caps = agent_capabilities(parent);
+    if let Some(cap) = inherited.iter().find(|cap| !parent_caps.contains(cap)) {
+        serial_println!(
+            "[SECURITY] Agent {} tried to pass capability {} it does not hold to '{}'",
+            parent.0,
+            cap.0,
+            name
+        );
+        return Err(ERR_PERMISSION_DENIED);
+    }
+
+    let child = spawn_agent(name, inherited.to_vec(), Some(parent))?;
+    for &cap in inherited {
+        crate::capability::delegate_capability(cap);
+    }
+    Ok(child)
+}
+
+/// Bind service `name` to `agent_id` so other agents can find it with `lookup_name`.
+/// A name held by an agent that is no longer running passes to the new holder, so a
+/// restarted service can reclaim its name under its new PID.
+pub fn register_name(agent_id: AgentId, name: &str) -> Result<(), NameError> {
+    if name.is_empty() || name.len() > MAX_SERVICE_NAME_LEN {
+        return Err(NameError::Invalid);
+    }
+    let mut reg = REGISTRY.lock();
+    if !reg.agents.contains_key(&agent_id) {
+        return Err(NameError::UnknownAgent);
+    }
+    if let Some(holder) = reg.names.get(name) {
+        let live = reg.agents.get(holder).is_some_and(|a| a.is_live());
+        if *holder != agent_id && live {
+            return Err(NameError::Taken);
+        }
+    }
+    reg.names.insert(String::from(name), agent_id);
+    Ok(())
+}
+
+/// The running agent registered under service `name`.
+pub fn lookup_name(name: &str) -> Option<AgentId> {
+    let reg = REGISTRY.lock();
+    let id = *reg.names.get(name)?;
+    reg.agents
+        .get(&id)
+        .filter(|a| a.state == AgentState::Running)
+        .map(|a| a.id)
+}
+
+/// Returns the agent's depth in the spawn tree.
+pub fn agent_depth(agent_id: AgentId) -> Option<u32> {
+    REGISTRY.lock().agents.get(&agent_id).map(|a| a.depth)
+}
+
+/// Returns the agent that spawned `agent_id`, if any.
+pub fn agent_parent(agent_id: AgentId) -> Option<AgentId> {
+    REGISTRY.lock().agents.get(&agent_id).and_then(|a| a.parent)
+}
+
+/// Returns the IDs of the agents `agent_id` spawned.
+pub fn agent_children(agent_id: AgentId) -> Vec<AgentId> {
+    REGISTRY
+        .lock()
+        .agents
+        .values()
+        .filter(|a| a.parent == Some(agent_id))
+        .map(|a| a.id)
+        .collect()
+}
+
+/// Returns the `allowed_prefix` a `Spawn` capability granted to `agent_id` must carry
+/// so it cannot spawn more than it already may: that of the nearest agent, starting
+/// with `agent_id` itself and walking up its spawners, that holds a `Spawn`
+/// capability. `None` if that agent may spawn raw bytes or no agent in the chain can
+/// spawn at all.
+pub fn inherited_spawn_prefix(agent_id: AgentId) -> Option<String> {
+    let mut next = Some(agent_id);
+    while let Some(id) = next {
+        let caps = agent_capabilities(id);
+        if crate::capability::can_spawn_bytes(&caps) {
+            return None;
+        }
+        if let Some(prefix) = crate::capability::spawn_prefix(&caps) {
+            return Some(prefix);
+        }
+        next = agent_parent(id);
+    }
+    None
 }
 
 /// Returns a cloned capability list for `agent_id`, or empty vec if not found.
@@ -70,6 +279,29 @@ pub fn agent_pid(agent_id: AgentId) -> u64 {
     agent_id.0
 }
 
+/// Raise `signal` (one or more `SIGNAL_*` bits) for a running agent, out of band from
+/// its IPC queue. A signal already pending is not queued twice. Returns false if the
+/// agent is not running.
+pub fn send_signal(agent_id: AgentId, signal: u32) -> bool {
+    let mut reg = REGISTRY.lock();
+    match reg.agents.get_mut(&agent_id) {
+        Some(agent) if agent.state == AgentState::Running => {
+            agent.pending_signals |= signal;
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Take the signals pending for `agent_id`, clearing them.
+pub fn take_signals(agent_id: AgentId) -> u32 {
+    REGISTRY
+        .lock()
+        .agents
+        .get_mut(&agent_id)
+        .map_or(0, |agent| core::mem::take(&mut agent.pending_signals))
+}
+
 /// Dynamically grant a capability to an already-running agent.
 /// Used by the Kernel Supervisor's capability escalation protocol.
 pub fn grant_capability_to_agent(agent_id: AgentId, cap: CapabilityId) {
@@ -79,12 +311,87 @@ pub fn grant_capability_to_agent(agent_id: AgentId, cap: CapabilityId) {
     }
 }
 
+/// Remove one occurrence of `cap` from `agent_id`'s capability list, without touching
+/// its reference count. Returns false if the agent does not hold it.
+pub fn remove_capability_from_agent(agent_id: AgentId, cap: CapabilityId) -> bool {
+    let mut reg = REGISTRY.lock();
+    let Some(agent) = reg.agents.get_mut(&agent_id) else {
+        return false;
+    };
+    match agent.capabilities.iter().position(|&held| held == cap) {
+        Some(index) => {
+            agent.capabilities.remove(index);
+            true
+        }
+        None => false,
+    }
+}
+
 /// Mark an agent as terminated and revoke all its capabilities.
+/// Any sockets and locks it still holds are released.
 pub fn terminate_agent(agent_id: AgentId) {
     let mut reg = REGISTRY.lock();
     if let Some(agent) = reg.agents.get_mut(&agent_id) {
         agent.state = AgentState::Terminated;
     }
+    drop(reg);
+    reclaim_agent(agent_id);
+}
+
+/// When set, `reclaim_agent` also deletes the VFS files an exiting agent owned.
+static CLEANUP_OWNED_FILES: AtomicBool = AtomicBool::new(false);
+
+/// Choose whether files written by an agent are deleted when it is reclaimed.
+pub fn set_cleanup_owned_files(enabled: bool) {
+    CLEANUP_OWNED_FILES.store(enabled, Ordering::Relaxed);
+}
+
+/// Tear down an agent that has stopped for good so nothing it held leaks to a later
+/// holder of its PID: release sockets and locks, zero and drop its IPC endpoint,
+/// unregister its service names, revoke its capabilities and, if
+/// `set_cleanup_owned_files` is on, delete its files.
+pub fn reclaim_agent(agent_id: AgentId) {
+    let pid = agent_id.0;
+    reclaim_resources(pid);
+    crate::ipc::destroy_endpoint(crate::ipc::ProcessId(pid));
+    crate::audit::forget_denials(pid);
+
+    let caps = {
+        let mut reg = REGISTRY.lock();
+        reg.names.retain(|_, holder| *holder != agent_id);
+        reg.agents
+            .get_mut(&agent_id)
+            .map(|agent| core::mem::take(&mut agent.capabilities))
+            .unwrap_or_default()
+    };
+    for cap in &caps {
+        crate::capability::revoke_capability(*cap);
+    }
+
+    let served = crate::wasm::remove_file_server(pid);
+    let deleted = if CLEANUP_OWNED_FILES.load(Ordering::Relaxed) {
+        crate::vfs::delete_owned_by(pid)
+    } else {
+        0
+    };
+    serial_println!(
+        "[RECLAIM] Agent {}: {} capabilities revoked, {} file(s) deleted, {} dynamic file(s) removed",
+        pid,
+        caps.len(),
+        deleted,
+        served
+    );
+}
+
+/// Close the sockets, stop the raw frame capture and release the locks held by an
+/// agent whose module has stopped.
+pub fn reclaim_resources(pid: u64) {
+    crate::sockets::reap(pid);
+    crate::net::stop_raw_capture(pid);
+    let released = crate::locks::release_all(pid);
+    if released > 0 {
+        serial_println!("[LOCK] Released {} lock(s) of Agent {}", released, pid);
+    }
 }
 
 /// Returns agent name for display.
@@ -95,3 +402,840 @@ pub fn agent_name(agent_id: AgentId) -> Option<String> {
         .get(&agent_id)
         .map(|a| a.name.clone())
 }
+
+/// Attach a module path and restart policy to an agent so the supervisor can re-run it.
+/// Resets the agent's restart count.
+pub fn set_supervision(agent_id: AgentId, module_path: &str, policy: RestartPolicy) {
+    let mut reg = REGISTRY.lock();
+    if let Some(agent) = reg.agents.get_mut(&agent_id) {
+        agent.module_path = Some(String::from(module_path));
+        agent.restart_policy = policy;
+        agent.restarts = 0;
+        agent.state = AgentState::Running;
+    }
+}
+
+/// Returns the VFS path of the agent's module, if it is supervised.
+pub fn agent_module_path(agent_id: AgentId) -> Option<String> {
+    REGISTRY
+        .lock()
+        .agents
+        .get(&agent_id)
+        .and_then(|a| a.module_path.clone())
+}
+
+/// Returns how many times the supervisor has restarted `agent_id`.
+pub fn restart_count(agent_id: AgentId) -> u32 {
+    REGISTRY
+        .lock()
+        .agents
+        .get(&agent_id)
+        .map_or(0, |a| a.restarts)
+}
+
+/// Decide whether the supervisor should re-run the agent after an exit.
+/// On `true` the restart is counted; on `false` a live agent is marked `Exited`
+/// (one already terminated stays `Terminated`).
+pub fn should_restart(agent_id: AgentId, clean_exit: bool) -> bool {
+    let mut reg = REGISTRY.lock();
+    let Some(agent) = reg.agents.get_mut(&agent_id) else {
+        return false;
+    };
+
+    let restart = agent.is_live()
+        && match agent.restart_policy {
+            RestartPolicy::Never => false,
+            RestartPolicy::OnFailure { max_restarts } => {
+                !clean_exit && agent.restarts < max_restarts
+            }
+            RestartPolicy::Always => true,
+        };
+
+    if restart {
+        agent.restarts += 1;
+    } else if agent.is_live() {
+        agent.state = AgentState::Exited;
+    }
+    restart
+}
+
+/// Agents whose modules are mid-execution, run round-robin one fuel slice at a time.
+static RUN_QUEUE: Mutex<VecDeque<WasmTask>> = Mutex::new(VecDeque::new());
+
+/// Queue an instantiated module for `agent_id` on the executor.
+pub fn spawn_task(agent_id: AgentId, task: WasmTask) {
+    if let Some(agent) = REGISTRY.lock().agents.get_mut(&agent_id) {
+        agent.state = AgentState::Running;
+    }
+    RUN_QUEUE.lock().push_back(task);
+}
+
+/// Called with the outcome of an agent's executor task in place of the usual exit
+/// handling, so the supervisor can decide what happens to the agent next.
+pub type ExitHook = Box<dyn FnOnce(Result<(), String>) + Send>;
+
+static EXIT_HOOKS: Mutex<BTreeMap<AgentId, ExitHook>> = Mutex::new(BTreeMap::new());
+
+/// Hand the outcome of `agent_id`'s next executor task to `hook`. The agent is then
+/// neither marked `Exited` nor reclaimed by the executor; that is up to the hook.
+pub fn on_exit(agent_id: AgentId, hook: ExitHook) {
+    EXIT_HOOKS.lock().insert(agent_id, hook);
+}
+
+/// Run the exit hook registered for `agent_id`, if any. Returns false if there is none.
+fn run_exit_hook(agent_id: AgentId, outcome: Result<(), String>) -> bool {
+    let hook = EXIT_HOOKS.lock().remove(&agent_id);
+    match hook {
+        Some(hook) => {
+            hook(outcome);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Pending spawns the executor instantiates per scheduler iteration.
+pub const SPAWNS_PER_ITERATION: usize = 4;
+/// Spawns that may wait in the spawn queue at once; `queue_spawn` refuses more.
+pub const MAX_PENDING_SPAWNS: usize = 64;
+
+/// Instantiates a pending agent's module and hands it to `spawn_task`.
+pub type SpawnLoader = Box<dyn FnOnce() -> Result<(), String> + Send>;
+
+struct PendingSpawn {
+    agent_id: AgentId,
+    load: SpawnLoader,
+    /// Uptime in milliseconds before which the spawn is left queued.
+    not_before: u64,
+}
+
+/// Spawns waiting to be instantiated, so a burst of them is spread over several
+/// scheduler iterations instead of stalling the kernel.
+static SPAWN_QUEUE: Mutex<VecDeque<PendingSpawn>> = Mutex::new(VecDeque::new());
+
+/// Mark `agent_id` `Pending` and queue `load` to instantiate its module from the
+/// executor. Returns `ERR_QUOTA_EXCEEDED` if `MAX_PENDING_SPAWNS` are already waiting;
+/// the agent is left as it was.
+pub fn queue_spawn(agent_id: AgentId, load: SpawnLoader) -> Result<(), u32> {
+    queue_spawn_after(agent_id, 0, load)
+}
+
+/// Like `queue_spawn`, but the executor leaves the spawn queued until the uptime
+/// reaches `not_before` ms. The supervisor queues restarts this way to back off
+/// without stalling other agents.
+pub fn queue_spawn_after(agent_id: AgentId, not_before: u64, load: SpawnLoader) -> Result<(), u32> {
+    let mut queue = SPAWN_QUEUE.lock();
+    if queue.len() >= MAX_PENDING_SPAWNS {
+        return Err(ERR_QUOTA_EXCEEDED);
+    }
+    if let Some(agent) = REGISTRY.lock().agents.get_mut(&agent_id) {
+        agent.state = AgentState::Pending;
+    }
+    queue.push_back(PendingSpawn {
+        agent_id,
+        load,
+        not_before,
+    });
+    Ok(())
+}
+
+/// Number of spawns waiting to be instantiated.
+pub fn pending_spawns() -> usize {
+    SPAWN_QUEUE.lock().len()
+}
+
+/// Instantiate up to `SPAWNS_PER_ITERATION` queued spawns that are due; the rest keep
+/// their order in the queue. An agent terminated while it waited is skipped; one whose
+/// module fails to load is terminated.
+fn run_pending_spawns() {
+    let now = crate::time::uptime_ms();
+    let queued = pending_spawns();
+    let mut started = 0;
+    for _ in 0..queued {
+        if started == SPAWNS_PER_ITERATION {
+            return;
+        }
+        let Some(spawn) = SPAWN_QUEUE.lock().pop_front() else {
+            return;
+        };
+        if spawn.not_before > now {
+            SPAWN_QUEUE.lock().push_back(spawn);
+            continue;
+        }
+        started += 1;
+        let pending = REGISTRY
+            .lock()
+            .agents
+            .get(&spawn.agent_id)
+            .is_some_and(|a| a.state == AgentState::Pending);
+        if !pending {
+            continue;
+        }
+        if let Err(e) = (spawn.load)() {
+            serial_println!("[SPAWN] Agent {} failed to load: {}", spawn.agent_id.0, e);
+            terminate_agent(spawn.agent_id);
+        }
+    }
+}
+
+/// Resumptions kept by the schedule trace; the oldest are dropped first.
+pub const MAX_SCHEDULE_TRACE: usize = 256;
+
+static SCHEDULE_TRACE_ENABLED: AtomicBool = AtomicBool::new(false);
+static SCHEDULE_TRACE: Mutex<VecDeque<AgentId>> = Mutex::new(VecDeque::new());
+
+/// Start or stop recording which agent the executor resumes for each slice. Turning
+/// the trace on clears what was recorded before.
+pub fn set_schedule_trace(enabled: bool) {
+    if enabled {
+        SCHEDULE_TRACE.lock().clear();
+    }
+    SCHEDULE_TRACE_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// The agents resumed since the trace was enabled, one entry per slice, oldest first.
+pub fn schedule_trace() -> Vec<AgentId> {
+    SCHEDULE_TRACE.lock().iter().copied().collect()
+}
+
+fn trace_resume(agent_id: AgentId) {
+    if !SCHEDULE_TRACE_ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut trace = SCHEDULE_TRACE.lock();
+    if trace.len() >= MAX_SCHEDULE_TRACE {
+        trace.pop_front();
+    }
+    trace.push_back(agent_id);
+}
+
+/// How one slice of an agent ended, as seen by the scheduler.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SliceOutcome {
+    /// The agent ran out of fuel for the slice or yielded; resume it later.
+    Yielded,
+    Finished,
+    Failed(String),
+    /// The heap was under pressure and the agent could not release enough memory.
+    Killed,
+}
+
+/// Resume `task` for one fuel slice. Under memory pressure the agent is first asked to
+/// shrink and is not resumed if that fails; otherwise the resumption is recorded in the
+/// schedule trace. Every path that runs agents goes through here.
+pub fn resume(task: &mut WasmTask) -> SliceOutcome {
+    let pid = task.agent_pid();
+    if crate::allocator::under_memory_pressure() && !relieve_memory_pressure(task) {
+        return SliceOutcome::Killed;
+    }
+
+    trace_resume(AgentId(pid));
+    match task.run_slice() {
+        Ok(TaskStatus::Yielded) => SliceOutcome::Yielded,
+        Ok(TaskStatus::Finished) => SliceOutcome::Finished,
+        Err(e) => SliceOutcome::Failed(e),
+    }
+}
+
+/// Terminate an agent that `resume` killed for memory.
+fn kill_for_memory(pid: u64) {
+    serial_println!("[OOM] Heap still low; killing Agent {}", pid);
+    if let Some(agent) = REGISTRY.lock().agents.get_mut(&AgentId(pid)) {
+        agent.state = AgentState::Terminated;
+    }
+    reclaim_agent(AgentId(pid));
+}
+
+/// Do one round of executor work: instantiate queued loads and a few pending spawns
+/// (see `SPAWNS_PER_ITERATION`), then run one slice of the task at the front of the run
+/// queue. The queue lock is not held while the agent runs, so host functions may spawn
+/// more tasks. Returns false once the run queue and the spawn queue are both empty.
+pub fn run_executor_step() -> bool {
+    crate::wasm::run_pending_loads();
+    run_pending_spawns();
+    let Some(mut task) = RUN_QUEUE.lock().pop_front() else {
+        return pending_spawns() > 0;
+    };
+    let pid = task.agent_pid();
+
+    let outcome = match resume(&mut task) {
+        SliceOutcome::Yielded => {
+            RUN_QUEUE.lock().push_back(task);
+            return true;
+        }
+        SliceOutcome::Killed => {
+            drop(task);
+            kill_for_memory(pid);
+            run_exit_hook(
+                AgentId(pid),
+                Err(String::from("Killed under memory pressure")),
+            );
+            return true;
+        }
+        SliceOutcome::Finished => {
+            serial_println!("[EXEC] Agent {} finished", pid);
+            Ok(())
+        }
+        SliceOutcome::Failed(e) => {
+            serial_println!("[EXEC] Agent {} failed: {}", pid, e);
+            Err(e)
+        }
+    };
+
+    drop(task);
+    if run_exit_hook(AgentId(pid), outcome) {
+        return true;
+    }
+    if let Some(agent) = REGISTRY.lock().agents.get_mut(&AgentId(pid)) {
+        agent.state = AgentState::Exited;
+    }
+    reclaim_agent(AgentId(pid));
+    true
+}
+
+/// Run queued agents round-robin until every one of them has finished or failed and
+/// the spawn queue is empty.
+///
+/// The order is deterministic: tasks run in the order they were queued with
+/// `spawn_task`, one slice each; a task that yields goes to the back of the queue, and
+/// tasks spawned meanwhile join the back as well. With tasks A, B, C, D queued in that
+/// order the slices run A B C D A B C D ..., each dropping out once it finishes.
+pub fn run_executor() {
+    while run_executor_step() {
+        wait_for_deferred_spawns();
+    }
+}
+
+/// If the only work left is spawns waiting for their not-before time, halt until the
+/// next interrupt rather than spin on the spawn queue.
+pub fn wait_for_deferred_spawns() {
+    if !RUN_QUEUE.lock().is_empty() {
+        return;
+    }
+    let now = crate::time::uptime_ms();
+    let deferred = {
+        let queue = SPAWN_QUEUE.lock();
+        !queue.is_empty() && queue.iter().all(|spawn| spawn.not_before > now)
+    };
+    if deferred {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Run `task` to completion on the calling thread, as the supervisor does, without
+/// starving the executor: every time the task yields, one `run_executor_step` runs,
+/// so queued agents and spawns interleave with it slice by slice. An agent killed for
+/// memory is terminated and reported as an error.
+pub fn run_to_completion(task: &mut WasmTask) -> Result<(), String> {
+    loop {
+        match resume(task) {
+            SliceOutcome::Yielded => {
+                run_executor_step();
+            }
+            SliceOutcome::Finished => return Ok(()),
+            SliceOutcome::Failed(e) => return Err(e),
+            SliceOutcome::Killed => {
+                kill_for_memory(task.agent_pid());
+                return Err(String::from("Killed under memory pressure"));
+            }
+        }
+    }
+}
+
+/// Give an agent the chance to shrink via its `on_memory_pressure` export.
+/// Returns true if the heap is no longer under pressure afterwards.
+fn relieve_memory_pressure(task: &mut WasmTask) -> bool {
+    let pid = task.agent_pid();
+    match task.notify_memory_pressure() {
+        Ok(true) => serial_println!("[OOM] Agent {} notified of memory pressure", pid),
+        Ok(false) => {}
+        Err(e) => serial_println!("[OOM] Agent {}: {}", pid, e),
+    }
+    !crate::allocator::under_memory_pressure()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use crate::wasm::WasmRuntime;
+    use alloc::sync::Arc;
+
+    fn state(agent_id: AgentId) -> Option<AgentState> {
+        REGISTRY
+            .lock()
+            .agents
+            .get(&agent_id)
+            .map(|a| a.state.clone())
+    }
+
+    #[test_case]
+    fn supervisor_restarts_a_trapping_agent_up_to_its_limit() {
+        let path = "/test/supervise-trap.wasm";
+        testing::install(path, testing::trapping_module());
+        let agent = testing::spawn_agent("supervise-trap", Vec::new());
+        set_supervision(agent, path, RestartPolicy::OnFailure { max_restarts: 2 });
+
+        assert!(WasmRuntime::new().supervise(agent).is_err());
+        assert_eq!(restart_count(agent), 2);
+        assert_eq!(state(agent), Some(AgentState::Exited));
+    }
+
+    #[test_case]
+    fn deferred_spawn_waits_for_its_deadline() {
+        let agent = testing::spawn_agent("deferred-spawn", Vec::new());
+        let started = Arc::new(AtomicBool::new(false));
+        let flag = started.clone();
+        queue_spawn_after(
+            agent,
+            crate::time::uptime_ms() + 50,
+            Box::new(move || {
+                flag.store(true, Ordering::SeqCst);
+                Ok(())
+            }),
+        )
+        .unwrap();
+
+        assert!(run_executor_step());
+        assert!(!started.load(Ordering::SeqCst));
+        assert_eq!(pending_spawns(), 1);
+        assert_eq!(state(agent), Some(AgentState::Pending));
+
+        run_executor();
+        assert!(started.load(Ordering::SeqCst));
+        assert_eq!(pending_spawns(), 0);
+    }
+
+    #[test_case]
+    fn exit_hook_receives_the_outcome_instead_of_reaping() {
+        let agent = testing::spawn_agent("exit-hook", Vec::new());
+        let outcome = Arc::new(Mutex::new(None));
+        let slot = outcome.clone();
+        on_exit(agent, Box::new(move |result| *slot.lock() = Some(result)));
+        WasmRuntime::new()
+            .spawn_module(&testing::trapping_module(), agent.0)
+            .unwrap();
+        run_executor();
+
+        assert!(matches!(*outcome.lock(), Some(Err(_))));
+        assert_eq!(state(agent), Some(AgentState::Running));
+    }
+
+    #[test_case]
+    fn clean_exit_is_not_restarted_on_failure_policy() {
+        let agent = testing::spawn_agent("supervise-clean", Vec::new());
+        set_supervision(
+            agent,
+            "/unused",
+            RestartPolicy::OnFailure { max_restarts: 3 },
+        );
+        assert!(!should_restart(agent, true));
+        assert_eq!(restart_count(agent), 0);
+        assert_eq!(state(agent), Some(AgentState::Exited));
+    }
+
+    /// Size of the cache file a `cache_module` agent drops under memory pressure.
+    const CACHE_SIZE: usize = 256 * 1024;
+
+    /// An agent whose `_start` does nothing and whose cache lives in the VFS file
+    /// `cache`; with `on_pressure` it exports an `on_memory_pressure` that empties it.
+    fn cache_module(cache: &str, on_pressure: bool) -> Vec<u8> {
+        use testing::{Code, ModuleBuilder, I32};
+
+        let mut m = ModuleBuilder::new();
+        let file_write = m.import("file_write", &[I32, I32, I32, I32], &[I32]);
+        let start = m.func(&[], &[], &[], Code::new());
+        m.export("_start", start).data(0, cache.as_bytes());
+        if on_pressure {
+            let empty_cache = Code::new()
+                .i32(0)
+                .i32(cache.len() as i32)
+                .i32(0)
+                .i32(0)
+                .call(file_write)
+                .drop();
+            let release = m.func(&[], &[], &[], empty_cache);
+            m.export("on_memory_pressure", release);
+        }
+        m.build()
+    }
+
+    /// An agent allowed to write under `/agent/`, with a `CACHE_SIZE` cache file there
+    /// whose path is returned.
+    fn agent_with_cache(name: &str) -> (AgentId, String) {
+        let files = crate::capability::Capability::FileSystem {
+            path_prefix: String::from("/agent/"),
+            read: true,
+            write: true,
+        };
+        let agent = testing::spawn_agent(name, alloc::vec![files]);
+        let cache = alloc::format!("/agent/{name}.cache");
+        crate::vfs::write_file(&cache, &alloc::vec![0xAA; CACHE_SIZE], agent.0);
+        (agent, cache)
+    }
+
+    /// Allocate small blocks until the heap is just under `LOW_MEMORY_THRESHOLD`, so
+    /// freeing the cache file relieves the pressure. Drop the result to give it back.
+    fn exhaust_heap() -> Vec<Vec<u8>> {
+        let mut ballast = Vec::new();
+        while !crate::allocator::under_memory_pressure() {
+            ballast.push(alloc::vec![0u8; 16 * 1024]);
+        }
+        ballast
+    }
+
+    #[test_case]
+    fn agent_that_frees_memory_under_pressure_is_not_killed() {
+        let (agent, cache) = agent_with_cache("oom-cooperative");
+        WasmRuntime::new()
+            .spawn_module(&cache_module(&cache, true), agent.0)
+            .unwrap();
+
+        let ballast = exhaust_heap();
+        run_executor();
+        let relieved = !crate::allocator::under_memory_pressure();
+        drop(ballast);
+
+        assert!(relieved);
+        assert_eq!(crate::vfs::open_file(&cache), Some(Vec::new()));
+        assert_eq!(state(agent), Some(AgentState::Exited));
+        assert!(testing::logged(&alloc::format!(
+            "[OOM] Agent {} notified of memory pressure",
+            agent.0
+        )));
+    }
+
+    #[test_case]
+    fn supervised_agent_is_asked_to_free_memory_before_it_runs() {
+        let (agent, cache) = agent_with_cache("oom-supervised");
+        let path = "/test/oom-supervised.wasm";
+        testing::install(path, cache_module(&cache, true));
+        set_supervision(agent, path, RestartPolicy::Never);
+
+        let ballast = exhaust_heap();
+        let result = WasmRuntime::new().supervise(agent);
+        drop(ballast);
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(crate::vfs::open_file(&cache), Some(Vec::new()));
+        assert_eq!(state(agent), Some(AgentState::Exited));
+    }
+
+    #[test_case]
+    fn supervised_agent_that_cannot_free_memory_is_killed() {
+        let (agent, cache) = agent_with_cache("oom-stubborn");
+        let path = "/test/oom-stubborn.wasm";
+        testing::install(path, cache_module(&cache, false));
+        set_supervision(agent, path, RestartPolicy::OnFailure { max_restarts: 3 });
+
+        let ballast = exhaust_heap();
+        let result = WasmRuntime::new().supervise(agent);
+        drop(ballast);
+        crate::vfs::delete_file(&cache, agent.0);
+
+        assert_eq!(result, Err(String::from("Killed under memory pressure")));
+        assert_eq!(state(agent), Some(AgentState::Terminated));
+        assert_eq!(restart_count(agent), 0);
+        assert!(testing::logged(&alloc::format!(
+            "[OOM] Heap still low; killing Agent {}",
+            agent.0
+        )));
+    }
+
+    #[test_case]
+    fn registered_names_resolve_to_their_holder() {
+        let service = testing::spawn_agent("name-service", Vec::new());
+        let rival = testing::spawn_agent("name-rival", Vec::new());
+        assert_eq!(register_name(service, "svc.registry"), Ok(()));
+        assert_eq!(lookup_name("svc.registry"), Some(service));
+        assert_eq!(register_name(rival, "svc.registry"), Err(NameError::Taken));
+        assert_eq!(register_name(rival, ""), Err(NameError::Invalid));
+        assert_eq!(lookup_name("svc.unknown"), None);
+    }
+
+    #[test_case]
+    fn restarted_service_reclaims_its_name_under_the_new_pid() {
+        let old = testing::spawn_agent("name-old", Vec::new());
+        register_name(old, "svc.restart").unwrap();
+        REGISTRY.lock().agents.get_mut(&old).unwrap().state = AgentState::Exited;
+        assert_eq!(lookup_name("svc.restart"), None);
+
+        let new = testing::spawn_agent("name-new", Vec::new());
+        assert_eq!(register_name(new, "svc.restart"), Ok(()));
+        assert_eq!(lookup_name("svc.restart"), Some(new));
+    }
+
+    #[test_case]
+    fn signals_coalesce_until_polled() {
+        let agent = testing::spawn_agent("signalled", Vec::new());
+        assert_eq!(take_signals(agent), 0);
+        assert!(send_signal(agent, SIGNAL_RELOAD));
+        assert!(send_signal(agent, SIGNAL_PAUSE));
+        assert!(send_signal(agent, SIGNAL_RELOAD));
+        assert_eq!(take_signals(agent), SIGNAL_RELOAD | SIGNAL_PAUSE);
+        assert_eq!(take_signals(agent), 0);
+    }
+
+    #[test_case]
+    fn only_running_agents_can_be_signalled() {
+        let agent = testing::spawn_agent("signal-exited", Vec::new());
+        REGISTRY.lock().agents.get_mut(&agent).unwrap().state = AgentState::Exited;
+        assert!(!send_signal(agent, SIGNAL_TERMINATE));
+        assert!(!send_signal(AgentId(u64::MAX), SIGNAL_TERMINATE));
+        assert_eq!(take_signals(agent), 0);
+    }
+
+    fn spawner(max_depth: u32) -> CapabilityId {
+        crate::capability::create_capability(crate::capability::Capability::Spawn {
+            max_children: 4,
+            max_depth,
+            allowed_prefix: None,
+        })
+    }
+
+    #[test_case]
+    fn spawn_chains_stop_at_the_maximum_depth() {
+        let cap = spawner(MAX_SPAWN_DEPTH);
+        let mut parent = spawn_agent("depth-0", alloc::vec![cap], None).unwrap();
+        for level in 1..=MAX_SPAWN_DEPTH {
+            parent = spawn_agent("depth-n", alloc::vec![cap], Some(parent)).unwrap();
+            assert_eq!(REGISTRY.lock().agents[&parent].depth, level);
+        }
+        assert_eq!(
+            spawn_agent("too-deep", alloc::vec![cap], Some(parent)),
+            Err(ERR_CAPABILITY_SPAWN)
+        );
+    }
+
+    #[test_case]
+    fn spawn_capability_can_lower_the_depth_limit() {
+        let cap = spawner(1);
+        let root = spawn_agent("shallow-root", alloc::vec![cap], None).unwrap();
+        let child = spawn_agent("shallow-child", alloc::vec![cap], Some(root)).unwrap();
+        assert_eq!(
+            spawn_agent("shallow-grandchild", Vec::new(), Some(child)),
+            Err(ERR_CAPABILITY_SPAWN)
+        );
+    }
+
+    #[test_case]
+    fn reclaimed_pid_starts_with_an_empty_queue() {
+        let agent = testing::spawn_agent("reclaim-queue", Vec::new());
+        let pid = crate::ipc::ProcessId(agent.0);
+        crate::ipc::create_endpoint(pid).unwrap();
+        crate::ipc::send_message(pid, pid, b"stale".to_vec(), Vec::new()).unwrap();
+
+        reclaim_agent(agent);
+        assert_eq!(crate::ipc::queue_stats(pid).map(|(len, _)| len), Some(0));
+        crate::ipc::create_endpoint(pid).unwrap();
+        assert!(crate::ipc::receive_message(pid).is_none());
+    }
+
+    #[test_case]
+    fn owned_file_cleanup_spares_system_and_other_files() {
+        let agent = testing::spawn_agent("reclaim-files", Vec::new());
+        let other = testing::spawn_agent("reclaim-other", Vec::new());
+        testing::install("/reclaim/system.txt", b"system".to_vec());
+        assert!(crate::vfs::write_file(
+            "/reclaim/mine.txt",
+            b"mine",
+            agent.0
+        ));
+        assert!(crate::vfs::write_file(
+            "/reclaim/theirs.txt",
+            b"theirs",
+            other.0
+        ));
+
+        set_cleanup_owned_files(true);
+        reclaim_agent(agent);
+        set_cleanup_owned_files(false);
+
+        assert!(crate::vfs::open_file("/reclaim/mine.txt").is_none());
+        assert_eq!(
+            crate::vfs::open_file("/reclaim/theirs.txt"),
+            Some(b"theirs".to_vec())
+        );
+        assert_eq!(
+            crate::vfs::open_file("/reclaim/system.txt"),
+            Some(b"system".to_vec())
+        );
+    }
+
+    #[test_case]
+    fn owned_files_are_kept_unless_cleanup_is_enabled() {
+        let agent = testing::spawn_agent("reclaim-keep", Vec::new());
+        assert!(crate::vfs::write_file(
+            "/reclaim/kept.txt",
+            b"kept",
+            agent.0
+        ));
+        reclaim_agent(agent);
+        assert_eq!(
+            crate::vfs::open_file("/reclaim/kept.txt"),
+            Some(b"kept".to_vec())
+        );
+    }
+
+    #[test_case]
+    fn executor_resumes_agents_round_robin_in_spawn_order() {
+        let runtime = WasmRuntime::new();
+        let yields = [3, 2, 3, 1];
+        let agents: Vec<AgentId> = yields
+            .iter()
+            .map(|&n| {
+                let agent = testing::spawn_agent("round-robin", Vec::new());
+                let wasm = testing::yielding_module(n);
+                runtime.spawn_module(&wasm, agent.0).unwrap();
+                agent
+            })
+            .collect();
+
+        set_schedule_trace(true);
+        run_executor();
+        set_schedule_trace(false);
+
+        let [a, b, c, d] = [agents[0], agents[1], agents[2], agents[3]];
+        let ours: Vec<AgentId> = schedule_trace()
+            .into_iter()
+            .filter(|id| agents.contains(id))
+            .collect();
+        assert_eq!(ours, [a, b, c, d, a, b, c, d, a, b, c, a, c]);
+    }
+
+    /// A module whose `_start` logs "begin", spins for `iterations` without a host call,
+    /// then logs "end".
+    fn spinning_module(iterations: i32) -> Vec<u8> {
+        use testing::{Code, ModuleBuilder, I32, I32_SUB};
+
+        let mut m = ModuleBuilder::new();
+        let debug_log = m.import("debug_log", &[I32, I32], &[]);
+        let body = Code::new()
+            .i32(0)
+            .i32(5)
+            .call(debug_log)
+            .i32(iterations)
+            .local_set(0)
+            .loop_()
+            .local_get(0)
+            .i32(1)
+            .op(I32_SUB)
+            .local_set(0)
+            .local_get(0)
+            .br_if(0)
+            .end()
+            .i32(8)
+            .i32(3)
+            .call(debug_log);
+        let start = m.func(&[], &[], &[I32], body);
+        m.export("_start", start).data(0, b"begin").data(8, b"end");
+        m.build()
+    }
+
+    #[test_case]
+    fn compute_bound_agents_are_preempted_and_their_logs_interleave() {
+        let runtime = WasmRuntime::new();
+        let wasm = spinning_module(200_000);
+        let a = testing::spawn_agent("spinner-a", Vec::new());
+        let b = testing::spawn_agent("spinner-b", Vec::new());
+        runtime.spawn_module(&wasm, a.0).unwrap();
+        runtime.spawn_module(&wasm, b.0).unwrap();
+        run_executor();
+
+        // Neither loop makes a host call, so without preemption A would log "end"
+        // before B had run at all.
+        let log = testing::log_tail();
+        let at = |agent: AgentId, message: &str| {
+            let line = alloc::format!("[Wasm Agent {}] {}\n", agent.0, message);
+            log.find(&line).unwrap_or_else(|| panic!("missing {line}"))
+        };
+        assert!(at(a, "begin") < at(b, "begin"));
+        assert!(at(b, "begin") < at(a, "end"));
+        assert!(at(a, "end") < at(b, "end"));
+    }
+
+    #[test_case]
+    fn schedule_trace_is_off_until_enabled_and_keeps_the_newest() {
+        set_schedule_trace(false);
+        let before = schedule_trace();
+        trace_resume(AgentId(956_001));
+        assert_eq!(schedule_trace(), before);
+
+        set_schedule_trace(true);
+        for pid in 0..MAX_SCHEDULE_TRACE as u64 + 3 {
+            trace_resume(AgentId(pid));
+        }
+        set_schedule_trace(false);
+        let trace = schedule_trace();
+        assert_eq!(trace.len(), MAX_SCHEDULE_TRACE);
+        assert_eq!(trace[0], AgentId(3));
+    }
+
+    /// Queue a spawn for a new agent that runs `wasm` once loaded.
+    fn queue_module(runtime: &WasmRuntime, wasm: &[u8]) -> AgentId {
+        let agent = testing::spawn_agent("queued", Vec::new());
+        let runtime = runtime.clone();
+        let wasm = wasm.to_vec();
+        queue_spawn(
+            agent,
+            Box::new(move || runtime.spawn_module(&wasm, agent.0)),
+        )
+        .unwrap();
+        agent
+    }
+
+    #[test_case]
+    fn queued_spawns_load_a_few_per_iteration_and_then_run() {
+        let runtime = WasmRuntime::new();
+        run_executor();
+        let wasm = testing::yielding_module(1);
+        let agents: Vec<AgentId> = (0..10).map(|_| queue_module(&runtime, &wasm)).collect();
+        let pending = || {
+            agents
+                .iter()
+                .filter(|&&a| state(a) == Some(AgentState::Pending))
+                .count()
+        };
+        assert_eq!(pending(), 10);
+        assert_eq!(pending_spawns(), 10);
+
+        assert!(run_executor_step());
+        assert_eq!(pending(), 10 - SPAWNS_PER_ITERATION);
+        assert!(run_executor_step());
+        assert_eq!(pending(), 10 - 2 * SPAWNS_PER_ITERATION);
+        assert!(run_executor_step());
+        assert_eq!(pending(), 0);
+        assert_eq!(pending_spawns(), 0);
+
+        run_executor();
+        for agent in agents {
+            assert_eq!(state(agent), Some(AgentState::Exited));
+            assert!(testing::logged(&alloc::format!(
+                "[EXEC] Agent {} finished",
+                agent.0
+            )));
+        }
+    }
+
+    #[test_case]
+    fn spawn_queue_refuses_spawns_past_its_bound() {
+        let runtime = WasmRuntime::new();
+        run_executor();
+        let wasm = testing::trapping_module();
+        let queued: Vec<AgentId> = (0..MAX_PENDING_SPAWNS)
+            .map(|_| queue_module(&runtime, &wasm))
+            .collect();
+
+        let refused = testing::spawn_agent("queue-overflow", Vec::new());
+        let load: SpawnLoader = Box::new(|| Ok(()));
+        assert_eq!(queue_spawn(refused, load).unwrap_err(), ERR_QUOTA_EXCEEDED);
+        assert_eq!(state(refused), Some(AgentState::Running));
+
+        // Agents terminated while they wait are skipped.
+        for &agent in &queued {
+            terminate_agent(agent);
+        }
+        run_executor();
+        assert_eq!(pending_spawns(), 0);
+    }
+}
@@ -0,0 +1,458 @@
+//! In-kernel test support. `cargo test` boots the kernel under QEMU with the tests
+//! compiled in; `kernel_main` calls `run_tests` once the heap, VFS and capability
+//! system are up, and the result leaves through the isa-debug-exit device.
+//!
+//! Host functions are exercised with small modules assembled by `ModuleBuilder`, as
+//! the kernel has no Wasm text parser.
+
+use crate::capability::{create_capability, Capability};
+use crate::task::AgentId;
+use crate::wasm::WasmRuntime;
+use crate::{serial_print, serial_println};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::panic::PanicInfo;
+use smoltcp::iface::SocketHandle;
+use smoltcp::wire::{EthernetAddress, Ipv4Address};
+use wasmi::Value;
+
+/// Exit codes for `arch::shutdown`; QEMU exits with `(code << 1) | 1`.
+const EXIT_SUCCESS: u32 = 0x10;
+const EXIT_FAILURE: u32 = 0x11;
+
+pub trait Testable {
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        serial_print!("{}...\t", core::any::type_name::<T>());
+        self();
+        serial_println!("[ok]");
+    }
+}
+
+pub fn run_tests(tests: &[&dyn Testable]) {
+    serial_println!("Running {} tests", tests.len());
+    for test in tests {
+        test.run();
+    }
+    crate::arch::shutdown(EXIT_SUCCESS);
+}
+
+/// Panic handler for test builds: report the failing test and exit QEMU.
+pub fn fail(info: &PanicInfo) -> ! {
+    serial_println!("[failed]\n");
+    serial_println!("Error: {}\n", info);
+    crate::arch::shutdown(EXIT_FAILURE);
+}
+
+/// The recent serial output (see `crashdump::write_log_tail`), for asserting on log
+/// lines.
+pub fn log_tail() -> String {
+    let mut tail = String::new();
+    let _ = crate::crashdump::write_log_tail(&mut tail);
+    tail
+}
+
+/// Whether `needle` appears in the recent serial output.
+pub fn logged(needle: &str) -> bool {
+    log_tail().contains(needle)
+}
+
+/// Spawn a kernel-owned agent holding a new capability for each of `caps`.
+pub fn spawn_agent(name: &str, caps: Vec<Capability>) -> AgentId {
+    let ids = caps.into_iter().map(create_capability).collect();
+    crate::task::spawn_agent(name, ids, None).expect("kernel-spawned agents are not limited")
+}
+
+/// Register `wasm` as a read-only VFS file at `path`, as the initramfs would.
+pub fn install(path: &str, wasm: Vec<u8>) {
+    crate::vfs::register_file(path, wasm.leak(), 0);
+}
+
+/// I/O base with no device behind it, so a driver built on it touches no hardware.
+pub const NO_DEVICE: u16 = 0xFF00;
+
+/// Bring up the network stack, unless one is already up, over an RTL8139 driver with
+/// no card behind it: frames sent go nowhere and nothing is received, but sockets can
+/// be opened, configured and closed.
+pub fn network() {
+    let up = crate::net::NETWORK.lock().is_some();
+    if !up {
+        let mut nic = crate::rtl8139::Rtl8139::new(NO_DEVICE, 0);
+        // With no card to read it from the MAC comes out as broadcast; use QEMU's default.
+        nic.mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+        crate::net::init(nic).expect("failed to bring up the test network stack");
+    }
+}
+
+/// The address `net::init` gives the guest.
+pub const GUEST_IP: Ipv4Address = Ipv4Address::new(10, 0, 2, 15);
+
+/// `network`, with `GUEST_IP` pinned to the NIC's own MAC so that connections to it
+/// loop back to sockets opened with `listen`.
+pub fn loopback() {
+    network();
+    let mac = crate::net::with_network(|net| EthernetAddress(net.device.mac)).unwrap();
+    crate::net::add_static_arp(GUEST_IP, mac).unwrap();
+}
+
+/// Open a kernel-owned TCP socket listening on `port`, for agents to connect to over
+/// `loopback`. Remove it from `net.sockets` when done.
+pub fn listen(port: u16) -> SocketHandle {
+    use smoltcp::socket::tcp::{Socket, SocketBuffer};
+
+    let mut socket = Socket::new(
+        SocketBuffer::new(alloc::vec![0; 256]),
+        SocketBuffer::new(alloc::vec![0; 256]),
+    );
+    socket.listen(port).unwrap();
+    crate::net::with_network(|net| net.add_socket(socket))
+        .unwrap()
+        .unwrap_or_else(|_| panic!("no room for a listening socket"))
+}
+
+/// Instantiate `wasm` for `agent` and call its export `name` once.
+pub fn call(
+    runtime: &WasmRuntime,
+    wasm: &[u8],
+    agent: AgentId,
+    name: &str,
+    args: &[Value],
+) -> Result<Vec<Value>, String> {
+    let mut handle = runtime.instantiate(wasm, agent.0)?;
+    runtime.call_export(&mut handle, name, args)
+}
+
+/// `call` for an export that takes no arguments and returns a host function's status
+/// code.
+pub fn call_status(runtime: &WasmRuntime, wasm: &[u8], agent: AgentId, name: &str) -> u32 {
+    let results = call(runtime, wasm, agent, name, &[]).expect("export call failed");
+    results[0].i32().expect("export did not return an i32") as u32
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValType {
+    I32,
+    I64,
+}
+
+impl ValType {
+    fn code(self) -> u8 {
+        match self {
+            ValType::I32 => 0x7f,
+            ValType::I64 => 0x7e,
+        }
+    }
+}
+
+pub use ValType::{I32, I64};
+
+/// Assembles a Wasm module: `env` imports, functions, one exported page of linear
+/// memory named `memory`, and data segments. Function indices count the imports
+/// first, so add every import before the first function.
+#[derive(Default)]
+pub struct ModuleBuilder {
+    types: Vec<(Vec<ValType>, Vec<ValType>)>,
+    imports: Vec<(String, u32)>,
+    funcs: Vec<(u32, Vec<ValType>, Vec<u8>)>,
+    exports: Vec<(String, u32)>,
+    start: Option<u32>,
+    data: Vec<(u32, Vec<u8>)>,
+}
+
+impl ModuleBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn type_index(&mut self, params: &[ValType], results: &[ValType]) -> u32 {
+        let ty = (params.to_vec(), results.to_vec());
+        match self.types.iter().position(|t| *t == ty) {
+            Some(index) => index as u32,
+            None => {
+                self.types.push(ty);
+                self.types.len() as u32 - 1
+            }
+        }
+    }
+
+    /// Import `env.<name>` and return its function index.
+    pub fn import(&mut self, name: &str, params: &[ValType], results: &[ValType]) -> u32 {
+        assert!(self.funcs.is_empty(), "imports must come before functions");
+        let ty = self.type_index(params, results);
+        self.imports.push((String::from(name), ty));
+        self.imports.len() as u32 - 1
+    }
+
+    /// Define a function with extra `locals` after its parameters and return its index.
+    /// `body` is completed with the final `end`.
+    pub fn func(
+        &mut self,
+        params: &[ValType],
+        results: &[ValType],
+        locals: &[ValType],
+        body: Code,
+    ) -> u32 {
+        let ty = self.type_index(params, results);
+        self.funcs.push((ty, locals.to_vec(), body.0));
+        (self.imports.len() + self.funcs.len()) as u32 - 1
+    }
+
+    pub fn export(&mut self, name: &str, func: u32) -> &mut Self {
+        self.exports.push((String::from(name), func));
+        self
+    }
+
+    /// Run `func` as the module's start function when it is instantiated.
+    pub fn start(&mut self, func: u32) -> &mut Self {
+        self.start = Some(func);
+        self
+    }
+
+    /// Place `bytes` in linear memory at `offset` when the module is instantiated.
+    pub fn data(&mut self, offset: u32, bytes: &[u8]) -> &mut Self {
+        self.data.push((offset, bytes.to_vec()));
+        self
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let mut out = Vec::from(&b"\0asm\x01\0\0\0"[..]);
+
+        let mut types = Vec::new();
+        uleb(&mut types, self.types.len() as u64);
+        for (params, results) in &self.types {
+            types.push(0x60);
+            uleb(&mut types, params.len() as u64);
+            types.extend(params.iter().map(|t| t.code()));
+            uleb(&mut types, results.len() as u64);
+            types.extend(results.iter().map(|t| t.code()));
+        }
+        section(&mut out, 1, &types);
+
+        let mut imports = Vec::new();
+        uleb(&mut imports, self.imports.len() as u64);
+        for (name, ty) in &self.imports {
+            name_bytes(&mut imports, "env");
+            name_bytes(&mut imports, name);
+            imports.push(0x00);
+            uleb(&mut imports, u64::from(*ty));
+        }
+        section(&mut out, 2, &imports);
+
+        let mut funcs = Vec::new();
+        uleb(&mut funcs, self.funcs.len() as u64);
+        for (ty, _, _) in &self.funcs {
+            uleb(&mut funcs, u64::from(*ty));
+        }
+        section(&mut out, 3, &funcs);
+
+        // One page of memory, no maximum.
+        section(&mut out, 5, &[0x01, 0x00, 0x01]);
+
+        let mut exports = Vec::new();
+        uleb(&mut exports, self.exports.len() as u64 + 1);
+        name_bytes(&mut exports, "memory");
+        exports.extend_from_slice(&[0x02, 0x00]);
+        for (name, func) in &self.exports {
+            name_bytes(&mut exports, name);
+            exports.push(0x00);
+            uleb(&mut exports, u64::from(*func));
+        }
+        section(&mut out, 7, &exports);
+
+        if let Some(func) = self.start {
+            let mut start = Vec::new();
+            uleb(&mut start, u64::from(func));
+            section(&mut out, 8, &start);
+        }
+
+        let mut code = Vec::new();
+        uleb(&mut code, self.funcs.len() as u64);
+        for (_, locals, body) in &self.funcs {
+            let mut func = Vec::new();
+            uleb(&mut func, locals.len() as u64);
+            for local in locals {
+                func.extend_from_slice(&[0x01, local.code()]);
+            }
+            func.extend_from_slice(body);
+            func.push(0x0b);
+            uleb(&mut code, func.len() as u64);
+            code.extend_from_slice(&func);
+        }
+        section(&mut out, 10, &code);
+
+        let mut data = Vec::new();
+        uleb(&mut data, self.data.len() as u64);
+        for (offset, bytes) in &self.data {
+            data.extend_from_slice(&[0x00, 0x41]);
+            sleb(&mut data, i64::from(*offset));
+            data.push(0x0b);
+            uleb(&mut data, bytes.len() as u64);
+            data.extend_from_slice(bytes);
+        }
+        section(&mut out, 11, &data);
+
+        out
+    }
+}
+
+/// A function body, built one instruction at a time.
+#[derive(Default)]
+pub struct Code(Vec<u8>);
+
+impl Code {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a raw opcode without immediates, e.g. `op(0x6a)` for `i32.add`.
+    pub fn op(mut self, opcode: u8) -> Self {
+        self.0.push(opcode);
+        self
+    }
+
+    pub fn i32(mut self, value: i32) -> Self {
+        self.0.push(0x41);
+        sleb(&mut self.0, i64::from(value));
+        self
+    }
+
+    pub fn i64(mut self, value: i64) -> Self {
+        self.0.push(0x42);
+        sleb(&mut self.0, value);
+        self
+    }
+
+    pub fn call(mut self, func: u32) -> Self {
+        self.0.push(0x10);
+        uleb(&mut self.0, u64::from(func));
+        self
+    }
+
+    pub fn drop(self) -> Self {
+        self.op(0x1a)
+    }
+
+    pub fn local_get(mut self, index: u32) -> Self {
+        self.0.push(0x20);
+        uleb(&mut self.0, u64::from(index));
+        self
+    }
+
+    pub fn local_set(mut self, index: u32) -> Self {
+        self.0.push(0x21);
+        uleb(&mut self.0, u64::from(index));
+        self
+    }
+
+    /// `i32.load` from the address on the stack plus `offset`.
+    pub fn load32(mut self, offset: u32) -> Self {
+        self.0.extend_from_slice(&[0x28, 0x02]);
+        uleb(&mut self.0, u64::from(offset));
+        self
+    }
+
+    /// `i32.store` of the value on top of the stack to the address below it.
+    pub fn store32(mut self, offset: u32) -> Self {
+        self.0.extend_from_slice(&[0x36, 0x02]);
+        uleb(&mut self.0, u64::from(offset));
+        self
+    }
+
+    /// `i64.store` of the value on top of the stack to the address below it.
+    pub fn store64(mut self, offset: u32) -> Self {
+        self.0.extend_from_slice(&[0x37, 0x03]);
+        uleb(&mut self.0, u64::from(offset));
+        self
+    }
+
+    /// Open a `loop` producing no value; `br_if(0)` inside jumps back to its start.
+    pub fn loop_(self) -> Self {
+        self.op(0x03).op(0x40)
+    }
+
+    pub fn br_if(mut self, depth: u32) -> Self {
+        self.0.push(0x0d);
+        uleb(&mut self.0, u64::from(depth));
+        self
+    }
+
+    pub fn end(self) -> Self {
+        self.op(0x0b)
+    }
+}
+
+/// A module whose `_start` traps at once.
+pub fn trapping_module() -> Vec<u8> {
+    let mut m = ModuleBuilder::new();
+    let start = m.func(&[], &[], &[], Code::new().op(UNREACHABLE));
+    m.export("_start", start);
+    m.build()
+}
+
+/// A module whose `_start` calls `env.yield_now` `yields` times, so it runs for
+/// `yields + 1` slices.
+pub fn yielding_module(yields: i32) -> Vec<u8> {
+    let mut m = ModuleBuilder::new();
+    let yield_now = m.import("yield_now", &[], &[]);
+    let body = Code::new()
+        .i32(yields)
+        .local_set(0)
+        .loop_()
+        .call(yield_now)
+        .local_get(0)
+        .i32(1)
+        .op(I32_SUB)
+        .local_set(0)
+        .local_get(0)
+        .br_if(0)
+        .end();
+    let start = m.func(&[], &[], &[I32], body);
+    m.export("_start", start);
+    m.build()
+}
+
+// Opcodes without immediates, for `Code::op`.
+pub const I32_EQZ: u8 = 0x45;
+pub const I32_ADD: u8 = 0x6a;
+pub const I32_SUB: u8 = 0x6b;
+pub const I32_DIV_U: u8 = 0x6e;
+pub const UNREACHABLE: u8 = 0x00;
+
+fn section(out: &mut Vec<u8>, id: u8, contents: &[u8]) {
+    out.push(id);
+    uleb(out, contents.len() as u64);
+    out.extend_from_slice(contents);
+}
+
+fn name_bytes(out: &mut Vec<u8>, name: &str) {
+    uleb(out, name.len() as u64);
+    out.extend_from_slice(name.as_bytes());
+}
+
+fn uleb(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn sleb(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        let done = (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0);
+        if done {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
@@ -1,3 +1,5 @@
+use alloc::format;
+use alloc::string::String;
 use core::sync::atomic::{AtomicU64, Ordering};
 use x86_64::instructions::port::Port;
 
@@ -14,8 +16,83 @@ pub fn uptime_ms() -> u64 {
     UPTIME_MS.load(Ordering::Relaxed)
 }
 
+/// Read the CPU timestamp counter. Raw cycles; see `nanos` for a calibrated clock.
+pub fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// PIT time the TSC is measured against during calibration.
+const TSC_CALIBRATION_MS: u64 = 90;
+
+/// TSC cycles per millisecond, 0 until `calibrate_tsc` has run.
+static TSC_CYCLES_PER_MS: AtomicU64 = AtomicU64::new(0);
+/// TSC value at calibration, the zero point of `nanos`.
+static TSC_BASE: AtomicU64 = AtomicU64::new(0);
+
+/// Estimate the TSC frequency by counting cycles across `TSC_CALIBRATION_MS` of PIT
+/// ticks. Call once at boot with interrupts enabled.
+pub fn calibrate_tsc() {
+    // Start on a tick edge so the measured interval is whole ticks.
+    let start_tick = uptime_ms();
+    while uptime_ms() == start_tick {
+        x86_64::instructions::hlt();
+    }
+    let start_ms = uptime_ms();
+    let start_tsc = rdtsc();
+
+    sleep_ms(TSC_CALIBRATION_MS);
+
+    let elapsed_ms = uptime_ms() - start_ms;
+    let cycles_per_ms = (rdtsc() - start_tsc) / elapsed_ms.max(1);
+    TSC_BASE.store(start_tsc, Ordering::Relaxed);
+    TSC_CYCLES_PER_MS.store(cycles_per_ms, Ordering::Relaxed);
+}
+
+/// Nanoseconds since TSC calibration. Falls back to PIT uptime (millisecond
+/// resolution) if the TSC has not been calibrated.
+pub fn nanos() -> u64 {
+    let cycles_per_ms = TSC_CYCLES_PER_MS.load(Ordering::Relaxed);
+    if cycles_per_ms == 0 {
+        return uptime_ms() * 1_000_000;
+    }
+    let cycles = rdtsc().saturating_sub(TSC_BASE.load(Ordering::Relaxed));
+    (cycles as u128 * 1_000_000 / cycles_per_ms as u128) as u64
+}
+
+/// Last value handed out by `monotonic_nanos`.
+static LAST_MONOTONIC_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// Like `nanos`, but strictly increasing across calls: if the clock appears to step
+/// back (e.g. `calibrate_tsc` ran again and moved the zero point), the previous value
+/// plus one is returned instead until the clock catches up.
+pub fn monotonic_nanos() -> u64 {
+    let now = nanos();
+    let mut last = LAST_MONOTONIC_NANOS.load(Ordering::Relaxed);
+    loop {
+        let next = now.max(last + 1);
+        match LAST_MONOTONIC_NANOS.compare_exchange_weak(
+            last,
+            next,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => return next,
+            Err(actual) => last = actual,
+        }
+    }
+}
+
+/// Halt the CPU until at least `ms` milliseconds of uptime have passed.
+/// Relies on the PIT interrupt to wake the core, so interrupts must be enabled.
+pub fn sleep_ms(ms: u64) {
+    let deadline = uptime_ms() + ms;
+    while uptime_ms() < deadline {
+        x86_64::instructions::hlt();
+    }
+}
+
 /// Read the current time from the CMOS Real-Time Clock.
-/// Returns a rough Unix-like timestamp (seconds since 2000-01-01 for simplicity).
+/// Returns seconds since the Unix epoch (1970-01-01T00:00:00Z), assuming the RTC runs in UTC.
 pub fn unix_timestamp() -> u64 {
     let sec = read_cmos(0x00) as u64;
     let min = read_cmos(0x02) as u64;
@@ -32,23 +109,49 @@ pub fn unix_timestamp() -> u64 {
     let month = bcd_to_bin(month);
     let year = bcd_to_bin(year) + 2000; // CMOS year is 0-99 → 2000-2099
 
-    // Rough Unix timestamp calculation (not perfectly accurate for all months)
-    let days_since_epoch = (year - 1970) * 365
-        + (year - 1969) / 4  // leap years
-        + month_days(month)
-        + day
-        - 1;
+    let days_since_epoch = days_from_civil(year as i64, month as u32, day as u32);
+    days_since_epoch.max(0) as u64 * 86400 + hour * 3600 + min * 60 + sec
+}
 
-    days_since_epoch * 86400 + hour * 3600 + min * 60 + sec
+/// Format a Unix timestamp as an ISO 8601 UTC string, e.g. `2024-02-29T13:45:00Z`.
+pub fn format_iso8601(ts: u64) -> String {
+    let (year, month, day) = civil_from_days((ts / 86400) as i64);
+    let secs = ts % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        (secs % 3600) / 60,
+        secs % 60
+    )
 }
 
-fn month_days(month: u64) -> u64 {
-    const CUMULATIVE: [u64; 12] = [0, 31, 59, 90, 120, 151, 181, 212, 243, 273, 304, 334];
-    if month >= 1 && month <= 12 {
-        CUMULATIVE[(month - 1) as usize]
-    } else {
-        0
-    }
+/// Days since 1970-01-01 for a proleptic Gregorian date (Howard Hinnant's algorithm).
+/// Handles the full leap-year rule, including the century exceptions.
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400; // [0, 399]
+    let mp = (month as i64 + 9) % 12; // March = 0
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1; // [0, 365]
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy; // [0, 146096]
+    era * 146097 + doe - 719468
+}
+
+/// Inverse of `days_from_civil`: converts days since 1970-01-01 to `(year, month, day)`.
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097; // [0, 146096]
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365; // [0, 399]
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100); // [0, 365]
+    let mp = (5 * doy + 2) / 153; // [0, 11], March = 0
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
 }
 
 fn bcd_to_bin(bcd: u64) -> u64 {
@@ -63,3 +166,83 @@ fn read_cmos(reg: u8) -> u8 {
         data_port.read()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn format_iso8601_handles_leap_days_and_midnight() {
+        assert_eq!(format_iso8601(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_iso8601(1_709_214_300), "2024-02-29T13:45:00Z");
+        assert_eq!(format_iso8601(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(format_iso8601(1_704_067_199), "2023-12-31T23:59:59Z");
+        assert_eq!(format_iso8601(1_704_067_200), "2024-01-01T00:00:00Z");
+    }
+
+    #[test_case]
+    fn civil_conversion_applies_the_century_rule() {
+        // 1900 and 2100 are not leap years; 2000 is.
+        assert_eq!(
+            civil_from_days(days_from_civil(2100, 2, 28) + 1),
+            (2100, 3, 1)
+        );
+        assert_eq!(
+            civil_from_days(days_from_civil(2000, 2, 28) + 1),
+            (2000, 2, 29)
+        );
+        assert_eq!(
+            days_from_civil(1900, 3, 1) - days_from_civil(1900, 2, 28),
+            1
+        );
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+    }
+
+    #[test_case]
+    fn nanos_is_calibrated_non_decreasing_and_advances() {
+        assert_ne!(TSC_CYCLES_PER_MS.load(Ordering::Relaxed), 0);
+        let start = nanos();
+        let mut last = start;
+        for _ in 0..1000 {
+            let now = nanos();
+            assert!(now >= last);
+            last = now;
+        }
+        sleep_ms(2);
+        assert!(nanos() - start >= 1_000_000);
+    }
+
+    #[test_case]
+    fn monotonic_nanos_strictly_increases() {
+        let mut last = monotonic_nanos();
+        for _ in 0..10_000 {
+            let now = monotonic_nanos();
+            assert!(now > last);
+            last = now;
+        }
+    }
+
+    #[test_case]
+    fn monotonic_nanos_never_steps_back_across_a_recalibration() {
+        let before = monotonic_nanos();
+        let base = TSC_BASE.load(Ordering::Relaxed);
+        let cycles_per_ms = TSC_CYCLES_PER_MS.load(Ordering::Relaxed);
+
+        // A new zero point and a faster apparent clock both make `nanos` jump back.
+        TSC_BASE.store(rdtsc(), Ordering::Relaxed);
+        TSC_CYCLES_PER_MS.store(cycles_per_ms * 2, Ordering::Relaxed);
+        assert!(nanos() < before);
+        let mut last = before;
+        for _ in 0..1000 {
+            let now = monotonic_nanos();
+            assert!(now > last);
+            last = now;
+        }
+        TSC_BASE.store(base, Ordering::Relaxed);
+        TSC_CYCLES_PER_MS.store(cycles_per_ms, Ordering::Relaxed);
+
+        // Once the clock is ahead of the clamp again it is followed.
+        let now = monotonic_nanos();
+        assert!(now > last && now >= nanos() - 1_000_000);
+    }
+}
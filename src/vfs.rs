@@ -1,5 +1,9 @@
+use crate::bytes::{read_slice, read_u32_le, read_u64_le, read_u8};
+use crate::crypto::sha256;
+use alloc::collections::{BTreeMap, VecDeque};
 use alloc::string::String;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
 use spin::Mutex;
 
 /// A file in the Virtual File System.
@@ -11,6 +15,12 @@ pub struct VirtualFile {
     pub data: Vec<u8>,
     pub owner_pid: u64, // 0 = system/initramfs
     pub read_only: bool,
+    /// Expected SHA-256 of `data`, if known. Set on every agent write and, for
+    /// initramfs files, from a `<name>.sha256` sidecar.
+    pub digest: Option<[u8; 32]>,
+    /// Last modification, in Unix seconds; 0 if unknown. Initramfs files carry the
+    /// archived timestamp, agent writes stamp the current time.
+    pub mtime: u64,
 }
 
 struct VfsRegistry {
@@ -25,19 +35,319 @@ impl VfsRegistry {
 
 static VFS: Mutex<VfsRegistry> = Mutex::new(VfsRegistry::new());
 
-/// Register a read-only system file (used by initramfs loader).
-pub fn register_file(name: &str, data: &'static [u8]) {
+/// Where the event log is served from.
+pub const EVENTS_PATH: &str = "/proc/vfs/events";
+/// Events kept by the log; the oldest are dropped first.
+pub const MAX_EVENTS: usize = 128;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VfsOp {
+    Write,
+    Delete,
+    /// The file moved from `from` to the event's `path`.
+    Rename {
+        from: String,
+    },
+}
+
+/// A mutation of a writable file, as recorded in the event log.
+#[derive(Debug, Clone)]
+pub struct VfsEvent {
+    /// Milliseconds since boot.
+    pub timestamp_ms: u64,
+    pub op: VfsOp,
+    pub path: String,
+    /// Agent that made the change.
+    pub pid: u64,
+    /// When the first write folded into this event happened. Equal to `timestamp_ms`
+    /// unless later writes were coalesced into it.
+    pub first_ms: u64,
+    /// Writes coalesced into this event after the first.
+    pub coalesced: u32,
+}
+
+static EVENTS: Mutex<VecDeque<VfsEvent>> = Mutex::new(VecDeque::new());
+
+/// Default for `set_coalesce_window`.
+pub const DEFAULT_COALESCE_MS: u64 = 50;
+
+static COALESCE_MS: AtomicU64 = AtomicU64::new(DEFAULT_COALESCE_MS);
+
+/// Fold writes to the same path that land within `ms` of the first one into a single
+/// event carrying the latest timestamp and writer, so a burst of writes does not flood
+/// the log. 0 records every write separately. Deletes and renames are never folded.
+pub fn set_coalesce_window(ms: u64) {
+    COALESCE_MS.store(ms, Ordering::Relaxed);
+}
+
+/// Log a mutation and drop the config cache if it touched the config file. Call it
+/// only after releasing `VFS` and `UPPER`, never from under either lock.
+fn record_event(op: VfsOp, path: &str, pid: u64) {
+    crate::config::invalidate_path(path);
+    if let VfsOp::Rename { from } = &op {
+        crate::config::invalidate_path(from);
+    }
+
+    let now = crate::time::uptime_ms();
+    let mut events = EVENTS.lock();
+    let window = COALESCE_MS.load(Ordering::Relaxed);
+    // Only the path's latest event may absorb the write, so a delete or rename in
+    // between still separates two writes.
+    let open = (window > 0 && op == VfsOp::Write)
+        .then(|| events.iter().rposition(|e| e.path == path))
+        .flatten()
+        .filter(|&i| {
+            events[i].op == VfsOp::Write && now.saturating_sub(events[i].first_ms) < window
+        });
+    // The folded event moves to the back, keeping the log in timestamp order.
+    if let Some(mut event) = open.and_then(|i| events.remove(i)) {
+        event.timestamp_ms = now;
+        event.pid = pid;
+        event.coalesced += 1;
+        events.push_back(event);
+        return;
+    }
+
+    if events.len() >= MAX_EVENTS {
+        events.pop_front();
+    }
+    events.push_back(VfsEvent {
+        timestamp_ms: now,
+        op,
+        path: String::from(path),
+        pid,
+        first_ms: now,
+        coalesced: 0,
+    });
+}
+
+/// The most recent `n` mutations, oldest first.
+pub fn recent_events(n: usize) -> Vec<VfsEvent> {
+    let events = EVENTS.lock();
+    events
+        .iter()
+        .skip(events.len().saturating_sub(n))
+        .cloned()
+        .collect()
+}
+
+/// Generator for `EVENTS_PATH`: one `timestamp_ms op path pid` line per event, with
+/// renames written as `rename from->path`.
+pub fn events_file() -> Vec<u8> {
+    use core::fmt::Write;
+
+    let mut out = String::new();
+    for event in recent_events(MAX_EVENTS) {
+        let _ = match &event.op {
+            VfsOp::Write => write!(out, "{} write {}", event.timestamp_ms, event.path),
+            VfsOp::Delete => write!(out, "{} delete {}", event.timestamp_ms, event.path),
+            VfsOp::Rename { from } => {
+                write!(out, "{} rename {from}->{}", event.timestamp_ms, event.path)
+            }
+        };
+        let _ = writeln!(out, " {}", event.pid);
+    }
+    out.into_bytes()
+}
+
+/// Produces the current contents of a dynamic file each time it is read.
+pub type FileGenerator = fn() -> Vec<u8>;
+
+/// Produces the contents of a file served by an agent, given the owner's PID and the
+/// file's path. `None` means the agent could not answer the read.
+pub type AgentFileReader = fn(u64, &str) -> Option<Vec<u8>>;
+
+#[derive(Clone, Copy)]
+enum DynamicFile {
+    Kernel(FileGenerator),
+    Agent { owner: u64, reader: AgentFileReader },
+}
+
+/// Read-only files whose contents are generated on every read (e.g. `/proc/...`).
+static DYNAMIC_FILES: Mutex<BTreeMap<String, DynamicFile>> = Mutex::new(BTreeMap::new());
+
+/// Register a read-only file at `name` whose contents come from `generator`.
+/// Dynamic files shadow stored files of the same name and cannot be written or deleted.
+pub fn register_dynamic_file(name: &str, generator: FileGenerator) {
+    DYNAMIC_FILES
+        .lock()
+        .insert(String::from(name), DynamicFile::Kernel(generator));
+}
+
+/// Register a dynamic file at `name` served by agent `owner` through `reader`.
+/// Returns false if another dynamic file already has that name.
+pub fn register_agent_file(name: &str, owner: u64, reader: AgentFileReader) -> bool {
+    let mut files = DYNAMIC_FILES.lock();
+    if files.contains_key(name) {
+        return false;
+    }
+    files.insert(String::from(name), DynamicFile::Agent { owner, reader });
+    true
+}
+
+/// Remove every dynamic file served by agent `owner`, returning how many there were.
+pub fn remove_agent_files(owner: u64) -> usize {
+    let mut files = DYNAMIC_FILES.lock();
+    let before = files.len();
+    files.retain(|_, file| !matches!(file, DynamicFile::Agent { owner: o, .. } if *o == owner));
+    before - files.len()
+}
+
+fn is_dynamic(name: &str) -> bool {
+    DYNAMIC_FILES.lock().contains_key(name)
+}
+
+/// Where the boot code mounts the overlay over the shared initramfs files.
+pub const SHARED_PREFIX: &str = "/shared/";
+
+/// Most bytes one agent's upper layer may hold, summed over all its overlay copies.
+pub const MAX_UPPER_BYTES: usize = 1024 * 1024;
+
+/// Overlay mounts as `(prefix, lower)`: agent writes under `prefix` go to the agent's
+/// upper layer, and reads of files it has not written fall through to the same path
+/// under `lower`, a read-only lower layer.
+static OVERLAYS: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+
+/// An agent's private copy of an overlay file.
+struct UpperFile {
+    data: Vec<u8>,
+    /// Unix seconds of the agent's last write.
+    mtime: u64,
+}
+
+/// Every agent's upper layer: its private copies of overlay files, keyed by `(pid, path)`.
+static UPPER: Mutex<BTreeMap<(u64, String), UpperFile>> = Mutex::new(BTreeMap::new());
+
+/// Mount an overlay at `prefix` (e.g. `"/shared/"`) over the files under `lower` (e.g.
+/// `"/"`, the initramfs root): `prefix` + `etc/hosts` shows `lower` + `etc/hosts` to
+/// every agent, but an agent's writes there only change its own view. Both must end
+/// in `/`. Remounting a prefix replaces its lower layer.
+pub fn mount_overlay(prefix: &str, lower: &str) {
+    let mut overlays = OVERLAYS.lock();
+    overlays.retain(|(p, _)| p != prefix);
+    overlays.push((String::from(prefix), String::from(lower)));
+}
+
+/// Whether `name` is under an overlay mount.
+pub fn is_overlay(name: &str) -> bool {
+    OVERLAYS
+        .lock()
+        .iter()
+        .any(|(prefix, _)| name.starts_with(prefix.as_str()))
+}
+
+/// The lower-layer file an overlay path shows when the agent has no copy of its own;
+/// any other path names itself.
+fn lower_path(name: &str) -> String {
+    OVERLAYS
+        .lock()
+        .iter()
+        .find_map(|(prefix, lower)| {
+            let rest = name.strip_prefix(prefix.as_str())?;
+            Some(alloc::format!("{lower}{rest}"))
+        })
+        .unwrap_or_else(|| String::from(name))
+}
+
+fn upper_file(name: &str, pid: u64) -> Option<Vec<u8>> {
+    UPPER
+        .lock()
+        .get(&(pid, String::from(name)))
+        .map(|f| f.data.clone())
+}
+
+/// Whether agent `pid` may write `len` bytes to `name`: always outside an overlay,
+/// and under one only if its upper layer stays within `MAX_UPPER_BYTES` with the copy
+/// of `name` replaced.
+pub fn fits_upper_quota(name: &str, len: usize, pid: u64) -> bool {
+    pid == 0 || !is_overlay(name) || upper_bytes_with(&UPPER.lock(), name, len, pid).is_some()
+}
+
+/// Bytes `pid`'s upper layer would hold with its copy of `name` at `len` bytes, or
+/// `None` if that exceeds `MAX_UPPER_BYTES`.
+fn upper_bytes_with(
+    upper: &BTreeMap<(u64, String), UpperFile>,
+    name: &str,
+    len: usize,
+    pid: u64,
+) -> Option<usize> {
+    let others: usize = upper
+        .iter()
+        .filter(|((owner, path), _)| *owner == pid && path != name)
+        .map(|(_, f)| f.data.len())
+        .sum();
+    Some(others + len).filter(|&total| total <= MAX_UPPER_BYTES)
+}
+
+/// Whether `name` is `prefix` or lies beneath it, matching whole path components: a
+/// prefix without a trailing `/` covers `/a` and `/a/b` but not `/ab`. The empty
+/// prefix covers everything.
+fn under_prefix(name: &str, prefix: &str) -> bool {
+    match name.strip_prefix(prefix) {
+        Some(rest) => {
+            prefix.is_empty() || prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/')
+        }
+        None => false,
+    }
+}
+
+/// Register a read-only system file (used by initramfs loader), last modified at
+/// `mtime` (Unix seconds).
+pub fn register_file(name: &str, data: &'static [u8], mtime: u64) {
     let mut reg = VFS.lock();
     reg.files.push(VirtualFile {
         name: String::from(name),
         data: data.to_vec(),
         owner_pid: 0,
         read_only: true,
+        digest: None,
+        mtime,
     });
 }
 
+/// What `stat_as` reports about a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileStat {
+    pub size: usize,
+    pub read_only: bool,
+    /// Unix seconds; 0 if unknown.
+    pub mtime: u64,
+}
+
+/// Size, mode and modification time of `name` as agent `pid` sees it. An agent's
+/// overlay copy is writable and carries the time of its last write; otherwise an
+/// overlay path reports its lower-layer file. `None` for missing and generated
+/// (`/proc`) files.
+pub fn stat_as(name: &str, pid: u64) -> Option<FileStat> {
+    if let Some(f) = UPPER.lock().get(&(pid, String::from(name))) {
+        return Some(FileStat {
+            size: f.data.len(),
+            read_only: false,
+            mtime: f.mtime,
+        });
+    }
+    let name = lower_path(name);
+    VFS.lock()
+        .files
+        .iter()
+        .find(|f| f.name == name)
+        .map(|f| FileStat {
+            size: f.data.len(),
+            read_only: f.read_only,
+            mtime: f.mtime,
+        })
+}
+
 /// Retrieve a file's contents by name.
 pub fn open_file(name: &str) -> Option<Vec<u8>> {
+    // Copy the generator out so it runs without the table locked.
+    let dynamic = DYNAMIC_FILES.lock().get(name).copied();
+    match dynamic {
+        Some(DynamicFile::Kernel(generator)) => return Some(generator()),
+        Some(DynamicFile::Agent { owner, reader }) => return reader(owner, name),
+        None => {}
+    }
+
     let reg = VFS.lock();
     reg.files
         .iter()
@@ -45,24 +355,93 @@ pub fn open_file(name: &str) -> Option<Vec<u8>> {
         .map(|f| f.data.clone())
 }
 
+/// Retrieve a file's contents as agent `pid` sees them: its own upper-layer copy if it
+/// has written one under an overlay, otherwise the shared lower-layer file.
+pub fn open_file_as(name: &str, pid: u64) -> Option<Vec<u8>> {
+    upper_file(name, pid).or_else(|| open_file(&lower_path(name)))
+}
+
+/// Whether a file named `name` exists, without copying its contents.
+pub fn exists(name: &str) -> bool {
+    is_dynamic(name) || VFS.lock().files.iter().any(|f| f.name == name)
+}
+
+/// Whether a file named `name` exists in agent `pid`'s view.
+pub fn exists_as(name: &str, pid: u64) -> bool {
+    UPPER.lock().contains_key(&(pid, String::from(name))) || exists(&lower_path(name))
+}
+
 /// List all file names in the VFS.
 pub fn list_files() -> Vec<String> {
-    let reg = VFS.lock();
-    reg.files.iter().map(|f| f.name.clone()).collect()
+    list_files_prefix("")
 }
 
-/// List files matching a path prefix.
+/// List the files at or beneath `prefix`, matching whole path components (see
+/// `under_prefix`).
 pub fn list_files_prefix(prefix: &str) -> Vec<String> {
-    let reg = VFS.lock();
-    reg.files
+    let mut names: Vec<String> = VFS
+        .lock()
+        .files
         .iter()
-        .filter(|f| f.name.starts_with(prefix))
+        .filter(|f| under_prefix(&f.name, prefix))
         .map(|f| f.name.clone())
-        .collect()
+        .collect();
+    for name in DYNAMIC_FILES.lock().keys() {
+        if under_prefix(name, prefix) && !names.contains(name) {
+            names.push(name.clone());
+        }
+    }
+    names
+}
+
+/// List the files at or beneath `prefix` in agent `pid`'s view: overlay paths showing
+/// lower-layer files, and files it has only created in its upper layer.
+pub fn list_files_prefix_as(prefix: &str, pid: u64) -> Vec<String> {
+    let mut names = list_files_prefix(prefix);
+    let overlays = OVERLAYS.lock().clone();
+    for (mount, lower) in &overlays {
+        for file in list_files_prefix(lower) {
+            // `/shared/` over `/` would otherwise list `/shared/shared/...` too.
+            if under_prefix(&file, mount) {
+                continue;
+            }
+            let name = alloc::format!("{mount}{}", &file[lower.len()..]);
+            if under_prefix(&name, prefix) && !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    for (owner, name) in UPPER.lock().keys() {
+        if *owner == pid && under_prefix(name, prefix) && !names.contains(name) {
+            names.push(name.clone());
+        }
+    }
+    names
 }
 
 /// Write or overwrite a file in the VFS. Returns true on success.
+/// Under an overlay, an agent's write goes to its upper layer, so the shared file
+/// (even a read-only one) is shadowed for that agent only; it fails if the upper
+/// layer would grow past `MAX_UPPER_BYTES`.
 pub fn write_file(name: &str, data: &[u8], owner_pid: u64) -> bool {
+    if is_dynamic(name) {
+        return false;
+    }
+    if owner_pid != 0 && is_overlay(name) {
+        let mut upper = UPPER.lock();
+        if upper_bytes_with(&upper, name, data.len(), owner_pid).is_none() {
+            return false;
+        }
+        let file = UpperFile {
+            data: data.to_vec(),
+            mtime: crate::time::unix_timestamp(),
+        };
+        upper.insert((owner_pid, String::from(name)), file);
+        drop(upper);
+        record_event(VfsOp::Write, name, owner_pid);
+        return true;
+    }
+    let mtime = crate::time::unix_timestamp();
     let mut reg = VFS.lock();
 
     // Check if file exists
@@ -72,23 +451,903 @@ pub fn write_file(name: &str, data: &[u8], owner_pid: u64) -> bool {
         }
         existing.data = data.to_vec();
         existing.owner_pid = owner_pid;
+        existing.digest = Some(sha256(data));
+        existing.mtime = mtime;
+    } else {
+        // Create new file
+        reg.files.push(VirtualFile {
+            name: String::from(name),
+            data: data.to_vec(),
+            owner_pid,
+            read_only: false,
+            digest: Some(sha256(data)),
+            mtime,
+        });
+    }
+    drop(reg);
+    record_event(VfsOp::Write, name, owner_pid);
+    true
+}
+
+/// Copy `src` to `dst` without leaving the kernel, creating or overwriting `dst` as a
+/// writable file owned by `owner_pid`. Read-only sources may be copied; a read-only
+/// destination is never overwritten. Returns false if `src` is missing or `dst` is
+/// read-only. Under an overlay, `src` is read and `dst` written in the agent's view.
+pub fn copy(src: &str, dst: &str, owner_pid: u64) -> bool {
+    if is_overlay(src) || is_overlay(dst) {
+        return match open_file_as(src, owner_pid) {
+            Some(data) => write_file(dst, &data, owner_pid),
+            None => false,
+        };
+    }
+    let mtime = crate::time::unix_timestamp();
+    let mut reg = VFS.lock();
+    let Some(data) = reg
+        .files
+        .iter()
+        .find(|f| f.name == src)
+        .map(|f| f.data.clone())
+    else {
+        return false;
+    };
+    let digest = Some(sha256(&data));
+
+    if let Some(existing) = reg.files.iter_mut().find(|f| f.name == dst) {
+        if existing.read_only {
+            return false;
+        }
+        existing.data = data;
+        existing.owner_pid = owner_pid;
+        existing.digest = digest;
+        existing.mtime = mtime;
+    } else {
+        reg.files.push(VirtualFile {
+            name: String::from(dst),
+            data,
+            owner_pid,
+            read_only: false,
+            digest,
+            mtime,
+        });
+    }
+    drop(reg);
+    record_event(VfsOp::Write, dst, owner_pid);
+    true
+}
+
+/// Move writable file `src` to `dst`, replacing a writable `dst`. Returns false if
+/// `src` is missing or read-only, or `dst` is read-only. Overlay files cannot be
+/// renamed: the shared copy would stay visible under the old name.
+pub fn rename(src: &str, dst: &str, pid: u64) -> bool {
+    if is_dynamic(dst) || is_overlay(src) || is_overlay(dst) {
+        return false;
+    }
+    let mut reg = VFS.lock();
+    if !reg.files.iter().any(|f| f.name == src && !f.read_only) {
+        return false;
+    }
+    if src == dst {
         return true;
     }
+    if reg.files.iter().any(|f| f.name == dst && f.read_only) {
+        return false;
+    }
 
-    // Create new file
-    reg.files.push(VirtualFile {
-        name: String::from(name),
-        data: data.to_vec(),
-        owner_pid,
-        read_only: false,
-    });
+    reg.files.retain(|f| f.name != dst);
+    if let Some(file) = reg.files.iter_mut().find(|f| f.name == src) {
+        file.name = String::from(dst);
+    }
+    drop(reg);
+    record_event(
+        VfsOp::Rename {
+            from: String::from(src),
+        },
+        dst,
+        pid,
+    );
     true
 }
 
-/// Delete a file from the VFS. Returns true if deleted.
-pub fn delete_file(name: &str) -> bool {
+/// Exchange two writable files in one step: each name ends up with the other's
+/// contents, owner, digest and mtime, with no moment where either is missing.
+/// Returns false if either file is missing or read-only, or under an overlay (the
+/// agent's copies and the shared files would disagree). Swapping a file with itself
+/// succeeds without changes.
+pub fn swap(a: &str, b: &str, pid: u64) -> bool {
+    if is_dynamic(a) || is_dynamic(b) || is_overlay(a) || is_overlay(b) {
+        return false;
+    }
+    let mut reg = VFS.lock();
+    let writable = |name: &str| {
+        reg.files
+            .iter()
+            .position(|f| f.name == name && !f.read_only)
+    };
+    let (Some(i), Some(j)) = (writable(a), writable(b)) else {
+        return false;
+    };
+    if i == j {
+        return true;
+    }
+
+    // Exchanging the names moves everything else with them.
+    reg.files[i].name = String::from(b);
+    reg.files[j].name = String::from(a);
+    drop(reg);
+    record_event(VfsOp::Write, a, pid);
+    record_event(VfsOp::Write, b, pid);
+    true
+}
+
+/// Patch opcode: `0x01 offset:u32le len:u32le` copies `len` bytes of the original file
+/// starting at `offset`.
+pub const PATCH_OP_COPY: u8 = 0x01;
+/// Patch opcode: `0x02 len:u32le bytes[len]` appends the given literal bytes.
+pub const PATCH_OP_ADD: u8 = 0x02;
+
+/// Why `apply_patch` rejected a patch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchError {
+    NotFound,
+    ReadOnly,
+    Malformed,
+    /// The result would not fit in the agent's overlay upper layer.
+    QuotaExceeded,
+}
+
+/// Build new contents from `original` and a delta. A patch is a sequence of
+/// COPY/ADD ops (see `PATCH_OP_COPY` and `PATCH_OP_ADD`) applied in order;
+/// the output is the concatenation of their results. Returns `None` if an op is
+/// unknown, truncated, or copies outside `original`.
+pub fn patch_bytes(original: &[u8], patch: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while pos < patch.len() {
+        match read_u8(patch, pos)? {
+            PATCH_OP_COPY => {
+                let offset = read_u32_le(patch, pos + 1)? as usize;
+                let len = read_u32_le(patch, pos + 5)? as usize;
+                out.extend_from_slice(read_slice(original, offset, len)?);
+                pos += 9;
+            }
+            PATCH_OP_ADD => {
+                let len = read_u32_le(patch, pos + 1)? as usize;
+                out.extend_from_slice(read_slice(patch, pos + 5, len)?);
+                pos += 5 + len;
+            }
+            _ => return None,
+        }
+    }
+    Some(out)
+}
+
+/// Replace an agent file's contents with the result of applying `patch` to it on
+/// behalf of `pid`. Returns the new file length. Under an overlay the patch applies
+/// to the agent's view and the result goes to its upper layer.
+pub fn apply_patch(name: &str, patch: &[u8], pid: u64) -> Result<usize, PatchError> {
+    if is_dynamic(name) {
+        return Err(PatchError::ReadOnly);
+    }
+    if is_overlay(name) {
+        let original = open_file_as(name, pid).ok_or(PatchError::NotFound)?;
+        let data = patch_bytes(&original, patch).ok_or(PatchError::Malformed)?;
+        let len = data.len();
+        if !fits_upper_quota(name, len, pid) {
+            return Err(PatchError::QuotaExceeded);
+        }
+        if !write_file(name, &data, pid) {
+            return Err(PatchError::ReadOnly);
+        }
+        return Ok(len);
+    }
+    let mtime = crate::time::unix_timestamp();
+    let mut reg = VFS.lock();
+    let file = reg
+        .files
+        .iter_mut()
+        .find(|f| f.name == name)
+        .ok_or(PatchError::NotFound)?;
+    if file.read_only {
+        return Err(PatchError::ReadOnly);
+    }
+
+    let data = patch_bytes(&file.data, patch).ok_or(PatchError::Malformed)?;
+    file.digest = Some(sha256(&data));
+    file.data = data;
+    file.mtime = mtime;
+    let len = file.data.len();
+    drop(reg);
+    record_event(VfsOp::Write, name, pid);
+    Ok(len)
+}
+
+/// Delete a file from the VFS on behalf of `pid`. Returns true if deleted.
+/// Under an overlay only `pid`'s upper-layer copy is removed, uncovering the shared file.
+pub fn delete_file(name: &str, pid: u64) -> bool {
+    if is_overlay(name) {
+        let deleted = UPPER.lock().remove(&(pid, String::from(name))).is_some();
+        if deleted {
+            record_event(VfsOp::Delete, name, pid);
+        }
+        return deleted;
+    }
     let mut reg = VFS.lock();
     let before = reg.files.len();
     reg.files.retain(|f| f.name != name || f.read_only);
-    reg.files.len() < before
+    let deleted = reg.files.len() < before;
+    drop(reg);
+    if deleted {
+        record_event(VfsOp::Delete, name, pid);
+    }
+    deleted
+}
+
+/// Delete every writable file owned by `owner_pid`, zeroing the contents first.
+/// This includes the agent's upper layer. System files are never touched. Returns the
+/// number deleted.
+pub fn delete_owned_by(owner_pid: u64) -> usize {
+    let mut deleted = Vec::new();
+    UPPER.lock().retain(|(pid, name), file| {
+        if *pid != owner_pid {
+            return true;
+        }
+        file.data.fill(0);
+        deleted.push(name.clone());
+        false
+    });
+
+    VFS.lock().files.retain_mut(|f| {
+        if f.owner_pid != owner_pid || f.read_only {
+            return true;
+        }
+        f.data.fill(0);
+        deleted.push(core::mem::take(&mut f.name));
+        false
+    });
+
+    for name in &deleted {
+        record_event(VfsOp::Delete, name, owner_pid);
+    }
+    deleted.len()
+}
+
+/// Magic prefix of a serialized `VfsSnapshot`.
+const SNAPSHOT_MAGIC: &[u8; 4] = b"VFS1";
+
+/// The writable (agent-created) files of the VFS at a point in time.
+#[derive(Debug, Clone)]
+pub struct VfsSnapshot {
+    files: Vec<VirtualFile>,
+}
+
+impl VfsSnapshot {
+    /// Serialize for persistence: `"VFS1" count:u32le` followed by, per file,
+    /// `name_len:u32le name owner_pid:u64le has_digest:u8 [digest; 32] data_len:u32le data`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(SNAPSHOT_MAGIC);
+        out.extend_from_slice(&(self.files.len() as u32).to_le_bytes());
+        for file in &self.files {
+            out.extend_from_slice(&(file.name.len() as u32).to_le_bytes());
+            out.extend_from_slice(file.name.as_bytes());
+            out.extend_from_slice(&file.owner_pid.to_le_bytes());
+            match file.digest {
+                Some(digest) => {
+                    out.push(1);
+                    out.extend_from_slice(&digest);
+                }
+                None => out.push(0),
+            }
+            out.extend_from_slice(&(file.data.len() as u32).to_le_bytes());
+            out.extend_from_slice(&file.data);
+        }
+        out
+    }
+
+    /// Parse a snapshot produced by `to_bytes`. Returns `None` if it is truncated or malformed.
+    /// Modification times are not serialized, so restored files report an mtime of 0.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if read_slice(bytes, 0, 4)? != SNAPSHOT_MAGIC {
+            return None;
+        }
+        let count = read_u32_le(bytes, 4)? as usize;
+        let mut pos = 8;
+
+        let mut files = Vec::new();
+        for _ in 0..count {
+            let name_len = read_u32_le(bytes, pos)? as usize;
+            let name = core::str::from_utf8(read_slice(bytes, pos + 4, name_len)?).ok()?;
+            pos += 4 + name_len;
+            let owner_pid = read_u64_le(bytes, pos)?;
+            pos += 8;
+            let digest = match read_u8(bytes, pos)? {
+                0 => None,
+                1 => {
+                    let digest = read_slice(bytes, pos + 1, 32)?.try_into().ok()?;
+                    pos += 32;
+                    Some(digest)
+                }
+                _ => return None,
+            };
+            pos += 1;
+            let data_len = read_u32_le(bytes, pos)? as usize;
+            let data = read_slice(bytes, pos + 4, data_len)?;
+            pos += 4 + data_len;
+
+            files.push(VirtualFile {
+                name: String::from(name),
+                data: data.to_vec(),
+                owner_pid,
+                read_only: false,
+                digest,
+                mtime: 0,
+            });
+        }
+        Some(VfsSnapshot { files })
+    }
+}
+
+/// Capture every writable file. Read-only initramfs files are not included.
+pub fn snapshot() -> VfsSnapshot {
+    let reg = VFS.lock();
+    VfsSnapshot {
+        files: reg.files.iter().filter(|f| !f.read_only).cloned().collect(),
+    }
+}
+
+/// Replace all writable files with those in `snapshot`, leaving read-only files intact.
+/// Every path whose contents change is logged as a kernel (pid 0) write or delete.
+pub fn restore(snapshot: &VfsSnapshot) {
+    let mut changes = Vec::new();
+    {
+        let mut reg = VFS.lock();
+        let (previous, system): (Vec<VirtualFile>, Vec<VirtualFile>) =
+            core::mem::take(&mut reg.files)
+                .into_iter()
+                .partition(|f| !f.read_only);
+        reg.files = system;
+        for file in &snapshot.files {
+            // A system file registered after the snapshot keeps precedence.
+            if !reg.files.iter().any(|f| f.name == file.name) {
+                reg.files.push(file.clone());
+            }
+        }
+
+        for old in &previous {
+            if !reg.files.iter().any(|f| !f.read_only && f.name == old.name) {
+                changes.push((VfsOp::Delete, old.name.clone()));
+            }
+        }
+        for new in reg.files.iter().filter(|f| !f.read_only) {
+            if !previous
+                .iter()
+                .any(|old| old.name == new.name && old.data == new.data)
+            {
+                changes.push((VfsOp::Write, new.name.clone()));
+            }
+        }
+    }
+
+    for (op, path) in changes {
+        record_event(op, &path, 0);
+    }
+}
+
+/// Record the expected SHA-256 digest for an existing file. Returns false if not found.
+pub fn set_digest(name: &str, digest: [u8; 32]) -> bool {
+    let mut reg = VFS.lock();
+    match reg.files.iter_mut().find(|f| f.name == name) {
+        Some(file) => {
+            file.digest = Some(digest);
+            true
+        }
+        None => false,
+    }
+}
+
+/// CRC-32 of a file's contents: a cheap way to notice a file changed or was corrupted,
+/// where `verify`'s SHA-256 is not needed.
+pub fn crc32(name: &str) -> Option<u32> {
+    open_file(name).map(|data| crate::checksum::crc32(&data))
+}
+
+/// Recompute a file's SHA-256 and compare it against the stored digest.
+/// Returns false if the file is missing, has no recorded digest, or has been altered.
+pub fn verify(name: &str) -> bool {
+    let reg = VFS.lock();
+    reg.files
+        .iter()
+        .find(|f| f.name == name)
+        .and_then(|f| f.digest.map(|digest| sha256(&f.data) == digest))
+        .unwrap_or(false)
+}
+
+/// Normalize an absolute path: collapse repeated `/`, drop `.` segments and resolve `..`.
+/// A trailing `/` is preserved so directory-style prefixes stay prefixes.
+/// Returns `None` for relative paths or if `..` would climb above the root.
+pub fn normalize_path(path: &str) -> Option<String> {
+    if !path.starts_with('/') {
+        return None;
+    }
+
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop()?;
+            }
+            _ => segments.push(segment),
+        }
+    }
+
+    let mut normalized = String::from("/");
+    normalized.push_str(&segments.join("/"));
+    if path.ends_with('/') && !segments.is_empty() {
+        normalized.push('/');
+    }
+    Some(normalized)
+}
+
+/// Resolve `path` against the working directory `cwd` (relative paths) and normalize it.
+pub fn resolve_path(cwd: &str, path: &str) -> Option<String> {
+    if path.starts_with('/') {
+        normalize_path(path)
+    } else {
+        let mut joined = String::from(cwd);
+        if !joined.ends_with('/') {
+            joined.push('/');
+        }
+        joined.push_str(path);
+        normalize_path(&joined)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn written_file_verifies_against_its_digest() {
+        assert!(write_file("/test/verify-ok.txt", b"payload", 1));
+        assert!(verify("/test/verify-ok.txt"));
+    }
+
+    #[test_case]
+    fn tampered_file_fails_verification() {
+        register_file("/test/verify-tampered.txt", b"tampered", 0);
+        assert!(!verify("/test/verify-tampered.txt"));
+
+        assert!(set_digest("/test/verify-tampered.txt", sha256(b"original")));
+        assert!(!verify("/test/verify-tampered.txt"));
+        assert!(set_digest("/test/verify-tampered.txt", sha256(b"tampered")));
+        assert!(verify("/test/verify-tampered.txt"));
+    }
+    #[test_case]
+    fn resolve_path_joins_relative_paths_and_collapses_dots() {
+        assert_eq!(
+            resolve_path("/agent/3/", "a.txt").as_deref(),
+            Some("/agent/3/a.txt")
+        );
+        assert_eq!(
+            resolve_path("/agent/3", "./x/../b").as_deref(),
+            Some("/agent/3/b")
+        );
+        assert_eq!(
+            resolve_path("/agent/3/", "/etc//hosts").as_deref(),
+            Some("/etc/hosts")
+        );
+        assert_eq!(resolve_path("/agent/3/", "../../.."), None);
+        assert_eq!(normalize_path("/agent/"), Some(String::from("/agent/")));
+        assert_eq!(normalize_path("relative"), None);
+    }
+    fn copy_op(offset: u32, len: u32) -> Vec<u8> {
+        let mut op = alloc::vec![PATCH_OP_COPY];
+        op.extend_from_slice(&offset.to_le_bytes());
+        op.extend_from_slice(&len.to_le_bytes());
+        op
+    }
+
+    fn add_op(bytes: &[u8]) -> Vec<u8> {
+        let mut op = alloc::vec![PATCH_OP_ADD];
+        op.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        op.extend_from_slice(bytes);
+        op
+    }
+
+    #[test_case]
+    fn patch_copies_from_the_original_and_inserts_new_bytes() {
+        let name = "/test/patch.txt";
+        assert!(write_file(name, b"hello world", 1));
+        let patch = [copy_op(0, 6), add_op(b"kernel"), copy_op(5, 0)].concat();
+        assert_eq!(apply_patch(name, &patch, 1), Ok(12));
+        assert_eq!(open_file(name).as_deref(), Some(&b"hello kernel"[..]));
+        assert!(verify(name));
+    }
+
+    #[test_case]
+    fn malformed_patches_leave_the_file_untouched() {
+        let name = "/test/patch-malformed.txt";
+        assert!(write_file(name, b"original", 1));
+        let out_of_range = copy_op(4, 10);
+        let truncated = &add_op(b"abc")[..6];
+        for patch in [&out_of_range[..], truncated, &[0x7f][..]] {
+            assert_eq!(apply_patch(name, patch, 1), Err(PatchError::Malformed));
+        }
+        assert_eq!(open_file(name).as_deref(), Some(&b"original"[..]));
+        assert_eq!(
+            apply_patch("/test/patch-missing.txt", &add_op(b"x"), 1),
+            Err(PatchError::NotFound)
+        );
+    }
+
+    #[test_case]
+    fn restore_rolls_back_writable_files_only() {
+        register_file("/test/snap-system.txt", b"system", 0);
+        assert!(write_file("/test/snap-kept.txt", b"original", 7));
+        assert!(write_file("/test/snap-deleted.txt", b"doomed", 7));
+        let before = snapshot();
+
+        assert!(write_file("/test/snap-kept.txt", b"modified", 7));
+        assert!(delete_file("/test/snap-deleted.txt", 7));
+        assert!(write_file("/test/snap-new.txt", b"new", 7));
+        restore(&before);
+
+        assert_eq!(
+            open_file("/test/snap-kept.txt").as_deref(),
+            Some(&b"original"[..])
+        );
+        assert_eq!(
+            open_file("/test/snap-deleted.txt").as_deref(),
+            Some(&b"doomed"[..])
+        );
+        assert!(open_file("/test/snap-new.txt").is_none());
+        assert_eq!(
+            open_file("/test/snap-system.txt").as_deref(),
+            Some(&b"system"[..])
+        );
+    }
+
+    #[test_case]
+    fn restore_logs_changed_paths_and_reloads_the_config() {
+        use crate::config::{get, CONFIG_PATH};
+
+        assert!(write_file(CONFIG_PATH, b"test.restored = before\n", 0));
+        assert!(write_file("/test/restore-same.txt", b"same", 7));
+        assert!(write_file("/test/restore-changed.txt", b"old", 7));
+        let before = snapshot();
+
+        assert!(write_file(CONFIG_PATH, b"test.restored = after\n", 0));
+        assert_eq!(get("test.restored").as_deref(), Some("after"));
+        assert!(write_file("/test/restore-changed.txt", b"new", 7));
+        assert!(write_file("/test/restore-added.txt", b"added", 7));
+        // Writes just before would otherwise absorb the restore's events.
+        set_coalesce_window(0);
+        restore(&before);
+        set_coalesce_window(DEFAULT_COALESCE_MS);
+
+        assert_eq!(get("test.restored").as_deref(), Some("before"));
+        let events: Vec<(VfsOp, String, u64)> = recent_events(3)
+            .into_iter()
+            .map(|e| (e.op, e.path, e.pid))
+            .collect();
+        assert!(events.contains(&(VfsOp::Delete, String::from("/test/restore-added.txt"), 0)));
+        assert!(events.contains(&(VfsOp::Write, String::from("/test/restore-changed.txt"), 0)));
+        assert!(events.contains(&(VfsOp::Write, String::from(CONFIG_PATH), 0)));
+        assert!(!events
+            .iter()
+            .any(|(_, path, _)| path == "/test/restore-same.txt"));
+        delete_file(CONFIG_PATH, 0);
+    }
+
+    #[test_case]
+    fn reaping_the_config_owner_drops_the_cached_config() {
+        use crate::config::{get, CONFIG_PATH};
+
+        assert!(write_file(CONFIG_PATH, b"test.reaped = yes\n", 948_001));
+        assert_eq!(get("test.reaped").as_deref(), Some("yes"));
+        // Invalidation runs once the registry is unlocked; doing it from inside
+        // the sweep used to hold the VFS lock across the config cache's.
+        assert_eq!(delete_owned_by(948_001), 1);
+        assert!(!exists(CONFIG_PATH));
+        assert_eq!(get("test.reaped"), None);
+    }
+
+    #[test_case]
+    fn snapshots_round_trip_through_bytes() {
+        assert!(write_file("/test/snap-serialized.txt", b"persist me", 9));
+        assert!(set_digest(
+            "/test/snap-serialized.txt",
+            sha256(b"persist me")
+        ));
+        let bytes = snapshot().to_bytes();
+
+        let parsed = VfsSnapshot::from_bytes(&bytes).unwrap();
+        let file = parsed
+            .files
+            .iter()
+            .find(|f| f.name == "/test/snap-serialized.txt")
+            .unwrap();
+        assert_eq!(
+            (file.data.as_slice(), file.owner_pid),
+            (&b"persist me"[..], 9)
+        );
+        assert_eq!(file.digest, Some(sha256(b"persist me")));
+        assert!(VfsSnapshot::from_bytes(&bytes[..bytes.len() - 1]).is_none());
+        assert!(VfsSnapshot::from_bytes(b"VFS2\0\0\0\0").is_none());
+    }
+
+    #[test_case]
+    fn copy_duplicates_system_files_but_never_overwrites_them() {
+        register_file("/test/copy-system.txt", b"template", 0);
+        assert!(copy("/test/copy-system.txt", "/test/copy-writable.txt", 5));
+        assert_eq!(
+            open_file("/test/copy-writable.txt").as_deref(),
+            Some(&b"template"[..])
+        );
+        assert!(stat_as("/test/copy-writable.txt", 5).is_some_and(|s| !s.read_only));
+
+        assert!(write_file("/test/copy-edit.txt", b"edited", 5));
+        assert!(!copy("/test/copy-edit.txt", "/test/copy-system.txt", 5));
+        assert_eq!(
+            open_file("/test/copy-system.txt").as_deref(),
+            Some(&b"template"[..])
+        );
+        assert!(!copy(
+            "/test/copy-missing.txt",
+            "/test/copy-writable.txt",
+            5
+        ));
+    }
+
+    #[test_case]
+    fn write_and_delete_are_logged_in_order() {
+        assert!(write_file("/test/events.txt", b"x", 41));
+        assert!(delete_file("/test/events.txt", 42));
+
+        let events = recent_events(2);
+        assert_eq!(events.len(), 2);
+        assert_eq!(
+            (&events[0].op, events[0].path.as_str(), events[0].pid),
+            (&VfsOp::Write, "/test/events.txt", 41)
+        );
+        assert_eq!(
+            (&events[1].op, events[1].path.as_str(), events[1].pid),
+            (&VfsOp::Delete, "/test/events.txt", 42)
+        );
+        assert!(events[0].timestamp_ms <= events[1].timestamp_ms);
+
+        let log = String::from_utf8(events_file()).unwrap();
+        let line = alloc::format!("{} delete /test/events.txt 42\n", events[1].timestamp_ms);
+        assert!(log.ends_with(&line));
+    }
+
+    #[test_case]
+    fn event_log_drops_the_oldest_events() {
+        set_coalesce_window(0);
+        for i in 0..MAX_EVENTS + 3 {
+            assert!(write_file(&alloc::format!("/test/events-{i}.txt"), b"", 7));
+        }
+        set_coalesce_window(DEFAULT_COALESCE_MS);
+
+        let events = recent_events(usize::MAX);
+        assert_eq!(events.len(), MAX_EVENTS);
+        assert_eq!(events[0].path, "/test/events-3.txt");
+        assert_eq!(
+            events[MAX_EVENTS - 1].path,
+            alloc::format!("/test/events-{}.txt", MAX_EVENTS + 2)
+        );
+    }
+
+    #[test_case]
+    fn crc32_tracks_file_contents() {
+        write_file("/test/crc.txt", b"123456789", 0);
+        assert_eq!(crc32("/test/crc.txt"), Some(0xcbf4_3926));
+        write_file("/test/crc.txt", b"123456780", 0);
+        assert_ne!(crc32("/test/crc.txt"), Some(0xcbf4_3926));
+        assert_eq!(crc32("/test/crc-missing.txt"), None);
+    }
+
+    #[test_case]
+    fn overlay_writes_only_change_the_writers_view() {
+        const A: u64 = 957_001;
+        const B: u64 = 957_002;
+        mount_overlay(SHARED_PREFIX, "/");
+        register_file("/test/overlay-x", b"original", 0);
+
+        assert!(write_file("/shared/test/overlay-x", b"changed", A));
+        assert_eq!(
+            open_file_as("/shared/test/overlay-x", A),
+            Some(b"changed".to_vec())
+        );
+        assert_eq!(
+            open_file_as("/shared/test/overlay-x", B),
+            Some(b"original".to_vec())
+        );
+        assert_eq!(open_file("/test/overlay-x"), Some(b"original".to_vec()));
+
+        assert!(write_file("/shared/only-a", b"new", A));
+        assert!(exists_as("/shared/only-a", A));
+        assert!(!exists_as("/shared/only-a", B));
+        assert!(list_files_prefix_as("/shared/", A).contains(&String::from("/shared/only-a")));
+        assert!(!list_files_prefix_as("/shared/", B).contains(&String::from("/shared/only-a")));
+    }
+
+    #[test_case]
+    fn deleting_an_overlay_copy_uncovers_the_shared_file() {
+        const A: u64 = 957_003;
+        mount_overlay(SHARED_PREFIX, "/");
+        register_file("/test/overlay-y", b"original", 0);
+        write_file("/shared/test/overlay-y", b"changed", A);
+
+        assert!(delete_file("/shared/test/overlay-y", A));
+        assert_eq!(
+            open_file_as("/shared/test/overlay-y", A),
+            Some(b"original".to_vec())
+        );
+        assert!(!delete_file("/shared/test/overlay-y", A));
+
+        write_file("/shared/test/overlay-y", b"changed", A);
+        write_file("/shared/z", b"new", A);
+        assert_eq!(delete_owned_by(A), 2);
+        assert_eq!(
+            open_file_as("/shared/test/overlay-y", A),
+            Some(b"original".to_vec())
+        );
+        assert!(!exists_as("/shared/z", A));
+    }
+
+    #[test_case]
+    fn overlay_paths_show_the_lower_root_until_written() {
+        const A: u64 = 957_004;
+        mount_overlay(SHARED_PREFIX, "/");
+        register_file("/test/overlay-lower.txt", b"lower", 1_234);
+
+        let shared = "/shared/test/overlay-lower.txt";
+        assert!(exists_as(shared, A));
+        assert!(list_files_prefix_as("/shared/test", A).contains(&String::from(shared)));
+        let lower = FileStat {
+            size: 5,
+            read_only: true,
+            mtime: 1_234,
+        };
+        assert_eq!(stat_as(shared, A), Some(lower));
+
+        let before = crate::time::unix_timestamp();
+        assert!(write_file(shared, b"mine", A));
+        let stat = stat_as(shared, A).unwrap();
+        assert_eq!((stat.size, stat.read_only), (4, false));
+        assert!(stat.mtime >= before && stat.mtime <= crate::time::unix_timestamp());
+        delete_owned_by(A);
+    }
+
+    #[test_case]
+    fn an_agents_upper_layer_is_capped() {
+        const A: u64 = 957_005;
+        mount_overlay(SHARED_PREFIX, "/");
+        let full = alloc::vec![0u8; MAX_UPPER_BYTES - 1];
+
+        assert!(write_file("/shared/test/cap-big", &full, A));
+        assert!(write_file("/shared/test/cap-small", b"x", A));
+        assert!(!fits_upper_quota("/shared/test/cap-more", 1, A));
+        assert!(!write_file("/shared/test/cap-more", b"y", A));
+        assert!(!exists_as("/shared/test/cap-more", A));
+        // Replacing a copy only counts its new size.
+        assert!(write_file("/shared/test/cap-big", b"", A));
+        assert!(write_file("/shared/test/cap-more", b"y", A));
+        // Other agents have their own allowance.
+        assert!(fits_upper_quota(
+            "/shared/test/cap-more",
+            MAX_UPPER_BYTES,
+            A + 1
+        ));
+        assert_eq!(delete_owned_by(A), 3);
+    }
+
+    #[test_case]
+    fn listing_prefixes_match_whole_components() {
+        assert!(write_file("/test/list/a.txt", b"a", 957_006));
+        assert!(write_file("/test/listing.txt", b"b", 957_006));
+
+        for prefix in ["/test/list", "/test/list/"] {
+            let names = list_files_prefix(prefix);
+            assert!(
+                names.contains(&String::from("/test/list/a.txt")),
+                "{prefix}"
+            );
+            assert!(
+                !names.contains(&String::from("/test/listing.txt")),
+                "{prefix}"
+            );
+        }
+        assert!(list_files_prefix("/test/listing.txt").contains(&String::from("/test/listing.txt")));
+        delete_owned_by(957_006);
+    }
+
+    /// Write events for `path` currently in the log.
+    fn write_events(path: &str) -> Vec<VfsEvent> {
+        recent_events(usize::MAX)
+            .into_iter()
+            .filter(|e| e.path == path && e.op == VfsOp::Write)
+            .collect()
+    }
+
+    #[test_case]
+    fn rapid_writes_within_the_window_produce_one_event() {
+        // With interrupts off the clock cannot tick between the writes.
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            for pid in [61, 62, 63] {
+                assert!(write_file("/test/coalesce-burst.txt", b"x", pid));
+            }
+        });
+
+        let events = write_events("/test/coalesce-burst.txt");
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].pid, events[0].coalesced), (63, 2));
+        assert!(events[0].first_ms <= events[0].timestamp_ms);
+    }
+
+    #[test_case]
+    fn writes_spanning_windows_produce_two_events() {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            assert!(write_file("/test/coalesce-span.txt", b"1", 64));
+            crate::time::tick(DEFAULT_COALESCE_MS);
+            assert!(write_file("/test/coalesce-span.txt", b"2", 65));
+        });
+
+        let events = write_events("/test/coalesce-span.txt");
+        assert_eq!(events.len(), 2);
+        assert_eq!((events[0].pid, events[0].coalesced), (64, 0));
+        assert_eq!((events[1].pid, events[1].coalesced), (65, 0));
+        assert!(events[1].timestamp_ms - events[0].timestamp_ms >= DEFAULT_COALESCE_MS);
+    }
+
+    #[test_case]
+    fn a_delete_between_writes_keeps_them_apart() {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            assert!(write_file("/test/coalesce-delete.txt", b"1", 66));
+            assert!(delete_file("/test/coalesce-delete.txt", 66));
+            assert!(write_file("/test/coalesce-delete.txt", b"2", 66));
+        });
+
+        let events = write_events("/test/coalesce-delete.txt");
+        assert_eq!(events.len(), 2);
+        assert!(events.iter().all(|e| e.coalesced == 0));
+    }
+
+    #[test_case]
+    fn swap_exchanges_contents_and_metadata() {
+        assert!(write_file("/test/swap-a.txt", b"old", 71));
+        assert!(write_file("/test/swap-b.txt", b"replacement", 72));
+
+        assert!(swap("/test/swap-a.txt", "/test/swap-b.txt", 73));
+        assert_eq!(open_file("/test/swap-a.txt"), Some(b"replacement".to_vec()));
+        assert_eq!(open_file("/test/swap-b.txt"), Some(b"old".to_vec()));
+        let owner = |name: &str| {
+            VFS.lock()
+                .files
+                .iter()
+                .find(|f| f.name == name)
+                .map(|f| f.owner_pid)
+        };
+        assert_eq!(owner("/test/swap-a.txt"), Some(72));
+        assert!(verify("/test/swap-a.txt") && verify("/test/swap-b.txt"));
+        let events = recent_events(2);
+        assert!(events.iter().all(|e| e.op == VfsOp::Write && e.pid == 73));
+
+        assert!(swap("/test/swap-a.txt", "/test/swap-a.txt", 73));
+        assert_eq!(open_file("/test/swap-a.txt"), Some(b"replacement".to_vec()));
+    }
+
+    #[test_case]
+    fn swap_refuses_system_and_missing_files() {
+        register_file("/test/swap-system.txt", b"system", 0);
+        assert!(write_file("/test/swap-user.txt", b"user", 74));
+
+        assert!(!swap("/test/swap-user.txt", "/test/swap-system.txt", 74));
+        assert!(!swap("/test/swap-system.txt", "/test/swap-user.txt", 74));
+        assert!(!swap("/test/swap-user.txt", "/test/swap-missing.txt", 74));
+        assert_eq!(open_file("/test/swap-system.txt"), Some(b"system".to_vec()));
+        assert_eq!(open_file("/test/swap-user.txt"), Some(b"user".to_vec()));
+    }
 }
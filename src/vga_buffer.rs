@@ -140,5 +140,23 @@ macro_rules! println {
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
+    // A handler must not spin on a lock the code it interrupted may hold; if the
+    // console is busy, output from interrupt context (e.g. a keystroke echo) is dropped.
+    if crate::interrupts::in_interrupt() {
+        if let Some(mut writer) = WRITER.try_lock() {
+            let _ = writer.write_fmt(args);
+        }
+        return;
+    }
     WRITER.lock().write_fmt(args).unwrap();
 }
+
+/// The characters on screen, top row first.
+#[cfg(test)]
+pub fn screen() -> alloc::vec::Vec<u8> {
+    let writer = WRITER.lock();
+    writer.buffer.chars.iter()
+        .flatten()
+        .map(|c| c.read().ascii_character)
+        .collect()
+}
@@ -1,9 +1,26 @@
-use crate::capability::can_send_to;
-use crate::ipc::{send_message, ProcessId};
+use crate::capability::{can_send_to, path_under, Capability, CapabilityId, RequestDecision};
+use crate::ipc::{send_message, AckStatus, ProcessId};
+use crate::net::NetError;
+use crate::sandbox::SandboxProfile;
+use crate::syscall_errors::{
+    error_message, ERR_CONNECTION_REFUSED, ERR_GENERAL, ERR_INVALID_ARGUMENT,
+    ERR_NETWORK_UNREACHABLE, ERR_NOT_FOUND, ERR_PENDING, ERR_PERMISSION_DENIED, ERR_QUOTA_EXCEEDED,
+    ERR_RATE_LIMITED, ERR_TIMEOUT, OK,
+};
 use crate::task::{agent_capabilities, AgentId};
 use crate::{println, serial_println};
-use alloc::{string::String, vec::Vec};
-use wasmi::{Engine, Extern, Linker, Memory, Module, Store};
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    string::String,
+    sync::Arc,
+    vec::Vec,
+};
+use core::fmt;
+use wasmi::{
+    Config, Engine, Extern, Instance, IntoFunc, Linker, Memory, Module, Store, StoreLimits,
+    StoreLimitsBuilder, TypedFunc, TypedResumableCall, TypedResumableInvocation, Value,
+};
 
 #[derive(Debug)]
 pub struct HostError(String);
@@ -19,550 +36,882 @@ impl wasmi::core::HostError for HostError {}
 
 use wasmi::core::Trap;
 
-// We need a dummy state for the Store. We can use this to keep track of the current agent ID if needed.
-pub struct WasmState {
-    pub agent_pid: u64,
+/// Host error for a failure during the module's start function that may clear on its
+/// own, such as a full IPC queue. Start failures carrying it are retryable.
+#[derive(Debug)]
+pub struct TransientError(String);
+
+impl core::fmt::Display for TransientError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "transient: {}", self.0)
+    }
 }
 
-pub struct WasmRuntime {
-    engine: Engine,
+impl wasmi::core::HostError for TransientError {}
+
+/// Why a module could not be brought up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadError {
+    /// Signature, compile, link or entry-point errors, or a start function trap.
+    /// Retrying cannot help.
+    Permanent(String),
+    /// A host call made by the start function failed transiently; worth retrying.
+    Transient(String),
 }
 
-impl WasmRuntime {
-    pub fn new() -> Self {
-        let engine = Engine::default();
-        Self { engine }
+impl LoadError {
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, LoadError::Transient(_))
     }
+}
 
-    pub fn execute_module(&self, wasm_bytes: &[u8], agent_pid: u64) -> Result<(), String> {
-        serial_println!(
-            "[WASM] Engine compiling module of length: {}",
-            wasm_bytes.len()
-        );
-        let mut store = Store::new(&self.engine, WasmState { agent_pid });
-        let module = Module::new(&self.engine, wasm_bytes)
-            .map_err(|e| alloc::format!("Failed to compile module: {e}"))?;
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Permanent(msg) => write!(f, "{msg}"),
+            LoadError::Transient(msg) => write!(f, "{msg} (retryable)"),
+        }
+    }
+}
 
-        let mut linker = <Linker<WasmState>>::new(&self.engine);
+impl From<String> for LoadError {
+    fn from(msg: String) -> Self {
+        LoadError::Permanent(msg)
+    }
+}
 
-        // Host Function: env.debug_log(ptr, len)
-        // Allows the Wasm module to print to the microkernel's serial output.
-        linker
-            .define(
-                "env",
-                "debug_log",
-                wasmi::Func::wrap(
-                    &mut store,
-                    |mut caller: wasmi::Caller<'_, WasmState>,
-                     ptr: u32,
-                     len: u32|
-                     -> Result<(), Trap> {
-                        let memory = get_memory(&mut caller)?;
-                        let mut buf = alloc::vec![0u8; len as usize];
-                        memory.read(&caller, ptr as usize, &mut buf).map_err(|_| {
-                            Trap::from(HostError(String::from("Memory read failed")))
-                        })?;
+impl From<LoadError> for String {
+    fn from(err: LoadError) -> Self {
+        alloc::format!("{err}")
+    }
+}
 
-                        if let Ok(s) = core::str::from_utf8(&buf) {
-                            serial_println!("[Wasm Agent {}] {}", caller.data().agent_pid, s);
-                            println!("[Wasm Agent {}] {}", caller.data().agent_pid, s);
-                        }
-                        Ok(())
-                    },
-                ),
-            )
-            .map_err(|e| alloc::format!("Failed to define debug_log: {e}"))?;
+/// Classify an error from running a module's start function. Only traps raised with
+/// `TransientError` are retryable.
+pub fn classify_start_error(error: &wasmi::Error) -> LoadError {
+    let msg = alloc::format!("Failed to start module: {error}");
+    match error {
+        wasmi::Error::Trap(trap) if trap.downcast_ref::<TransientError>().is_some() => {
+            LoadError::Transient(msg)
+        }
+        _ => LoadError::Permanent(msg),
+    }
+}
 
-        // Host Function: env.send_ipc(target_pid, msg_ptr, msg_len)
-        linker
-            .define(
-                "env",
-                "send_ipc",
-                wasmi::Func::wrap(
-                    &mut store,
-                    |mut caller: wasmi::Caller<'_, WasmState>,
-                     target_pid: u64,
-                     ptr: u32,
-                     len: u32|
-                     -> Result<u32, Trap> {
-                        let memory = get_memory(&mut caller)?;
-                        let mut buf = alloc::vec![0u8; len as usize];
-                        memory.read(&caller, ptr as usize, &mut buf).map_err(|_| {
-                            Trap::from(HostError(String::from("Memory read failed")))
-                        })?;
+/// Attempts at instantiating a supervised module whose start fails transiently.
+const MAX_START_ATTEMPTS: u32 = 3;
+/// Delay before the next of those attempts is due on the spawn queue.
+const START_RETRY_DELAY_MS: u64 = 50;
 
-                        let sender_pid = ProcessId(caller.data().agent_pid);
-                        let recipient_pid = ProcessId(target_pid);
+/// Backoff before the first restart of a crashed agent; doubles on each further restart.
+const RESTART_BACKOFF_MS: u64 = 100;
+/// Upper bound on the restart backoff.
+const MAX_RESTART_BACKOFF_MS: u64 = 5_000;
 
-                        // SECURITY CHECK: Ensure Wasm Agent is granted the Capability to message target_pid!
-                        let sender_caps = agent_capabilities(AgentId(sender_pid.0));
-                        if !can_send_to(&sender_caps, target_pid) {
-                            serial_println!(
-                                "[SECURITY] Agent {} denied send to Agent {}",
-                                sender_pid.0,
-                                target_pid
-                            );
-                            return Ok(2); // Permission Denied
-                        }
+/// Where a supervised agent's final outcome is left for `WasmRuntime::supervise`.
+type SupervisedOutcome = Arc<spin::Mutex<Option<Result<(), String>>>>;
 
-                        // For now, we pass empty capabilities. In the future, the Wasm module could specify which capabilities to delegate.
-                        match send_message(sender_pid, recipient_pid, buf, Vec::new()) {
-                            Ok(_) => Ok(0),  // Success
-                            Err(_) => Ok(1), // General Error
-                        }
-                    },
-                ),
-            )
-            .map_err(|e| alloc::format!("Failed to define send_ipc: {e}"))?;
+/// Fuel an agent may burn before it is asked to yield to the executor.
+pub const FUEL_SLICE: u64 = 100_000;
+/// Total fuel granted to a module run; exhausting it traps the agent.
+pub const FUEL_LIMIT: u64 = 10_000_000_000;
 
-        // Host Function: env.tcp_request(ip_ptr: u32, port: u32, payload_ptr: u32, len: u32) -> u32
-        linker
-            .define(
-                "env",
-                "tcp_request",
-                wasmi::Func::wrap(
-                    &mut store,
-                    |mut caller: wasmi::Caller<'_, WasmState>,
-                     ip_ptr: u32,
-                     port: u32,
-                     ptr: u32,
-                     len: u32|
-                     -> Result<u32, Trap> {
-                        let memory = get_memory(&mut caller)?;
+const WASM_PAGE_SIZE: usize = 64 * 1024;
 
-                        let agent_pid = caller.data().agent_pid;
-                        let caps = agent_capabilities(AgentId(agent_pid));
+/// Fuel and memory budget a module is instantiated with.
+struct InstanceLimits {
+    fuel: u64,
+    /// Cap on linear memory in 64 KiB pages; `None` leaves it to the module.
+    max_memory_pages: Option<u32>,
+}
 
-                        // SECURITY CHECK: Ensure Wasm Agent is granted the Network Capability!
-                        if !crate::capability::can_access_network(&caps) {
-                            serial_println!("[SECURITY] Agent {} denied network access", agent_pid);
-                            return Ok(2); // Permission Denied
-                        }
+const DEFAULT_LIMITS: InstanceLimits = InstanceLimits {
+    fuel: FUEL_LIMIT,
+    max_memory_pages: None,
+};
 
-                        let mut ip_buf = [0u8; 4];
-                        memory
-                            .read(&caller, ip_ptr as usize, &mut ip_buf)
-                            .map_err(|_| Trap::from(HostError(String::from("IP read failed"))))?;
+impl InstanceLimits {
+    fn store_limits(&self) -> StoreLimits {
+        let mut builder = StoreLimitsBuilder::new();
+        if let Some(pages) = self.max_memory_pages {
+            builder = builder.memory_size(pages as usize * WASM_PAGE_SIZE);
+        }
+        builder.build()
+    }
+}
 
-                        let mut payload_buf = alloc::vec![0u8; len as usize];
-                        memory
-                            .read(&caller, ptr as usize, &mut payload_buf)
-                            .map_err(|_| {
-                                Trap::from(HostError(String::from("Payload read failed")))
-                            })?;
+/// Most bytes the open chunked write sessions of all agents may buffer at once, so
+/// many sessions cannot exhaust the kernel heap between them.
+pub const MAX_WRITE_SESSION_BYTES: usize = 2 * 1024 * 1024;
+/// Open file handles (chunked write sessions) an agent may hold at once, unless
+/// overridden by the `wasm.max_open_handles` config key.
+pub const DEFAULT_MAX_OPEN_HANDLES: usize = 32;
+/// Lowest file handle handed out. Handles start above every status code, so a handle
+/// passed where a status is expected (or the reverse) never reads as a valid one.
+const FIRST_FILE_HANDLE: u32 = 0x100;
 
-                        serial_println!(
-                            "[NET] Agent {} requesting TCP to {}.{}.{}.{}:{} (Payload: {} bytes)",
-                            agent_pid,
-                            ip_buf[0],
-                            ip_buf[1],
-                            ip_buf[2],
-                            ip_buf[3],
-                            port,
-                            len
-                        );
+/// The per-agent open file handle limit: `wasm.max_open_handles`, or
+/// `DEFAULT_MAX_OPEN_HANDLES` if unset or invalid.
+pub fn max_open_handles() -> usize {
+    crate::config::get_int("wasm.max_open_handles")
+        .and_then(|limit| usize::try_from(limit).ok())
+        .unwrap_or(DEFAULT_MAX_OPEN_HANDLES)
+}
 
-                        if let Some(ref mut net) = *crate::net::NETWORK.lock() {
-                            use smoltcp::socket::tcp::{Socket, SocketBuffer};
-                            use smoltcp::wire::IpAddress;
+/// Compiled modules kept by a new runtime's module cache.
+pub const DEFAULT_MODULE_CACHE_CAPACITY: usize = 16;
 
-                            let rx_buffer = SocketBuffer::new(alloc::vec![0; 1500]);
-                            let tx_buffer = SocketBuffer::new(alloc::vec![0; 1500]);
-                            let mut socket = Socket::new(rx_buffer, tx_buffer);
+/// How long `env.tcp_probe` waits for a handshake reply.
+const TCP_PROBE_TIMEOUT_MS: u64 = 1_000;
 
-                            let endpoint = (
-                                IpAddress::v4(ip_buf[0], ip_buf[1], ip_buf[2], ip_buf[3]),
-                                port as u16,
-                            );
-                            if socket.connect(net.iface.context(), endpoint, 49152).is_ok() {
-                                let mut handle = net.sockets.add(socket);
+/// Returned by `env.socket_state` when there is no state to report.
+pub const SOCKET_STATE_UNKNOWN: u32 = u32::MAX;
 
-                                // Force a poll to emit the bare-metal SYN frame!
-                                net.iface.poll(
-                                    smoltcp::time::Instant::from_millis(1),
-                                    &mut net.device,
-                                    &mut net.sockets,
-                                );
-                                serial_println!(
-                                    "  -> TCP SYN packet emitted to hardware DMA ring!"
-                                );
+/// Most capabilities a parent may pass to a child in one `spawn_agent_with_caps`.
+const MAX_INHERITED_CAPS: u32 = 32;
 
-                                net.sockets.remove(handle);
-                                return Ok(0); // Queued successfully
-                            }
-                        }
+/// Granularity of `get_time` for agents without `Capability::Clock` in strict mode.
+const COARSE_TIME_SECS: u64 = 60;
+/// Granularity of `get_uptime_ms` for agents without `Capability::Clock` in strict mode.
+const COARSE_UPTIME_MS: u64 = 1_000;
 
-                        Ok(1) // Error
-                    },
-                ),
-            )
-            .map_err(|e| alloc::format!("Failed to define tcp_request: {e}"))?;
+/// Host error used to suspend a module between fuel slices. Never reported as a failure.
+#[derive(Debug)]
+struct Yield;
 
-        // Host Function: env.resolve_dns(name_ptr: u32, name_len: u32, out_ip_ptr: u32) -> u32
-        linker
-            .define(
-                "env",
-                "resolve_dns",
-                wasmi::Func::wrap(
-                    &mut store,
-                    |mut caller: wasmi::Caller<'_, WasmState>,
-                     name_ptr: u32,
-                     name_len: u32,
-                     out_ip_ptr: u32|
-                     -> Result<u32, Trap> {
-                        let memory = get_memory(&mut caller)?;
+impl core::fmt::Display for Yield {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "yield")
+    }
+}
 
-                        let agent_pid = caller.data().agent_pid;
-                        let caps = agent_capabilities(AgentId(agent_pid));
+impl wasmi::core::HostError for Yield {}
 
-                        if !crate::capability::can_access_network(&caps) {
-                            serial_println!("[SECURITY] Agent {} denied DNS access", agent_pid);
-                            return Ok(2); // Permission Denied
-                        }
+// We need a dummy state for the Store. We can use this to keep track of the current agent ID if needed.
+pub struct WasmState {
+    pub agent_pid: u64,
+    /// When set, every host call is logged to serial by `traced`.
+    pub trace: bool,
+    /// Working directory relative VFS paths are resolved against. Always ends in `/`.
+    pub cwd: String,
+    /// Fuel-consumed mark at which the current slice ends. `None` while the module is
+    /// not running under the executor (e.g. during its start section).
+    slice_end: Option<u64>,
+    /// Return values of the host call that triggered a yield, fed back on resume.
+    pending_return: Vec<Value>,
+    /// Call and byte counters per host function, maintained by `traced`.
+    host_calls: BTreeMap<&'static str, HostCallStats>,
+    /// Name of the host function currently executing.
+    current_call: &'static str,
+    /// Open chunked write sessions by handle. Dropped with the store, so an agent that
+    /// exits without committing leaves no file behind; the drop logs them as leaked.
+    write_sessions: BTreeMap<u32, WriteSession>,
+    next_write_session: u32,
+    /// Lock the agent is waiting for in `lock_acquire`; checked by `run_slice` before
+    /// the agent is resumed.
+    lock_wait: Option<LockWait>,
+    /// Lookup the agent is waiting on in `resolve_dns`; polled by `run_slice` before the
+    /// agent is resumed.
+    dns_wait: Option<DnsWait>,
+    /// True while the module's start function runs; transient host failures then trap
+    /// with `TransientError` (see `transient_failure`).
+    starting: bool,
+    /// `key=val` pairs buffered by `debug_log_kv` until the line is flushed.
+    kv_line: Vec<String>,
+    /// Memory growth limits, enforced through `Store::limiter`.
+    limits: StoreLimits,
+    log_routing: Arc<spin::Mutex<LogRouting>>,
+}
 
-                        let mut name_buf = alloc::vec![0u8; name_len as usize];
-                        memory
-                            .read(&caller, name_ptr as usize, &mut name_buf)
-                            .map_err(|_| Trap::from(HostError(String::from("Name read failed"))))?;
+impl WasmState {
+    /// Write an agent log line to the sinks configured for this agent.
+    fn log_line(&self, line: fmt::Arguments<'_>) {
+        let sinks = self.log_routing.lock().sinks_for(self.agent_pid);
+        if sinks.serial {
+            serial_println!("{line}");
+        }
+        if sinks.vga {
+            println!("{line}");
+        }
+    }
+}
 
-                        let domain = core::str::from_utf8(&name_buf).map_err(|_| {
-                            Trap::from(HostError(String::from("Invalid UTF-8 domain")))
-                        })?;
+impl Drop for WasmState {
+    /// Runs when the agent's store is reaped. Handles still open at that point were
+    /// never committed: report them as a leak before their buffers are freed.
+    fn drop(&mut self) {
+        if self.write_sessions.is_empty() {
+            return;
+        }
+        serial_println!(
+            "[WARN] Agent {} exited with {} unclosed file handle(s)",
+            self.agent_pid,
+            self.write_sessions.len()
+        );
+        for (handle, session) in &self.write_sessions {
+            serial_println!(
+                "[WARN]   handle {}: {} ({} bytes discarded)",
+                handle,
+                session.path,
+                session.data.len()
+            );
+        }
+    }
+}
 
-                        serial_println!("[DNS] Agent {} resolving: {}", agent_pid, domain);
+/// A file being built by `file_write_begin`/`file_write_chunk`, published on commit.
+/// Its buffered bytes count towards `WRITE_SESSION_BYTES` until it is dropped.
+struct WriteSession {
+    path: String,
+    data: Vec<u8>,
+}
 
-                        match crate::dns::resolve(domain) {
-                            Some(ip) => {
-                                memory
-                                    .write(&mut caller, out_ip_ptr as usize, &ip)
-                                    .map_err(|_| {
-                                        Trap::from(HostError(String::from("IP write failed")))
-                                    })?;
-                                Ok(0) // Success
-                            }
-                            None => Ok(1), // Resolution failed
-                        }
-                    },
-                ),
-            )
-            .map_err(|e| alloc::format!("Failed to define resolve_dns: {e}"))?;
+impl Drop for WriteSession {
+    fn drop(&mut self) {
+        *WRITE_SESSION_BYTES.lock() -= self.data.len();
+    }
+}
 
-        // Host Function: env.file_read(path_ptr, path_len, out_ptr, out_len_ptr) -> u32
-        linker
-            .define(
-                "env",
-                "file_read",
-                wasmi::Func::wrap(
-                    &mut store,
-                    |mut caller: wasmi::Caller<'_, WasmState>,
-                     path_ptr: u32,
-                     path_len: u32,
-                     out_ptr: u32,
-                     out_len_ptr: u32|
-                     -> Result<u32, Trap> {
-                        let memory = get_memory(&mut caller)?;
-                        let agent_pid = caller.data().agent_pid;
-                        let caps = agent_capabilities(AgentId(agent_pid));
+/// Bytes buffered by every open write session, bounded by `MAX_WRITE_SESSION_BYTES`.
+static WRITE_SESSION_BYTES: spin::Mutex<usize> = spin::Mutex::new(0);
 
-                        let mut path_buf = alloc::vec![0u8; path_len as usize];
-                        memory
-                            .read(&caller, path_ptr as usize, &mut path_buf)
-                            .map_err(|_| Trap::from(HostError(String::from("Path read failed"))))?;
-                        let path = core::str::from_utf8(&path_buf)
-                            .map_err(|_| Trap::from(HostError(String::from("Invalid path"))))?;
+/// Bytes currently buffered by open chunked write sessions, across all agents.
+pub fn write_session_bytes() -> usize {
+    *WRITE_SESSION_BYTES.lock()
+}
 
-                        if !crate::capability::can_read_file(&caps, path) {
-                            serial_println!(
-                                "[SECURITY] Agent {} denied file read: {}",
-                                agent_pid,
-                                path
-                            );
-                            return Ok(2);
-                        }
+/// Reserve `len` more buffered session bytes, unless that would pass
+/// `MAX_WRITE_SESSION_BYTES`.
+fn reserve_write_session_bytes(len: usize) -> bool {
+    let mut used = WRITE_SESSION_BYTES.lock();
+    match used.checked_add(len) {
+        Some(total) if total <= MAX_WRITE_SESSION_BYTES => {
+            *used = total;
+            true
+        }
+        _ => false,
+    }
+}
 
-                        match crate::vfs::open_file(path) {
-                            Some(data) => {
-                                let write_len = data.len() as u32;
-                                memory.write(&mut caller, out_ptr as usize, &data).map_err(
-                                    |_| Trap::from(HostError(String::from("Data write failed"))),
-                                )?;
-                                memory
-                                    .write(
-                                        &mut caller,
-                                        out_len_ptr as usize,
-                                        &write_len.to_le_bytes(),
-                                    )
-                                    .map_err(|_| {
-                                        Trap::from(HostError(String::from("Len write failed")))
-                                    })?;
-                                Ok(0)
-                            }
-                            None => Ok(3), // Not found
-                        }
-                    },
-                ),
-            )
-            .map_err(|e| alloc::format!("Failed to define file_read: {e}"))?;
+/// A pending `lock_acquire`: the agent stays suspended until it gets the lock or
+/// `deadline_ms` (uptime) passes.
+struct LockWait {
+    name: String,
+    deadline_ms: u64,
+}
 
-        // Host Function: env.file_write(path_ptr, path_len, data_ptr, data_len) -> u32
-        linker
-            .define(
-                "env",
-                "file_write",
-                wasmi::Func::wrap(
-                    &mut store,
-                    |mut caller: wasmi::Caller<'_, WasmState>,
-                     path_ptr: u32,
-                     path_len: u32,
-                     data_ptr: u32,
-                     data_len: u32|
-                     -> Result<u32, Trap> {
-                        let memory = get_memory(&mut caller)?;
-                        let agent_pid = caller.data().agent_pid;
-                        let caps = agent_capabilities(AgentId(agent_pid));
+/// A pending `resolve_dns`: the agent stays suspended until `lookup` completes, then
+/// gets the address at `out_ip_ptr` in `memory`.
+struct DnsWait {
+    lookup: crate::dns::PendingResolve,
+    memory: Memory,
+    out_ip_ptr: u32,
+}
 
-                        let mut path_buf = alloc::vec![0u8; path_len as usize];
-                        memory
-                            .read(&caller, path_ptr as usize, &mut path_buf)
-                            .map_err(|_| Trap::from(HostError(String::from("Path read failed"))))?;
-                        let path = core::str::from_utf8(&path_buf)
-                            .map_err(|_| Trap::from(HostError(String::from("Invalid path"))))?;
+/// `resolve_dns` status for a failed lookup.
+fn dns_status(error: crate::dns::DnsError) -> u32 {
+    match error {
+        crate::dns::DnsError::Timeout => ERR_TIMEOUT,
+        crate::dns::DnsError::NotFound => ERR_GENERAL, // Resolution failed
+        crate::dns::DnsError::InvalidName => ERR_INVALID_ARGUMENT,
+    }
+}
 
-                        if !crate::capability::can_write_file(&caps, path) {
-                            serial_println!(
-                                "[SECURITY] Agent {} denied file write: {}",
-                                agent_pid,
-                                path
-                            );
-                            return Ok(2);
-                        }
+/// Per-host-function counters collected while a module runs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HostCallStats {
+    pub calls: u64,
+    /// Payload bytes moved between guest memory and the kernel (paths and
+    /// out-length words are not counted).
+    pub bytes: u64,
+}
 
-                        let mut data_buf = alloc::vec![0u8; data_len as usize];
-                        memory
-                            .read(&caller, data_ptr as usize, &mut data_buf)
-                            .map_err(|_| Trap::from(HostError(String::from("Data read failed"))))?;
+/// Result of `WasmRuntime::profile`.
+#[derive(Debug, Clone)]
+pub struct ProfileReport {
+    pub fuel_consumed: u64,
+    pub host_calls: BTreeMap<&'static str, HostCallStats>,
+}
 
-                        if crate::vfs::write_file(path, &data_buf, agent_pid) {
-                            serial_println!(
-                                "[VFS] Agent {} wrote {} bytes to {}",
-                                agent_pid,
-                                data_len,
-                                path
-                            );
-                            Ok(0)
-                        } else {
-                            Ok(1) // Write failed (e.g. read-only system file)
-                        }
-                    },
-                ),
-            )
-            .map_err(|e| alloc::format!("Failed to define file_write: {e}"))?;
+/// Number of buckets in a `LatencyHistogram`.
+pub const LATENCY_BUCKETS: usize = 48;
 
-        // Host Function: env.file_list(prefix_ptr, prefix_len, out_ptr, out_len_ptr) -> u32
-        linker
-            .define(
-                "env",
-                "file_list",
-                wasmi::Func::wrap(
-                    &mut store,
-                    |mut caller: wasmi::Caller<'_, WasmState>,
-                     prefix_ptr: u32,
-                     prefix_len: u32,
-                     out_ptr: u32,
-                     out_len_ptr: u32|
-                     -> Result<u32, Trap> {
-                        let memory = get_memory(&mut caller)?;
-                        let agent_pid = caller.data().agent_pid;
-                        let caps = agent_capabilities(AgentId(agent_pid));
+/// Exponential histogram of host-call durations in TSC cycles. Bucket 0 counts
+/// zero-cycle samples; bucket `i` counts durations in `[2^(i-1), 2^i)`, with the last
+/// bucket absorbing everything larger.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    pub buckets: [u64; LATENCY_BUCKETS],
+}
 
-                        let mut prefix_buf = alloc::vec![0u8; prefix_len as usize];
-                        memory
-                            .read(&caller, prefix_ptr as usize, &mut prefix_buf)
-                            .map_err(|_| {
-                                Trap::from(HostError(String::from("Prefix read failed")))
-                            })?;
-                        let prefix = core::str::from_utf8(&prefix_buf)
-                            .map_err(|_| Trap::from(HostError(String::from("Invalid prefix"))))?;
+impl LatencyHistogram {
+    const fn new() -> Self {
+        LatencyHistogram {
+            buckets: [0; LATENCY_BUCKETS],
+        }
+    }
 
-                        if !crate::capability::can_read_file(&caps, prefix) {
-                            serial_println!(
-                                "[SECURITY] Agent {} denied file list: {}",
-                                agent_pid,
-                                prefix
-                            );
-                            return Ok(2);
-                        }
+    /// Bucket index a duration of `cycles` falls into.
+    pub fn bucket_for(cycles: u64) -> usize {
+        ((u64::BITS - cycles.leading_zeros()) as usize).min(LATENCY_BUCKETS - 1)
+    }
 
-                        let files = crate::vfs::list_files_prefix(prefix);
-                        let listing = files.join("\n");
-                        let listing_bytes = listing.as_bytes();
-                        let write_len = listing_bytes.len() as u32;
+    fn record(&mut self, cycles: u64) {
+        self.buckets[Self::bucket_for(cycles)] += 1;
+    }
 
-                        memory
-                            .write(&mut caller, out_ptr as usize, listing_bytes)
-                            .map_err(|_| {
-                                Trap::from(HostError(String::from("List write failed")))
-                            })?;
-                        memory
-                            .write(&mut caller, out_len_ptr as usize, &write_len.to_le_bytes())
-                            .map_err(|_| Trap::from(HostError(String::from("Len write failed"))))?;
-                        Ok(0)
-                    },
-                ),
-            )
-            .map_err(|e| alloc::format!("Failed to define file_list: {e}"))?;
+    /// Total number of samples recorded.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+}
 
-        // Host Function: env.get_time() -> u64
-        linker
-            .define(
-                "env",
-                "get_time",
-                wasmi::Func::wrap(
-                    &mut store,
-                    |_caller: wasmi::Caller<'_, WasmState>| -> Result<u64, Trap> {
-                        Ok(crate::time::unix_timestamp())
-                    },
-                ),
-            )
-            .map_err(|e| alloc::format!("Failed to define get_time: {e}"))?;
+/// Host-call latency histograms across every module run, keyed by host function.
+static LATENCY: spin::Mutex<BTreeMap<&'static str, LatencyHistogram>> =
+    spin::Mutex::new(BTreeMap::new());
 
-        // Host Function: env.get_uptime_ms() -> u64
-        linker
-            .define(
-                "env",
-                "get_uptime_ms",
-                wasmi::Func::wrap(
-                    &mut store,
-                    |_caller: wasmi::Caller<'_, WasmState>| -> Result<u64, Trap> {
-                        Ok(crate::time::uptime_ms())
-                    },
-                ),
-            )
-            .map_err(|e| alloc::format!("Failed to define get_uptime_ms: {e}"))?;
+impl WasmState {
+    /// Attribute `bytes` of payload traffic to the host call currently running.
+    fn add_bytes(&mut self, bytes: usize) {
+        if let Some(stats) = self.host_calls.get_mut(self.current_call) {
+            stats.bytes += bytes as u64;
+        }
+    }
+}
 
-        // Host Function: env.request_capability(cap_type: u32, detail_ptr: u32, detail_len: u32) -> u32
-        // cap_type: 0=Network, 1=FileSystem, 2=Spawn
-        // detail: for FileSystem = path prefix string; for others = unused
-        linker
-            .define(
-                "env",
-                "request_capability",
-                wasmi::Func::wrap(
-                    &mut store,
-                    |mut caller: wasmi::Caller<'_, WasmState>,
-                     cap_type: u32,
-                     detail_ptr: u32,
-                     detail_len: u32|
-                     -> Result<u32, Trap> {
-                        let memory = get_memory(&mut caller)?;
-                        let agent_pid = caller.data().agent_pid;
+/// Outcome of running a `WasmTask` for one slice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskStatus {
+    /// The agent used up its slice and can be resumed.
+    Yielded,
+    /// The agent's entry point returned.
+    Finished,
+}
 
-                        let mut detail_buf = alloc::vec![0u8; detail_len as usize];
-                        if detail_len > 0 {
-                            memory
-                                .read(&caller, detail_ptr as usize, &mut detail_buf)
-                                .map_err(|_| {
-                                    Trap::from(HostError(String::from("Detail read failed")))
-                                })?;
-                        }
+/// Optional export a module can provide to release memory when the kernel heap is low.
+const MEMORY_PRESSURE_EXPORT: &str = "on_memory_pressure";
 
-                        let detail_str = core::str::from_utf8(&detail_buf).unwrap_or("");
+/// An instantiated agent module that can be run a slice at a time, keeping its
+/// `Store` and suspended call stack alive between slices.
+pub struct WasmTask {
+    store: Store<WasmState>,
+    entry: TypedFunc<(), ()>,
+    invocation: Option<TypedResumableInvocation<()>>,
+    /// The module's `on_memory_pressure` export, if it has one.
+    on_memory_pressure: Option<TypedFunc<(), ()>>,
+}
 
-                        serial_println!(
-                            "[ESCALATION] Agent {} requests capability type={} detail='{}'",
-                            agent_pid,
-                            cap_type,
-                            detail_str
-                        );
+impl WasmTask {
+    pub fn agent_pid(&self) -> u64 {
+        self.store.data().agent_pid
+    }
 
-                        // Send IPC escalation to Kernel Supervisor (PID 0)
-                        let ipc_msg =
-                            alloc::format!("CAP_REQUEST:{}:{}:{}", agent_pid, cap_type, detail_str);
-                        let sender = crate::ipc::ProcessId(agent_pid);
-                        let _ = crate::ipc::send_message(
-                            sender,
-                            crate::ipc::KERNEL_SUPERVISOR_PID,
-                            ipc_msg.into_bytes(),
-                            Vec::new(),
-                        );
+    /// Ask the agent to free memory by calling its `on_memory_pressure` export.
+    /// Returns `Ok(false)` if the module does not export one. The callback runs to
+    /// completion outside the slice budget.
+    pub fn notify_memory_pressure(&mut self) -> Result<bool, String> {
+        let Some(callback) = self.on_memory_pressure else {
+            return Ok(false);
+        };
 
-                        // Auto-grant policy: for now, the kernel grants all requested capabilities.
-                        // In production, this would check a policy engine or prompt the user.
-                        match cap_type {
-                            0 => {
-                                // Network
-                                let cap = crate::capability::create_capability(
-                                    crate::capability::Capability::Network,
-                                );
-                                crate::task::grant_capability_to_agent(
-                                    crate::task::AgentId(agent_pid),
-                                    cap,
-                                );
-                                serial_println!(
-                                    "[ESCALATION] Granted Network to Agent {}",
-                                    agent_pid
-                                );
-                                Ok(0)
-                            }
-                            1 => {
-                                // FileSystem
-                                let prefix = if detail_str.is_empty() {
-                                    "/agent/"
-                                } else {
-                                    detail_str
-                                };
-                                let cap = crate::capability::create_capability(
-                                    crate::capability::Capability::FileSystem {
-                                        path_prefix: String::from(prefix),
-                                        read: true,
-                                        write: true,
-                                    },
-                                );
-                                crate::task::grant_capability_to_agent(
-                                    crate::task::AgentId(agent_pid),
-                                    cap,
-                                );
-                                serial_println!(
-                                    "[ESCALATION] Granted FileSystem('{}') to Agent {}",
-                                    prefix,
-                                    agent_pid
-                                );
-                                Ok(0)
-                            }
-                            2 => {
-                                // Spawn
-                                let cap = crate::capability::create_capability(
-                                    crate::capability::Capability::Spawn { max_children: 5 },
-                                );
-                                crate::task::grant_capability_to_agent(
-                                    crate::task::AgentId(agent_pid),
-                                    cap,
-                                );
-                                serial_println!(
-                                    "[ESCALATION] Granted Spawn to Agent {}",
-                                    agent_pid
-                                );
-                                Ok(0)
-                            }
-                            _ => {
-                                serial_println!(
-                                    "[ESCALATION] Unknown capability type {} from Agent {}",
-                                    cap_type,
-                                    agent_pid
-                                );
-                                Ok(1) // Unknown type
-                            }
-                        }
-                    },
-                ),
-            )
-            .map_err(|e| alloc::format!("Failed to define request_capability: {e}"))?;
+        let slice_end = self.store.data_mut().slice_end.take();
+        let result = callback.call(&mut self.store, ());
+        self.store.data_mut().slice_end = slice_end;
+        result
+            .map(|()| true)
+            .map_err(|e| alloc::format!("{MEMORY_PRESSURE_EXPORT} failed: {e}"))
+    }
 
-        let instance = linker
-            .instantiate(&mut store, &module)
-            .map_err(|e| alloc::format!("Failed to instantiate module: {e}"))?
-            .start(&mut store)
-            .map_err(|e| alloc::format!("Failed to start module: {e}"))?;
+    /// Run the agent until it finishes or spends `FUEL_SLICE` fuel. The agent is
+    /// suspended at the next host call, function entry or loop head after that (see
+    /// `preempt`), so a compute-bound agent cannot hold the executor.
+    pub fn run_slice(&mut self) -> Result<TaskStatus, String> {
+        if let Some(wait) = &self.store.data().lock_wait {
+            let result = if crate::locks::try_acquire(&wait.name, self.agent_pid()) {
+                OK
+            } else if crate::time::uptime_ms() >= wait.deadline_ms {
+                ERR_TIMEOUT
+            } else {
+                return Ok(TaskStatus::Yielded);
+            };
+            let state = self.store.data_mut();
+            state.lock_wait = None;
+            state.pending_return = result.to_values();
+        }
+        if let Some(wait) = self.store.data_mut().dns_wait.as_mut() {
+            let Some(result) = wait.lookup.poll() else {
+                return Ok(TaskStatus::Yielded);
+            };
+            let (memory, out_ip_ptr) = (wait.memory, wait.out_ip_ptr);
+            self.store.data_mut().dns_wait = None;
+            let status = match result {
+                Ok(ip) => {
+                    // `resolve_dns` checked the range, and memory never shrinks.
+                    memory
+                        .write(&mut self.store, out_ip_ptr as usize, &ip)
+                        .map_err(|e| alloc::format!("Execution failed: {e}"))?;
+                    OK
+                }
+                Err(e) => dns_status(e),
+            };
+            self.store.data_mut().pending_return = status.to_values();
+        }
+
+        let consumed = self.store.fuel_consumed().unwrap_or(0);
+        self.store.data_mut().slice_end = Some(consumed + FUEL_SLICE);
+
+        let call = match self.invocation.take() {
+            None => self
+                .entry
+                .call_resumable(&mut self.store, ())
+                .map_err(|e| alloc::format!("Execution failed: {e}"))?,
+            Some(invocation) => {
+                let inputs = core::mem::take(&mut self.store.data_mut().pending_return);
+                invocation
+                    .resume(&mut self.store, &inputs)
+                    .map_err(|e| alloc::format!("Execution failed: {e}"))?
+            }
+        };
+
+        match call {
+            TypedResumableCall::Finished(()) => Ok(TaskStatus::Finished),
+            TypedResumableCall::Resumable(invocation) => {
+                if invocation.host_error().downcast_ref::<Yield>().is_none() {
+                    return Err(alloc::format!(
+                        "Execution failed: {}",
+                        invocation.host_error()
+                    ));
+                }
+                self.invocation = Some(invocation);
+                Ok(TaskStatus::Yielded)
+            }
+        }
+    }
+}
+
+/// Name of the custom section carrying a module's HMAC-SHA256 signature.
+pub const SIGNATURE_SECTION: &[u8] = b"signature";
+/// A signed module ends with a custom section: id 0, size, name length, the name, then
+/// the 32-byte MAC over every byte before the section.
+const SIGNATURE_TRAILER_LEN: usize = 3 + SIGNATURE_SECTION.len() + 32;
+
+/// Split a signed module into the signed bytes and the MAC from its trailing
+/// `signature` custom section. Returns `None` if the module does not end with one.
+pub fn split_signature(wasm_bytes: &[u8]) -> Option<(&[u8], [u8; 32])> {
+    let trailer_start = wasm_bytes.len().checked_sub(SIGNATURE_TRAILER_LEN)?;
+    let (signed, trailer) = wasm_bytes.split_at(trailer_start);
+    let (header, mac) = trailer.split_at(3 + SIGNATURE_SECTION.len());
+    let section_len = (SIGNATURE_TRAILER_LEN - 2) as u8;
+    if header[..3] != [0x00, section_len, SIGNATURE_SECTION.len() as u8]
+        || &header[3..] != SIGNATURE_SECTION
+    {
+        return None;
+    }
+    Some((signed, mac.try_into().ok()?))
+}
+
+/// Append a `signature` custom section holding the HMAC-SHA256 of `wasm_bytes` under `key`.
+pub fn sign_module(wasm_bytes: &[u8], key: &[u8]) -> Vec<u8> {
+    let mac = crate::crypto::hmac_sha256(key, wasm_bytes);
+    let mut signed = Vec::with_capacity(wasm_bytes.len() + SIGNATURE_TRAILER_LEN);
+    signed.extend_from_slice(wasm_bytes);
+    signed.extend_from_slice(&[
+        0x00,
+        (SIGNATURE_TRAILER_LEN - 2) as u8,
+        SIGNATURE_SECTION.len() as u8,
+    ]);
+    signed.extend_from_slice(SIGNATURE_SECTION);
+    signed.extend_from_slice(&mac);
+    signed
+}
+
+/// Compiled modules keyed by the SHA-256 of their bytes, so running the same module
+/// again (e.g. a supervised restart) skips compilation. Holds at most `capacity`
+/// modules, evicting the least recently used.
+struct ModuleCache {
+    entries: BTreeMap<[u8; 32], CachedModule>,
+    capacity: usize,
+    /// Bumped on every lookup and insert; orders entries by recency.
+    clock: u64,
+}
+
+struct CachedModule {
+    module: Arc<Module>,
+    last_used: u64,
+}
+
+impl ModuleCache {
+    fn new(capacity: usize) -> Self {
+        ModuleCache {
+            entries: BTreeMap::new(),
+            capacity,
+            clock: 0,
+        }
+    }
+
+    fn get(&mut self, key: &[u8; 32]) -> Option<Arc<Module>> {
+        self.clock += 1;
+        let entry = self.entries.get_mut(key)?;
+        entry.last_used = self.clock;
+        Some(entry.module.clone())
+    }
+
+    fn insert(&mut self, key: [u8; 32], module: Arc<Module>) {
+        self.clock += 1;
+        self.entries.insert(
+            key,
+            CachedModule {
+                module,
+                last_used: self.clock,
+            },
+        );
+        self.shrink();
+    }
+
+    fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.shrink();
+    }
+
+    /// Evict least-recently-used entries until at most `capacity` remain.
+    fn shrink(&mut self) {
+        while self.entries.len() > self.capacity {
+            let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| *key)
+            else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+    }
+}
+
+/// Where agent log lines (`debug_log`, `debug_log_kv`) are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogSinks {
+    pub serial: bool,
+    pub vga: bool,
+}
+
+impl Default for LogSinks {
+    fn default() -> Self {
+        LogSinks {
+            serial: true,
+            vga: true,
+        }
+    }
+}
+
+/// Longest `debug_log` message printed by default; the rest is cut off.
+pub const DEFAULT_MAX_LOG_LEN: usize = 1024;
+
+/// Runtime-wide log settings plus per-agent sink overrides, shared with running agents
+/// so changes apply to their next log line.
+struct LogRouting {
+    default: LogSinks,
+    per_agent: BTreeMap<u64, LogSinks>,
+    max_len: usize,
+}
+
+impl Default for LogRouting {
+    fn default() -> Self {
+        LogRouting {
+            default: LogSinks::default(),
+            per_agent: BTreeMap::new(),
+            max_len: DEFAULT_MAX_LOG_LEN,
+        }
+    }
+}
+
+impl LogRouting {
+    fn sinks_for(&self, agent_pid: u64) -> LogSinks {
+        self.per_agent
+            .get(&agent_pid)
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+#[derive(Clone)]
+pub struct WasmRuntime {
+    engine: Engine,
+    trace: bool,
+    /// HMAC key modules must be signed with; `None` disables verification.
+    verify_key: Option<Vec<u8>>,
+    /// Shared by clones of the runtime, which share its engine.
+    module_cache: Arc<spin::Mutex<ModuleCache>>,
+    log_routing: Arc<spin::Mutex<LogRouting>>,
+}
+
+impl WasmRuntime {
+    pub fn new() -> Self {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        Self {
+            engine,
+            trace: false,
+            verify_key: None,
+            module_cache: Arc::new(spin::Mutex::new(ModuleCache::new(
+                DEFAULT_MODULE_CACHE_CAPACITY,
+            ))),
+            log_routing: Arc::new(spin::Mutex::new(LogRouting::default())),
+        }
+    }
+
+    /// Choose where agent log lines go, for agents without an override. Both sinks
+    /// are on by default.
+    pub fn set_log_sinks(&self, serial: bool, vga: bool) {
+        self.log_routing.lock().default = LogSinks { serial, vga };
+    }
+
+    /// Cap `debug_log` messages at `max_len` bytes; longer ones end in a truncation
+    /// marker.
+    pub fn set_max_log_len(&self, max_len: usize) {
+        self.log_routing.lock().max_len = max_len;
+    }
+
+    /// Route one agent's log lines differently from the rest, e.g. keep a chatty agent
+    /// off the screen. `None` removes the override. Takes effect immediately, also for
+    /// an agent that is already running.
+    pub fn set_agent_log_sinks(&self, agent_pid: u64, sinks: Option<LogSinks>) {
+        let mut routing = self.log_routing.lock();
+        match sinks {
+            Some(sinks) => routing.per_agent.insert(agent_pid, sinks),
+            None => routing.per_agent.remove(&agent_pid),
+        };
+    }
+
+    /// Keep at most `capacity` compiled modules, evicting the least recently used ones
+    /// now if there are more. 0 disables caching.
+    pub fn set_cache_capacity(&self, capacity: usize) {
+        self.module_cache.lock().set_capacity(capacity);
+    }
+
+    /// Compile `wasm_bytes`, or reuse the module compiled from identical bytes before.
+    fn compile(&self, wasm_bytes: &[u8]) -> Result<Arc<Module>, String> {
+        let key = crate::crypto::sha256(wasm_bytes);
+        if let Some(module) = self.module_cache.lock().get(&key) {
+            return Ok(module);
+        }
+        serial_println!(
+            "[WASM] Engine compiling module of length: {}",
+            wasm_bytes.len()
+        );
+        let instrumented = crate::preempt::instrument(wasm_bytes)
+            .map_err(|e| alloc::format!("Failed to compile module: {e}"))?;
+        let module = Arc::new(
+            Module::new(&self.engine, &instrumented[..])
+                .map_err(|e| alloc::format!("Failed to compile module: {e}"))?,
+        );
+        self.module_cache.lock().insert(key, module.clone());
+        Ok(module)
+    }
+
+    /// Require every module to carry a valid `signature` section made with `key`.
+    /// `None` turns verification off again.
+    pub fn set_verify_key(&mut self, key: Option<&[u8]>) {
+        self.verify_key = key.map(Vec::from);
+    }
+
+    /// Check a module's signature against the verify key, if one is configured.
+    fn verify_module(&self, wasm_bytes: &[u8]) -> Result<(), String> {
+        let Some(key) = &self.verify_key else {
+            return Ok(());
+        };
+        let (signed, mac) = split_signature(wasm_bytes)
+            .ok_or_else(|| String::from("Module rejected: missing signature"))?;
+        if !crate::crypto::digest_eq(&crate::crypto::hmac_sha256(key, signed), &mac) {
+            return Err(String::from("Module rejected: invalid signature"));
+        }
+        Ok(())
+    }
+
+    /// Enable or disable syscall tracing for modules executed by this runtime.
+    /// Each host call is logged as `[TRACE pid=N] name(args) -> result`.
+    pub fn set_trace(&mut self, enabled: bool) {
+        self.trace = enabled;
+    }
+
+    /// Run a supervised agent's module from its VFS path, re-running it according to
+    /// the agent's `RestartPolicy` with exponential backoff between attempts. The agent
+    /// runs on the executor alongside every other queued agent, and a restart is queued
+    /// with a not-before deadline rather than slept on, so the backoff stalls nobody.
+    /// Returns the outcome of the final run; the agent is left `Exited`, or
+    /// `Terminated` if it was killed for memory.
+    pub fn supervise(&self, agent_id: AgentId) -> Result<(), String> {
+        let outcome = Arc::new(spin::Mutex::new(None));
+        self.start_supervised(agent_id, 1, outcome.clone());
+        loop {
+            if let Some(result) = outcome.lock().take() {
+                return result;
+            }
+            if !crate::task::run_executor_step() {
+                return Err(String::from(
+                    "Supervised agent was dropped before it exited",
+                ));
+            }
+            crate::task::wait_for_deferred_spawns();
+        }
+    }
+
+    /// Load a supervised agent's module and queue it on the executor, with an exit hook
+    /// that hands the outcome to `supervised_exit`. A start that fails transiently is
+    /// queued again after `START_RETRY_DELAY_MS`, up to `MAX_START_ATTEMPTS` attempts.
+    fn start_supervised(&self, agent_id: AgentId, attempt: u32, outcome: SupervisedOutcome) {
+        let pid = crate::task::agent_pid(agent_id);
+        let loaded = match crate::task::agent_module_path(agent_id) {
+            Some(path) => match crate::vfs::open_file(&path) {
+                Some(wasm_bytes) => match self.instantiate_task(&wasm_bytes, pid) {
+                    Err(err) if err.is_retryable() && attempt < MAX_START_ATTEMPTS => {
+                        serial_println!(
+                            "[SUPERVISOR] Agent {pid} start failed transiently ({err}); retrying (attempt {attempt}/{MAX_START_ATTEMPTS})"
+                        );
+                        self.queue_supervised(agent_id, START_RETRY_DELAY_MS, attempt + 1, outcome);
+                        return;
+                    }
+                    result => result.map_err(String::from),
+                },
+                None => Err(alloc::format!("Module {path} not found in VFS")),
+            },
+            None => Err(String::from("Agent has no module path")),
+        };
+        match loaded {
+            Ok(task) => {
+                let runtime = self.clone();
+                crate::task::on_exit(
+                    agent_id,
+                    Box::new(move |result| runtime.supervised_exit(agent_id, result, outcome)),
+                );
+                crate::task::spawn_task(agent_id, task);
+            }
+            Err(err) => self.supervised_exit(agent_id, Err(err), outcome),
+        }
+    }
+
+    /// Reclaim a supervised agent after a run and either queue its restart after the
+    /// backoff or report `result` as the final outcome.
+    fn supervised_exit(
+        &self,
+        agent_id: AgentId,
+        result: Result<(), String>,
+        outcome: SupervisedOutcome,
+    ) {
+        let pid = crate::task::agent_pid(agent_id);
+        crate::task::reclaim_resources(pid);
+
+        if !crate::task::should_restart(agent_id, result.is_ok()) {
+            *outcome.lock() = Some(result);
+            return;
+        }
+
+        let restarts = crate::task::restart_count(agent_id);
+        let backoff = (RESTART_BACKOFF_MS << (restarts - 1).min(16)).min(MAX_RESTART_BACKOFF_MS);
+        serial_println!(
+            "[SUPERVISOR] Restarting Agent {} ({}) in {}ms (restart #{})",
+            pid,
+            crate::task::agent_module_path(agent_id).unwrap_or_default(),
+            backoff,
+            restarts
+        );
+        self.queue_supervised(agent_id, backoff, 1, outcome);
+    }
+
+    /// Queue `start_supervised` on the executor's spawn queue, due in `delay_ms`.
+    fn queue_supervised(
+        &self,
+        agent_id: AgentId,
+        delay_ms: u64,
+        attempt: u32,
+        outcome: SupervisedOutcome,
+    ) {
+        let runtime = self.clone();
+        let slot = outcome.clone();
+        let queued = crate::task::queue_spawn_after(
+            agent_id,
+            crate::time::uptime_ms() + delay_ms,
+            Box::new(move || {
+                runtime.start_supervised(agent_id, attempt, slot);
+                Ok(())
+            }),
+        );
+        if let Err(code) = queued {
+            *outcome.lock() = Some(Err(alloc::format!(
+                "Restart could not be queued (error {code})"
+            )));
+        }
+    }
+
+    /// Run a module to completion on the calling thread, resuming it after every slice
+    /// (see `task::run_to_completion`).
+    pub fn execute_module(&self, wasm_bytes: &[u8], agent_pid: u64) -> Result<(), String> {
+        let mut task = self.instantiate_task(wasm_bytes, agent_pid)?;
+        crate::task::run_to_completion(&mut task)
+    }
+
+    /// Run a module to completion under `profile`: its capabilities and rate limit are
+    /// granted to `agent_pid`, its log level becomes the agent's log routing, and the
+    /// module gets the profile's fuel and memory budget.
+    pub fn execute_sandboxed(
+        &self,
+        wasm_bytes: &[u8],
+        agent_pid: u64,
+        profile: &SandboxProfile,
+    ) -> Result<(), String> {
+        profile.grant(agent_pid);
+        self.set_agent_log_sinks(agent_pid, Some(profile.log_level.sinks()));
+        let limits = InstanceLimits {
+            fuel: profile.fuel,
+            max_memory_pages: Some(profile.max_memory_pages),
+        };
+        let mut task = self.instantiate_task_limited(wasm_bytes, agent_pid, &limits)?;
+        crate::task::run_to_completion(&mut task)
+    }
+
+    /// Snapshot of the per-host-function latency histograms collected so far.
+    pub fn latency_report(&self) -> BTreeMap<&'static str, LatencyHistogram> {
+        LATENCY.lock().clone()
+    }
+
+    /// Run a module to completion and report the fuel it consumed along with call
+    /// counts and payload bytes per host function.
+    pub fn profile(&self, wasm_bytes: &[u8], agent_pid: u64) -> Result<ProfileReport, String> {
+        let mut task = self.instantiate_task(wasm_bytes, agent_pid)?;
+        crate::task::run_to_completion(&mut task)?;
+        Ok(ProfileReport {
+            fuel_consumed: task.store.fuel_consumed().unwrap_or(0),
+            host_calls: core::mem::take(&mut task.store.data_mut().host_calls),
+        })
+    }
+
+    /// Compile and instantiate a module for `agent_pid` and queue it on the executor,
+    /// where it is interleaved with other agents a fuel slice at a time.
+    pub fn spawn_module(&self, wasm_bytes: &[u8], agent_pid: u64) -> Result<(), String> {
+        let task = self.instantiate_task(wasm_bytes, agent_pid)?;
+        crate::task::spawn_task(AgentId(agent_pid), task);
+        Ok(())
+    }
+
+    /// Compile, link and instantiate a module, returning it ready to run its entry point.
+    /// Start-function failures are classified with `classify_start_error`.
+    pub fn instantiate_task(
+        &self,
+        wasm_bytes: &[u8],
+        agent_pid: u64,
+    ) -> Result<WasmTask, LoadError> {
+        self.instantiate_task_limited(wasm_bytes, agent_pid, &DEFAULT_LIMITS)
+    }
+
+    fn instantiate_task_limited(
+        &self,
+        wasm_bytes: &[u8],
+        agent_pid: u64,
+        limits: &InstanceLimits,
+    ) -> Result<WasmTask, LoadError> {
+        let (store, instance) = self.link_instance(wasm_bytes, agent_pid, limits)?;
 
         // Look for an "_start" or "main" function to execute
         let start_func = instance
@@ -574,18 +923,5546 @@ impl WasmRuntime {
             .typed::<(), ()>(&store)
             .map_err(|e| alloc::format!("Start func has wrong signature: {e}"))?;
 
-        typed_func
-            .call(&mut store, ())
-            .map_err(|e| alloc::format!("Execution failed: {e}"))?;
+        let on_memory_pressure = instance
+            .get_func(&store, MEMORY_PRESSURE_EXPORT)
+            .and_then(|f| f.typed::<(), ()>(&store).ok());
 
-        Ok(())
+        Ok(WasmTask {
+            store,
+            entry: typed_func,
+            invocation: None,
+            on_memory_pressure,
+        })
     }
-}
 
-// Helper to extract the single exported memory from a Caller
-fn get_memory<'a>(caller: &mut wasmi::Caller<'a, WasmState>) -> Result<Memory, Trap> {
-    caller
-        .get_export("memory")
-        .and_then(Extern::into_memory)
-        .ok_or_else(|| Trap::from(HostError(String::from("Failed to find 'memory' export"))))
+    /// Instantiate a module without running an entry point, for modules used as
+    /// libraries or long-lived services. Call its exports with `call_export`.
+    pub fn instantiate(
+        &self,
+        wasm_bytes: &[u8],
+        agent_pid: u64,
+    ) -> Result<InstanceHandle, LoadError> {
+        let (store, instance) = self.link_instance(wasm_bytes, agent_pid, &DEFAULT_LIMITS)?;
+        Ok(InstanceHandle { store, instance })
+    }
+
+    /// Call the exported function `name` on an instantiated module and return its results.
+    /// The call runs to completion; it is not sliced by the executor.
+    pub fn call_export(
+        &self,
+        handle: &mut InstanceHandle,
+        name: &str,
+        args: &[Value],
+    ) -> Result<Vec<Value>, String> {
+        let func = handle
+            .instance
+            .get_func(&handle.store, name)
+            .ok_or_else(|| alloc::format!("No export named {name}"))?;
+        let mut results: Vec<Value> = func
+            .ty(&handle.store)
+            .results()
+            .iter()
+            .map(|&ty| Value::default(ty))
+            .collect();
+        func.call(&mut handle.store, args, &mut results)
+            .map_err(|e| alloc::format!("Call to {name} failed: {e}"))?;
+        Ok(results)
+    }
+
+    /// Verify, compile, link and instantiate a module, running its start section.
+    /// Fails before compiling if a verify key is set and the signature does not match.
+    fn link_instance(
+        &self,
+        wasm_bytes: &[u8],
+        agent_pid: u64,
+        limits: &InstanceLimits,
+    ) -> Result<(Store<WasmState>, Instance), LoadError> {
+        self.verify_module(wasm_bytes)?;
+        let module = self.compile(wasm_bytes)?;
+        self.link_module(module, agent_pid, limits)
+    }
+
+    /// Link and instantiate an already compiled module, running its start section.
+    fn link_module(
+        &self,
+        module: Arc<Module>,
+        agent_pid: u64,
+        limits: &InstanceLimits,
+    ) -> Result<(Store<WasmState>, Instance), LoadError> {
+        let mut store = Store::new(
+            &self.engine,
+            WasmState {
+                agent_pid,
+                trace: self.trace,
+                cwd: alloc::format!("/agent/{agent_pid}/"),
+                slice_end: None,
+                pending_return: Vec::new(),
+                host_calls: BTreeMap::new(),
+                current_call: "",
+                write_sessions: BTreeMap::new(),
+                next_write_session: FIRST_FILE_HANDLE,
+                lock_wait: None,
+                dns_wait: None,
+                starting: false,
+                kv_line: Vec::new(),
+                log_routing: self.log_routing.clone(),
+                limits: limits.store_limits(),
+            },
+        );
+        store.limiter(|state| &mut state.limits);
+        store
+            .add_fuel(limits.fuel)
+            .map_err(|e| alloc::format!("Failed to add fuel: {e}"))?;
+
+        let mut linker = <Linker<WasmState>>::new(&self.engine);
+
+        // Injected into every module by `preempt::instrument` at function entries and
+        // loop heads: suspends the module once the slice's fuel is spent.
+        linker
+            .func_wrap(
+                crate::preempt::PREEMPT_MODULE,
+                crate::preempt::PREEMPT_FUNC,
+                |caller: wasmi::Caller<'_, WasmState>| -> Result<(), Trap> {
+                    match caller.data().slice_end {
+                        Some(slice_end) if caller.fuel_consumed().unwrap_or(0) >= slice_end => {
+                            Err(Trap::from(Yield))
+                        }
+                        _ => Ok(()),
+                    }
+                },
+            )
+            .map_err(|e| alloc::format!("Failed to define preemption check: {e}"))?;
+
+        let mut host = HostModule {
+            linker: &mut linker,
+        };
+
+        // Host Function: env.debug_log(ptr, len)
+        // Allows the Wasm module to print to the microkernel's serial output.
+        host.register(
+            "debug_log",
+            |mut caller: wasmi::Caller<'_, WasmState>, ptr: u32, len: u32| -> Result<(), Trap> {
+                traced(
+                    &mut caller,
+                    "debug_log",
+                    format_args!("{ptr}, {len}"),
+                    |caller| {
+                        let max_len = caller.data().log_routing.lock().max_len;
+                        let buf = read_bytes(caller, ptr, len.min(max_len as u32))?;
+                        caller.data_mut().add_bytes(buf.len());
+
+                        let message = render_log_message(&buf, len as usize);
+                        let state = caller.data();
+                        state
+                            .log_line(format_args!("[Wasm Agent {}] {}", state.agent_pid, message));
+                        Ok(())
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.debug_log_kv(key_ptr, key_len, val_ptr, val_len)
+        // Structured logging: buffers `key=val` and emits the buffered pairs as one
+        // line, prefixed with the agent PID, when called with an empty key.
+        host.register(
+            "debug_log_kv",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             key_ptr: u32,
+             key_len: u32,
+             val_ptr: u32,
+             val_len: u32|
+             -> Result<(), Trap> {
+                traced(
+                    &mut caller,
+                    "debug_log_kv",
+                    format_args!("{key_ptr}, {key_len}, {val_ptr}, {val_len}"),
+                    |caller| {
+                        if key_len == 0 {
+                            let pid = caller.data().agent_pid;
+                            let pairs = core::mem::take(&mut caller.data_mut().kv_line);
+                            if !pairs.is_empty() {
+                                let line = pairs.join(" ");
+                                caller.data().log_line(format_args!("[Agent {pid}] {line}"));
+                            }
+                            return Ok(());
+                        }
+                        let key_buf = read_bytes(caller, key_ptr, key_len)?;
+                        let val_buf = read_bytes(caller, val_ptr, val_len)?;
+                        caller.data_mut().add_bytes(key_buf.len() + val_buf.len());
+
+                        let key = String::from_utf8_lossy(&key_buf);
+                        let val = String::from_utf8_lossy(&val_buf);
+                        caller
+                            .data_mut()
+                            .kv_line
+                            .push(alloc::format!("{key}={val}"));
+                        Ok(())
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.spawn_agent_with_caps(wasm_ptr, wasm_len, cap_ids_ptr, cap_count) -> u64
+        // Spawn a child agent running the given module and queue it on the executor.
+        // cap_ids_ptr holds cap_count u64le capability ids the child starts with; each
+        // must be held by the caller. Returns the child's PID, or 0 if the caller may
+        // not spawn (including when its Spawn capability is limited to an
+        // allowed_prefix), lacks one of the capabilities or the spawn queue is full. The child
+        // stays Pending until the executor instantiates it from the spawn queue; if the
+        // module fails to load the child is terminated.
+        let runtime = self.clone();
+        host.register(
+            "spawn_agent_with_caps",
+            move |mut caller: wasmi::Caller<'_, WasmState>,
+                  wasm_ptr: u32,
+                  wasm_len: u32,
+                  cap_ids_ptr: u32,
+                  cap_count: u32|
+                  -> Result<u64, Trap> {
+                traced(
+                    &mut caller,
+                    "spawn_agent_with_caps",
+                    format_args!("{wasm_ptr}, {wasm_len}, {cap_ids_ptr}, {cap_count}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        if cap_count > MAX_INHERITED_CAPS {
+                            return Ok(0);
+                        }
+                        let parent_caps = agent_capabilities(AgentId(agent_pid));
+                        if crate::capability::can_spawn(&parent_caps)
+                            && !crate::capability::can_spawn_bytes(&parent_caps)
+                        {
+                            crate::audit::log_denial(
+                                agent_pid,
+                                format_args!(
+                                    "Agent {agent_pid} denied spawning raw module bytes"
+                                ),
+                            );
+                            return Ok(0);
+                        }
+                        let cap_bytes = read_bytes(caller, cap_ids_ptr, cap_count * 8)?;
+                        let caps: Vec<CapabilityId> = cap_bytes
+                            .chunks_exact(8)
+                            .filter_map(|id| crate::bytes::read_u64_le(id, 0))
+                            .map(CapabilityId)
+                            .collect();
+                        let wasm = read_bytes(caller, wasm_ptr, wasm_len)?;
+                        caller.data_mut().add_bytes(wasm.len());
+
+                        let name = alloc::format!("child-of-{agent_pid}");
+                        let child = match crate::task::spawn_agent_with_caps(
+                            &name,
+                            AgentId(agent_pid),
+                            &caps,
+                        ) {
+                            Ok(child) => child,
+                            Err(_) => return Ok(0),
+                        };
+                        let child_pid = crate::task::agent_pid(child);
+                        let runtime = runtime.clone();
+                        let load = Box::new(move || runtime.spawn_module(&wasm, child_pid));
+                        if let Err(e) = crate::task::queue_spawn(child, load) {
+                            serial_println!(
+                                "[SPAWN] Agent {agent_pid} spawn queue full (error {e})"
+                            );
+                            crate::task::terminate_agent(child);
+                            return Ok(0);
+                        }
+
+                        serial_println!(
+                            "[SPAWN] Agent {agent_pid} spawned Agent {child_pid} with {} capabilities",
+                            caps.len()
+                        );
+                        Ok(child_pid)
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.spawn_from_file(path_ptr, path_len) -> u64
+        // Spawn a child agent running the module stored at a VFS path. The path must lie
+        // under the allowed_prefix of one of the caller's Spawn capabilities (any path
+        // if one has none). The module is read from the shared VFS (the lower layer for
+        // an overlay path), never the caller's overlay copies. The child starts with no capabilities. Returns its PID, or 0
+        // if the spawn is denied, the file is missing or the spawn queue is full.
+        let runtime = self.clone();
+        host.register(
+            "spawn_from_file",
+            move |mut caller: wasmi::Caller<'_, WasmState>,
+                  path_ptr: u32,
+                  path_len: u32|
+                  -> Result<u64, Trap> {
+                traced(
+                    &mut caller,
+                    "spawn_from_file",
+                    format_args!("{path_ptr}, {path_len}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        let caps = agent_capabilities(AgentId(agent_pid));
+                        let path = read_str(caller, path_ptr, path_len)?;
+                        let Some(path) = crate::vfs::resolve_path(&caller.data().cwd, &path) else {
+                            return Ok(0);
+                        };
+                        if !crate::capability::can_spawn_from(&caps, &path) {
+                            crate::audit::log_denial(
+                                agent_pid,
+                                format_args!("Agent {agent_pid} denied spawning {path}"),
+                            );
+                            return Ok(0);
+                        }
+                        // The kernel has no upper layer, so this is the shared view.
+                        let Some(wasm) = crate::vfs::open_file_as(&path, 0) else {
+                            return Ok(0);
+                        };
+
+                        let child = match crate::task::spawn_agent_with_caps(
+                            &path,
+                            AgentId(agent_pid),
+                            &[],
+                        ) {
+                            Ok(child) => child,
+                            Err(_) => return Ok(0),
+                        };
+                        let child_pid = crate::task::agent_pid(child);
+                        let runtime = runtime.clone();
+                        let load = Box::new(move || runtime.spawn_module(&wasm, child_pid));
+                        if let Err(e) = crate::task::queue_spawn(child, load) {
+                            serial_println!(
+                                "[SPAWN] Agent {agent_pid} spawn queue full (error {e})"
+                            );
+                            crate::task::terminate_agent(child);
+                            return Ok(0);
+                        }
+
+                        serial_println!(
+                            "[SPAWN] Agent {agent_pid} spawned Agent {child_pid} from {path}"
+                        );
+                        Ok(child_pid)
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.register_dynamic_file(path_ptr, path_len) -> u32
+        // Register a read-only file whose reads are answered by this module's
+        // `read_dynamic_file(id) -> u64` export, run in a separate library instance.
+        // Files are numbered from 0 in registration order. The path must lie under
+        // /agent/<pid>/ and the caller needs Capability::ServeFiles. The files are
+        // removed when the agent exits. The library instance is loaded between executor
+        // slices, so the first registration ends the caller's slice; by the time it
+        // resumes its files can be read.
+        let runtime = self.clone();
+        let served_module = module.clone();
+        host.register(
+            "register_dynamic_file",
+            move |mut caller: wasmi::Caller<'_, WasmState>,
+                  path_ptr: u32,
+                  path_len: u32|
+                  -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "register_dynamic_file",
+                    format_args!("{path_ptr}, {path_len}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        let caps = agent_capabilities(AgentId(agent_pid));
+
+                        let path = read_str(caller, path_ptr, path_len)?;
+                        let Some(path) = crate::vfs::resolve_path(&caller.data().cwd, &path) else {
+                            return Ok(ERR_INVALID_ARGUMENT);
+                        };
+                        let namespace = alloc::format!("/agent/{agent_pid}/");
+                        if !crate::capability::can_serve_files(&caps)
+                            || !path.starts_with(&namespace)
+                        {
+                            crate::audit::log_denial(
+                                agent_pid,
+                                format_args!("Agent {agent_pid} denied serving file: {path}"),
+                            );
+                            return Ok(ERR_PERMISSION_DENIED);
+                        }
+                        if served_module.get_export(FILE_GENERATOR_EXPORT).is_none() {
+                            return Ok(ERR_INVALID_ARGUMENT);
+                        }
+
+                        let mut servers = FILE_SERVERS.lock();
+                        let is_new = !servers.contains_key(&agent_pid);
+                        let server = servers.entry(agent_pid).or_insert_with(|| FileServer {
+                            files: Vec::new(),
+                            handle: None,
+                        });
+                        if server.files.len() >= MAX_SERVED_FILES {
+                            return Ok(ERR_QUOTA_EXCEEDED);
+                        }
+                        if !crate::vfs::register_agent_file(&path, agent_pid, read_agent_file) {
+                            if is_new {
+                                servers.remove(&agent_pid);
+                            }
+                            return Ok(ERR_GENERAL); // Name already served
+                        }
+                        server.files.push(path.clone());
+                        drop(servers);
+
+                        if is_new {
+                            PENDING_LOADS.lock().push_back(PendingLoad::FileServer {
+                                runtime: runtime.clone(),
+                                module: served_module.clone(),
+                                agent_pid,
+                            });
+                            if let Some(slice_end) = caller.data_mut().slice_end.as_mut() {
+                                *slice_end = 0;
+                            }
+                        }
+                        serial_println!("[VFS] Agent {agent_pid} serving {path}");
+                        Ok(OK)
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.register_name(name_ptr, name_len) -> u32
+        // Publish the caller under a service name for env.resolve_agent. Returns OK,
+        // ERR_INVALID_ARGUMENT (empty or too long) or ERR_PERMISSION_DENIED (held by
+        // another running agent).
+        host.register(
+            "register_name",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             name_ptr: u32,
+             name_len: u32|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "register_name",
+                    format_args!("{name_ptr}, {name_len}"),
+                    |caller| {
+                        if name_len as usize > crate::task::MAX_SERVICE_NAME_LEN {
+                            return Ok(ERR_INVALID_ARGUMENT);
+                        }
+                        let name = read_str(caller, name_ptr, name_len)?;
+                        let agent_pid = caller.data().agent_pid;
+                        Ok(
+                            match crate::task::register_name(AgentId(agent_pid), &name) {
+                                Ok(()) => OK,
+                                Err(crate::task::NameError::Taken) => ERR_PERMISSION_DENIED,
+                                Err(_) => ERR_INVALID_ARGUMENT,
+                            },
+                        )
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.resolve_agent(name_ptr, name_len) -> u64
+        // PID of the running agent registered under a service name, or 0 if none.
+        host.register(
+            "resolve_agent",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             name_ptr: u32,
+             name_len: u32|
+             -> Result<u64, Trap> {
+                traced(
+                    &mut caller,
+                    "resolve_agent",
+                    format_args!("{name_ptr}, {name_len}"),
+                    |caller| {
+                        if name_len as usize > crate::task::MAX_SERVICE_NAME_LEN {
+                            return Ok(0);
+                        }
+                        let name = read_str(caller, name_ptr, name_len)?;
+                        Ok(crate::task::lookup_name(&name).map_or(0, crate::task::agent_pid))
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.send_ipc(target_pid, msg_ptr, msg_len)
+        host.register(
+            "send_ipc",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             target_pid: u64,
+             ptr: u32,
+             len: u32|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "send_ipc",
+                    format_args!("{target_pid}, {ptr}, {len}"),
+                    |caller| {
+                        let buf = read_bytes(caller, ptr, len)?;
+                        caller.data_mut().add_bytes(buf.len());
+
+                        let sender_pid = ProcessId(caller.data().agent_pid);
+                        let recipient_pid = ProcessId(target_pid);
+
+                        // SECURITY CHECK: Ensure Wasm Agent is granted the Capability to message target_pid!
+                        let sender_caps = agent_capabilities(AgentId(sender_pid.0));
+                        if !can_send_to(&sender_caps, target_pid) {
+                            crate::audit::log_denial(
+                                sender_pid.0,
+                                format_args!(
+                                    "Agent {} denied send to Agent {}",
+                                    sender_pid.0, target_pid
+                                ),
+                            );
+                            return Ok(2); // Permission Denied
+                        }
+
+                        // For now, we pass empty capabilities. In the future, the Wasm module could specify which capabilities to delegate.
+                        match send_message(sender_pid, recipient_pid, buf, Vec::new()) {
+                            Ok(_) => Ok(0), // Success
+                            Err(reason @ "Message queue full") => {
+                                transient_failure(caller, ERR_GENERAL, reason)
+                            }
+                            Err(_) => Ok(1), // General Error
+                        }
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.send_ipc_acked(target_pid, msg_ptr, msg_len) -> u64
+        // Like send_ipc, but requests a delivery acknowledgement. Returns the message id
+        // to pass to poll_ack, or 0 if the message could not be sent.
+        host.register(
+            "send_ipc_acked",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             target_pid: u64,
+             ptr: u32,
+             len: u32|
+             -> Result<u64, Trap> {
+                traced(
+                    &mut caller,
+                    "send_ipc_acked",
+                    format_args!("{target_pid}, {ptr}, {len}"),
+                    |caller| {
+                        let buf = read_bytes(caller, ptr, len)?;
+                        caller.data_mut().add_bytes(buf.len());
+
+                        let sender_pid = caller.data().agent_pid;
+                        let sender_caps = agent_capabilities(AgentId(sender_pid));
+                        if !can_send_to(&sender_caps, target_pid) {
+                            crate::audit::log_denial(
+                                sender_pid,
+                                format_args!(
+                                    "Agent {sender_pid} denied send to Agent {target_pid}"
+                                ),
+                            );
+                            return Ok(0);
+                        }
+
+                        Ok(crate::ipc::send_message_acked(
+                            ProcessId(sender_pid),
+                            ProcessId(target_pid),
+                            buf,
+                            Vec::new(),
+                        )
+                        .unwrap_or(0))
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.poll_ack(msg_id: u64) -> u32
+        // Returns OK once the recipient has received the message, ERR_PENDING while it is
+        // still queued, ERR_TIMEOUT if it went unreceived for ACK_TIMEOUT_MS, and
+        // ERR_NOT_FOUND for unknown ids. OK and ERR_TIMEOUT are reported only once.
+        host.register(
+            "poll_ack",
+            |mut caller: wasmi::Caller<'_, WasmState>, msg_id: u64| -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "poll_ack",
+                    format_args!("{msg_id}"),
+                    |caller| {
+                        let sender_pid = ProcessId(caller.data().agent_pid);
+                        Ok(match crate::ipc::ack_status(sender_pid, msg_id) {
+                            Some(AckStatus::Delivered) => OK,
+                            Some(AckStatus::Pending) => ERR_PENDING,
+                            Some(AckStatus::TimedOut) => ERR_TIMEOUT,
+                            None => ERR_NOT_FOUND,
+                        })
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.receive_ipc_kind(kind, sender_ptr, out_ptr, out_len_ptr) -> u32
+        // Receive the oldest message of one kind (0 = text, 1 = capability request,
+        // 2 = delivery ack), leaving other kinds queued. Writes the sender (u64le) to
+        // sender_ptr, the payload to out_ptr and its length to out_len_ptr; capabilities
+        // carried by the message join the caller's set. Returns OK, ERR_NOT_FOUND (no
+        // such message) or ERR_INVALID_ARGUMENT (unknown kind).
+        host.register(
+            "receive_ipc_kind",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             kind: u32,
+             sender_ptr: u32,
+             out_ptr: u32,
+             out_len_ptr: u32|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "receive_ipc_kind",
+                    format_args!("{kind}, {sender_ptr}, {out_ptr}, {out_len_ptr}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        let Some(kind) = crate::ipc::MessageKind::from_u32(kind) else {
+                            return Ok(ERR_INVALID_ARGUMENT);
+                        };
+                        let Some(message) =
+                            crate::ipc::receive_matching(ProcessId(agent_pid), kind)
+                        else {
+                            return Ok(ERR_NOT_FOUND);
+                        };
+
+                        for &cap in &message.capabilities {
+                            crate::task::grant_capability_to_agent(AgentId(agent_pid), cap);
+                        }
+                        write_bytes(caller, sender_ptr, &message.sender.0.to_le_bytes())?;
+                        write_bytes(caller, out_ptr, &message.data)?;
+                        caller.data_mut().add_bytes(message.data.len());
+                        write_u32(caller, out_len_ptr, message.data.len() as u32)?;
+                        Ok(OK)
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.set_dead_letters(enabled) -> u32
+        // Opt in (1) or out (0) of keeping messages send_ipc could not deliver (no
+        // endpoint or full queue) for a later retry. Opting out discards them.
+        host.register(
+            "set_dead_letters",
+            |mut caller: wasmi::Caller<'_, WasmState>, enabled: u32| -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "set_dead_letters",
+                    format_args!("{enabled}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        crate::ipc::set_dead_letters(ProcessId(agent_pid), enabled != 0);
+                        Ok(OK)
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.drain_dead_letters(out_ptr, out_len_ptr) -> u32
+        // Take the caller's undelivered messages. Output is a u32le count followed by,
+        // per message, recipient (u64le), data length (u32le) and the data, oldest
+        // first.
+        host.register(
+            "drain_dead_letters",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             out_ptr: u32,
+             out_len_ptr: u32|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "drain_dead_letters",
+                    format_args!("{out_ptr}, {out_len_ptr}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        let letters = crate::ipc::drain_dead_letters(ProcessId(agent_pid));
+                        let mut out = Vec::new();
+                        out.extend_from_slice(&(letters.len() as u32).to_le_bytes());
+                        for letter in &letters {
+                            out.extend_from_slice(&letter.recipient.0.to_le_bytes());
+                            out.extend_from_slice(&(letter.data.len() as u32).to_le_bytes());
+                            out.extend_from_slice(&letter.data);
+                        }
+
+                        write_bytes(caller, out_ptr, &out)?;
+                        caller.data_mut().add_bytes(out.len());
+                        write_u32(caller, out_len_ptr, out.len() as u32)?;
+                        Ok(OK)
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.inspect_ipc(target_pid, out_ptr, out_len_ptr) -> u32
+        // Non-destructive peek at another agent's IPC queue. Requires
+        // Capability::IpcInspect for target_pid; every attempt is audited. Output is a
+        // u32le message count followed by, per message, sender (u64le), data length
+        // (u32le) and the data.
+        host.register(
+            "inspect_ipc",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             target_pid: u64,
+             out_ptr: u32,
+             out_len_ptr: u32|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "inspect_ipc",
+                    format_args!("{target_pid}, {out_ptr}, {out_len_ptr}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        let caps = agent_capabilities(AgentId(agent_pid));
+                        let allowed = crate::capability::can_inspect_ipc(&caps, target_pid);
+                        crate::audit::record(crate::audit::AuditEvent::IpcInspect {
+                            inspector: agent_pid,
+                            target: target_pid,
+                            allowed,
+                        });
+                        if !allowed {
+                            crate::audit::log_denial(
+                                agent_pid,
+                                format_args!(
+                                    "Agent {agent_pid} denied IPC inspection of Agent {target_pid}"
+                                ),
+                            );
+                            return Ok(ERR_PERMISSION_DENIED);
+                        }
+
+                        let messages = crate::ipc::peek_messages(ProcessId(target_pid));
+                        let mut out = Vec::new();
+                        out.extend_from_slice(&(messages.len() as u32).to_le_bytes());
+                        for message in &messages {
+                            out.extend_from_slice(&message.sender.0.to_le_bytes());
+                            out.extend_from_slice(&(message.data.len() as u32).to_le_bytes());
+                            out.extend_from_slice(&message.data);
+                        }
+
+                        write_bytes(caller, out_ptr, &out)?;
+                        caller.data_mut().add_bytes(out.len());
+                        write_u32(caller, out_len_ptr, out.len() as u32)?;
+                        Ok(OK)
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.ipc_queue_stats(target_pid, out_len_ptr, out_cap_ptr) -> u32
+        // Writes how many messages are queued for target_pid and how many its queue holds
+        // (u32le each), so a sender can back off before hitting a full queue. Only the
+        // depth is revealed, so Capability::Process with send rights is enough;
+        // Capability::IpcInspect works too. Returns OK, ERR_PERMISSION_DENIED or
+        // ERR_NOT_FOUND.
+        host.register(
+            "ipc_queue_stats",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             target_pid: u64,
+             out_len_ptr: u32,
+             out_cap_ptr: u32|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "ipc_queue_stats",
+                    format_args!("{target_pid}, {out_len_ptr}, {out_cap_ptr}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        let caps = agent_capabilities(AgentId(agent_pid));
+                        if !can_send_to(&caps, target_pid)
+                            && !crate::capability::can_inspect_ipc(&caps, target_pid)
+                        {
+                            crate::audit::log_denial(
+                                agent_pid,
+                                format_args!(
+                                    "Agent {agent_pid} denied queue stats of Agent {target_pid}"
+                                ),
+                            );
+                            return Ok(ERR_PERMISSION_DENIED);
+                        }
+
+                        let Some((len, capacity)) = crate::ipc::queue_stats(ProcessId(target_pid))
+                        else {
+                            return Ok(ERR_NOT_FOUND);
+                        };
+                        write_u32(caller, out_len_ptr, len as u32)?;
+                        write_u32(caller, out_cap_ptr, capacity as u32)?;
+                        Ok(OK)
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.set_my_queue_depth(depth) -> u32
+        // Resize the caller's own IPC queue, e.g. to absorb bursts. Returns OK, or
+        // ERR_INVALID_ARGUMENT if depth is 0, above `ipc::max_queue_depth()` or below
+        // the number of messages already queued.
+        host.register(
+            "set_my_queue_depth",
+            |mut caller: wasmi::Caller<'_, WasmState>, depth: u32| -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "set_my_queue_depth",
+                    format_args!("{depth}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        Ok(
+                            match crate::ipc::set_queue_depth(ProcessId(agent_pid), depth as usize)
+                            {
+                                Ok(()) => OK,
+                                Err(_) => ERR_INVALID_ARGUMENT,
+                            },
+                        )
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.get_pid() -> u64
+        // The PID the agent was launched with, for telling others where to reply.
+        host.register(
+            "get_pid",
+            |mut caller: wasmi::Caller<'_, WasmState>| -> Result<u64, Trap> {
+                traced(&mut caller, "get_pid", format_args!(""), |caller| {
+                    Ok(caller.data().agent_pid)
+                })
+            },
+        )?;
+
+        // Host Function: env.get_parent_pid() -> u64
+        // The PID of the agent that spawned this one, or 0 for agents the kernel started.
+        host.register(
+            "get_parent_pid",
+            |mut caller: wasmi::Caller<'_, WasmState>| -> Result<u64, Trap> {
+                traced(&mut caller, "get_parent_pid", format_args!(""), |caller| {
+                    let agent_pid = caller.data().agent_pid;
+                    Ok(crate::task::agent_parent(AgentId(agent_pid)).map_or(0, |p| p.0))
+                })
+            },
+        )?;
+
+        // Host Function: env.poll_signal() -> u32
+        // Returns the mask of signals sent to the agent since the last poll (see the
+        // `task::SIGNAL_*` bits) and clears it. 0 means none are pending.
+        host.register(
+            "poll_signal",
+            |mut caller: wasmi::Caller<'_, WasmState>| -> Result<u32, Trap> {
+                traced(&mut caller, "poll_signal", format_args!(""), |caller| {
+                    Ok(crate::task::take_signals(AgentId(caller.data().agent_pid)))
+                })
+            },
+        )?;
+
+        // Host Function: env.yield_now()
+        // Give up the rest of the slice; the agent resumes right after the call on its
+        // next turn. A no-op outside the executor.
+        host.register(
+            "yield_now",
+            |mut caller: wasmi::Caller<'_, WasmState>| -> Result<(), Trap> {
+                traced(&mut caller, "yield_now", format_args!(""), |caller| {
+                    if caller.data().slice_end.is_none() {
+                        return Ok(());
+                    }
+                    caller.data_mut().pending_return = Vec::new();
+                    Err(Trap::from(Yield))
+                })
+            },
+        )?;
+
+        // Host Function: env.lock_acquire(name_ptr, name_len, timeout_ms) -> u32
+        // Take a named kernel lock. If another agent holds it, the caller is suspended
+        // until the lock is released (OK) or timeout_ms passes (ERR_TIMEOUT). Outside
+        // the executor nothing else can release it, so contention fails at once.
+        host.register(
+            "lock_acquire",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             name_ptr: u32,
+             name_len: u32,
+             timeout_ms: u32|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "lock_acquire",
+                    format_args!("{name_ptr}, {name_len}, {timeout_ms}"),
+                    |caller| {
+                        if name_len == 0 || name_len as usize > crate::locks::MAX_NAME_LEN {
+                            return Ok(ERR_INVALID_ARGUMENT);
+                        }
+                        let name = read_str(caller, name_ptr, name_len)?;
+                        let agent_pid = caller.data().agent_pid;
+
+                        if crate::locks::try_acquire(&name, agent_pid) {
+                            return Ok(OK);
+                        }
+                        if timeout_ms == 0 || caller.data().slice_end.is_none() {
+                            return Ok(ERR_TIMEOUT);
+                        }
+
+                        let state = caller.data_mut();
+                        state.lock_wait = Some(LockWait {
+                            name,
+                            deadline_ms: crate::time::uptime_ms() + u64::from(timeout_ms),
+                        });
+                        state.pending_return = ERR_TIMEOUT.to_values();
+                        Err(Trap::from(Yield))
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.lock_release(name_ptr, name_len) -> u32
+        // Returns OK, ERR_NOT_FOUND (lock not held) or ERR_PERMISSION_DENIED (held by
+        // another agent).
+        host.register(
+            "lock_release",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             name_ptr: u32,
+             name_len: u32|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "lock_release",
+                    format_args!("{name_ptr}, {name_len}"),
+                    |caller| {
+                        let name = read_str(caller, name_ptr, name_len)?;
+                        Ok(
+                            match crate::locks::release(&name, caller.data().agent_pid) {
+                                Ok(()) => OK,
+                                Err(crate::locks::ReleaseError::NotHeld) => ERR_NOT_FOUND,
+                                Err(crate::locks::ReleaseError::NotOwner) => ERR_PERMISSION_DENIED,
+                            },
+                        )
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.tcp_request(ip_ptr: u32, port: u32, payload_ptr: u32, len: u32) -> u32
+        // Open a TCP connection and emit its SYN. Returns OK, ERR_PERMISSION_DENIED,
+        // ERR_RATE_LIMITED, ERR_INVALID_ARGUMENT (port above 65535), ERR_QUOTA_EXCEEDED
+        // (socket limit), ERR_GENERAL (connection could not be set up, e.g. port 0),
+        // ERR_NETWORK_UNREACHABLE or ERR_TIMEOUT.
+        host.register(
+            "tcp_request",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             ip_ptr: u32,
+             port: u32,
+             ptr: u32,
+             len: u32|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "tcp_request",
+                    format_args!("{ip_ptr}, {port}, {ptr}, {len}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        let caps = agent_capabilities(AgentId(agent_pid));
+
+                        // SECURITY CHECK: Ensure Wasm Agent is granted the Network Capability!
+                        if !crate::capability::can_access_network(&caps) {
+                            crate::audit::log_denial(
+                                agent_pid,
+                                format_args!("Agent {agent_pid} denied network access"),
+                            );
+                            return Ok(ERR_PERMISSION_DENIED);
+                        }
+
+                        if !crate::ratelimit::check(agent_pid) {
+                            serial_println!("[NET] Agent {} rate limited (tcp_request)", agent_pid);
+                            return Ok(ERR_RATE_LIMITED);
+                        }
+                        let Ok(port) = u16::try_from(port) else {
+                            return Ok(ERR_INVALID_ARGUMENT);
+                        };
+
+                        let ip_buf = read_bytes(caller, ip_ptr, 4)?;
+
+                        let payload_buf = read_bytes(caller, ptr, len)?;
+                        caller.data_mut().add_bytes(payload_buf.len());
+
+                        serial_println!(
+                            "[NET] Agent {} requesting TCP to {}.{}.{}.{}:{} (Payload: {} bytes)",
+                            agent_pid,
+                            ip_buf[0],
+                            ip_buf[1],
+                            ip_buf[2],
+                            ip_buf[3],
+                            port,
+                            len
+                        );
+
+                        let queued = crate::net::with_network(|net| {
+                            use smoltcp::wire::IpAddress;
+
+                            let endpoint = (
+                                IpAddress::v4(ip_buf[0], ip_buf[1], ip_buf[2], ip_buf[3]),
+                                port,
+                            );
+                            let socket_id = match crate::sockets::connect_tcp(
+                                net,
+                                agent_pid,
+                                endpoint.into(),
+                            ) {
+                                Ok(id) => id,
+                                Err(crate::sockets::SocketError::Connect) => return ERR_GENERAL,
+                                Err(e) => {
+                                    serial_println!(
+                                        "[NET] Agent {agent_pid} socket limit reached: {e:?}"
+                                    );
+                                    return ERR_QUOTA_EXCEEDED;
+                                }
+                            };
+
+                            // Force a poll to emit the bare-metal SYN frame!
+                            net.iface.poll(
+                                smoltcp::time::Instant::from_millis(1),
+                                &mut net.device,
+                                &mut net.sockets,
+                            );
+                            serial_println!("  -> TCP SYN packet emitted to hardware DMA ring!");
+
+                            crate::sockets::close(net, agent_pid, socket_id);
+                            OK
+                        });
+
+                        match queued {
+                            Ok(code) => Ok(code),
+                            Err(NetError::Timeout) => Ok(ERR_TIMEOUT),
+                            Err(NetError::Unavailable) => Ok(ERR_NETWORK_UNREACHABLE),
+                        }
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.tcp_probe(ip_ptr, port) -> u32
+        // Check whether a TCP port accepts connections without sending data. Requires
+        // Capability::Network and counts against the rate limit. Returns OK (open),
+        // ERR_CONNECTION_REFUSED (closed), ERR_TIMEOUT (filtered: no reply within
+        // TCP_PROBE_TIMEOUT_MS), ERR_PERMISSION_DENIED, ERR_RATE_LIMITED,
+        // ERR_INVALID_ARGUMENT (port out of range) or ERR_NETWORK_UNREACHABLE.
+        host.register(
+            "tcp_probe",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             ip_ptr: u32,
+             port: u32|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "tcp_probe",
+                    format_args!("{ip_ptr}, {port}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        let caps = agent_capabilities(AgentId(agent_pid));
+                        if !crate::capability::can_access_network(&caps) {
+                            crate::audit::log_denial(
+                                agent_pid,
+                                format_args!("Agent {agent_pid} denied network access"),
+                            );
+                            return Ok(ERR_PERMISSION_DENIED);
+                        }
+                        if !crate::ratelimit::check(agent_pid) {
+                            serial_println!("[NET] Agent {agent_pid} rate limited (tcp_probe)");
+                            return Ok(ERR_RATE_LIMITED);
+                        }
+                        let Ok(port) = u16::try_from(port) else {
+                            return Ok(ERR_INVALID_ARGUMENT);
+                        };
+                        let ip = read_bytes(caller, ip_ptr, 4)?;
+                        let addr = smoltcp::wire::Ipv4Address::from_bytes(&ip);
+
+                        Ok(
+                            match crate::net::tcp_probe(addr, port, TCP_PROBE_TIMEOUT_MS) {
+                                Ok(crate::net::PortState::Open) => OK,
+                                Ok(crate::net::PortState::Closed) => ERR_CONNECTION_REFUSED,
+                                Ok(crate::net::PortState::Filtered) => ERR_TIMEOUT,
+                                Err(NetError::Unavailable) => ERR_NETWORK_UNREACHABLE,
+                                Err(NetError::Timeout) => ERR_TIMEOUT,
+                            },
+                        )
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.tcp_connect(ip_ptr, port, handle_ptr) -> u32
+        // Open a TCP connection owned by the caller and write its socket handle (u32le)
+        // to handle_ptr. Returns at once: the SYN goes out the next time the stack is
+        // polled (e.g. by env.net_flush) and socket_state reports the handshake's
+        // progress. The handle stays valid for the socket_* functions until
+        // env.socket_close or the agent exits. Requires Capability::Network and counts
+        // against the rate limit. Returns OK, ERR_PERMISSION_DENIED, ERR_RATE_LIMITED,
+        // ERR_INVALID_ARGUMENT (port 0 or out of range), ERR_QUOTA_EXCEEDED (socket
+        // limit reached), ERR_GENERAL (no free local port), ERR_NETWORK_UNREACHABLE or
+        // ERR_TIMEOUT.
+        host.register(
+            "tcp_connect",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             ip_ptr: u32,
+             port: u32,
+             handle_ptr: u32|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "tcp_connect",
+                    format_args!("{ip_ptr}, {port}, {handle_ptr}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        let caps = agent_capabilities(AgentId(agent_pid));
+                        if !crate::capability::can_access_network(&caps) {
+                            crate::audit::log_denial(
+                                agent_pid,
+                                format_args!("Agent {agent_pid} denied network access"),
+                            );
+                            return Ok(ERR_PERMISSION_DENIED);
+                        }
+                        if !crate::ratelimit::check(agent_pid) {
+                            serial_println!("[NET] Agent {agent_pid} rate limited (tcp_connect)");
+                            return Ok(ERR_RATE_LIMITED);
+                        }
+                        let port = match u16::try_from(port) {
+                            Ok(port) if port != 0 => port,
+                            _ => return Ok(ERR_INVALID_ARGUMENT),
+                        };
+                        let ip = read_bytes(caller, ip_ptr, 4)?;
+                        let remote = (smoltcp::wire::Ipv4Address::from_bytes(&ip), port);
+
+                        let result = crate::net::with_network(|net| {
+                            crate::sockets::connect_tcp(net, agent_pid, remote.into())
+                        });
+                        match result {
+                            Ok(Ok(handle)) => {
+                                write_u32(caller, handle_ptr, handle)?;
+                                Ok(OK)
+                            }
+                            Ok(Err(crate::sockets::SocketError::Connect)) => Ok(ERR_GENERAL),
+                            Ok(Err(e)) => {
+                                serial_println!(
+                                    "[NET] Agent {agent_pid} socket limit reached: {e:?}"
+                                );
+                                Ok(ERR_QUOTA_EXCEEDED)
+                            }
+                            Err(NetError::Unavailable) => Ok(ERR_NETWORK_UNREACHABLE),
+                            Err(NetError::Timeout) => Ok(ERR_TIMEOUT),
+                        }
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.socket_close(handle) -> u32
+        // Close one of the caller's sockets; its connection is dropped without a FIN.
+        // Returns OK,
+        // ERR_NOT_FOUND (not the caller's socket), ERR_NETWORK_UNREACHABLE or
+        // ERR_TIMEOUT.
+        host.register(
+            "socket_close",
+            |mut caller: wasmi::Caller<'_, WasmState>, handle: u32| -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "socket_close",
+                    format_args!("{handle}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        let result = crate::net::with_network(|net| {
+                            crate::sockets::close(net, agent_pid, handle)
+                        });
+                        Ok(match result {
+                            Ok(true) => OK,
+                            Ok(false) => ERR_NOT_FOUND,
+                            Err(NetError::Unavailable) => ERR_NETWORK_UNREACHABLE,
+                            Err(NetError::Timeout) => ERR_TIMEOUT,
+                        })
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.socket_set_option(handle, option, value) -> u32
+        // Tune one of the caller's sockets; option codes and value encodings are listed
+        // on `sockets::SocketOption`. Returns OK, ERR_NOT_FOUND (not the caller's
+        // socket), ERR_INVALID_ARGUMENT (unknown option, option not valid for the socket
+        // type, or value out of range), ERR_NETWORK_UNREACHABLE or ERR_TIMEOUT.
+        host.register(
+            "socket_set_option",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             handle: u32,
+             option: u32,
+             value: u64|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "socket_set_option",
+                    format_args!("{handle}, {option}, {value}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        let Some(option) = crate::sockets::SocketOption::from_u32(option) else {
+                            return Ok(ERR_INVALID_ARGUMENT);
+                        };
+                        let result = crate::net::with_network(|net| {
+                            crate::sockets::set_option(net, agent_pid, handle, option, value)
+                        });
+                        Ok(match result {
+                            Ok(Ok(())) => OK,
+                            Ok(Err(e)) => socket_option_error(e),
+                            Err(NetError::Unavailable) => ERR_NETWORK_UNREACHABLE,
+                            Err(NetError::Timeout) => ERR_TIMEOUT,
+                        })
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.socket_get_option(handle, option, out_ptr) -> u32
+        // Read an option back as a u64le at out_ptr. Same codes and errors as
+        // socket_set_option.
+        host.register(
+            "socket_get_option",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             handle: u32,
+             option: u32,
+             out_ptr: u32|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "socket_get_option",
+                    format_args!("{handle}, {option}, {out_ptr}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        let Some(option) = crate::sockets::SocketOption::from_u32(option) else {
+                            return Ok(ERR_INVALID_ARGUMENT);
+                        };
+                        let result = crate::net::with_network(|net| {
+                            crate::sockets::get_option(net, agent_pid, handle, option)
+                        });
+                        match result {
+                            Ok(Ok(value)) => {
+                                write_bytes(caller, out_ptr, &value.to_le_bytes())?;
+                                Ok(OK)
+                            }
+                            Ok(Err(e)) => Ok(socket_option_error(e)),
+                            Err(NetError::Unavailable) => Ok(ERR_NETWORK_UNREACHABLE),
+                            Err(NetError::Timeout) => Ok(ERR_TIMEOUT),
+                        }
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.socket_state(handle) -> u32
+        // Poll a TCP connection's progress instead of blindly reading: returns the state
+        // code from `sockets::tcp_state_code` (2 SynSent while the handshake is pending, 4
+        // Established once it completes, 0 Closed if it failed), or SOCKET_STATE_UNKNOWN
+        // if handle is not one of the caller's TCP sockets or the network is down.
+        host.register(
+            "socket_state",
+            |mut caller: wasmi::Caller<'_, WasmState>, handle: u32| -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "socket_state",
+                    format_args!("{handle}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        let result = crate::net::with_network(|net| {
+                            crate::sockets::tcp_state(net, agent_pid, handle)
+                        });
+                        Ok(match result {
+                            Ok(Ok(code)) => code,
+                            _ => SOCKET_STATE_UNKNOWN,
+                        })
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.tcp_send(handle, ptr, len, out_sent_ptr) -> u32
+        // Queue up to len bytes on one of the caller's TCP connections and poll the stack
+        // so they go out. Writes the number queued (u32le) to out_sent_ptr: fewer than
+        // len once the send buffer is full. Counted by socket_stats. Returns OK,
+        // ERR_NOT_FOUND (not the caller's socket), ERR_INVALID_ARGUMENT (not a TCP
+        // socket), ERR_GENERAL (not established yet, or closed; see socket_state),
+        // ERR_NETWORK_UNREACHABLE or ERR_TIMEOUT.
+        host.register(
+            "tcp_send",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             handle: u32,
+             ptr: u32,
+             len: u32,
+             out_sent_ptr: u32|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "tcp_send",
+                    format_args!("{handle}, {ptr}, {len}, {out_sent_ptr}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        let len = len.min(crate::sockets::TCP_BUFFER_SIZE as u32);
+                        let data = read_bytes(caller, ptr, len)?;
+                        let result = crate::net::with_network(|net| {
+                            let sent = crate::sockets::send(net, agent_pid, handle, &data)?;
+                            let now = smoltcp::time::Instant::from_millis(
+                                crate::time::uptime_ms() as i64
+                            );
+                            net.iface.poll(now, &mut net.device, &mut net.sockets);
+                            Ok(sent)
+                        });
+                        match result {
+                            Ok(Ok(sent)) => {
+                                caller.data_mut().add_bytes(sent);
+                                write_u32(caller, out_sent_ptr, sent as u32)?;
+                                Ok(OK)
+                            }
+                            Ok(Err(e)) => Ok(socket_transfer_error(e)),
+                            Err(NetError::Unavailable) => Ok(ERR_NETWORK_UNREACHABLE),
+                            Err(NetError::Timeout) => Ok(ERR_TIMEOUT),
+                        }
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.tcp_recv(handle, out_ptr, out_len_ptr) -> u32
+        // Poll the stack, then take the bytes received so far on one of the caller's TCP
+        // connections. out_ptr needs room for `sockets::TCP_BUFFER_SIZE` bytes; the
+        // number taken (0 if nothing has arrived) goes to out_len_ptr. Counted by
+        // socket_stats. Same errors as tcp_send.
+        host.register(
+            "tcp_recv",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             handle: u32,
+             out_ptr: u32,
+             out_len_ptr: u32|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "tcp_recv",
+                    format_args!("{handle}, {out_ptr}, {out_len_ptr}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        let mut data = alloc::vec![0; crate::sockets::TCP_BUFFER_SIZE];
+                        let result = crate::net::with_network(|net| {
+                            let now = smoltcp::time::Instant::from_millis(
+                                crate::time::uptime_ms() as i64
+                            );
+                            net.iface.poll(now, &mut net.device, &mut net.sockets);
+                            crate::sockets::recv(net, agent_pid, handle, &mut data)
+                        });
+                        match result {
+                            Ok(Ok(received)) => {
+                                write_bytes(caller, out_ptr, &data[..received])?;
+                                caller.data_mut().add_bytes(received);
+                                write_u32(caller, out_len_ptr, received as u32)?;
+                                Ok(OK)
+                            }
+                            Ok(Err(e)) => Ok(socket_transfer_error(e)),
+                            Err(NetError::Unavailable) => Ok(ERR_NETWORK_UNREACHABLE),
+                            Err(NetError::Timeout) => Ok(ERR_TIMEOUT),
+                        }
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.socket_stats(handle, out_sent_ptr, out_recv_ptr) -> u32
+        // Write the payload bytes sent and received on one of the caller's sockets since
+        // it was opened (u64le each, as moved by tcp_send and tcp_recv), for measuring
+        // throughput. Returns OK or
+        // ERR_NOT_FOUND (not the caller's socket).
+        host.register(
+            "socket_stats",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             handle: u32,
+             out_sent_ptr: u32,
+             out_recv_ptr: u32|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "socket_stats",
+                    format_args!("{handle}, {out_sent_ptr}, {out_recv_ptr}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        let Some((sent, received)) =
+                            crate::sockets::transfer_stats(agent_pid, handle)
+                        else {
+                            return Ok(ERR_NOT_FOUND);
+                        };
+                        write_bytes(caller, out_sent_ptr, &sent.to_le_bytes())?;
+                        write_bytes(caller, out_recv_ptr, &received.to_le_bytes())?;
+                        Ok(OK)
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.net_flush() -> u32
+        // Push frames queued by the agent's sockets out to the NIC now instead of on the
+        // next poll, spinning for at most `net::MAX_FLUSH_MS`. Requires
+        // Capability::Network. Returns OK, ERR_PERMISSION_DENIED, ERR_NETWORK_UNREACHABLE
+        // or ERR_TIMEOUT.
+        host.register(
+            "net_flush",
+            |mut caller: wasmi::Caller<'_, WasmState>| -> Result<u32, Trap> {
+                traced(&mut caller, "net_flush", format_args!(""), |caller| {
+                    let agent_pid = caller.data().agent_pid;
+                    let caps = agent_capabilities(AgentId(agent_pid));
+                    if !crate::capability::can_access_network(&caps) {
+                        crate::audit::log_denial(
+                            agent_pid,
+                            format_args!("Agent {agent_pid} denied net_flush"),
+                        );
+                        return Ok(ERR_PERMISSION_DENIED);
+                    }
+
+                    Ok(match crate::net::flush() {
+                        Ok(_) => OK,
+                        Err(NetError::Unavailable) => ERR_NETWORK_UNREACHABLE,
+                        Err(NetError::Timeout) => ERR_TIMEOUT,
+                    })
+                })
+            },
+        )?;
+
+        // Host Function: env.eth_send(frame_ptr, len) -> u32
+        // Transmit a raw Ethernet frame (header and payload, no CRC) straight to the
+        // NIC, bypassing the IP stack. The source MAC must be the NIC's. Requires
+        // Capability::RawNetwork and counts against the rate limit. Returns OK,
+        // ERR_PERMISSION_DENIED, ERR_RATE_LIMITED, ERR_INVALID_ARGUMENT (bad length or
+        // foreign source MAC), ERR_NETWORK_UNREACHABLE or ERR_TIMEOUT.
+        host.register(
+            "eth_send",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             frame_ptr: u32,
+             len: u32|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "eth_send",
+                    format_args!("{frame_ptr}, {len}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        let caps = agent_capabilities(AgentId(agent_pid));
+                        if !crate::capability::can_use_raw_network(&caps) {
+                            crate::audit::log_denial(
+                                agent_pid,
+                                format_args!("Agent {agent_pid} denied raw frame send"),
+                            );
+                            return Ok(ERR_PERMISSION_DENIED);
+                        }
+                        if !crate::ratelimit::check(agent_pid) {
+                            serial_println!("[NET] Agent {agent_pid} rate limited (eth_send)");
+                            return Ok(ERR_RATE_LIMITED);
+                        }
+                        if len as usize > crate::rtl8139::MAX_FRAME_SIZE {
+                            return Ok(ERR_INVALID_ARGUMENT);
+                        }
+                        let frame = read_bytes(caller, frame_ptr, len)?;
+                        caller.data_mut().add_bytes(frame.len());
+
+                        Ok(match crate::net::send_raw(&frame) {
+                            Ok(()) => OK,
+                            Err(crate::net::RawSendError::BadLength)
+                            | Err(crate::net::RawSendError::ForeignSource) => ERR_INVALID_ARGUMENT,
+                            Err(crate::net::RawSendError::Net(NetError::Unavailable)) => {
+                                ERR_NETWORK_UNREACHABLE
+                            }
+                            Err(crate::net::RawSendError::Net(NetError::Timeout)) => ERR_TIMEOUT,
+                        })
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.eth_recv(out_ptr, out_len_ptr) -> u32
+        // Take the oldest captured Ethernet frame: a copy of one the NIC received (the
+        // IP stack still processes the original). out_ptr needs room for
+        // `rtl8139::MAX_FRAME_SIZE` bytes; the frame's length goes to out_len_ptr.
+        // Capture starts with the first call. Requires Capability::RawNetwork. Returns
+        // OK, ERR_NOT_FOUND (no frame waiting), ERR_PERMISSION_DENIED,
+        // ERR_NETWORK_UNREACHABLE or ERR_TIMEOUT.
+        host.register(
+            "eth_recv",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             out_ptr: u32,
+             out_len_ptr: u32|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "eth_recv",
+                    format_args!("{out_ptr}, {out_len_ptr}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        let caps = agent_capabilities(AgentId(agent_pid));
+                        if !crate::capability::can_use_raw_network(&caps) {
+                            // A revoked reader no longer keeps capture running.
+                            crate::net::stop_raw_capture(agent_pid);
+                            crate::audit::log_denial(
+                                agent_pid,
+                                format_args!("Agent {agent_pid} denied raw frame receive"),
+                            );
+                            return Ok(ERR_PERMISSION_DENIED);
+                        }
+
+                        match crate::net::recv_raw(agent_pid) {
+                            Ok(Some(frame)) => {
+                                write_bytes(caller, out_ptr, &frame)?;
+                                caller.data_mut().add_bytes(frame.len());
+                                write_u32(caller, out_len_ptr, frame.len() as u32)?;
+                                Ok(OK)
+                            }
+                            Ok(None) => Ok(ERR_NOT_FOUND),
+                            Err(NetError::Unavailable) => Ok(ERR_NETWORK_UNREACHABLE),
+                            Err(NetError::Timeout) => Ok(ERR_TIMEOUT),
+                        }
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.net_stats(out_ptr, out_len_ptr) -> u32
+        // Writes a `net::NetStats` in its `to_bytes` encoding to out_ptr and its length
+        // to out_len_ptr. Requires Capability::Network.
+        host.register(
+            "net_stats",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             out_ptr: u32,
+             out_len_ptr: u32|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "net_stats",
+                    format_args!("{out_ptr}, {out_len_ptr}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        let caps = agent_capabilities(AgentId(agent_pid));
+                        if !crate::capability::can_access_network(&caps) {
+                            crate::audit::log_denial(
+                                agent_pid,
+                                format_args!("Agent {agent_pid} denied net_stats"),
+                            );
+                            return Ok(ERR_PERMISSION_DENIED);
+                        }
+
+                        let bytes = crate::net::stats().to_bytes();
+                        write_bytes(caller, out_ptr, &bytes)?;
+                        caller.data_mut().add_bytes(bytes.len());
+                        write_u32(caller, out_len_ptr, bytes.len() as u32)?;
+                        Ok(OK)
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.resolve_dns(name_ptr: u32, name_len: u32, out_ip_ptr: u32) -> u32
+        // Writes the address to out_ip_ptr and returns OK, or ERR_GENERAL (no answer),
+        // ERR_TIMEOUT or ERR_INVALID_ARGUMENT (unencodable name). Under the executor the
+        // caller is suspended while the query is out, and callers resolving a name
+        // already being queried wait for that query's answer instead of sending another.
+        host.register(
+            "resolve_dns",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             name_ptr: u32,
+             name_len: u32,
+             out_ip_ptr: u32|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "resolve_dns",
+                    format_args!("{name_ptr}, {name_len}, {out_ip_ptr}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        let caps = agent_capabilities(AgentId(agent_pid));
+
+                        if !crate::capability::can_access_network(&caps) {
+                            crate::audit::log_denial(
+                                agent_pid,
+                                format_args!("Agent {agent_pid} denied DNS access"),
+                            );
+                            return Ok(2); // Permission Denied
+                        }
+
+                        if !crate::ratelimit::check(agent_pid) {
+                            serial_println!("[NET] Agent {} rate limited (resolve_dns)", agent_pid);
+                            return Ok(ERR_RATE_LIMITED);
+                        }
+
+                        let domain = read_str(caller, name_ptr, name_len)?;
+
+                        serial_println!("[DNS] Agent {} resolving: {}", agent_pid, domain);
+
+                        // Outside the executor nothing else can run meanwhile, so block.
+                        let lookup = if caller.data().slice_end.is_none() {
+                            crate::dns::Resolve::Done(crate::dns::resolve(&domain))
+                        } else {
+                            crate::dns::start_resolve(&domain)
+                        };
+                        match lookup {
+                            crate::dns::Resolve::Done(Ok(ip)) => {
+                                write_bytes(caller, out_ip_ptr, &ip)?;
+                                Ok(0) // Success
+                            }
+                            crate::dns::Resolve::Done(Err(e)) => Ok(dns_status(e)),
+                            crate::dns::Resolve::Pending(lookup) => {
+                                // Fail now, not on resume, if the answer cannot be stored.
+                                let memory = get_memory(caller)?;
+                                let end = out_ip_ptr as usize + 4;
+                                if end > memory.data(&*caller).len() {
+                                    return Err(host_error("Memory write failed"));
+                                }
+                                let state = caller.data_mut();
+                                state.dns_wait = Some(DnsWait {
+                                    lookup,
+                                    memory,
+                                    out_ip_ptr,
+                                });
+                                state.pending_return = ERR_TIMEOUT.to_values();
+                                Err(Trap::from(Yield))
+                            }
+                        }
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.resolve_mx(name_ptr, name_len, out_ptr, out_len_ptr) -> u32
+        // Writes one `preference exchange` line per MX record, newline-separated, to `out_ptr`.
+        host.register(
+            "resolve_mx",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             name_ptr: u32,
+             name_len: u32,
+             out_ptr: u32,
+             out_len_ptr: u32|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "resolve_mx",
+                    format_args!("{name_ptr}, {name_len}, {out_ptr}, {out_len_ptr}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        let caps = agent_capabilities(AgentId(agent_pid));
+
+                        if !crate::capability::can_access_network(&caps) {
+                            crate::audit::log_denial(
+                                agent_pid,
+                                format_args!("Agent {agent_pid} denied DNS access"),
+                            );
+                            return Ok(ERR_PERMISSION_DENIED);
+                        }
+
+                        if !crate::ratelimit::check(agent_pid) {
+                            serial_println!("[NET] Agent {} rate limited (resolve_mx)", agent_pid);
+                            return Ok(ERR_RATE_LIMITED);
+                        }
+
+                        let domain = read_str(caller, name_ptr, name_len)?;
+
+                        serial_println!("[DNS] Agent {} resolving MX: {}", agent_pid, domain);
+
+                        let records = match crate::dns::resolve_mx(&domain) {
+                            Ok(records) => records,
+                            Err(crate::dns::DnsError::Timeout) => return Ok(ERR_TIMEOUT),
+                            Err(crate::dns::DnsError::InvalidName) => {
+                                return Ok(ERR_INVALID_ARGUMENT)
+                            }
+                            Err(crate::dns::DnsError::NotFound) => Vec::new(),
+                        };
+                        let records = records
+                            .into_iter()
+                            .map(|(preference, exchange)| alloc::format!("{preference} {exchange}"))
+                            .collect::<Vec<_>>();
+                        if records.is_empty() {
+                            return Ok(ERR_NOT_FOUND);
+                        }
+
+                        let listing = records.join("\n");
+                        let write_len = listing.len() as u32;
+                        write_bytes(caller, out_ptr, listing.as_bytes())?;
+                        write_u32(caller, out_len_ptr, write_len)?;
+                        Ok(OK)
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.resolve_txt(name_ptr, name_len, out_ptr, out_len_ptr) -> u32
+        // Writes one line per TXT record, newline-separated, to `out_ptr`.
+        host.register(
+            "resolve_txt",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             name_ptr: u32,
+             name_len: u32,
+             out_ptr: u32,
+             out_len_ptr: u32|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "resolve_txt",
+                    format_args!("{name_ptr}, {name_len}, {out_ptr}, {out_len_ptr}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        let caps = agent_capabilities(AgentId(agent_pid));
+
+                        if !crate::capability::can_access_network(&caps) {
+                            crate::audit::log_denial(
+                                agent_pid,
+                                format_args!("Agent {agent_pid} denied DNS access"),
+                            );
+                            return Ok(ERR_PERMISSION_DENIED);
+                        }
+
+                        if !crate::ratelimit::check(agent_pid) {
+                            serial_println!("[NET] Agent {} rate limited (resolve_txt)", agent_pid);
+                            return Ok(ERR_RATE_LIMITED);
+                        }
+
+                        let domain = read_str(caller, name_ptr, name_len)?;
+
+                        serial_println!("[DNS] Agent {} resolving TXT: {}", agent_pid, domain);
+
+                        let records = match crate::dns::resolve_txt(&domain) {
+                            Ok(records) => records,
+                            Err(crate::dns::DnsError::Timeout) => return Ok(ERR_TIMEOUT),
+                            Err(crate::dns::DnsError::InvalidName) => {
+                                return Ok(ERR_INVALID_ARGUMENT)
+                            }
+                            Err(crate::dns::DnsError::NotFound) => Vec::new(),
+                        };
+                        if records.is_empty() {
+                            return Ok(ERR_NOT_FOUND);
+                        }
+
+                        let listing = records.join("\n");
+                        let write_len = listing.len() as u32;
+                        write_bytes(caller, out_ptr, listing.as_bytes())?;
+                        write_u32(caller, out_len_ptr, write_len)?;
+                        Ok(OK)
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.file_read(path_ptr, path_len, out_ptr, out_len_ptr) -> u32
+        host.register(
+            "file_read",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             path_ptr: u32,
+             path_len: u32,
+             out_ptr: u32,
+             out_len_ptr: u32|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "file_read",
+                    format_args!("{path_ptr}, {path_len}, {out_ptr}, {out_len_ptr}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        let caps = agent_capabilities(AgentId(agent_pid));
+
+                        let path = read_str(caller, path_ptr, path_len)?;
+
+                        let Some(path) = crate::vfs::resolve_path(&caller.data().cwd, &path) else {
+                            return Ok(ERR_INVALID_ARGUMENT);
+                        };
+
+                        if !crate::capability::can_read_file(&caps, &path) {
+                            crate::audit::log_denial(
+                                agent_pid,
+                                format_args!("Agent {agent_pid} denied file read: {path}"),
+                            );
+                            return Ok(2);
+                        }
+
+                        match crate::vfs::open_file_as(&path, agent_pid) {
+                            Some(data) => {
+                                let write_len = data.len() as u32;
+                                write_bytes(caller, out_ptr, &data)?;
+                                caller.data_mut().add_bytes(data.len());
+                                write_u32(caller, out_len_ptr, write_len)?;
+                                Ok(0)
+                            }
+                            None => Ok(3), // Not found
+                        }
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.file_read_many(paths_ptr, paths_len, out_ptr, out_len_ptr) -> u32
+        // Read a newline-separated list of paths in one call. Each path yields a status
+        // byte (OK, ERR_PERMISSION_DENIED, ERR_NOT_FOUND or ERR_INVALID_ARGUMENT), a
+        // u32le length and that many bytes of contents (empty unless the status is OK),
+        // in list order. Failed entries do not fail the batch.
+        host.register(
+            "file_read_many",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             paths_ptr: u32,
+             paths_len: u32,
+             out_ptr: u32,
+             out_len_ptr: u32|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "file_read_many",
+                    format_args!("{paths_ptr}, {paths_len}, {out_ptr}, {out_len_ptr}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        let caps = agent_capabilities(AgentId(agent_pid));
+                        let paths = read_str(caller, paths_ptr, paths_len)?;
+
+                        let mut out = Vec::new();
+                        for path in paths.lines().filter(|p| !p.is_empty()) {
+                            let data = match crate::vfs::resolve_path(&caller.data().cwd, path) {
+                                None => Err(ERR_INVALID_ARGUMENT),
+                                Some(path) if !crate::capability::can_read_file(&caps, &path) => {
+                                    crate::audit::log_denial(
+                                        agent_pid,
+                                        format_args!("Agent {agent_pid} denied file read: {path}"),
+                                    );
+                                    Err(ERR_PERMISSION_DENIED)
+                                }
+                                Some(path) => {
+                                    crate::vfs::open_file_as(&path, agent_pid).ok_or(ERR_NOT_FOUND)
+                                }
+                            };
+                            let (status, data) = match data {
+                                Ok(data) => (OK, data),
+                                Err(code) => (code, Vec::new()),
+                            };
+                            out.push(status as u8);
+                            out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                            out.extend_from_slice(&data);
+                        }
+
+                        write_bytes(caller, out_ptr, &out)?;
+                        caller.data_mut().add_bytes(out.len());
+                        write_u32(caller, out_len_ptr, out.len() as u32)?;
+                        Ok(OK)
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.file_write(path_ptr, path_len, data_ptr, data_len) -> u32
+        // ERR_QUOTA_EXCEEDED if an overlay write would grow the agent's upper layer past
+        // vfs::MAX_UPPER_BYTES.
+        host.register(
+            "file_write",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             path_ptr: u32,
+             path_len: u32,
+             data_ptr: u32,
+             data_len: u32|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "file_write",
+                    format_args!("{path_ptr}, {path_len}, {data_ptr}, {data_len}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        let caps = agent_capabilities(AgentId(agent_pid));
+
+                        let path = read_str(caller, path_ptr, path_len)?;
+
+                        let Some(path) = crate::vfs::resolve_path(&caller.data().cwd, &path) else {
+                            return Ok(ERR_INVALID_ARGUMENT);
+                        };
+
+                        if !crate::capability::can_write_file(&caps, &path) {
+                            crate::audit::log_denial(
+                                agent_pid,
+                                format_args!("Agent {agent_pid} denied file write: {path}"),
+                            );
+                            return Ok(2);
+                        }
+
+                        let data_buf = read_bytes(caller, data_ptr, data_len)?;
+                        caller.data_mut().add_bytes(data_buf.len());
+
+                        if !crate::vfs::fits_upper_quota(&path, data_buf.len(), agent_pid) {
+                            return Ok(ERR_QUOTA_EXCEEDED);
+                        }
+                        if crate::vfs::write_file(&path, &data_buf, agent_pid) {
+                            serial_println!(
+                                "[VFS] Agent {} wrote {} bytes to {}",
+                                agent_pid,
+                                data_len,
+                                path
+                            );
+                            Ok(0)
+                        } else {
+                            Ok(1) // Write failed (e.g. read-only system file)
+                        }
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.file_write_begin(path_ptr, path_len, handle_ptr) -> u32
+        // Open a chunked write session for a file and write its handle (a u32, at least
+        // 0x100) to handle_ptr. Returns OK, ERR_INVALID_ARGUMENT (invalid path),
+        // ERR_PERMISSION_DENIED (no write access) or ERR_GENERAL if the agent already
+        // holds max_open_handles() handles; on failure handle_ptr is left untouched.
+        // Chunks stay invisible until env.file_write_commit; exiting first discards them.
+        host.register(
+            "file_write_begin",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             path_ptr: u32,
+             path_len: u32,
+             handle_ptr: u32|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "file_write_begin",
+                    format_args!("{path_ptr}, {path_len}, {handle_ptr}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        let caps = agent_capabilities(AgentId(agent_pid));
+
+                        let path = read_str(caller, path_ptr, path_len)?;
+                        let Some(path) = crate::vfs::resolve_path(&caller.data().cwd, &path) else {
+                            return Ok(ERR_INVALID_ARGUMENT);
+                        };
+                        if !crate::capability::can_write_file(&caps, &path) {
+                            crate::audit::log_denial(
+                                agent_pid,
+                                format_args!("Agent {agent_pid} denied file write: {path}"),
+                            );
+                            return Ok(ERR_PERMISSION_DENIED);
+                        }
+
+                        let limit = max_open_handles();
+                        let state = caller.data_mut();
+                        if state.write_sessions.len() >= limit {
+                            serial_println!(
+                                "[VFS] Agent {} hit its open handle limit ({})",
+                                agent_pid,
+                                limit
+                            );
+                            return Ok(ERR_GENERAL);
+                        }
+                        let mut handle = state.next_write_session;
+                        while state.write_sessions.contains_key(&handle) {
+                            handle = handle.wrapping_add(1).max(FIRST_FILE_HANDLE);
+                        }
+                        write_u32(caller, handle_ptr, handle)?;
+                        let state = caller.data_mut();
+                        state.next_write_session = handle.wrapping_add(1).max(FIRST_FILE_HANDLE);
+                        state.write_sessions.insert(
+                            handle,
+                            WriteSession {
+                                path,
+                                data: Vec::new(),
+                            },
+                        );
+                        Ok(OK)
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.file_write_chunk(handle, data_ptr, data_len) -> u32
+        // Append to an open session. Returns OK, ERR_NOT_FOUND (unknown handle) or
+        // ERR_QUOTA_EXCEEDED if the sessions of all agents together would buffer more
+        // than MAX_WRITE_SESSION_BYTES.
+        host.register(
+            "file_write_chunk",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             handle: u32,
+             ptr: u32,
+             len: u32|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "file_write_chunk",
+                    format_args!("{handle}, {ptr}, {len}"),
+                    |caller| {
+                        if !caller.data().write_sessions.contains_key(&handle) {
+                            return Ok(ERR_NOT_FOUND);
+                        }
+                        if !reserve_write_session_bytes(len as usize) {
+                            return Ok(ERR_QUOTA_EXCEEDED);
+                        }
+
+                        // The reservation becomes part of the session's data, which
+                        // gives it back when the session is dropped.
+                        let chunk = read_bytes(caller, ptr, len).inspect_err(|_| {
+                            *WRITE_SESSION_BYTES.lock() -= len as usize;
+                        })?;
+                        let state = caller.data_mut();
+                        state.add_bytes(chunk.len());
+                        if let Some(session) = state.write_sessions.get_mut(&handle) {
+                            session.data.extend_from_slice(&chunk);
+                        }
+                        Ok(OK)
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.file_write_commit(handle) -> u32
+        // Publish the session's contents as the whole file in one step and close the
+        // session. Returns OK, ERR_NOT_FOUND (unknown handle), ERR_PERMISSION_DENIED
+        // (write access revoked meanwhile), ERR_QUOTA_EXCEEDED (overlay upper layer full)
+        // or ERR_GENERAL (read-only system file).
+        host.register(
+            "file_write_commit",
+            |mut caller: wasmi::Caller<'_, WasmState>, handle: u32| -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "file_write_commit",
+                    format_args!("{handle}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        let Some(session) = caller.data_mut().write_sessions.remove(&handle) else {
+                            return Ok(ERR_NOT_FOUND);
+                        };
+
+                        let caps = agent_capabilities(AgentId(agent_pid));
+                        if !crate::capability::can_write_file(&caps, &session.path) {
+                            return Ok(ERR_PERMISSION_DENIED);
+                        }
+                        if !crate::vfs::fits_upper_quota(
+                            &session.path,
+                            session.data.len(),
+                            agent_pid,
+                        ) {
+                            return Ok(ERR_QUOTA_EXCEEDED);
+                        }
+                        if crate::vfs::write_file(&session.path, &session.data, agent_pid) {
+                            serial_println!(
+                                "[VFS] Agent {} wrote {} bytes to {} in chunks",
+                                agent_pid,
+                                session.data.len(),
+                                session.path
+                            );
+                            Ok(OK)
+                        } else {
+                            Ok(ERR_GENERAL)
+                        }
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.file_copy(src_ptr, src_len, dst_ptr, dst_len) -> u32
+        // Copies a file inside the VFS. Needs read access to src and write access to dst.
+        // Returns OK, ERR_PERMISSION_DENIED, ERR_NOT_FOUND (no src) or ERR_GENERAL
+        // (dst is a read-only system file).
+        host.register(
+            "file_copy",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             src_ptr: u32,
+             src_len: u32,
+             dst_ptr: u32,
+             dst_len: u32|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "file_copy",
+                    format_args!("{src_ptr}, {src_len}, {dst_ptr}, {dst_len}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        let caps = agent_capabilities(AgentId(agent_pid));
+
+                        let src = read_str(caller, src_ptr, src_len)?;
+                        let dst = read_str(caller, dst_ptr, dst_len)?;
+                        let cwd = &caller.data().cwd;
+                        let (Some(src), Some(dst)) = (
+                            crate::vfs::resolve_path(cwd, &src),
+                            crate::vfs::resolve_path(cwd, &dst),
+                        ) else {
+                            return Ok(ERR_INVALID_ARGUMENT);
+                        };
+
+                        if !crate::capability::can_read_file(&caps, &src)
+                            || !crate::capability::can_write_file(&caps, &dst)
+                        {
+                            crate::audit::log_denial(
+                                agent_pid,
+                                format_args!("Agent {agent_pid} denied file copy: {src} -> {dst}"),
+                            );
+                            return Ok(ERR_PERMISSION_DENIED);
+                        }
+
+                        if !crate::vfs::exists_as(&src, agent_pid) {
+                            return Ok(ERR_NOT_FOUND);
+                        }
+                        if crate::vfs::copy(&src, &dst, agent_pid) {
+                            serial_println!("[VFS] Agent {agent_pid} copied {src} to {dst}");
+                            Ok(OK)
+                        } else {
+                            Ok(ERR_GENERAL) // Destination is a read-only system file
+                        }
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.file_swap(a_ptr, a_len, b_ptr, b_len) -> u32
+        // Atomically exchanges two files' contents, for replacing a file without a
+        // window where it is missing. Needs read and write access to both paths.
+        // Returns OK, ERR_PERMISSION_DENIED, ERR_NOT_FOUND or ERR_GENERAL (a read-only
+        // system file or an overlay path).
+        host.register(
+            "file_swap",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             a_ptr: u32,
+             a_len: u32,
+             b_ptr: u32,
+             b_len: u32|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "file_swap",
+                    format_args!("{a_ptr}, {a_len}, {b_ptr}, {b_len}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        let caps = agent_capabilities(AgentId(agent_pid));
+
+                        let a = read_str(caller, a_ptr, a_len)?;
+                        let b = read_str(caller, b_ptr, b_len)?;
+                        let cwd = &caller.data().cwd;
+                        let (Some(a), Some(b)) = (
+                            crate::vfs::resolve_path(cwd, &a),
+                            crate::vfs::resolve_path(cwd, &b),
+                        ) else {
+                            return Ok(ERR_INVALID_ARGUMENT);
+                        };
+
+                        let allowed = |path: &str| {
+                            crate::capability::can_read_file(&caps, path)
+                                && crate::capability::can_write_file(&caps, path)
+                        };
+                        if !allowed(&a) || !allowed(&b) {
+                            crate::audit::log_denial(
+                                agent_pid,
+                                format_args!("Agent {agent_pid} denied file swap: {a} <-> {b}"),
+                            );
+                            return Ok(ERR_PERMISSION_DENIED);
+                        }
+
+                        if !crate::vfs::exists_as(&a, agent_pid)
+                            || !crate::vfs::exists_as(&b, agent_pid)
+                        {
+                            return Ok(ERR_NOT_FOUND);
+                        }
+                        if crate::vfs::swap(&a, &b, agent_pid) {
+                            serial_println!("[VFS] Agent {agent_pid} swapped {a} and {b}");
+                            Ok(OK)
+                        } else {
+                            Ok(ERR_GENERAL)
+                        }
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.file_patch(path_ptr, path_len, patch_ptr, patch_len) -> u32
+        // Applies a COPY/ADD delta (format documented on `vfs::patch_bytes`) to an
+        // existing file instead of rewriting it whole.
+        host.register(
+            "file_patch",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             path_ptr: u32,
+             path_len: u32,
+             patch_ptr: u32,
+             patch_len: u32|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "file_patch",
+                    format_args!("{path_ptr}, {path_len}, {patch_ptr}, {patch_len}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        let caps = agent_capabilities(AgentId(agent_pid));
+
+                        let path = read_str(caller, path_ptr, path_len)?;
+
+                        let Some(path) = crate::vfs::resolve_path(&caller.data().cwd, &path)
+                        else {
+                            return Ok(ERR_INVALID_ARGUMENT);
+                        };
+
+                        if !crate::capability::can_write_file(&caps, &path) {
+                            crate::audit::log_denial(
+                                agent_pid,
+                                format_args!("Agent {agent_pid} denied file patch: {path}"),
+                            );
+                            return Ok(ERR_PERMISSION_DENIED);
+                        }
+
+                        let patch_buf = read_bytes(caller, patch_ptr, patch_len)?;
+                        caller.data_mut().add_bytes(patch_buf.len());
+
+                        match crate::vfs::apply_patch(&path, &patch_buf, agent_pid) {
+                            Ok(new_len) => {
+                                serial_println!(
+                                    "[VFS] Agent {agent_pid} patched {path} ({patch_len} byte delta -> {new_len} bytes)"
+                                );
+                                Ok(OK)
+                            }
+                            Err(crate::vfs::PatchError::NotFound) => Ok(ERR_NOT_FOUND),
+                            Err(crate::vfs::PatchError::ReadOnly) => {
+                                Ok(ERR_PERMISSION_DENIED)
+                            }
+                            Err(crate::vfs::PatchError::Malformed) => {
+                                Ok(ERR_INVALID_ARGUMENT)
+                            }
+                            Err(crate::vfs::PatchError::QuotaExceeded) => Ok(ERR_QUOTA_EXCEEDED),
+                        }
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.file_list(prefix_ptr, prefix_len, out_ptr, out_len_ptr) -> u32
+        host.register(
+            "file_list",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             prefix_ptr: u32,
+             prefix_len: u32,
+             out_ptr: u32,
+             out_len_ptr: u32|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "file_list",
+                    format_args!("{prefix_ptr}, {prefix_len}, {out_ptr}, {out_len_ptr}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        let caps = agent_capabilities(AgentId(agent_pid));
+
+                        let prefix = read_str(caller, prefix_ptr, prefix_len)?;
+
+                        let Some(prefix) = crate::vfs::resolve_path(&caller.data().cwd, &prefix)
+                        else {
+                            return Ok(ERR_INVALID_ARGUMENT);
+                        };
+
+                        // The agent may list a prefix only where it overlaps a granted
+                        // read prefix (e.g. `/` with `/agent/` granted), and only sees
+                        // entries under both. Prefixes match whole path segments, so
+                        // `/agent/` does not cover `/agents/`.
+                        let granted = crate::capability::readable_prefixes(&caps);
+                        let overlaps = granted
+                            .iter()
+                            .any(|g| path_under(&prefix, g) || path_under(g, &prefix));
+                        if !overlaps {
+                            crate::audit::log_denial(
+                                agent_pid,
+                                format_args!("Agent {agent_pid} denied file list: {prefix}"),
+                            );
+                            return Ok(2);
+                        }
+
+                        let files: Vec<String> =
+                            crate::vfs::list_files_prefix_as(&prefix, agent_pid)
+                                .into_iter()
+                                .filter(|f| {
+                                    path_under(f, &prefix)
+                                        && granted.iter().any(|g| path_under(f, g))
+                                })
+                                .collect();
+                        let listing = files.join("\n");
+                        let listing_bytes = listing.as_bytes();
+                        let write_len = listing_bytes.len() as u32;
+
+                        write_bytes(caller, out_ptr, listing_bytes)?;
+                        caller.data_mut().add_bytes(listing_bytes.len());
+                        write_u32(caller, out_len_ptr, write_len)?;
+                        Ok(0)
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.chdir(path_ptr, path_len) -> u32
+        // Changes the directory relative paths resolve against. Fails with
+        // ERR_INVALID_ARGUMENT if the path climbs above the VFS root.
+        host.register(
+            "chdir",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             path_ptr: u32,
+             path_len: u32|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "chdir",
+                    format_args!("{path_ptr}, {path_len}"),
+                    |caller| {
+                        let path = read_str(caller, path_ptr, path_len)?;
+
+                        let Some(mut cwd) = crate::vfs::resolve_path(&caller.data().cwd, &path)
+                        else {
+                            return Ok(ERR_INVALID_ARGUMENT);
+                        };
+                        if !cwd.ends_with('/') {
+                            cwd.push('/');
+                        }
+                        caller.data_mut().cwd = cwd;
+                        Ok(OK)
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.file_exists(path_ptr, path_len) -> u32
+        // Returns 1 if the file exists, 0 if it does not, and 2 (ERR_PERMISSION_DENIED)
+        // if the agent may not read the path. Contents are never copied.
+        host.register(
+            "file_exists",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             path_ptr: u32,
+             path_len: u32|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "file_exists",
+                    format_args!("{path_ptr}, {path_len}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        let caps = agent_capabilities(AgentId(agent_pid));
+
+                        let path = read_str(caller, path_ptr, path_len)?;
+
+                        let Some(path) = crate::vfs::resolve_path(&caller.data().cwd, &path) else {
+                            return Ok(ERR_INVALID_ARGUMENT);
+                        };
+
+                        if !crate::capability::can_read_file(&caps, &path) {
+                            return Ok(ERR_PERMISSION_DENIED);
+                        }
+
+                        Ok(crate::vfs::exists_as(&path, agent_pid) as u32)
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.file_stat(path_ptr, path_len, out_ptr) -> u32
+        // Writes 16 bytes to out_ptr: size:u32le, flags:u32le (bit 0 = read-only) and
+        // mtime:u64le in Unix seconds (0 if unknown; initramfs files report the time
+        // recorded in the archive). Returns OK, ERR_PERMISSION_DENIED, ERR_NOT_FOUND or
+        // ERR_INVALID_ARGUMENT.
+        host.register(
+            "file_stat",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             path_ptr: u32,
+             path_len: u32,
+             out_ptr: u32|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "file_stat",
+                    format_args!("{path_ptr}, {path_len}, {out_ptr}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        let caps = agent_capabilities(AgentId(agent_pid));
+
+                        let path = read_str(caller, path_ptr, path_len)?;
+                        let Some(path) = crate::vfs::resolve_path(&caller.data().cwd, &path) else {
+                            return Ok(ERR_INVALID_ARGUMENT);
+                        };
+                        if !crate::capability::can_read_file(&caps, &path) {
+                            return Ok(ERR_PERMISSION_DENIED);
+                        }
+                        let Some(stat) = crate::vfs::stat_as(&path, agent_pid) else {
+                            return Ok(ERR_NOT_FOUND);
+                        };
+
+                        let mut out = [0u8; 16];
+                        out[0..4].copy_from_slice(&(stat.size as u32).to_le_bytes());
+                        out[4..8].copy_from_slice(&u32::from(stat.read_only).to_le_bytes());
+                        out[8..16].copy_from_slice(&stat.mtime.to_le_bytes());
+                        write_bytes(caller, out_ptr, &out)?;
+                        Ok(OK)
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.file_verify(path_ptr, path_len) -> u32
+        // Returns OK if the file's contents match its recorded SHA-256 digest, and
+        // ERR_GENERAL if the file is missing, has no digest, or has been tampered with.
+        host.register(
+            "file_verify",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             path_ptr: u32,
+             path_len: u32|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "file_verify",
+                    format_args!("{path_ptr}, {path_len}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        let caps = agent_capabilities(AgentId(agent_pid));
+
+                        let path = read_str(caller, path_ptr, path_len)?;
+
+                        let Some(path) = crate::vfs::resolve_path(&caller.data().cwd, &path) else {
+                            return Ok(ERR_INVALID_ARGUMENT);
+                        };
+
+                        if !crate::capability::can_read_file(&caps, &path) {
+                            crate::audit::log_denial(
+                                agent_pid,
+                                format_args!("Agent {agent_pid} denied file verify: {path}"),
+                            );
+                            return Ok(ERR_PERMISSION_DENIED);
+                        }
+
+                        if crate::vfs::verify(&path) {
+                            Ok(OK)
+                        } else {
+                            serial_println!(
+                                "[VFS] Integrity check failed for {} (Agent {})",
+                                path,
+                                agent_pid
+                            );
+                            Ok(ERR_GENERAL)
+                        }
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.get_time() -> u64
+        host.register(
+            "get_time",
+            |mut caller: wasmi::Caller<'_, WasmState>| -> Result<u64, Trap> {
+                traced(&mut caller, "get_time", format_args!(""), |caller| {
+                    Ok(agent_unix_timestamp(caller.data().agent_pid))
+                })
+            },
+        )?;
+
+        // Host Function: env.get_time_iso8601(out_ptr, out_len_ptr) -> u32
+        // Writes the current UTC time as an ISO 8601 string, e.g. `2024-02-29T13:45:00Z`.
+        host.register(
+            "get_time_iso8601",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             out_ptr: u32,
+             out_len_ptr: u32|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "get_time_iso8601",
+                    format_args!("{out_ptr}, {out_len_ptr}"),
+                    |caller| {
+                        let formatted = crate::time::format_iso8601(agent_unix_timestamp(
+                            caller.data().agent_pid,
+                        ));
+                        let write_len = formatted.len() as u32;
+
+                        write_bytes(caller, out_ptr, formatted.as_bytes())?;
+                        write_u32(caller, out_len_ptr, write_len)?;
+                        Ok(OK)
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.get_uptime_ms() -> u64
+        host.register(
+            "get_uptime_ms",
+            |mut caller: wasmi::Caller<'_, WasmState>| -> Result<u64, Trap> {
+                traced(&mut caller, "get_uptime_ms", format_args!(""), |caller| {
+                    let uptime = crate::time::uptime_ms();
+                    let caps = agent_capabilities(AgentId(caller.data().agent_pid));
+                    if crate::capability::can_read_clock(&caps) {
+                        Ok(uptime)
+                    } else {
+                        Ok(uptime - uptime % COARSE_UPTIME_MS)
+                    }
+                })
+            },
+        )?;
+
+        // Host Function: env.get_nanos() -> u64
+        // Nanoseconds since boot-time TSC calibration, for fine-grained timing.
+        host.register(
+            "get_nanos",
+            |mut caller: wasmi::Caller<'_, WasmState>| -> Result<u64, Trap> {
+                traced(&mut caller, "get_nanos", format_args!(""), |caller| {
+                    let nanos = crate::time::nanos();
+                    let caps = agent_capabilities(AgentId(caller.data().agent_pid));
+                    if crate::capability::can_read_clock(&caps) {
+                        Ok(nanos)
+                    } else {
+                        Ok(nanos - nanos % (COARSE_UPTIME_MS * 1_000_000))
+                    }
+                })
+            },
+        )?;
+
+        // Host Function: env.monotonic_ns() -> u64
+        // Like get_nanos, but never goes backwards: each call returns more than the
+        // last, across all agents, even if the TSC is recalibrated. Coarsened values
+        // (no Capability::Clock in strict mode) may repeat but never decrease.
+        host.register(
+            "monotonic_ns",
+            |mut caller: wasmi::Caller<'_, WasmState>| -> Result<u64, Trap> {
+                traced(&mut caller, "monotonic_ns", format_args!(""), |caller| {
+                    let nanos = crate::time::monotonic_nanos();
+                    let caps = agent_capabilities(AgentId(caller.data().agent_pid));
+                    if crate::capability::can_read_clock(&caps) {
+                        Ok(nanos)
+                    } else {
+                        Ok(nanos - nanos % (COARSE_UPTIME_MS * 1_000_000))
+                    }
+                })
+            },
+        )?;
+
+        // Host Function: env.revoke_capability(index) -> u32
+        // Give up the caller's capability at `index` in the list_capabilities order.
+        // Holders it was delegated to keep their share. Returns OK or ERR_NOT_FOUND.
+        host.register(
+            "revoke_capability",
+            |mut caller: wasmi::Caller<'_, WasmState>, index: u32| -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "revoke_capability",
+                    format_args!("{index}"),
+                    |caller| {
+                        let agent = AgentId(caller.data().agent_pid);
+                        let held: Vec<CapabilityId> = agent_capabilities(agent)
+                            .into_iter()
+                            .filter(|&id| crate::capability::validate_capability(id).is_some())
+                            .collect();
+                        match held.get(index as usize) {
+                            Some(&cap) if crate::capability::revoke_from_agent(agent, cap) => {
+                                Ok(OK)
+                            }
+                            _ => Ok(ERR_NOT_FOUND),
+                        }
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.net_reinit() -> u32
+        // Reset the NIC and rebuild the network stack. Requires Capability::Port for the
+        // card's I/O base. All open sockets are dropped. Returns OK, ERR_PERMISSION_DENIED,
+        // ERR_NETWORK_UNREACHABLE (no NIC) or ERR_TIMEOUT (stack lock held).
+        host.register(
+            "net_reinit",
+            |mut caller: wasmi::Caller<'_, WasmState>| -> Result<u32, Trap> {
+                traced(&mut caller, "net_reinit", format_args!(""), |caller| {
+                    let agent_pid = caller.data().agent_pid;
+                    if let Some(code) = deny_net_admin(agent_pid, "net_reinit") {
+                        return Ok(code);
+                    }
+
+                    serial_println!("[NET] Agent {agent_pid} requested network reinit");
+                    Ok(match crate::net::reinit() {
+                        Ok(_) => OK,
+                        Err(NetError::Unavailable) => ERR_NETWORK_UNREACHABLE,
+                        Err(NetError::Timeout) => ERR_TIMEOUT,
+                    })
+                })
+            },
+        )?;
+
+        // Host Function: env.net_add_static_arp(ip_ptr, mac_ptr) -> u32
+        // Pin the 4-byte IPv4 address at ip_ptr to the 6-byte MAC at mac_ptr, so traffic
+        // to it never waits on ARP. Requires Capability::Port for the NIC's I/O base, as
+        // net_reinit does. Returns OK, ERR_PERMISSION_DENIED, ERR_NETWORK_UNREACHABLE or
+        // ERR_TIMEOUT.
+        host.register(
+            "net_add_static_arp",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             ip_ptr: u32,
+             mac_ptr: u32|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "net_add_static_arp",
+                    format_args!("{ip_ptr}, {mac_ptr}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        if let Some(code) = deny_net_admin(agent_pid, "net_add_static_arp") {
+                            return Ok(code);
+                        }
+                        let ip = read_bytes(caller, ip_ptr, 4)?;
+                        let mac = read_bytes(caller, mac_ptr, 6)?;
+                        let ip = smoltcp::wire::Ipv4Address::from_bytes(&ip);
+                        let mac = smoltcp::wire::EthernetAddress::from_bytes(&mac);
+
+                        serial_println!("[NET] Agent {agent_pid} pinned {ip} to {mac}");
+                        Ok(match crate::net::add_static_arp(ip, mac) {
+                            Ok(()) => OK,
+                            Err(NetError::Unavailable) => ERR_NETWORK_UNREACHABLE,
+                            Err(NetError::Timeout) => ERR_TIMEOUT,
+                        })
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.net_remove_static_arp(ip_ptr) -> u32
+        // Drop the static ARP entry for the IPv4 address at ip_ptr. Same capability as
+        // net_add_static_arp. Returns OK, ERR_PERMISSION_DENIED or ERR_NOT_FOUND.
+        host.register(
+            "net_remove_static_arp",
+            |mut caller: wasmi::Caller<'_, WasmState>, ip_ptr: u32| -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "net_remove_static_arp",
+                    format_args!("{ip_ptr}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        if let Some(code) = deny_net_admin(agent_pid, "net_remove_static_arp") {
+                            return Ok(code);
+                        }
+                        let ip = read_bytes(caller, ip_ptr, 4)?;
+                        let ip = smoltcp::wire::Ipv4Address::from_bytes(&ip);
+                        if crate::net::remove_static_arp(ip) {
+                            Ok(OK)
+                        } else {
+                            Ok(ERR_NOT_FOUND)
+                        }
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.system_reboot() -> u32
+        // Flush the serial log and reset the machine. Requires Capability::Power; only
+        // returns (with ERR_PERMISSION_DENIED) when the caller lacks it.
+        host.register(
+            "system_reboot",
+            |mut caller: wasmi::Caller<'_, WasmState>| -> Result<u32, Trap> {
+                traced(&mut caller, "system_reboot", format_args!(""), |caller| {
+                    let agent_pid = caller.data().agent_pid;
+                    let caps = agent_capabilities(AgentId(agent_pid));
+                    if !crate::capability::can_control_power(&caps) {
+                        crate::audit::log_denial(
+                            agent_pid,
+                            format_args!("Agent {agent_pid} denied system_reboot"),
+                        );
+                        return Ok(ERR_PERMISSION_DENIED);
+                    }
+
+                    serial_println!("[POWER] Agent {agent_pid} requested reboot");
+                    crate::serial::flush();
+                    crate::arch::reboot()
+                })
+            },
+        )?;
+
+        // Host Function: env.system_shutdown(code) -> u32
+        // Flush the serial log and power off, exiting QEMU with `code` when the
+        // debug-exit device is present. Requires Capability::Power; only returns (with
+        // ERR_PERMISSION_DENIED) when the caller lacks it.
+        host.register(
+            "system_shutdown",
+            |mut caller: wasmi::Caller<'_, WasmState>, code: u32| -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "system_shutdown",
+                    format_args!("{code}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        let caps = agent_capabilities(AgentId(agent_pid));
+                        if !crate::capability::can_control_power(&caps) {
+                            crate::audit::log_denial(
+                                agent_pid,
+                                format_args!("Agent {agent_pid} denied system_shutdown"),
+                            );
+                            return Ok(ERR_PERMISSION_DENIED);
+                        }
+
+                        serial_println!("[POWER] Agent {agent_pid} requested shutdown ({code})");
+                        crate::serial::flush();
+                        crate::arch::shutdown(code)
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.list_capabilities(out_ptr, out_len_ptr) -> u32
+        // Writes the caller's capability set, encoded with `capability::encode_list`, to
+        // out_ptr and its length to out_len_ptr.
+        host.register(
+            "list_capabilities",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             out_ptr: u32,
+             out_len_ptr: u32|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "list_capabilities",
+                    format_args!("{out_ptr}, {out_len_ptr}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        write_capability_list(caller, agent_pid, out_ptr, out_len_ptr)
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.list_agent_capabilities(target_pid, out_ptr, out_len_ptr) -> u32
+        // As list_capabilities, for another agent. Requires Capability::IpcInspect for
+        // target_pid; attempts are audited like inspect_ipc.
+        host.register(
+            "list_agent_capabilities",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             target_pid: u64,
+             out_ptr: u32,
+             out_len_ptr: u32|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "list_agent_capabilities",
+                    format_args!("{target_pid}, {out_ptr}, {out_len_ptr}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+                        if target_pid != agent_pid {
+                            let caps = agent_capabilities(AgentId(agent_pid));
+                            let allowed =
+                                crate::capability::can_inspect_ipc(&caps, target_pid);
+                            crate::audit::record(crate::audit::AuditEvent::IpcInspect {
+                                inspector: agent_pid,
+                                target: target_pid,
+                                allowed,
+                            });
+                            if !allowed {
+                                crate::audit::log_denial(
+                                    agent_pid,
+                                    format_args!("Agent {agent_pid} denied capability listing of Agent {target_pid}"),
+                                );
+                                return Ok(ERR_PERMISSION_DENIED);
+                            }
+                        }
+                        write_capability_list(caller, target_pid, out_ptr, out_len_ptr)
+                    },
+                )
+            },
+        )?;
+
+        // Host Function: env.request_capability(cap_type: u32, detail_ptr: u32, detail_len: u32) -> u32
+        // cap_type: 0=Network, 1=FileSystem, 2=Spawn
+        // detail: for FileSystem = path prefix string; for others = unused
+        // A granted Spawn keeps the allowed_prefix the agent or its nearest spawning
+        // ancestor is limited to.
+        // Returns OK (granted), ERR_PERMISSION_DENIED (refused by policy),
+        // ERR_INVALID_ARGUMENT (unknown cap_type) or ERR_PENDING (left to the supervisor;
+        // the agent may retry later).
+        host.register(
+            "request_capability",
+            |mut caller: wasmi::Caller<'_, WasmState>,
+             cap_type: u32,
+             detail_ptr: u32,
+             detail_len: u32|
+             -> Result<u32, Trap> {
+                traced(
+                    &mut caller,
+                    "request_capability",
+                    format_args!("{cap_type}, {detail_ptr}, {detail_len}"),
+                    |caller| {
+                        let agent_pid = caller.data().agent_pid;
+
+                        let detail_buf = read_bytes(caller, detail_ptr, detail_len)?;
+
+                        let detail_str = core::str::from_utf8(&detail_buf).unwrap_or("");
+
+                        serial_println!(
+                            "[ESCALATION] Agent {} requests capability type={} detail='{}'",
+                            agent_pid,
+                            cap_type,
+                            detail_str
+                        );
+
+                        // The prefix is canonicalized the way file paths are, so the grant
+                        // (and the supervisor's view of it) cannot be dodged with `..`.
+                        let capability = match cap_type {
+                            0 => Capability::Network,
+                            1 => {
+                                let requested = if detail_str.is_empty() {
+                                    "/agent/"
+                                } else {
+                                    detail_str
+                                };
+                                let Some(path_prefix) =
+                                    crate::vfs::resolve_path(&caller.data().cwd, requested)
+                                else {
+                                    return Ok(ERR_INVALID_ARGUMENT);
+                                };
+                                Capability::FileSystem {
+                                    path_prefix,
+                                    read: true,
+                                    write: true,
+                                }
+                            }
+                            // An agent limited to spawning from a prefix, or spawned by one
+                            // that is, must not escape the limit by asking again.
+                            2 => Capability::Spawn {
+                                max_children: 5,
+                                max_depth: crate::task::MAX_SPAWN_DEPTH,
+                                allowed_prefix: crate::task::inherited_spawn_prefix(AgentId(
+                                    agent_pid,
+                                )),
+                            },
+                            _ => {
+                                serial_println!(
+                                    "[ESCALATION] Unknown capability type {} from Agent {}",
+                                    cap_type,
+                                    agent_pid
+                                );
+                                return Ok(ERR_INVALID_ARGUMENT);
+                            }
+                        };
+
+                        // Policy: explicit per-agent decisions win; otherwise the kernel grants
+                        // everything, or nothing in strict mode.
+                        let decision = crate::capability::request_decision(agent_pid, cap_type);
+                        if decision == RequestDecision::Deny {
+                            crate::audit::log_denial(
+                                agent_pid,
+                                format_args!("Policy denied capability type={cap_type} to Agent {agent_pid}"),
+                            );
+                            return Ok(ERR_PERMISSION_DENIED);
+                        }
+
+                        // Send IPC escalation to Kernel Supervisor (PID 0), with the
+                        // canonical prefix for FileSystem requests.
+                        let detail = match &capability {
+                            Capability::FileSystem { path_prefix, .. } => path_prefix.as_str(),
+                            _ => "",
+                        };
+                        let mut ipc_msg = crate::ipc::CAP_REQUEST_PREFIX.to_vec();
+                        ipc_msg.extend_from_slice(
+                            alloc::format!("{agent_pid}:{cap_type}:{detail}").as_bytes(),
+                        );
+                        let sender = crate::ipc::ProcessId(agent_pid);
+                        let _ = crate::ipc::send_message(
+                            sender,
+                            crate::ipc::KERNEL_SUPERVISOR_PID,
+                            ipc_msg,
+                            Vec::new(),
+                        );
+
+                        if decision == RequestDecision::Prompt {
+                            serial_println!(
+                                "[ESCALATION] Capability type={cap_type} for Agent {agent_pid} awaits the supervisor"
+                            );
+                            return Ok(ERR_PENDING);
+                        }
+
+                        serial_println!(
+                            "[ESCALATION] Granted {:?} to Agent {}",
+                            capability,
+                            agent_pid
+                        );
+                        let cap = crate::capability::create_capability(capability);
+                        crate::task::grant_capability_to_agent(AgentId(agent_pid), cap);
+                        Ok(OK)
+                    },
+                )
+            },
+        )?;
+
+        let pre = linker
+            .instantiate(&mut store, &module)
+            .map_err(|e| alloc::format!("Failed to instantiate module: {e}"))?;
+        store.data_mut().starting = true;
+        let started = pre.start(&mut store);
+        store.data_mut().starting = false;
+        let instance = started.map_err(|e| classify_start_error(&e))?;
+
+        Ok((store, instance))
+    }
+}
+
+/// Run the loads queued by host functions: file servers from `register_dynamic_file`
+/// (children from `spawn_agent_with_caps` go through `task::queue_spawn`). Compiling and linking take the engine's
+/// resource lock for writing, which is held for reading while any module runs, so the
+/// executor calls this between slices rather than from inside a host call.
+pub fn run_pending_loads() {
+    while let Some(load) = PENDING_LOADS.lock().pop_front() {
+        match load {
+            PendingLoad::FileServer {
+                runtime,
+                module,
+                agent_pid,
+            } => match runtime.link_module(module, agent_pid, &DEFAULT_LIMITS) {
+                Ok((store, instance)) => {
+                    // The owner may have exited while the server was waiting to load.
+                    if let Some(server) = FILE_SERVERS.lock().get_mut(&agent_pid) {
+                        server.handle = Some(InstanceHandle { store, instance });
+                    }
+                }
+                Err(e) => {
+                    serial_println!(
+                        "[VFS] Agent {} file server failed to load: {}",
+                        agent_pid,
+                        e
+                    );
+                    remove_file_server(agent_pid);
+                }
+            },
+        }
+    }
+}
+
+/// A module waiting to be instantiated outside of any running host call.
+enum PendingLoad {
+    /// A library instance of an agent's module that serves its dynamic files.
+    FileServer {
+        runtime: WasmRuntime,
+        module: Arc<Module>,
+        agent_pid: u64,
+    },
+}
+
+static PENDING_LOADS: spin::Mutex<VecDeque<PendingLoad>> = spin::Mutex::new(VecDeque::new());
+
+/// Export a file-serving module provides: `read_dynamic_file(id) -> u64`, returning the
+/// file's address in its memory in the high 32 bits and its length in the low 32.
+const FILE_GENERATOR_EXPORT: &str = "read_dynamic_file";
+
+/// Dynamic files a single agent may serve.
+const MAX_SERVED_FILES: usize = 16;
+
+/// Largest file contents a generator may return.
+const MAX_SERVED_FILE_BYTES: usize = 64 * 1024;
+
+/// The dynamic files an agent serves and the library instance that generates them.
+struct FileServer {
+    /// Registered paths; a file's id is its index here.
+    files: Vec<String>,
+    /// `None` until the instance has loaded, and while a read is using it.
+    handle: Option<InstanceHandle>,
+}
+
+static FILE_SERVERS: spin::Mutex<BTreeMap<u64, FileServer>> = spin::Mutex::new(BTreeMap::new());
+
+/// Remove the dynamic files served by `agent_pid` and drop its library instance,
+/// returning how many files there were.
+pub fn remove_file_server(agent_pid: u64) -> usize {
+    let server = FILE_SERVERS.lock().remove(&agent_pid);
+    drop(server);
+    crate::vfs::remove_agent_files(agent_pid)
+}
+
+/// `vfs::AgentFileReader` for files registered through `register_dynamic_file`.
+/// Reads fail while the server is still loading or already busy with another read,
+/// which includes a generator reading one of its own files.
+fn read_agent_file(owner: u64, path: &str) -> Option<Vec<u8>> {
+    let (id, mut handle) = {
+        let mut servers = FILE_SERVERS.lock();
+        let server = servers.get_mut(&owner)?;
+        let id = server.files.iter().position(|f| f == path)?;
+        (id as u32, server.handle.take()?)
+    };
+    let result = generate_file(&mut handle, id);
+    if let Some(server) = FILE_SERVERS.lock().get_mut(&owner) {
+        server.handle = Some(handle);
+    }
+    match result {
+        Ok(data) => Some(data),
+        Err(e) => {
+            serial_println!("[VFS] Agent {} failed to serve {}: {}", owner, path, e);
+            None
+        }
+    }
+}
+
+/// Call the generator export for file `id` and copy its output out of the instance.
+fn generate_file(handle: &mut InstanceHandle, id: u32) -> Result<Vec<u8>, String> {
+    let generator = handle
+        .instance
+        .get_typed_func::<u32, u64>(&handle.store, FILE_GENERATOR_EXPORT)
+        .map_err(|e| alloc::format!("Bad {FILE_GENERATOR_EXPORT} export: {e}"))?;
+    let packed = generator
+        .call(&mut handle.store, id)
+        .map_err(|e| alloc::format!("Call to {FILE_GENERATOR_EXPORT} failed: {e}"))?;
+    let (ptr, len) = ((packed >> 32) as usize, (packed & 0xffff_ffff) as usize);
+    if len > MAX_SERVED_FILE_BYTES {
+        return Err(alloc::format!("Generated {len} bytes"));
+    }
+    let memory = handle
+        .instance
+        .get_memory(&handle.store, "memory")
+        .ok_or_else(|| String::from("No memory export"))?;
+    let mut data = alloc::vec![0u8; len];
+    memory
+        .read(&handle.store, ptr, &mut data)
+        .map_err(|e| alloc::format!("Out of bounds: {e}"))?;
+    Ok(data)
+}
+
+/// A module instantiated by `WasmRuntime::instantiate`, kept alive between calls.
+pub struct InstanceHandle {
+    store: Store<WasmState>,
+    instance: Instance,
+}
+
+impl InstanceHandle {
+    pub fn agent_pid(&self) -> u64 {
+        self.store.data().agent_pid
+    }
+}
+
+/// Registers host functions under the `env` import module.
+struct HostModule<'a> {
+    linker: &'a mut Linker<WasmState>,
+}
+
+impl HostModule<'_> {
+    fn register<Params, Args>(
+        &mut self,
+        name: &str,
+        func: impl IntoFunc<WasmState, Params, Args>,
+    ) -> Result<(), String> {
+        self.linker
+            .func_wrap("env", name, func)
+            .map(|_| ())
+            .map_err(|e| alloc::format!("Failed to define {name}: {e}"))
+    }
+}
+
+/// The error code to return if `agent_pid` may not administer the NIC, i.e. lacks
+/// Capability::Port for its I/O base. Denials are logged against `call`.
+fn deny_net_admin(agent_pid: u64, call: &str) -> Option<u32> {
+    let io_base = match crate::net::with_network(|net| net.device.io_base()) {
+        Ok(io_base) => io_base,
+        Err(NetError::Unavailable) => return Some(ERR_NETWORK_UNREACHABLE),
+        Err(NetError::Timeout) => return Some(ERR_TIMEOUT),
+    };
+    if crate::capability::can_access_port(&agent_capabilities(AgentId(agent_pid)), io_base) {
+        return None;
+    }
+    crate::audit::log_denial(agent_pid, format_args!("Agent {agent_pid} denied {call}"));
+    Some(ERR_PERMISSION_DENIED)
+}
+
+/// Fail a host call with `code` for a condition that may clear on its own. While the
+/// module's start function runs this traps with `TransientError` instead, so the
+/// supervisor can retry instantiation rather than leave the module half-initialized.
+fn transient_failure(
+    caller: &wasmi::Caller<'_, WasmState>,
+    code: u32,
+    reason: &str,
+) -> Result<u32, Trap> {
+    if caller.data().starting {
+        Err(Trap::from(TransientError(String::from(reason))))
+    } else {
+        Ok(code)
+    }
+}
+
+/// Render the first `buf.len()` bytes of a `total_len`-byte log message. Invalid UTF-8
+/// is shown as the valid prefix followed by the remaining bytes in hex. If the message
+/// was cut short, a character split by the cut is dropped and a marker gives the number
+/// of bytes not shown.
+fn render_log_message(buf: &[u8], total_len: usize) -> String {
+    use core::fmt::Write;
+
+    let truncated = total_len > buf.len();
+    let mut out = String::new();
+    let mut shown = buf.len();
+    match core::str::from_utf8(buf) {
+        Ok(text) => out.push_str(text),
+        Err(e) => {
+            let (valid, rest) = buf.split_at(e.valid_up_to());
+            out.push_str(core::str::from_utf8(valid).unwrap_or_default());
+            if truncated && e.error_len().is_none() {
+                shown = valid.len();
+            } else {
+                out.push_str(" <invalid utf-8:");
+                for byte in rest {
+                    let _ = write!(out, " {byte:02x}");
+                }
+                out.push('>');
+            }
+        }
+    }
+    if truncated {
+        let _ = write!(out, " ...[truncated {} bytes]", total_len - shown);
+    }
+    out
+}
+
+fn socket_option_error(e: crate::sockets::OptionError) -> u32 {
+    match e {
+        crate::sockets::OptionError::NotFound => ERR_NOT_FOUND,
+        crate::sockets::OptionError::Unsupported | crate::sockets::OptionError::InvalidValue => {
+            ERR_INVALID_ARGUMENT
+        }
+    }
+}
+
+fn socket_transfer_error(e: crate::sockets::TransferError) -> u32 {
+    match e {
+        crate::sockets::TransferError::NotFound => ERR_NOT_FOUND,
+        crate::sockets::TransferError::Unsupported => ERR_INVALID_ARGUMENT,
+        crate::sockets::TransferError::NotConnected => ERR_GENERAL,
+    }
+}
+
+/// Encode `pid`'s capabilities into guest memory at `out_ptr`, storing the length at
+/// `out_len_ptr`.
+fn write_capability_list(
+    caller: &mut wasmi::Caller<'_, WasmState>,
+    pid: u64,
+    out_ptr: u32,
+    out_len_ptr: u32,
+) -> Result<u32, Trap> {
+    let caps = crate::capability::dump_capabilities(&agent_capabilities(AgentId(pid)));
+    let bytes = crate::capability::encode_list(&caps);
+    write_bytes(caller, out_ptr, &bytes)?;
+    caller.data_mut().add_bytes(bytes.len());
+    write_u32(caller, out_len_ptr, bytes.len() as u32)?;
+    Ok(OK)
+}
+
+fn host_error(message: &str) -> Trap {
+    Trap::from(HostError(String::from(message)))
+}
+
+/// Copy `len` bytes of guest memory starting at `ptr`.
+fn read_bytes(
+    caller: &mut wasmi::Caller<'_, WasmState>,
+    ptr: u32,
+    len: u32,
+) -> Result<Vec<u8>, Trap> {
+    let mut buf = alloc::vec![0u8; len as usize];
+    if len > 0 {
+        get_memory(caller)?
+            .read(&caller, ptr as usize, &mut buf)
+            .map_err(|_| host_error("Memory read failed"))?;
+    }
+    Ok(buf)
+}
+
+/// Copy a UTF-8 string out of guest memory.
+fn read_str(caller: &mut wasmi::Caller<'_, WasmState>, ptr: u32, len: u32) -> Result<String, Trap> {
+    String::from_utf8(read_bytes(caller, ptr, len)?).map_err(|_| host_error("Invalid UTF-8 string"))
+}
+
+/// Copy `data` into guest memory at `ptr`.
+fn write_bytes(
+    caller: &mut wasmi::Caller<'_, WasmState>,
+    ptr: u32,
+    data: &[u8],
+) -> Result<(), Trap> {
+    get_memory(caller)?
+        .write(&mut *caller, ptr as usize, data)
+        .map_err(|_| host_error("Memory write failed"))
+}
+
+/// Store a little-endian `u32` (e.g. an out-length) into guest memory at `ptr`.
+fn write_u32(caller: &mut wasmi::Caller<'_, WasmState>, ptr: u32, value: u32) -> Result<(), Trap> {
+    write_bytes(caller, ptr, &value.to_le_bytes())
+}
+
+// Helper to extract the single exported memory from a Caller
+fn get_memory<'a>(caller: &mut wasmi::Caller<'a, WasmState>) -> Result<Memory, Trap> {
+    caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| host_error("Failed to find 'memory' export"))
+}
+
+// Wall-clock time as `pid` may see it: exact with `Capability::Clock` (or outside
+// strict mode), otherwise rounded down to `COARSE_TIME_SECS`.
+fn agent_unix_timestamp(pid: u64) -> u64 {
+    let now = crate::time::unix_timestamp();
+    if crate::capability::can_read_clock(&agent_capabilities(AgentId(pid))) {
+        now
+    } else {
+        now - now % COARSE_TIME_SECS
+    }
+}
+
+/// A value a host function hands back to the guest, rendered for syscall traces.
+trait HostReturn {
+    fn describe(&self) -> String;
+    /// The value as wasmi sees it, used to resume a call suspended by a yield.
+    fn to_values(&self) -> Vec<Value>;
+}
+
+impl HostReturn for () {
+    fn describe(&self) -> String {
+        String::from("OK")
+    }
+
+    fn to_values(&self) -> Vec<Value> {
+        Vec::new()
+    }
+}
+
+impl HostReturn for u32 {
+    fn describe(&self) -> String {
+        String::from(error_message(*self))
+    }
+
+    fn to_values(&self) -> Vec<Value> {
+        alloc::vec![Value::I32(*self as i32)]
+    }
+}
+
+impl HostReturn for u64 {
+    fn describe(&self) -> String {
+        alloc::format!("{self}")
+    }
+
+    fn to_values(&self) -> Vec<Value> {
+        alloc::vec![Value::I64(*self as i64)]
+    }
+}
+
+// Shared wrapper around every host function body. When tracing is disabled this is
+// a single flag check; the arguments are only formatted once tracing is on.
+// Every call is counted for `WasmRuntime::profile` and timed for `latency_report`.
+// It is also one of the executor's preemption points (with `kernel.preempt`): once the
+// slice's fuel is spent, the call's result is stashed and the module is suspended with
+// a `Yield`.
+fn traced<'a, R, F>(
+    caller: &mut wasmi::Caller<'a, WasmState>,
+    name: &'static str,
+    args: fmt::Arguments<'_>,
+    body: F,
+) -> Result<R, Trap>
+where
+    R: HostReturn,
+    F: FnOnce(&mut wasmi::Caller<'a, WasmState>) -> Result<R, Trap>,
+{
+    let state = caller.data_mut();
+    state.host_calls.entry(name).or_default().calls += 1;
+    state.current_call = name;
+
+    let started = crate::time::rdtsc();
+    let ret = if caller.data().trace {
+        let pid = caller.data().agent_pid;
+        let result = body(caller);
+        match &result {
+            Ok(ret) => serial_println!(
+                "[TRACE pid={}] {}({}) -> {}",
+                pid,
+                name,
+                args,
+                ret.describe()
+            ),
+            Err(trap) => {
+                serial_println!("[TRACE pid={}] {}({}) -> trap: {}", pid, name, args, trap)
+            }
+        }
+        result
+    } else {
+        body(caller)
+    };
+    let elapsed = crate::time::rdtsc().wrapping_sub(started);
+    LATENCY
+        .lock()
+        .entry(name)
+        .or_insert(LatencyHistogram::new())
+        .record(elapsed);
+    let ret = ret?;
+
+    if let Some(slice_end) = caller.data().slice_end {
+        if caller.fuel_consumed().unwrap_or(0) >= slice_end {
+            caller.data_mut().pending_return = ret.to_values();
+            return Err(Trap::from(Yield));
+        }
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capability::Capability;
+    use crate::testing::{self, Code, ModuleBuilder, I32, I64};
+    use alloc::format;
+
+    /// Where host calls in test modules write their output.
+    const OUT: i32 = 1024;
+    /// Where host calls in test modules write an output length.
+    const OUT_LEN: i32 = 1020;
+
+    /// A module whose `run` export calls `env.<name>` with the constant i32 `args` and
+    /// returns its status code; `data` is placed at address 0.
+    fn status_module(name: &str, args: &[i32], data: &[u8]) -> Vec<u8> {
+        let mut m = ModuleBuilder::new();
+        let params = alloc::vec![I32; args.len()];
+        let import = m.import(name, &params, &[I32]);
+        let body = args.iter().fold(Code::new(), |code, &arg| code.i32(arg));
+        let run = m.func(&[], &[I32], &[], body.call(import));
+        m.export("run", run).data(0, data);
+        m.build()
+    }
+
+    /// Call `run` on a fresh instance of `wasm` for `agent`, returning its status code
+    /// and the instance's linear memory afterwards.
+    fn run_with_memory(runtime: &WasmRuntime, wasm: &[u8], agent: AgentId) -> (u32, Vec<u8>) {
+        let mut handle = runtime.instantiate(wasm, agent.0).unwrap();
+        let status = runtime.call_export(&mut handle, "run", &[]).unwrap()[0]
+            .i32()
+            .unwrap() as u32;
+        let memory = handle.instance.get_memory(&handle.store, "memory").unwrap();
+        (status, memory.data(&handle.store).to_vec())
+    }
+
+    /// The `len`-prefixed output a host call wrote at `OUT`/`OUT_LEN`.
+    fn output(memory: &[u8]) -> &[u8] {
+        let len = crate::bytes::read_u32_le(memory, OUT_LEN as usize).unwrap() as usize;
+        &memory[OUT as usize..OUT as usize + len]
+    }
+
+    /// A module whose `run` export logs "hi" and then reads the clock.
+    fn log_then_time_module() -> Vec<u8> {
+        let mut m = ModuleBuilder::new();
+        let debug_log = m.import("debug_log", &[I32, I32], &[]);
+        let get_time = m.import("get_time", &[], &[I64]);
+        let body = Code::new()
+            .i32(0)
+            .i32(2)
+            .call(debug_log)
+            .call(get_time)
+            .drop();
+        let run = m.func(&[], &[], &[], body);
+        m.export("run", run).data(0, b"hi");
+        m.build()
+    }
+
+    #[test_case]
+    fn trace_logs_host_calls_in_order() {
+        let mut runtime = WasmRuntime::new();
+        runtime.set_trace(true);
+        let agent = testing::spawn_agent("trace-on", Vec::new());
+        testing::call(&runtime, &log_then_time_module(), agent, "run", &[]).unwrap();
+
+        let tail = testing::log_tail();
+        let log = tail
+            .find(&format!("[TRACE pid={}] debug_log(0, 2) -> OK", agent.0))
+            .expect("debug_log was not traced");
+        let time = tail
+            .find(&format!("[TRACE pid={}] get_time() -> ", agent.0))
+            .expect("get_time was not traced");
+        assert!(log < time);
+    }
+
+    #[test_case]
+    fn trace_is_off_by_default() {
+        let runtime = WasmRuntime::new();
+        let agent = testing::spawn_agent("trace-off", Vec::new());
+        testing::call(&runtime, &log_then_time_module(), agent, "run", &[]).unwrap();
+        assert!(!testing::logged(&format!("[TRACE pid={}]", agent.0)));
+    }
+
+    /// A module whose `revoke` export revokes the caller's first capability.
+    fn revoke_first_module() -> Vec<u8> {
+        let mut m = ModuleBuilder::new();
+        let revoke = m.import("revoke_capability", &[I32], &[I32]);
+        let run = m.func(&[], &[I32], &[], Code::new().i32(0).call(revoke));
+        m.export("revoke", run);
+        m.build()
+    }
+
+    #[test_case]
+    fn revoke_capability_drops_it_from_the_callers_list() {
+        let runtime = WasmRuntime::new();
+        let agent = testing::spawn_agent("revoker", alloc::vec![Capability::Network]);
+        let cap = agent_capabilities(agent)[0];
+
+        assert_eq!(
+            testing::call_status(&runtime, &revoke_first_module(), agent, "revoke"),
+            OK
+        );
+        assert!(agent_capabilities(agent).is_empty());
+        assert_eq!(crate::capability::refcount(cap), 0);
+        assert_eq!(
+            testing::call_status(&runtime, &revoke_first_module(), agent, "revoke"),
+            ERR_NOT_FOUND
+        );
+    }
+
+    #[test_case]
+    fn file_list_only_returns_entries_under_granted_prefixes() {
+        crate::vfs::register_file("/agent/list-visible.txt", b"", 0);
+        crate::vfs::register_file("/agents/list-sibling.txt", b"", 0);
+        crate::vfs::register_file("/system/list-secret.txt", b"", 0);
+        let runtime = WasmRuntime::new();
+        let agent = testing::spawn_agent(
+            "lister",
+            alloc::vec![Capability::FileSystem {
+                path_prefix: String::from("/agent/"),
+                read: true,
+                write: false,
+            }],
+        );
+
+        let list_root = status_module("file_list", &[0, 1, OUT, OUT_LEN], b"/");
+        let (status, memory) = run_with_memory(&runtime, &list_root, agent);
+        assert_eq!(status, OK);
+        let listing = core::str::from_utf8(output(&memory)).unwrap();
+        assert!(listing.lines().any(|f| f == "/agent/list-visible.txt"));
+        assert!(listing.lines().all(|f| f.starts_with("/agent/")));
+
+        let list_system = status_module("file_list", &[0, 8, OUT, OUT_LEN], b"/system/");
+        assert_eq!(
+            testing::call_status(&runtime, &list_system, agent, "run"),
+            ERR_PERMISSION_DENIED
+        );
+    }
+    #[test_case]
+    fn get_time_iso8601_writes_a_utc_timestamp() {
+        let runtime = WasmRuntime::new();
+        let agent = testing::spawn_agent("iso8601", Vec::new());
+        let wasm = status_module("get_time_iso8601", &[OUT, OUT_LEN], b"");
+        let (status, memory) = run_with_memory(&runtime, &wasm, agent);
+        assert_eq!(status, OK);
+        let formatted = core::str::from_utf8(output(&memory)).unwrap();
+        assert_eq!(formatted.len(), "2024-02-29T13:45:00Z".len());
+        assert_eq!(&formatted[10..11], "T");
+        assert!(formatted.ends_with('Z'));
+    }
+    /// An agent that may read everything under `/agent/`.
+    fn agent_reader(name: &str) -> AgentId {
+        testing::spawn_agent(name, alloc::vec![agent_files(false)])
+    }
+
+    /// An agent that may read and write everything under `/agent/`.
+    fn agent_writer(name: &str) -> AgentId {
+        testing::spawn_agent(name, alloc::vec![agent_files(true)])
+    }
+
+    fn agent_files(write: bool) -> Capability {
+        Capability::FileSystem {
+            path_prefix: String::from("/agent/"),
+            read: true,
+            write,
+        }
+    }
+
+    #[test_case]
+    fn relative_reads_resolve_against_the_working_directory() {
+        let runtime = WasmRuntime::new();
+        let agent = agent_reader("cwd-default");
+        let path = format!("/agent/{}/notes.txt", agent.0);
+        crate::vfs::write_file(&path, b"relative", 0);
+
+        // The default working directory is /agent/<pid>/.
+        let read = status_module("file_read", &[0, 9, OUT, OUT_LEN], b"notes.txt");
+        let (status, memory) = run_with_memory(&runtime, &read, agent);
+        assert_eq!(status, OK);
+        assert_eq!(output(&memory), b"relative");
+
+        crate::vfs::write_file("/agent/cwd-test/data.txt", b"moved", 0);
+        let mut m = ModuleBuilder::new();
+        let chdir = m.import("chdir", &[I32, I32], &[I32]);
+        let file_read = m.import("file_read", &[I32, I32, I32, I32], &[I32]);
+        let body = Code::new()
+            .i32(0)
+            .i32(15)
+            .call(chdir)
+            .drop()
+            .i32(32)
+            .i32(8)
+            .i32(OUT)
+            .i32(OUT_LEN)
+            .call(file_read);
+        let run = m.func(&[], &[I32], &[], body);
+        m.export("run", run)
+            .data(0, b"/agent/cwd-test")
+            .data(32, b"data.txt");
+        let (status, memory) = run_with_memory(&runtime, &m.build(), agent);
+        assert_eq!(status, OK);
+        assert_eq!(output(&memory), b"moved");
+    }
+
+    #[test_case]
+    fn dot_dot_cannot_escape_granted_prefixes() {
+        let runtime = WasmRuntime::new();
+        let agent = agent_reader("cwd-escape");
+        crate::vfs::register_file("/system/cwd-secret.txt", b"secret", 0);
+
+        let escape = b"../../system/cwd-secret.txt";
+        let read = status_module("file_read", &[0, escape.len() as i32, OUT, OUT_LEN], escape);
+        assert_eq!(
+            testing::call_status(&runtime, &read, agent, "run"),
+            ERR_PERMISSION_DENIED
+        );
+
+        let absolute = b"/agent/../system/cwd-secret.txt";
+        let read = status_module(
+            "file_read",
+            &[0, absolute.len() as i32, OUT, OUT_LEN],
+            absolute,
+        );
+        assert_eq!(
+            testing::call_status(&runtime, &read, agent, "run"),
+            ERR_PERMISSION_DENIED
+        );
+        assert!(testing::logged(&format!(
+            "Agent {} denied file read: /system/cwd-secret.txt",
+            agent.0
+        )));
+
+        let above_root = b"../../../etc";
+        let chdir = status_module("chdir", &[0, above_root.len() as i32], above_root);
+        assert_eq!(
+            testing::call_status(&runtime, &chdir, agent, "run"),
+            ERR_INVALID_ARGUMENT
+        );
+    }
+    #[test_case]
+    fn strict_mode_only_grants_allow_listed_requests() {
+        let runtime = WasmRuntime::new();
+        let denied = testing::spawn_agent("strict-denied", Vec::new());
+        let allowed = testing::spawn_agent("strict-allowed", Vec::new());
+        crate::capability::allow_request(allowed.0, 0);
+        let request_network = status_module("request_capability", &[0, 0, 0], b"");
+
+        crate::capability::set_strict_mode(true);
+        let denied_status = testing::call_status(&runtime, &request_network, denied, "run");
+        let allowed_status = testing::call_status(&runtime, &request_network, allowed, "run");
+        crate::capability::set_strict_mode(false);
+
+        assert_eq!(denied_status, ERR_PERMISSION_DENIED);
+        assert!(!crate::capability::can_access_network(&agent_capabilities(
+            denied
+        )));
+        assert_eq!(allowed_status, OK);
+        assert!(crate::capability::can_access_network(&agent_capabilities(
+            allowed
+        )));
+    }
+    #[test_case]
+    fn file_patch_requires_write_access_and_a_valid_delta() {
+        let runtime = WasmRuntime::new();
+        let path = b"/agent/patched.txt";
+        crate::vfs::write_file("/agent/patched.txt", b"abc", 0);
+        // ADD "xyz" then COPY 1 byte from offset 2.
+        let mut patch = alloc::vec![crate::vfs::PATCH_OP_ADD, 3, 0, 0, 0];
+        patch.extend_from_slice(b"xyz");
+        patch.extend_from_slice(&[crate::vfs::PATCH_OP_COPY, 2, 0, 0, 0, 1, 0, 0, 0]);
+        let module = |patch: &[u8]| {
+            let mut data = path.to_vec();
+            data.extend_from_slice(patch);
+            let args = [0, path.len() as i32, path.len() as i32, patch.len() as i32];
+            status_module("file_patch", &args, &data)
+        };
+
+        let reader = agent_reader("patch-reader");
+        assert_eq!(
+            testing::call_status(&runtime, &module(&patch), reader, "run"),
+            ERR_PERMISSION_DENIED
+        );
+
+        let writer = agent_writer("patch-writer");
+        assert_eq!(
+            testing::call_status(&runtime, &module(&patch[..6]), writer, "run"),
+            ERR_INVALID_ARGUMENT
+        );
+        assert_eq!(
+            testing::call_status(&runtime, &module(&patch), writer, "run"),
+            OK
+        );
+        assert_eq!(
+            crate::vfs::open_file("/agent/patched.txt").as_deref(),
+            Some(&b"xyzc"[..])
+        );
+    }
+    #[test_case]
+    fn profile_counts_host_calls_and_bytes() {
+        crate::vfs::write_file("/agent/profiled.txt", b"12345", 0);
+        let mut m = ModuleBuilder::new();
+        let file_read = m.import("file_read", &[I32, I32, I32, I32], &[I32]);
+        let tcp_request = m.import("tcp_request", &[I32, I32, I32, I32], &[I32]);
+        let read = |code: Code| {
+            code.i32(0)
+                .i32(19)
+                .i32(OUT)
+                .i32(OUT_LEN)
+                .call(file_read)
+                .drop()
+        };
+        let body = read(read(read(Code::new())))
+            .i32(32)
+            .i32(80)
+            .i32(36)
+            .i32(4)
+            .call(tcp_request)
+            .drop();
+        let start = m.func(&[], &[], &[], body);
+        m.export("_start", start)
+            .data(0, b"/agent/profiled.txt")
+            .data(32, &[10, 0, 2, 2])
+            .data(36, b"ping");
+
+        let agent = agent_reader("profiled");
+        let report = WasmRuntime::new().profile(&m.build(), agent.0).unwrap();
+        assert_eq!(report.host_calls["file_read"].calls, 3);
+        assert_eq!(report.host_calls["file_read"].bytes, 15);
+        assert_eq!(report.host_calls["tcp_request"].calls, 1);
+        assert!(report.fuel_consumed > 0);
+    }
+
+    #[test_case]
+    fn debug_log_kv_buffers_pairs_until_an_empty_key() {
+        let mut m = ModuleBuilder::new();
+        let kv = m.import("debug_log_kv", &[I32, I32, I32, I32], &[]);
+        let pair = |code: Code, key: i32, val: i32| code.i32(key).i32(1).i32(val).i32(1).call(kv);
+        let flush = |code: Code| code.i32(0).i32(0).i32(0).i32(0).call(kv);
+        let body = flush(pair(pair(Code::new(), 0, 1), 2, 3));
+        let run = m.func(&[], &[], &[], body);
+        m.export("run", run).data(0, b"a1b2");
+
+        let agent = testing::spawn_agent("kv-logger", Vec::new());
+        testing::call(&WasmRuntime::new(), &m.build(), agent, "run", &[]).unwrap();
+        assert!(testing::logged(&format!("[Agent {}] a=1 b=2\n", agent.0)));
+    }
+
+    /// A module whose `run` export returns `env.<name>()` as an i64.
+    fn clock_module(name: &str) -> Vec<u8> {
+        let mut m = ModuleBuilder::new();
+        let clock = m.import(name, &[], &[I64]);
+        let run = m.func(&[], &[I64], &[], Code::new().call(clock));
+        m.export("run", run);
+        m.build()
+    }
+
+    #[test_case]
+    fn strict_mode_coarsens_the_clock_without_a_clock_capability() {
+        let runtime = WasmRuntime::new();
+        let trusted = testing::spawn_agent("clock-trusted", alloc::vec![Capability::Clock]);
+        let untrusted = testing::spawn_agent("clock-untrusted", Vec::new());
+        let read = |agent, name| {
+            testing::call(&runtime, &clock_module(name), agent, "run", &[]).unwrap()[0]
+                .i64()
+                .unwrap() as u64
+        };
+
+        crate::capability::set_strict_mode(true);
+        let before = crate::time::uptime_ms();
+        let trusted_uptime = read(trusted, "get_uptime_ms");
+        let untrusted_uptime = read(untrusted, "get_uptime_ms");
+        let after = crate::time::uptime_ms();
+        let trusted_time = read(trusted, "get_time");
+        let untrusted_time = read(untrusted, "get_time");
+        crate::capability::set_strict_mode(false);
+
+        assert!((before..=after).contains(&trusted_uptime));
+        assert_eq!(untrusted_uptime % COARSE_UPTIME_MS, 0);
+        assert!(
+            before - before % COARSE_UPTIME_MS <= untrusted_uptime && untrusted_uptime <= after
+        );
+        assert_eq!(untrusted_time % COARSE_TIME_SECS, 0);
+        assert!(trusted_time - untrusted_time < COARSE_TIME_SECS + 1);
+    }
+
+    #[test_case]
+    fn monotonic_ns_increases_across_calls_and_agents() {
+        let runtime = WasmRuntime::new();
+        let a = testing::spawn_agent("monotonic-a", alloc::vec![Capability::Clock]);
+        let b = testing::spawn_agent("monotonic-b", alloc::vec![Capability::Clock]);
+        let wasm = clock_module("monotonic_ns");
+        let mut last = 0;
+        for agent in [a, b, a, b] {
+            let now = testing::call(&runtime, &wasm, agent, "run", &[]).unwrap()[0]
+                .i64()
+                .unwrap() as u64;
+            assert!(now > last);
+            last = now;
+        }
+        assert!(crate::time::monotonic_nanos() > last);
+    }
+
+    #[test_case]
+    fn network_calls_time_out_on_a_held_stack_lock() {
+        let runtime = WasmRuntime::new();
+        let agent = testing::spawn_agent("net-stuck", alloc::vec![Capability::Network]);
+        let request = status_module(
+            "tcp_request",
+            &[0, 80, 4, 4],
+            &[10, 0, 2, 2, b'p', b'i', b'n', b'g'],
+        );
+
+        let held = crate::net::NETWORK.lock();
+        let status = testing::call_status(&runtime, &request, agent, "run");
+        drop(held);
+
+        assert_eq!(status, ERR_TIMEOUT);
+        assert_ne!(
+            crate::net::with_network(|_| ()),
+            Err(crate::net::NetError::Timeout)
+        );
+    }
+
+    #[test_case]
+    fn tcp_request_reports_failures_with_status_codes() {
+        testing::network();
+        let runtime = WasmRuntime::new();
+        let request = |agent, port| {
+            let wasm = status_module(
+                "tcp_request",
+                &[0, port, 4, 4],
+                &[10, 0, 2, 2, b'p', b'i', b'n', b'g'],
+            );
+            testing::call_status(&runtime, &wasm, agent, "run")
+        };
+        let denied = testing::spawn_agent("tcp-request-denied", Vec::new());
+        let agent = testing::spawn_agent("tcp-request", alloc::vec![Capability::Network]);
+
+        assert_eq!(request(denied, 80), ERR_PERMISSION_DENIED);
+        assert_eq!(request(agent, 65_536), ERR_INVALID_ARGUMENT);
+        assert_eq!(request(agent, 0), ERR_GENERAL);
+
+        let defaults = crate::sockets::limits();
+        crate::sockets::set_limits(crate::sockets::total_open(), defaults.1);
+        let full = request(agent, 80);
+        crate::sockets::set_limits(defaults.0, defaults.1);
+        assert_eq!(full, ERR_QUOTA_EXCEEDED);
+        assert_eq!(request(agent, 80), OK);
+    }
+
+    #[test_case]
+    fn latency_buckets_are_powers_of_two() {
+        assert_eq!(LatencyHistogram::bucket_for(0), 0);
+        assert_eq!(LatencyHistogram::bucket_for(1), 1);
+        assert_eq!(LatencyHistogram::bucket_for(3), 2);
+        assert_eq!(LatencyHistogram::bucket_for(1024), 11);
+        assert_eq!(LatencyHistogram::bucket_for(u64::MAX), LATENCY_BUCKETS - 1);
+    }
+
+    #[test_case]
+    fn latency_report_counts_each_host_call() {
+        crate::vfs::write_file("/agent/timed.txt", b"tick", 0);
+        let runtime = WasmRuntime::new();
+        let agent = agent_reader("latency");
+        let read = status_module("file_read", &[0, 16, OUT, OUT_LEN], b"/agent/timed.txt");
+        let before = runtime.latency_report().get("file_read").copied();
+
+        for _ in 0..3 {
+            assert_eq!(testing::call_status(&runtime, &read, agent, "run"), OK);
+        }
+
+        let after = runtime.latency_report()["file_read"];
+        let before = before.map_or([0; LATENCY_BUCKETS], |h| h.buckets);
+        let added: Vec<u64> = (0..LATENCY_BUCKETS)
+            .map(|i| after.buckets[i] - before[i])
+            .collect();
+        assert_eq!(added.iter().sum::<u64>(), 3);
+        // Reading a file takes at least one TSC cycle.
+        assert_eq!(added[0], 0);
+    }
+
+    /// A module whose `run` export calls `env.debug_log(ptr, len)` with `message` at
+    /// address 0.
+    fn debug_log_module(ptr: i32, len: i32, message: &[u8]) -> Vec<u8> {
+        let mut m = ModuleBuilder::new();
+        let debug_log = m.import("debug_log", &[I32, I32], &[]);
+        let run = m.func(&[], &[], &[], Code::new().i32(ptr).i32(len).call(debug_log));
+        m.export("run", run).data(0, message);
+        m.build()
+    }
+
+    #[test_case]
+    fn debug_log_prints_the_message_with_the_agent_prefix() {
+        let runtime = WasmRuntime::new();
+        let agent = testing::spawn_agent("debug-logger", Vec::new());
+        let wasm = debug_log_module(0, 11, b"hello world");
+        testing::call(&runtime, &wasm, agent, "run", &[]).unwrap();
+        assert!(testing::logged(&format!(
+            "[Wasm Agent {}] hello world\n",
+            agent.0
+        )));
+
+        let past_the_end = debug_log_module(65_530, 100, b"");
+        let err = testing::call(&runtime, &past_the_end, agent, "run", &[]).unwrap_err();
+        assert!(err.contains("Memory read failed"), "{err}");
+    }
+
+    #[test_case]
+    fn file_read_reports_missing_files_and_bad_paths() {
+        let runtime = WasmRuntime::new();
+        let agent = agent_reader("file-reader");
+        let missing = status_module("file_read", &[0, 18, OUT, OUT_LEN], b"/agent/missing.txt");
+        assert_eq!(
+            testing::call_status(&runtime, &missing, agent, "run"),
+            ERR_NOT_FOUND
+        );
+
+        let not_utf8 = status_module("file_read", &[0, 2, OUT, OUT_LEN], &[0xC3, 0x28]);
+        let err = testing::call(&runtime, &not_utf8, agent, "run", &[]).unwrap_err();
+        assert!(err.contains("Invalid UTF-8 string"), "{err}");
+    }
+
+    #[test_case]
+    fn request_capability_reports_each_policy_outcome() {
+        use crate::capability::{set_request_policy, RequestDecision};
+
+        let runtime = WasmRuntime::new();
+        let request = |agent, cap_type| {
+            let wasm = status_module("request_capability", &[cap_type, 0, 0], b"");
+            testing::call_status(&runtime, &wasm, agent, "run")
+        };
+        let allowed = testing::spawn_agent("request-allowed", Vec::new());
+        let denied = testing::spawn_agent("request-denied", Vec::new());
+        let prompted = testing::spawn_agent("request-prompted", Vec::new());
+        set_request_policy(allowed.0, 0, RequestDecision::Allow);
+        set_request_policy(denied.0, 0, RequestDecision::Deny);
+        set_request_policy(prompted.0, 0, RequestDecision::Prompt);
+
+        assert_eq!(request(allowed, 0), OK);
+        assert_eq!(request(denied, 0), ERR_PERMISSION_DENIED);
+        assert_eq!(request(prompted, 0), ERR_PENDING);
+        assert_eq!(request(allowed, 99), ERR_INVALID_ARGUMENT);
+
+        assert!(crate::capability::can_access_network(&agent_capabilities(
+            allowed
+        )));
+        assert!(agent_capabilities(denied).is_empty());
+        assert!(agent_capabilities(prompted).is_empty());
+    }
+
+    #[test_case]
+    fn request_capability_validates_before_escalating() {
+        use crate::capability::{set_request_policy, RequestDecision};
+        use crate::ipc::{peek_messages, KERNEL_SUPERVISOR_PID};
+
+        let runtime = WasmRuntime::new();
+        let request = |agent: AgentId, cap_type, detail: &[u8]| {
+            let wasm = status_module(
+                "request_capability",
+                &[cap_type, 0, detail.len() as i32],
+                detail,
+            );
+            testing::call_status(&runtime, &wasm, agent, "run")
+        };
+        let escalations = |agent: AgentId| -> Vec<Vec<u8>> {
+            peek_messages(KERNEL_SUPERVISOR_PID)
+                .into_iter()
+                .filter(|m| m.sender == ProcessId(agent.0))
+                .map(|m| m.data)
+                .collect()
+        };
+
+        // Make room in the supervisor's queue, which earlier tests may have filled.
+        while crate::ipc::receive_message(KERNEL_SUPERVISOR_PID).is_some() {}
+        let agent = testing::spawn_agent("request-validated", Vec::new());
+        set_request_policy(agent.0, 1, RequestDecision::Allow);
+        set_request_policy(agent.0, 0, RequestDecision::Deny);
+        assert_eq!(request(agent, 99, b""), ERR_INVALID_ARGUMENT);
+        assert_eq!(request(agent, 1, b"/.."), ERR_INVALID_ARGUMENT);
+        assert_eq!(request(agent, 0, b""), ERR_PERMISSION_DENIED);
+        assert!(escalations(agent).is_empty());
+
+        assert_eq!(request(agent, 1, b"/agent/x/../shared//docs/"), OK);
+        let expected = format!("CAP_REQUEST:{}:1:/agent/shared/docs/", agent.0);
+        assert_eq!(escalations(agent), [expected.into_bytes()]);
+        let caps = agent_capabilities(agent);
+        assert!(crate::capability::can_read_file(
+            &caps,
+            "/agent/shared/docs/a.txt"
+        ));
+        assert!(!crate::capability::can_read_file(&caps, "/agent/x/a.txt"));
+    }
+
+    #[test_case]
+    fn file_exists_checks_presence_and_read_access() {
+        crate::vfs::write_file("/agent/exists.txt", b"here", 0);
+        crate::vfs::register_file("/system/exists-secret.txt", b"", 0);
+        let runtime = WasmRuntime::new();
+        let agent = agent_reader("exists-checker");
+        let exists = |path: &[u8]| {
+            let wasm = status_module("file_exists", &[0, path.len() as i32], path);
+            testing::call_status(&runtime, &wasm, agent, "run")
+        };
+
+        assert_eq!(exists(b"/agent/exists.txt"), 1);
+        assert_eq!(exists(b"/agent/exists-not.txt"), 0);
+        assert_eq!(exists(b"/system/exists-secret.txt"), ERR_PERMISSION_DENIED);
+        assert!(crate::vfs::exists("/system/exists-secret.txt"));
+    }
+
+    #[test_case]
+    fn verify_key_requires_a_valid_module_signature() {
+        let key = b"kernel signing key";
+        let agent = testing::spawn_agent("signed", Vec::new());
+        let module = clock_module("get_uptime_ms");
+        let mut runtime = WasmRuntime::new();
+        assert!(testing::call(&runtime, &module, agent, "run", &[]).is_ok());
+
+        runtime.set_verify_key(Some(key));
+        let signed = sign_module(&module, key);
+        assert!(testing::call(&runtime, &signed, agent, "run", &[]).is_ok());
+
+        let err = testing::call(&runtime, &module, agent, "run", &[]).unwrap_err();
+        assert!(err.contains("missing signature"), "{err}");
+        let forged = sign_module(&module, b"some other key");
+        let err = testing::call(&runtime, &forged, agent, "run", &[]).unwrap_err();
+        assert!(err.contains("invalid signature"), "{err}");
+        let mut tampered = signed.clone();
+        tampered[8] ^= 1;
+        let err = testing::call(&runtime, &tampered, agent, "run", &[]).unwrap_err();
+        assert!(err.contains("invalid signature"), "{err}");
+    }
+
+    #[test_case]
+    fn file_copy_checks_both_paths() {
+        crate::vfs::register_file("/system/copy-template.txt", b"template", 0);
+        crate::vfs::write_file("/agent/copy-source.txt", b"source", 0);
+        let runtime = WasmRuntime::new();
+        let copy = |agent, src: &[u8], dst: &[u8]| {
+            let data = [src, dst].concat();
+            let args = [0, src.len() as i32, src.len() as i32, dst.len() as i32];
+            let wasm = status_module("file_copy", &args, &data);
+            testing::call_status(&runtime, &wasm, agent, "run")
+        };
+
+        let reader = agent_reader("copy-reader");
+        let writer = agent_writer("copy-writer");
+        let src = b"/agent/copy-source.txt";
+        assert_eq!(
+            copy(reader, src, b"/agent/copy-dst.txt"),
+            ERR_PERMISSION_DENIED
+        );
+        assert_eq!(
+            copy(writer, b"/system/copy-template.txt", b"/agent/copy-dst.txt"),
+            ERR_PERMISSION_DENIED
+        );
+        assert_eq!(copy(writer, src, b"/agent/copy-dst.txt"), OK);
+        assert_eq!(
+            crate::vfs::open_file("/agent/copy-dst.txt").as_deref(),
+            Some(&b"source"[..])
+        );
+        assert_eq!(
+            copy(writer, b"/agent/copy-none.txt", b"/agent/copy-dst.txt"),
+            ERR_NOT_FOUND
+        );
+    }
+
+    /// A module whose start function sends "hi" to `target` with `env.send_ipc`.
+    fn send_on_start_module(target: u64) -> Vec<u8> {
+        let mut m = ModuleBuilder::new();
+        let send_ipc = m.import("send_ipc", &[I64, I32, I32], &[I32]);
+        let body = Code::new()
+            .i64(target as i64)
+            .i32(0)
+            .i32(2)
+            .call(send_ipc)
+            .drop();
+        let start = m.func(&[], &[], &[], body);
+        m.start(start).data(0, b"hi");
+        m.build()
+    }
+
+    #[test_case]
+    fn full_queue_during_start_is_a_retryable_load_error() {
+        let runtime = WasmRuntime::new();
+        let busy = testing::spawn_agent("start-busy", Vec::new());
+        crate::ipc::create_endpoint_with_depth(ProcessId(busy.0), 0).unwrap();
+        let sender = testing::spawn_agent(
+            "start-sender",
+            alloc::vec![Capability::Process {
+                pid: busy.0,
+                can_send: true,
+                can_receive: false,
+            }],
+        );
+
+        let err = runtime
+            .instantiate(&send_on_start_module(busy.0), sender.0)
+            .err()
+            .unwrap();
+        assert!(err.is_retryable(), "{err}");
+        assert!(alloc::format!("{err}").contains("Message queue full"));
+
+        crate::ipc::set_queue_depth(ProcessId(busy.0), 1).unwrap();
+        assert!(runtime
+            .instantiate(&send_on_start_module(busy.0), sender.0)
+            .is_ok());
+    }
+
+    #[test_case]
+    fn transient_start_failure_is_requeued_not_slept_on() {
+        let runtime = WasmRuntime::new();
+        let busy = testing::spawn_agent("retry-busy", Vec::new());
+        crate::ipc::create_endpoint_with_depth(ProcessId(busy.0), 0).unwrap();
+        let agent = testing::spawn_agent(
+            "retry-sender",
+            alloc::vec![Capability::Process {
+                pid: busy.0,
+                can_send: true,
+                can_receive: false,
+            }],
+        );
+        let mut m = ModuleBuilder::new();
+        let send_ipc = m.import("send_ipc", &[I64, I32, I32], &[I32]);
+        let body = Code::new()
+            .i64(busy.0 as i64)
+            .i32(0)
+            .i32(2)
+            .call(send_ipc)
+            .drop();
+        let start = m.func(&[], &[], &[], body);
+        let main = m.func(&[], &[], &[], Code::new());
+        m.start(start).export("_start", main).data(0, b"hi");
+        let path = "/test/retry-sender.wasm";
+        testing::install(path, m.build());
+        crate::task::set_supervision(agent, path, crate::task::RestartPolicy::Never);
+
+        let outcome: SupervisedOutcome = Arc::new(spin::Mutex::new(None));
+        let queued = crate::task::pending_spawns();
+        runtime.start_supervised(agent, 1, outcome.clone());
+        assert!(outcome.lock().is_none());
+        assert_eq!(crate::task::pending_spawns(), queued + 1);
+
+        crate::ipc::set_queue_depth(ProcessId(busy.0), 1).unwrap();
+        crate::task::run_executor();
+        assert_eq!(*outcome.lock(), Some(Ok(())));
+    }
+
+    #[test_case]
+    fn traps_and_bad_modules_are_permanent_load_errors() {
+        let runtime = WasmRuntime::new();
+        let agent = testing::spawn_agent("start-permanent", Vec::new());
+        let mut m = ModuleBuilder::new();
+        let start = m.func(&[], &[], &[], Code::new().op(testing::UNREACHABLE));
+        m.start(start);
+
+        let trapped = runtime.instantiate(&m.build(), agent.0).err().unwrap();
+        assert!(!trapped.is_retryable(), "{trapped}");
+        let garbage = runtime
+            .instantiate(b"\0asm garbage", agent.0)
+            .err()
+            .unwrap();
+        assert!(!garbage.is_retryable(), "{garbage}");
+    }
+
+    /// A module whose `run` export inspects `target`'s IPC queue into `OUT`.
+    fn inspect_module(target: u64) -> Vec<u8> {
+        let mut m = ModuleBuilder::new();
+        let inspect = m.import("inspect_ipc", &[I64, I32, I32], &[I32]);
+        let body = Code::new()
+            .i64(target as i64)
+            .i32(OUT)
+            .i32(OUT_LEN)
+            .call(inspect);
+        let run = m.func(&[], &[I32], &[], body);
+        m.export("run", run);
+        m.build()
+    }
+
+    fn inspections_of(target: AgentId) -> Vec<(u64, bool)> {
+        crate::audit::recent(usize::MAX)
+            .into_iter()
+            .filter_map(|record| match record.event {
+                crate::audit::AuditEvent::IpcInspect {
+                    inspector,
+                    target: t,
+                    allowed,
+                } if t == target.0 => Some((inspector, allowed)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test_case]
+    fn inspect_ipc_peeks_only_with_the_capability() {
+        let runtime = WasmRuntime::new();
+        let target = testing::spawn_agent("inspected", Vec::new());
+        let sender = testing::spawn_agent("inspected-peer", Vec::new());
+        let (from, to) = (ProcessId(sender.0), ProcessId(target.0));
+        send_message(from, to, b"secret".to_vec(), Vec::new()).unwrap();
+        let debugger = testing::spawn_agent(
+            "ipc-debugger",
+            alloc::vec![Capability::IpcInspect {
+                target_pid: target.0
+            }],
+        );
+        let snoop = testing::spawn_agent("ipc-snoop", Vec::new());
+
+        let (status, memory) = run_with_memory(&runtime, &inspect_module(target.0), debugger);
+        assert_eq!(status, OK);
+        let mut expected = 1u32.to_le_bytes().to_vec();
+        expected.extend_from_slice(&sender.0.to_le_bytes());
+        expected.extend_from_slice(&6u32.to_le_bytes());
+        expected.extend_from_slice(b"secret");
+        assert_eq!(output(&memory), &expected[..]);
+
+        assert_eq!(
+            testing::call_status(&runtime, &inspect_module(target.0), snoop, "run"),
+            ERR_PERMISSION_DENIED
+        );
+        assert_eq!(
+            inspections_of(target),
+            alloc::vec![(debugger.0, true), (snoop.0, false)]
+        );
+        assert_eq!(
+            crate::ipc::receive_message(to).unwrap().data,
+            b"secret".to_vec()
+        );
+    }
+
+    #[test_case]
+    fn yield_now_lets_a_queued_peer_run_before_the_caller_continues() {
+        let mut m = ModuleBuilder::new();
+        let debug_log = m.import("debug_log", &[I32, I32], &[]);
+        let yield_now = m.import("yield_now", &[], &[]);
+        let body = Code::new()
+            .i32(0)
+            .i32(6)
+            .call(debug_log)
+            .call(yield_now)
+            .i32(8)
+            .i32(5)
+            .call(debug_log);
+        let start = m.func(&[], &[], &[], body);
+        m.export("_start", start)
+            .data(0, b"before")
+            .data(8, b"after");
+        let mut p = ModuleBuilder::new();
+        let log = p.import("debug_log", &[I32, I32], &[]);
+        let start = p.func(&[], &[], &[], Code::new().i32(0).i32(4).call(log));
+        p.export("_start", start).data(0, b"peer");
+
+        let runtime = WasmRuntime::new();
+        let caller = testing::spawn_agent("yielder", Vec::new());
+        let other = testing::spawn_agent("yield-peer", Vec::new());
+        runtime.spawn_module(&m.build(), caller.0).unwrap();
+        runtime.spawn_module(&p.build(), other.0).unwrap();
+        crate::task::run_executor();
+
+        // Both bodies are far too short to be preempted, so the only switch back to
+        // the peer can come from `yield_now`.
+        let log = testing::log_tail();
+        let at = |agent: AgentId, message: &str| {
+            let line = format!("[Wasm Agent {}] {}\n", agent.0, message);
+            log.find(&line).unwrap_or_else(|| panic!("missing {line}"))
+        };
+        assert!(at(caller, "before") < at(other, "peer"));
+        assert!(at(other, "peer") < at(caller, "after"));
+    }
+
+    /// A module whose `_start` takes lock "L" waiting up to `timeout_ms`, traps unless
+    /// `lock_acquire` returned `expect`, yields `hold` times and releases the lock,
+    /// trapping if it was no longer the owner.
+    fn lock_module(timeout_ms: i32, expect: u32, hold: i32) -> Vec<u8> {
+        let mut m = ModuleBuilder::new();
+        let acquire = m.import("lock_acquire", &[I32, I32, I32], &[I32]);
+        let release = m.import("lock_release", &[I32, I32], &[I32]);
+        let yield_now = m.import("yield_now", &[], &[]);
+        // `1 / (status == expected)` traps on a mismatch.
+        let mut body = Code::new()
+            .i32(1)
+            .i32(0)
+            .i32(1)
+            .i32(timeout_ms)
+            .call(acquire)
+            .i32(expect as i32)
+            .op(testing::I32_SUB)
+            .op(testing::I32_EQZ)
+            .op(testing::I32_DIV_U)
+            .drop();
+        if expect == OK {
+            for _ in 0..hold {
+                body = body.call(yield_now);
+            }
+            body = body
+                .i32(1)
+                .i32(0)
+                .i32(1)
+                .call(release)
+                .op(testing::I32_EQZ)
+                .op(testing::I32_DIV_U)
+                .drop();
+        }
+        let start = m.func(&[], &[], &[], body);
+        m.export("_start", start).data(0, b"L");
+        m.build()
+    }
+
+    #[test_case]
+    fn second_lock_acquirer_waits_for_release() {
+        let runtime = WasmRuntime::new();
+        let holder = testing::spawn_agent("lock-holder", Vec::new());
+        let waiter = testing::spawn_agent("lock-waiter", Vec::new());
+        runtime
+            .spawn_module(&lock_module(0, OK, 2), holder.0)
+            .unwrap();
+        runtime
+            .spawn_module(&lock_module(60_000, OK, 0), waiter.0)
+            .unwrap();
+
+        crate::task::set_schedule_trace(true);
+        crate::task::run_executor();
+        crate::task::set_schedule_trace(false);
+
+        // The waiter is resumed while the holder yields but only gets the lock (and
+        // finishes) after the holder's release.
+        assert_eq!(
+            crate::task::schedule_trace(),
+            alloc::vec![holder, waiter, holder, waiter, holder, waiter]
+        );
+        assert!(testing::logged(&format!(
+            "[EXEC] Agent {} finished",
+            holder.0
+        )));
+        assert!(testing::logged(&format!(
+            "[EXEC] Agent {} finished",
+            waiter.0
+        )));
+        assert_eq!(crate::locks::owner("L"), None);
+    }
+
+    #[test_case]
+    fn lock_acquire_times_out_while_the_lock_is_held() {
+        let runtime = WasmRuntime::new();
+        let outside = 0xdead_0001;
+        assert!(crate::locks::try_acquire("L", outside));
+
+        let waiter = testing::spawn_agent("lock-timeout", Vec::new());
+        runtime
+            .spawn_module(&lock_module(20, ERR_TIMEOUT, 0), waiter.0)
+            .unwrap();
+        crate::task::run_executor();
+        assert!(testing::logged(&format!(
+            "[EXEC] Agent {} finished",
+            waiter.0
+        )));
+
+        // Outside the executor nobody can release the lock, so there is no wait.
+        let agent = testing::spawn_agent("lock-direct", Vec::new());
+        let wasm = status_module("lock_acquire", &[0, 1, 60_000], b"L");
+        assert_eq!(
+            testing::call_status(&runtime, &wasm, agent, "run"),
+            ERR_TIMEOUT
+        );
+
+        assert_eq!(crate::locks::owner("L"), Some(outside));
+        assert_eq!(crate::locks::release("L", outside), Ok(()));
+    }
+
+    #[test_case]
+    fn library_module_exports_can_be_called_repeatedly() {
+        let mut m = ModuleBuilder::new();
+        let body = Code::new().local_get(0).local_get(1).op(testing::I32_ADD);
+        let add = m.func(&[I32, I32], &[I32], &[], body);
+        m.export("add", add);
+        let wasm = m.build();
+
+        let runtime = WasmRuntime::new();
+        let agent = testing::spawn_agent("library", Vec::new());
+        // No `_start`: instantiating runs nothing and does not fail.
+        let mut handle = runtime.instantiate(&wasm, agent.0).unwrap();
+        assert_eq!(handle.agent_pid(), agent.0);
+
+        let sum = |handle: &mut InstanceHandle, a: i32, b: i32| {
+            runtime
+                .call_export(handle, "add", &[Value::I32(a), Value::I32(b)])
+                .unwrap()[0]
+                .i32()
+                .unwrap()
+        };
+        assert_eq!(sum(&mut handle, 2, 3), 5);
+        assert_eq!(sum(&mut handle, -7, 100), 93);
+        assert!(runtime.call_export(&mut handle, "sub", &[]).is_err());
+    }
+
+    /// A module whose `run` export lists `target`'s capabilities into `OUT`.
+    fn list_agent_module(target: u64) -> Vec<u8> {
+        let mut m = ModuleBuilder::new();
+        let list = m.import("list_agent_capabilities", &[I64, I32, I32], &[I32]);
+        let body = Code::new()
+            .i64(target as i64)
+            .i32(OUT)
+            .i32(OUT_LEN)
+            .call(list);
+        let run = m.func(&[], &[I32], &[], body);
+        m.export("run", run);
+        m.build()
+    }
+
+    #[test_case]
+    fn list_capabilities_round_trips_the_callers_set() {
+        let runtime = WasmRuntime::new();
+        let granted = alloc::vec![
+            Capability::Network,
+            Capability::FileSystem {
+                path_prefix: String::from("/agent/"),
+                read: true,
+                write: false,
+            },
+        ];
+        let agent = testing::spawn_agent("cap-lister", granted.clone());
+
+        let wasm = status_module("list_capabilities", &[OUT, OUT_LEN], b"");
+        let (status, memory) = run_with_memory(&runtime, &wasm, agent);
+        assert_eq!(status, OK);
+        let listed = crate::capability::decode_list(output(&memory)).unwrap();
+        assert_eq!(listed, granted);
+        assert_eq!(crate::capability::encode_list(&listed), output(&memory));
+    }
+
+    #[test_case]
+    fn listing_another_agent_requires_an_inspect_capability() {
+        let runtime = WasmRuntime::new();
+        let target = testing::spawn_agent("cap-target", alloc::vec![Capability::Network]);
+        let outsider = testing::spawn_agent("cap-outsider", Vec::new());
+        let inspector = testing::spawn_agent(
+            "cap-inspector",
+            alloc::vec![Capability::IpcInspect {
+                target_pid: target.0
+            }],
+        );
+
+        let wasm = list_agent_module(target.0);
+        let (status, _) = run_with_memory(&runtime, &wasm, outsider);
+        assert_eq!(status, ERR_PERMISSION_DENIED);
+        let (status, memory) = run_with_memory(&runtime, &wasm, inspector);
+        assert_eq!(status, OK);
+        assert_eq!(
+            crate::capability::decode_list(output(&memory)),
+            Some(alloc::vec![Capability::Network])
+        );
+    }
+
+    /// Where `session_module` reads chunk data from.
+    const CHUNK: i32 = 64;
+    /// Where `session_module` has `file_write_begin` store the new handle.
+    const HANDLE_OUT: i32 = 56;
+
+    /// A module for driving a write session from the test: `begin()` opens `path`
+    /// (placed at 0), `chunk(handle, len)` appends `len` bytes from `CHUNK` and
+    /// `commit(handle)` publishes the file, each returning the host call's result;
+    /// `handle()` returns the handle `begin()` stored.
+    fn session_module(path: &[u8], chunk: &[u8]) -> Vec<u8> {
+        let mut m = ModuleBuilder::new();
+        let begin = m.import("file_write_begin", &[I32, I32, I32], &[I32]);
+        let append = m.import("file_write_chunk", &[I32, I32, I32], &[I32]);
+        let commit = m.import("file_write_commit", &[I32], &[I32]);
+        let body = Code::new()
+            .i32(0)
+            .i32(path.len() as i32)
+            .i32(HANDLE_OUT)
+            .call(begin);
+        let begin = m.func(&[], &[I32], &[], body);
+        let handle = m.func(&[], &[I32], &[], Code::new().i32(HANDLE_OUT).load32(0));
+        let body = Code::new()
+            .local_get(0)
+            .i32(CHUNK)
+            .local_get(1)
+            .call(append);
+        let append = m.func(&[I32, I32], &[I32], &[], body);
+        let commit = m.func(&[I32], &[I32], &[], Code::new().local_get(0).call(commit));
+        m.export("begin", begin)
+            .export("handle", handle)
+            .export("chunk", append)
+            .export("commit", commit)
+            .data(0, path)
+            .data(CHUNK as u32, chunk);
+        m.build()
+    }
+
+    fn session_call(
+        runtime: &WasmRuntime,
+        handle: &mut InstanceHandle,
+        name: &str,
+        args: &[i32],
+    ) -> u32 {
+        let args: Vec<Value> = args.iter().map(|&arg| Value::I32(arg)).collect();
+        runtime.call_export(handle, name, &args).unwrap()[0]
+            .i32()
+            .unwrap() as u32
+    }
+
+    /// Open a session through `session_module`: the new handle, or the failure status.
+    fn begin_session(runtime: &WasmRuntime, handle: &mut InstanceHandle) -> Result<u32, u32> {
+        match session_call(runtime, handle, "begin", &[]) {
+            OK => Ok(session_call(runtime, handle, "handle", &[])),
+            status => Err(status),
+        }
+    }
+
+    #[test_case]
+    fn chunked_writes_appear_only_on_commit() {
+        let runtime = WasmRuntime::new();
+        let agent = agent_writer("chunked");
+        let wasm = session_module(b"/agent/chunked.txt", b"abcdefgh");
+        let mut instance = runtime.instantiate(&wasm, agent.0).unwrap();
+
+        let handle = begin_session(&runtime, &mut instance).unwrap();
+        assert!(handle >= FIRST_FILE_HANDLE);
+        for len in [3, 8, 1] {
+            assert_eq!(
+                session_call(&runtime, &mut instance, "chunk", &[handle as i32, len]),
+                OK
+            );
+        }
+        assert!(crate::vfs::open_file("/agent/chunked.txt").is_none());
+        assert_eq!(
+            session_call(&runtime, &mut instance, "commit", &[handle as i32]),
+            OK
+        );
+        assert_eq!(
+            crate::vfs::open_file("/agent/chunked.txt"),
+            Some(b"abcabcdefgha".to_vec())
+        );
+        assert_eq!(
+            session_call(&runtime, &mut instance, "commit", &[handle as i32]),
+            ERR_NOT_FOUND
+        );
+    }
+
+    #[test_case]
+    fn failed_begin_returns_a_status_and_no_handle() {
+        let runtime = WasmRuntime::new();
+        let agent = agent_writer("begin-failures");
+        let denied = session_module(b"/system/begin.txt", b"");
+        let mut instance = runtime.instantiate(&denied, agent.0).unwrap();
+        assert_eq!(
+            begin_session(&runtime, &mut instance),
+            Err(ERR_PERMISSION_DENIED)
+        );
+        assert_eq!(session_call(&runtime, &mut instance, "handle", &[]), 0);
+
+        let escaping = session_module(b"/..", b"");
+        let mut instance = runtime.instantiate(&escaping, agent.0).unwrap();
+        assert_eq!(
+            begin_session(&runtime, &mut instance),
+            Err(ERR_INVALID_ARGUMENT)
+        );
+    }
+
+    #[test_case]
+    fn exiting_without_commit_leaves_no_file() {
+        let runtime = WasmRuntime::new();
+        let agent = agent_writer("aborted");
+        let wasm = session_module(b"/agent/aborted.txt", b"data");
+        let before = write_session_bytes();
+
+        let mut instance = runtime.instantiate(&wasm, agent.0).unwrap();
+        let handle = begin_session(&runtime, &mut instance).unwrap();
+        assert_eq!(
+            session_call(&runtime, &mut instance, "chunk", &[handle as i32, 4]),
+            OK
+        );
+        assert_eq!(write_session_bytes(), before + 4);
+        drop(instance);
+
+        assert!(crate::vfs::open_file("/agent/aborted.txt").is_none());
+        assert_eq!(write_session_bytes(), before);
+        assert!(testing::logged(&format!(
+            "[WARN] Agent {} exited with 1 unclosed file handle(s)",
+            agent.0
+        )));
+    }
+
+    #[test_case]
+    fn open_handles_stop_at_the_limit_until_one_is_closed() {
+        let runtime = WasmRuntime::new();
+        let agent = agent_writer("handle-limit");
+        let wasm = session_module(b"/agent/handle-limit.txt", b"");
+        let mut instance = runtime.instantiate(&wasm, agent.0).unwrap();
+
+        let handles: Vec<u32> = (0..DEFAULT_MAX_OPEN_HANDLES)
+            .map(|_| begin_session(&runtime, &mut instance).unwrap())
+            .collect();
+        assert!(handles.iter().all(|&handle| handle >= FIRST_FILE_HANDLE));
+        assert_eq!(begin_session(&runtime, &mut instance), Err(ERR_GENERAL));
+        assert!(testing::logged(&format!(
+            "[VFS] Agent {} hit its open handle limit ({DEFAULT_MAX_OPEN_HANDLES})",
+            agent.0
+        )));
+
+        // Committing closes the handle and frees its slot for a new, distinct handle.
+        let closed = handles[5] as i32;
+        assert_eq!(
+            session_call(&runtime, &mut instance, "commit", &[closed]),
+            OK
+        );
+        let reopened = begin_session(&runtime, &mut instance).unwrap();
+        assert!(reopened >= FIRST_FILE_HANDLE);
+        assert!(!handles[..5].contains(&reopened) && !handles[6..].contains(&reopened));
+        assert_eq!(begin_session(&runtime, &mut instance), Err(ERR_GENERAL));
+        crate::vfs::delete_file("/agent/handle-limit.txt", 0);
+    }
+
+    #[test_case]
+    fn handle_limit_follows_the_config() {
+        crate::vfs::write_file(crate::config::CONFIG_PATH, b"wasm.max_open_handles=2\n", 0);
+        let runtime = WasmRuntime::new();
+        let agent = agent_writer("handle-config");
+        let wasm = session_module(b"/agent/handle-config.txt", b"");
+        let mut instance = runtime.instantiate(&wasm, agent.0).unwrap();
+        let opened: Vec<Result<u32, u32>> = (0..3)
+            .map(|_| begin_session(&runtime, &mut instance))
+            .collect();
+        assert!(opened[0].is_ok() && opened[1].is_ok());
+        assert_eq!(opened[2], Err(ERR_GENERAL));
+
+        crate::vfs::write_file(crate::config::CONFIG_PATH, b"wasm.max_open_handles=-1\n", 0);
+        assert_eq!(max_open_handles(), DEFAULT_MAX_OPEN_HANDLES);
+        crate::vfs::delete_file(crate::config::CONFIG_PATH, 0);
+        assert_eq!(max_open_handles(), DEFAULT_MAX_OPEN_HANDLES);
+    }
+
+    #[test_case]
+    fn exit_reports_each_leaked_handle() {
+        let runtime = WasmRuntime::new();
+        let agent = agent_writer("handle-leak");
+        let wasm = session_module(b"/agent/leaked.txt", b"leak");
+        let mut instance = runtime.instantiate(&wasm, agent.0).unwrap();
+
+        let handles: Vec<u32> = (0..3)
+            .map(|_| begin_session(&runtime, &mut instance).unwrap())
+            .collect();
+        let kept = handles[0] as i32;
+        assert_eq!(
+            session_call(&runtime, &mut instance, "chunk", &[kept, 4]),
+            OK
+        );
+        // A committed handle is closed and not reported.
+        let committed = handles[2] as i32;
+        assert_eq!(
+            session_call(&runtime, &mut instance, "commit", &[committed]),
+            OK
+        );
+        drop(instance);
+
+        // The warning names this agent, so only its own lines follow it.
+        let tail = testing::log_tail();
+        let header = format!(
+            "[WARN] Agent {} exited with 2 unclosed file handle(s)\n",
+            agent.0
+        );
+        let report = &tail[tail.find(&header).expect("leak warning") + header.len()..];
+        let lines: Vec<&str> = report
+            .lines()
+            .take_while(|l| l.starts_with("[WARN]   "))
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                format!(
+                    "[WARN]   handle {}: /agent/leaked.txt (4 bytes discarded)",
+                    handles[0]
+                ),
+                format!(
+                    "[WARN]   handle {}: /agent/leaked.txt (0 bytes discarded)",
+                    handles[1]
+                ),
+            ]
+        );
+        crate::vfs::delete_file("/agent/leaked.txt", 0);
+    }
+
+    #[test_case]
+    fn write_session_quota_spans_all_sessions() {
+        const STEP: i32 = 60 * 1024;
+        let runtime = WasmRuntime::new();
+        let wasm = session_module(b"/agent/quota.txt", b"");
+        let before = write_session_bytes();
+        let mut first = runtime
+            .instantiate(&wasm, agent_writer("quota-a").0)
+            .unwrap();
+        let mut second = runtime
+            .instantiate(&wasm, agent_writer("quota-b").0)
+            .unwrap();
+        let a = begin_session(&runtime, &mut first).unwrap() as i32;
+        let b = begin_session(&runtime, &mut second).unwrap() as i32;
+
+        // Alternate between two agents' sessions until the shared budget runs out.
+        let mut buffered = before;
+        let mut sessions = [(&mut first, a), (&mut second, b)];
+        for turn in 0.. {
+            let (instance, handle) = &mut sessions[turn % 2];
+            let status = session_call(&runtime, instance, "chunk", &[*handle, STEP]);
+            if status != OK {
+                assert_eq!(status, ERR_QUOTA_EXCEEDED);
+                break;
+            }
+            buffered += STEP as usize;
+        }
+        assert_eq!(write_session_bytes(), buffered);
+        assert!(buffered <= MAX_WRITE_SESSION_BYTES);
+        assert!(buffered + STEP as usize > MAX_WRITE_SESSION_BYTES);
+
+        // Committing one session returns its share of the budget.
+        assert_eq!(session_call(&runtime, &mut first, "commit", &[a]), OK);
+        assert!(write_session_bytes() < buffered);
+        assert_eq!(session_call(&runtime, &mut second, "chunk", &[b, STEP]), OK);
+        drop(second);
+        assert_eq!(write_session_bytes(), before);
+        crate::vfs::delete_file("/agent/quota.txt", 0);
+    }
+
+    #[test_case]
+    fn power_control_requires_the_power_capability() {
+        let runtime = WasmRuntime::new();
+        let agent = testing::spawn_agent("power-denied", alloc::vec![Capability::Network]);
+        let reboot = status_module("system_reboot", &[], b"");
+        let shutdown = status_module("system_shutdown", &[0x31], b"");
+
+        assert_eq!(
+            testing::call_status(&runtime, &reboot, agent, "run"),
+            ERR_PERMISSION_DENIED
+        );
+        assert!(testing::logged(&format!(
+            "Agent {} denied system_reboot",
+            agent.0
+        )));
+        assert_eq!(
+            testing::call_status(&runtime, &shutdown, agent, "run"),
+            ERR_PERMISSION_DENIED
+        );
+        assert!(testing::logged(&format!(
+            "Agent {} denied system_shutdown",
+            agent.0
+        )));
+    }
+
+    #[test_case]
+    fn receive_ipc_kind_takes_only_the_requested_kind() {
+        let runtime = WasmRuntime::new();
+        let sender = testing::spawn_agent("kind-host-sender", Vec::new());
+        let agent = testing::spawn_agent("kind-host-recipient", Vec::new());
+        let (from, to) = (ProcessId(sender.0), ProcessId(agent.0));
+        send_message(from, to, b"hello".to_vec(), Vec::new()).unwrap();
+        send_message(from, to, b"CAP_REQUEST:1:network:".to_vec(), Vec::new()).unwrap();
+
+        let receive = |kind| status_module("receive_ipc_kind", &[kind, 1000, OUT, OUT_LEN], b"");
+        let (status, memory) = run_with_memory(&runtime, &receive(1), agent);
+        assert_eq!(status, OK);
+        assert_eq!(output(&memory), b"CAP_REQUEST:1:network:");
+        assert_eq!(memory[1000..1008], sender.0.to_le_bytes());
+
+        assert_eq!(
+            run_with_memory(&runtime, &receive(1), agent).0,
+            ERR_NOT_FOUND
+        );
+        assert_eq!(
+            run_with_memory(&runtime, &receive(9), agent).0,
+            ERR_INVALID_ARGUMENT
+        );
+        let (status, memory) = run_with_memory(&runtime, &receive(0), agent);
+        assert_eq!((status, output(&memory)), (OK, &b"hello"[..]));
+    }
+
+    /// A module driving the socket host calls from the test, each export returning the
+    /// call's status: `connect()` opens a TCP connection to `ip:port` and stores the
+    /// handle at `OUT`, `set(handle, option, value)`, `get(handle, option)` (value at
+    /// `OUT + 8`), `state(handle)`, `flush()`, `close(handle)`, `send(handle, len)`
+    /// (the first `len` bytes of `SOCKET_PAYLOAD`; count at `OUT + 4`), `recv(handle)`
+    /// (count at `OUT + 4`, bytes at `OUT + 32`) and `stats(handle)` (sent and received
+    /// at `OUT + 16` and `OUT + 24`).
+    fn socket_module(ip: [u8; 4], port: i32) -> Vec<u8> {
+        let mut m = ModuleBuilder::new();
+        let connect = m.import("tcp_connect", &[I32, I32, I32], &[I32]);
+        let set = m.import("socket_set_option", &[I32, I32, I64], &[I32]);
+        let get = m.import("socket_get_option", &[I32, I32, I32], &[I32]);
+        let state = m.import("socket_state", &[I32], &[I32]);
+        let flush = m.import("net_flush", &[], &[I32]);
+        let close = m.import("socket_close", &[I32], &[I32]);
+        let send = m.import("tcp_send", &[I32, I32, I32, I32], &[I32]);
+        let recv = m.import("tcp_recv", &[I32, I32, I32], &[I32]);
+        let stats = m.import("socket_stats", &[I32, I32, I32], &[I32]);
+
+        let body = Code::new().i32(0).i32(port).i32(OUT).call(connect);
+        let connect = m.func(&[], &[I32], &[], body);
+        let body = Code::new().local_get(0).local_get(1).local_get(2).call(set);
+        let set = m.func(&[I32, I32, I64], &[I32], &[], body);
+        let body = Code::new().local_get(0).local_get(1).i32(OUT + 8).call(get);
+        let get = m.func(&[I32, I32], &[I32], &[], body);
+        let state = m.func(&[I32], &[I32], &[], Code::new().local_get(0).call(state));
+        let flush = m.func(&[], &[I32], &[], Code::new().call(flush));
+        let close = m.func(&[I32], &[I32], &[], Code::new().local_get(0).call(close));
+        let body = Code::new()
+            .local_get(0)
+            .i32(4)
+            .local_get(1)
+            .i32(OUT + 4)
+            .call(send);
+        let send = m.func(&[I32, I32], &[I32], &[], body);
+        let body = Code::new()
+            .local_get(0)
+            .i32(OUT + 32)
+            .i32(OUT + 4)
+            .call(recv);
+        let recv = m.func(&[I32], &[I32], &[], body);
+        let body = Code::new()
+            .local_get(0)
+            .i32(OUT + 16)
+            .i32(OUT + 24)
+            .call(stats);
+        let stats = m.func(&[I32], &[I32], &[], body);
+        m.export("connect", connect)
+            .export("set", set)
+            .export("get", get)
+            .export("state", state)
+            .export("flush", flush)
+            .export("close", close)
+            .export("send", send)
+            .export("recv", recv)
+            .export("stats", stats)
+            .data(0, &ip)
+            .data(4, SOCKET_PAYLOAD);
+        m.build()
+    }
+
+    /// What `socket_module`'s `send` export sends from.
+    const SOCKET_PAYLOAD: &[u8] = b"0123456789";
+
+    /// Call `name` on `instance` with `args` and return the status it reports.
+    fn socket_call(
+        runtime: &WasmRuntime,
+        instance: &mut InstanceHandle,
+        name: &str,
+        args: &[Value],
+    ) -> u32 {
+        runtime.call_export(instance, name, args).unwrap()[0]
+            .i32()
+            .unwrap() as u32
+    }
+
+    /// Read a little-endian u32 or u64 (`len` 4 or 8) from `instance`'s memory.
+    fn read_memory(instance: &InstanceHandle, offset: i32, len: usize) -> u64 {
+        let memory = instance
+            .instance
+            .get_memory(&instance.store, "memory")
+            .unwrap();
+        let mut bytes = [0u8; 8];
+        bytes[..len].copy_from_slice(&memory.data(&instance.store)[offset as usize..][..len]);
+        u64::from_le_bytes(bytes)
+    }
+
+    /// Open a connection from a fresh instance of `socket_module` for `agent`,
+    /// returning the instance and the socket handle.
+    fn connected(
+        runtime: &WasmRuntime,
+        agent: AgentId,
+        ip: [u8; 4],
+        port: i32,
+    ) -> (InstanceHandle, i32) {
+        let mut instance = runtime
+            .instantiate(&socket_module(ip, port), agent.0)
+            .unwrap();
+        assert_eq!(socket_call(runtime, &mut instance, "connect", &[]), OK);
+        let handle = read_memory(&instance, OUT, 4) as i32;
+        (instance, handle)
+    }
+
+    #[test_case]
+    fn socket_options_round_trip_on_an_owned_connection() {
+        testing::network();
+        let runtime = WasmRuntime::new();
+        let agent = testing::spawn_agent("sockopt", alloc::vec![Capability::Network]);
+        let (mut instance, handle) = connected(&runtime, agent, [10, 0, 2, 2], 80);
+
+        let mut set = |option: i32, value: i64| {
+            let args = [Value::I32(handle), Value::I32(option), Value::I64(value)];
+            socket_call(&runtime, &mut instance, "set", &args)
+        };
+        assert_eq!(set(0, 2500), OK);
+        assert_eq!(set(2, 0), OK);
+        assert_eq!(set(2, 7), ERR_INVALID_ARGUMENT);
+        assert_eq!(set(99, 1), ERR_INVALID_ARGUMENT);
+
+        let mut get = |option: i32| {
+            let args = [Value::I32(handle), Value::I32(option)];
+            let status = socket_call(&runtime, &mut instance, "get", &args);
+            (status, read_memory(&instance, OUT + 8, 8))
+        };
+        assert_eq!(get(0), (OK, 2500));
+        assert_eq!(get(2), (OK, 0));
+
+        assert_eq!(
+            socket_call(&runtime, &mut instance, "close", &[Value::I32(handle)]),
+            OK
+        );
+        assert_eq!(
+            socket_call(&runtime, &mut instance, "close", &[Value::I32(handle)]),
+            ERR_NOT_FOUND
+        );
+        assert_eq!(crate::sockets::open_count(agent.0), 0);
+    }
+
+    #[test_case]
+    fn sockets_answer_only_their_owner() {
+        testing::network();
+        let runtime = WasmRuntime::new();
+        let owner = testing::spawn_agent("sock-owner", alloc::vec![Capability::Network]);
+        let other = testing::spawn_agent("sock-other", alloc::vec![Capability::Network]);
+        let (mut instance, handle) = connected(&runtime, owner, [10, 0, 2, 2], 80);
+        let wasm = socket_module([10, 0, 2, 2], 80);
+        let mut stranger = runtime.instantiate(&wasm, other.0).unwrap();
+
+        let args = [Value::I32(handle), Value::I32(0), Value::I64(1)];
+        assert_eq!(
+            socket_call(&runtime, &mut stranger, "set", &args),
+            ERR_NOT_FOUND
+        );
+        assert_eq!(
+            socket_call(&runtime, &mut stranger, "close", &[Value::I32(handle)]),
+            ERR_NOT_FOUND
+        );
+        assert_eq!(
+            socket_call(&runtime, &mut instance, "close", &[Value::I32(handle)]),
+            OK
+        );
+
+        // Without the Network capability there is no connection to begin with.
+        let offline = testing::spawn_agent("sock-offline", Vec::new());
+        let mut instance = runtime.instantiate(&wasm, offline.0).unwrap();
+        assert_eq!(
+            socket_call(&runtime, &mut instance, "connect", &[]),
+            ERR_PERMISSION_DENIED
+        );
+    }
+
+    #[test_case]
+    fn socket_state_follows_the_handshake() {
+        testing::loopback();
+        let runtime = WasmRuntime::new();
+        let agent = testing::spawn_agent("sock-state", alloc::vec![Capability::Network]);
+        let listener = testing::listen(7158);
+        let ip = testing::GUEST_IP.0;
+
+        let (mut instance, handle) = connected(&runtime, agent, ip, 7158);
+        let state = |instance: &mut InstanceHandle, handle| {
+            socket_call(&runtime, instance, "state", &[Value::I32(handle)])
+        };
+        assert_eq!(state(&mut instance, handle), 2);
+        assert_eq!(socket_call(&runtime, &mut instance, "flush", &[]), OK);
+        assert_eq!(state(&mut instance, handle), 4);
+        socket_call(&runtime, &mut instance, "close", &[Value::I32(handle)]);
+        crate::net::with_network(|net| net.sockets.remove(listener)).unwrap();
+
+        // Nothing listens on 7159, so the handshake is reset.
+        let (mut instance, handle) = connected(&runtime, agent, ip, 7159);
+        socket_call(&runtime, &mut instance, "flush", &[]);
+        assert_eq!(state(&mut instance, handle), 0);
+        socket_call(&runtime, &mut instance, "close", &[Value::I32(handle)]);
+        assert_eq!(state(&mut instance, handle), SOCKET_STATE_UNKNOWN);
+    }
+
+    #[test_case]
+    fn socket_stats_total_the_bytes_sent_and_received() {
+        testing::loopback();
+        let runtime = WasmRuntime::new();
+        let agent = testing::spawn_agent("sock-bytes", alloc::vec![Capability::Network]);
+        let listener = testing::listen(7165);
+        let (mut instance, handle) = connected(&runtime, agent, testing::GUEST_IP.0, 7165);
+        let call = |instance: &mut InstanceHandle, name, args: &[Value]| {
+            socket_call(&runtime, instance, name, args)
+        };
+        assert_eq!(call(&mut instance, "flush", &[]), OK);
+
+        assert_eq!(
+            call(&mut instance, "send", &[Value::I32(handle), Value::I32(10)]),
+            OK
+        );
+        assert_eq!(read_memory(&instance, OUT + 4, 4), 10);
+        assert_eq!(
+            call(&mut instance, "send", &[Value::I32(handle), Value::I32(3)]),
+            OK
+        );
+        call(&mut instance, "flush", &[]);
+        crate::net::with_network(|net| {
+            let peer = net
+                .sockets
+                .get_mut::<smoltcp::socket::tcp::Socket>(listener);
+            let mut buf = [0; 32];
+            assert_eq!(peer.recv_slice(&mut buf), Ok(13));
+            assert_eq!(&buf[..13], b"0123456789012");
+            peer.send_slice(b"pong").unwrap();
+        })
+        .unwrap();
+
+        assert_eq!(call(&mut instance, "recv", &[Value::I32(handle)]), OK);
+        assert_eq!(read_memory(&instance, OUT + 4, 4), 4);
+        assert_eq!(
+            read_memory(&instance, OUT + 32, 4),
+            u32::from_le_bytes(*b"pong") as u64
+        );
+        assert_eq!(call(&mut instance, "stats", &[Value::I32(handle)]), OK);
+        assert_eq!(read_memory(&instance, OUT + 16, 8), 13);
+        assert_eq!(read_memory(&instance, OUT + 24, 8), 4);
+
+        let other = testing::spawn_agent("sock-bytes-other", alloc::vec![Capability::Network]);
+        let mut stranger = runtime
+            .instantiate(&socket_module(testing::GUEST_IP.0, 7165), other.0)
+            .unwrap();
+        assert_eq!(
+            call(&mut stranger, "send", &[Value::I32(handle), Value::I32(1)]),
+            ERR_NOT_FOUND
+        );
+        assert_eq!(
+            call(&mut stranger, "stats", &[Value::I32(handle)]),
+            ERR_NOT_FOUND
+        );
+
+        assert_eq!(call(&mut instance, "close", &[Value::I32(handle)]), OK);
+        crate::net::with_network(|net| net.sockets.remove(listener)).unwrap();
+    }
+
+    #[test_case]
+    fn file_read_many_marks_denied_and_missing_entries() {
+        crate::vfs::register_file("/agent/many-a.txt", b"alpha", 0);
+        crate::vfs::register_file("/system/many-b.txt", b"secret", 0);
+        crate::vfs::register_file("/agent/many-c.txt", b"gamma", 0);
+        let runtime = WasmRuntime::new();
+        let agent = agent_reader("many-reader");
+
+        let paths = b"/agent/many-a.txt\n/system/many-b.txt\n/agent/many-c.txt\n/agent/many-d.txt";
+        let args = [0, paths.len() as i32, OUT, OUT_LEN];
+        let wasm = status_module("file_read_many", &args, paths);
+        let (status, memory) = run_with_memory(&runtime, &wasm, agent);
+        assert_eq!(status, OK);
+
+        let mut expected = Vec::new();
+        for (status, data) in [
+            (OK, &b"alpha"[..]),
+            (ERR_PERMISSION_DENIED, b""),
+            (OK, b"gamma"),
+            (ERR_NOT_FOUND, b""),
+        ] {
+            expected.push(status as u8);
+            expected.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            expected.extend_from_slice(data);
+        }
+        assert_eq!(output(&memory), &expected[..]);
+    }
+
+    /// A module whose `_start` reads `path` and traps unless the read succeeds.
+    fn must_read_module(path: &str) -> Vec<u8> {
+        let mut m = ModuleBuilder::new();
+        let file_read = m.import("file_read", &[I32, I32, I32, I32], &[I32]);
+        let body = Code::new()
+            .i32(1)
+            .i32(0)
+            .i32(path.len() as i32)
+            .i32(OUT)
+            .i32(OUT_LEN)
+            .call(file_read)
+            .op(testing::I32_EQZ)
+            .op(testing::I32_DIV_U)
+            .drop();
+        let start = m.func(&[], &[], &[], body);
+        m.export("_start", start).data(0, path.as_bytes());
+        m.build()
+    }
+
+    /// Where `spawn_with_caps_module` places the child module.
+    const CHILD: i32 = 256;
+
+    /// A module whose `run` export spawns `child` with `caps` and returns its PID.
+    fn spawn_with_caps_module(child: &[u8], caps: &[CapabilityId]) -> Vec<u8> {
+        let ids: Vec<u8> = caps.iter().flat_map(|cap| cap.0.to_le_bytes()).collect();
+        let mut m = ModuleBuilder::new();
+        let spawn = m.import("spawn_agent_with_caps", &[I32, I32, I32, I32], &[I64]);
+        let body = Code::new()
+            .i32(CHILD)
+            .i32(child.len() as i32)
+            .i32(0)
+            .i32(caps.len() as i32)
+            .call(spawn);
+        let run = m.func(&[], &[I64], &[], body);
+        m.export("run", run).data(0, &ids).data(CHILD as u32, child);
+        m.build()
+    }
+
+    fn spawn_any() -> CapabilityId {
+        crate::capability::create_capability(Capability::Spawn {
+            max_children: 4,
+            max_depth: crate::task::MAX_SPAWN_DEPTH,
+            allowed_prefix: None,
+        })
+    }
+
+    #[test_case]
+    fn spawned_child_inherits_a_file_capability_and_runs() {
+        let runtime = WasmRuntime::new();
+        crate::vfs::write_file("/agent/inherited.txt", b"shared", 0);
+        let files = crate::capability::create_capability(agent_files(false));
+        let parent =
+            crate::task::spawn_agent("inherit-parent", alloc::vec![spawn_any(), files], None)
+                .unwrap();
+
+        let wasm = spawn_with_caps_module(&must_read_module("/agent/inherited.txt"), &[files]);
+        let results = testing::call(&runtime, &wasm, parent, "run", &[]).unwrap();
+        let child = results[0].i64().unwrap() as u64;
+        assert_ne!(child, 0);
+        assert_eq!(agent_capabilities(AgentId(child)), alloc::vec![files]);
+
+        // The child is loaded from the spawn queue once the executor runs.
+        crate::task::run_executor();
+        assert!(testing::logged(&format!("[EXEC] Agent {child} finished")));
+    }
+
+    #[test_case]
+    fn parent_cannot_pass_a_capability_it_lacks() {
+        let runtime = WasmRuntime::new();
+        let parent =
+            crate::task::spawn_agent("inherit-poor", alloc::vec![spawn_any()], None).unwrap();
+        let files = crate::capability::create_capability(agent_files(false));
+
+        let wasm = spawn_with_caps_module(&testing::yielding_module(1), &[files]);
+        let results = testing::call(&runtime, &wasm, parent, "run", &[]).unwrap();
+        assert_eq!(results[0].i64(), Some(0));
+        assert!(testing::logged(&format!(
+            "[SECURITY] Agent {} tried to pass capability {} it does not hold",
+            parent.0, files.0
+        )));
+    }
+
+    fn cached(runtime: &WasmRuntime, wasm: &[u8]) -> bool {
+        let key = crate::crypto::sha256(wasm);
+        runtime.module_cache.lock().entries.contains_key(&key)
+    }
+
+    #[test_case]
+    fn module_cache_evicts_the_least_recently_used_module() {
+        let runtime = WasmRuntime::new();
+        runtime.set_cache_capacity(2);
+        let [a, b, c] = [1, 2, 3].map(testing::yielding_module);
+        runtime.compile(&a).unwrap();
+        runtime.compile(&b).unwrap();
+        runtime.compile(&c).unwrap();
+        assert!(!cached(&runtime, &a));
+        assert!(cached(&runtime, &b) && cached(&runtime, &c));
+    }
+
+    #[test_case]
+    fn reusing_a_cached_module_keeps_it_from_eviction() {
+        let runtime = WasmRuntime::new();
+        runtime.set_cache_capacity(2);
+        let [a, b, c] = [4, 5, 6].map(testing::yielding_module);
+        let first = runtime.compile(&a).unwrap();
+        runtime.compile(&b).unwrap();
+        let again = runtime.compile(&a).unwrap();
+        assert!(Arc::ptr_eq(&first, &again));
+        runtime.compile(&c).unwrap();
+        assert!(cached(&runtime, &a) && cached(&runtime, &c));
+        assert!(!cached(&runtime, &b));
+
+        runtime.set_cache_capacity(0);
+        assert!(!cached(&runtime, &a) && !cached(&runtime, &c));
+    }
+
+    #[test_case]
+    fn agents_resolve_services_by_registered_name() {
+        let runtime = WasmRuntime::new();
+        let service = testing::spawn_agent("resolve-service", Vec::new());
+        let client = testing::spawn_agent("resolve-client", Vec::new());
+        let name = b"svc.resolve";
+        let register = status_module("register_name", &[0, name.len() as i32], name);
+        assert_eq!(
+            testing::call_status(&runtime, &register, service, "run"),
+            OK
+        );
+        assert_eq!(
+            testing::call_status(&runtime, &register, client, "run"),
+            ERR_PERMISSION_DENIED
+        );
+
+        let mut m = ModuleBuilder::new();
+        let resolve = m.import("resolve_agent", &[I32, I32], &[I64]);
+        let body = Code::new().i32(0).i32(name.len() as i32).call(resolve);
+        let run = m.func(&[], &[I64], &[], body);
+        m.export("run", run).data(0, name);
+        let results = testing::call(&runtime, &m.build(), client, "run", &[]).unwrap();
+        assert_eq!(results[0].i64(), Some(service.0 as i64));
+    }
+
+    fn screen_shows(text: &str) -> bool {
+        crate::vga_buffer::screen()
+            .windows(text.len())
+            .any(|w| w == text.as_bytes())
+    }
+
+    #[test_case]
+    fn serial_only_agents_leave_the_screen_untouched() {
+        let runtime = WasmRuntime::new();
+        let agent = testing::spawn_agent("quiet-logger", Vec::new());
+        let quiet = LogSinks {
+            serial: true,
+            vga: false,
+        };
+        runtime.set_agent_log_sinks(agent.0, Some(quiet));
+        let before = crate::vga_buffer::screen();
+        let wasm = debug_log_module(0, 11, b"serial only");
+        testing::call(&runtime, &wasm, agent, "run", &[]).unwrap();
+        assert_eq!(crate::vga_buffer::screen(), before);
+        assert!(testing::logged(&format!(
+            "[Wasm Agent {}] serial only\n",
+            agent.0
+        )));
+
+        // Without the override the runtime's default sinks apply again.
+        runtime.set_agent_log_sinks(agent.0, None);
+        testing::call(&runtime, &wasm, agent, "run", &[]).unwrap();
+        assert!(screen_shows(&format!(
+            "[Wasm Agent {}] serial only",
+            agent.0
+        )));
+    }
+
+    #[test_case]
+    fn disabling_serial_by_default_keeps_lines_off_the_serial_log() {
+        let runtime = WasmRuntime::new();
+        runtime.set_log_sinks(false, true);
+        let agent = testing::spawn_agent("vga-logger", Vec::new());
+        let wasm = debug_log_module(0, 11, b"screen only");
+        testing::call(&runtime, &wasm, agent, "run", &[]).unwrap();
+        let line = format!("[Wasm Agent {}] screen only", agent.0);
+        assert!(!testing::logged(&line));
+        assert!(screen_shows(&line));
+    }
+
+    #[test_case]
+    fn log_messages_cut_mid_character_drop_the_partial_character() {
+        // "héllo" with the cap falling inside the two-byte "é".
+        let text = "h\u{e9}llo".as_bytes();
+        assert_eq!(
+            render_log_message(&text[..2], text.len()),
+            "h ...[truncated 5 bytes]"
+        );
+        assert_eq!(render_log_message(text, text.len()), "h\u{e9}llo");
+        assert_eq!(
+            render_log_message(&[b'o', b'k', 0xff, 0x41], 4),
+            "ok <invalid utf-8: ff 41>"
+        );
+    }
+
+    #[test_case]
+    fn over_length_log_messages_are_capped_with_a_marker() {
+        let runtime = WasmRuntime::new();
+        runtime.set_max_log_len(5);
+        let agent = testing::spawn_agent("long-logger", Vec::new());
+        let wasm = debug_log_module(0, 11, b"hello world");
+        testing::call(&runtime, &wasm, agent, "run", &[]).unwrap();
+        assert!(testing::logged(&format!(
+            "[Wasm Agent {}] hello ...[truncated 6 bytes]\n",
+            agent.0
+        )));
+    }
+
+    #[test_case]
+    fn tcp_probe_maps_port_states_to_status_codes() {
+        testing::loopback();
+        let runtime = WasmRuntime::new();
+        let prober = testing::spawn_agent("prober", alloc::vec![Capability::Network]);
+        let ip = testing::GUEST_IP.0;
+        let probe = |agent, port| {
+            let wasm = status_module("tcp_probe", &[0, port], &ip);
+            testing::call_status(&runtime, &wasm, agent, "run")
+        };
+
+        let listener = testing::listen(7103);
+        let open = probe(prober, 7103);
+        crate::net::with_network(|net| net.sockets.remove(listener)).unwrap();
+        assert_eq!(open, OK);
+        assert_eq!(probe(prober, 7104), ERR_CONNECTION_REFUSED);
+        assert_eq!(probe(prober, 70_000), ERR_INVALID_ARGUMENT);
+
+        let offline = testing::spawn_agent("prober-offline", Vec::new());
+        assert_eq!(probe(offline, 7104), ERR_PERMISSION_DENIED);
+    }
+
+    #[test_case]
+    fn net_flush_requires_network_access() {
+        testing::network();
+        let runtime = WasmRuntime::new();
+        let wasm = status_module("net_flush", &[], &[]);
+        let online = testing::spawn_agent("flusher", alloc::vec![Capability::Network]);
+        assert_eq!(testing::call_status(&runtime, &wasm, online, "run"), OK);
+
+        let offline = testing::spawn_agent("flusher-offline", Vec::new());
+        assert_eq!(
+            testing::call_status(&runtime, &wasm, offline, "run"),
+            ERR_PERMISSION_DENIED
+        );
+        assert!(testing::logged(&format!(
+            "Agent {} denied net_flush",
+            offline.0
+        )));
+    }
+
+    /// A module whose `run` calls `env.ipc_queue_stats(target, 0, 4)` and returns its
+    /// status.
+    fn queue_stats_module(target: u64) -> Vec<u8> {
+        let mut m = ModuleBuilder::new();
+        let stats = m.import("ipc_queue_stats", &[I64, I32, I32], &[I32]);
+        let body = Code::new().i64(target as i64).i32(0).i32(4).call(stats);
+        let run = m.func(&[], &[I32], &[], body);
+        m.export("run", run);
+        m.build()
+    }
+
+    #[test_case]
+    fn queue_stats_report_a_partly_full_queue_to_senders() {
+        let runtime = WasmRuntime::new();
+        let consumer = testing::spawn_agent("backpressure-consumer", Vec::new());
+        let queue = ProcessId(consumer.0);
+        crate::ipc::set_queue_depth(queue, 8).unwrap();
+        for _ in 0..3 {
+            crate::ipc::send_message(
+                crate::ipc::KERNEL_SUPERVISOR_PID,
+                queue,
+                alloc::vec![1],
+                Vec::new(),
+            )
+            .unwrap();
+        }
+        let producer = testing::spawn_agent(
+            "backpressure-producer",
+            alloc::vec![Capability::Process {
+                pid: consumer.0,
+                can_send: true,
+                can_receive: false,
+            }],
+        );
+
+        let wasm = queue_stats_module(consumer.0);
+        let (status, memory) = run_with_memory(&runtime, &wasm, producer);
+        assert_eq!(status, OK);
+        assert_eq!(memory[0..4], 3u32.to_le_bytes());
+        assert_eq!(memory[4..8], 8u32.to_le_bytes());
+        assert_eq!(crate::ipc::peek_messages(queue).len(), 3);
+
+        let stranger = testing::spawn_agent("backpressure-stranger", Vec::new());
+        assert_eq!(
+            testing::call_status(&runtime, &wasm, stranger, "run"),
+            ERR_PERMISSION_DENIED
+        );
+        assert!(testing::logged(&format!(
+            "Agent {} denied queue stats of Agent {}",
+            stranger.0, consumer.0
+        )));
+    }
+
+    #[test_case]
+    fn raw_ethernet_needs_the_raw_network_capability() {
+        testing::network();
+        let runtime = WasmRuntime::new();
+        let mac = crate::net::with_network(|net| net.device.mac).unwrap();
+        let mut frame = alloc::vec![0xff; 6];
+        frame.extend_from_slice(&mac);
+        frame.extend_from_slice(&[0x88, 0xcc, 1, 2, 3]);
+        let send = status_module("eth_send", &[0, frame.len() as i32], &frame);
+        let recv = status_module("eth_recv", &[OUT, OUT + 1600], b"");
+
+        let raw = testing::spawn_agent("l2-agent", alloc::vec![Capability::RawNetwork]);
+        assert_eq!(testing::call_status(&runtime, &send, raw, "run"), OK);
+        while testing::call_status(&runtime, &recv, raw, "run") == OK {}
+        assert_eq!(
+            testing::call_status(&runtime, &recv, raw, "run"),
+            ERR_NOT_FOUND
+        );
+
+        let ip_only = testing::spawn_agent("l3-agent", alloc::vec![Capability::Network]);
+        assert_eq!(
+            testing::call_status(&runtime, &send, ip_only, "run"),
+            ERR_PERMISSION_DENIED
+        );
+        assert_eq!(
+            testing::call_status(&runtime, &recv, ip_only, "run"),
+            ERR_PERMISSION_DENIED
+        );
+    }
+
+    #[test_case]
+    fn agents_resize_only_their_own_queue_within_the_cap() {
+        let runtime = WasmRuntime::new();
+        let agent = testing::spawn_agent("queue-resizer", Vec::new());
+        let resize = |depth: usize| {
+            let wasm = status_module("set_my_queue_depth", &[depth as i32], b"");
+            testing::call_status(&runtime, &wasm, agent, "run")
+        };
+
+        assert_eq!(resize(100), OK);
+        assert_eq!(crate::ipc::queue_stats(ProcessId(agent.0)), Some((0, 100)));
+        assert_eq!(
+            resize(crate::ipc::max_queue_depth() + 1),
+            ERR_INVALID_ARGUMENT
+        );
+        assert_eq!(crate::ipc::queue_stats(ProcessId(agent.0)), Some((0, 100)));
+    }
+
+    /// Pop an i32 and trap unless it equals `expected`. Uses local 0.
+    fn expect_i32(code: Code, expected: i32) -> Code {
+        code.i32(expected)
+            .op(testing::I32_SUB)
+            .op(testing::I32_EQZ)
+            .local_set(0)
+            .i32(1)
+            .local_get(0)
+            .op(testing::I32_DIV_U)
+            .drop()
+    }
+
+    /// A module that serves `contents` at `path` and, from `_start`, registers the
+    /// file and reads it back, trapping unless the read returns `contents`.
+    fn self_serving_module(path: &str, contents: &[u8; 8]) -> Vec<u8> {
+        const CONTENTS: i32 = 512;
+        let mut m = ModuleBuilder::new();
+        let register = m.import("register_dynamic_file", &[I32, I32], &[I32]);
+        let file_read = m.import("file_read", &[I32, I32, I32, I32], &[I32]);
+        let len = path.len() as i32;
+        let mut body = Code::new().i32(0).i32(len).call(register);
+        body = expect_i32(body, OK as i32);
+        body = body.i32(0).i32(len).i32(OUT).i32(OUT_LEN).call(file_read);
+        body = expect_i32(body, OK as i32);
+        body = expect_i32(body.i32(OUT_LEN).load32(0), 8);
+        let head = i32::from_le_bytes(contents[..4].try_into().unwrap());
+        body = expect_i32(body.i32(OUT).load32(0), head);
+        let start = m.func(&[], &[], &[I32], body);
+        let packed = (i64::from(CONTENTS) << 32) | 8;
+        let generate = m.func(&[I32], &[I64], &[], Code::new().i64(packed));
+        m.export("_start", start)
+            .export(FILE_GENERATOR_EXPORT, generate)
+            .data(0, path.as_bytes())
+            .data(CONTENTS as u32, contents);
+        m.build()
+    }
+
+    #[test_case]
+    fn supervised_agent_reads_the_file_it_serves() {
+        let agent = testing::spawn_agent(
+            "file-server",
+            alloc::vec![Capability::ServeFiles, agent_files(false)],
+        );
+        let served = format!("/agent/{}/status", agent.0);
+        let module = "/test/file-server.wasm";
+        testing::install(module, self_serving_module(&served, b"state:up"));
+        crate::task::set_supervision(agent, module, crate::task::RestartPolicy::Never);
+
+        assert_eq!(WasmRuntime::new().supervise(agent), Ok(()));
+        assert!(testing::logged(&format!(
+            "[VFS] Agent {} serving {served}",
+            agent.0
+        )));
+        // Other readers are served too, until the owner is reclaimed.
+        assert_eq!(
+            crate::vfs::open_file(&served).as_deref(),
+            Some(&b"state:up"[..])
+        );
+        crate::task::reclaim_agent(agent);
+        assert!(crate::vfs::open_file(&served).is_none());
+    }
+
+    /// Call `env.<name>() -> u64` from a fresh instance for `agent`.
+    fn call_u64(runtime: &WasmRuntime, name: &str, agent: AgentId) -> u64 {
+        let mut m = ModuleBuilder::new();
+        let import = m.import(name, &[], &[I64]);
+        let run = m.func(&[], &[I64], &[], Code::new().call(import));
+        m.export("run", run);
+        let results = testing::call(runtime, &m.build(), agent, "run", &[]).unwrap();
+        results[0].i64().unwrap() as u64
+    }
+
+    #[test_case]
+    fn agents_learn_their_own_and_their_parents_pid() {
+        let runtime = WasmRuntime::new();
+        let parent =
+            crate::task::spawn_agent("pid-parent", alloc::vec![spawn_any()], None).unwrap();
+        let child = crate::task::spawn_agent("pid-child", Vec::new(), Some(parent)).unwrap();
+
+        assert_eq!(call_u64(&runtime, "get_pid", child), child.0);
+        assert_eq!(call_u64(&runtime, "get_parent_pid", child), parent.0);
+        assert_eq!(call_u64(&runtime, "get_pid", parent), parent.0);
+        assert_eq!(call_u64(&runtime, "get_parent_pid", parent), 0);
+    }
+
+    #[test_case]
+    fn poll_signal_returns_and_clears_the_pending_mask() {
+        use crate::task::{send_signal, SIGNAL_RELOAD, SIGNAL_TERMINATE};
+
+        let runtime = WasmRuntime::new();
+        let agent = testing::spawn_agent("signal-poller", Vec::new());
+        let poll = status_module("poll_signal", &[], &[]);
+        send_signal(agent, SIGNAL_RELOAD);
+        send_signal(agent, SIGNAL_TERMINATE);
+        assert_eq!(
+            testing::call_status(&runtime, &poll, agent, "run"),
+            SIGNAL_RELOAD | SIGNAL_TERMINATE
+        );
+        assert_eq!(testing::call_status(&runtime, &poll, agent, "run"), 0);
+    }
+
+    #[test_case]
+    fn requested_spawn_keeps_the_inherited_prefix() {
+        use crate::capability::{allow_request, can_spawn_bytes, can_spawn_from};
+
+        let runtime = WasmRuntime::new();
+        let request_spawn = |agent: AgentId| {
+            allow_request(agent.0, 2);
+            let wasm = status_module("request_capability", &[2, 0, 0], b"");
+            assert_eq!(testing::call_status(&runtime, &wasm, agent, "run"), OK);
+            agent_capabilities(agent)
+        };
+        let trusted = Capability::Spawn {
+            max_children: 4,
+            max_depth: crate::task::MAX_SPAWN_DEPTH,
+            allowed_prefix: Some(String::from("/trusted/")),
+        };
+        let limited = testing::spawn_agent("request-spawn-limited", alloc::vec![trusted]);
+        let child =
+            crate::task::spawn_agent_with_caps("request-spawn-child", limited, &[]).unwrap();
+        let free = testing::spawn_agent("request-spawn-free", Vec::new());
+
+        for agent in [limited, child] {
+            let caps = request_spawn(agent);
+            assert!(!can_spawn_bytes(&caps));
+            assert!(can_spawn_from(&caps, "/trusted/tool.wasm"));
+            assert!(!can_spawn_from(&caps, "/agent/tool.wasm"));
+        }
+        assert!(can_spawn_bytes(&request_spawn(free)));
+    }
+
+    /// A module whose `run` export spawns the VFS module at `path` and returns its PID.
+    fn spawn_from_file_module(path: &[u8]) -> Vec<u8> {
+        let mut m = ModuleBuilder::new();
+        let spawn = m.import("spawn_from_file", &[I32, I32], &[I64]);
+        let body = Code::new().i32(0).i32(path.len() as i32).call(spawn);
+        let run = m.func(&[], &[I64], &[], body);
+        m.export("run", run).data(0, path);
+        m.build()
+    }
+
+    fn spawn_under(prefix: &str) -> Capability {
+        Capability::Spawn {
+            max_children: 4,
+            max_depth: crate::task::MAX_SPAWN_DEPTH,
+            allowed_prefix: Some(String::from(prefix)),
+        }
+    }
+
+    #[test_case]
+    fn spawn_from_file_runs_modules_under_the_allowed_prefix() {
+        let runtime = WasmRuntime::new();
+        crate::vfs::register_file(
+            "/trusted/spawned.wasm",
+            testing::yielding_module(1).leak(),
+            0,
+        );
+        let parent =
+            testing::spawn_agent("from-file-parent", alloc::vec![spawn_under("/trusted/")]);
+
+        let wasm = spawn_from_file_module(b"/trusted/spawned.wasm");
+        let results = testing::call(&runtime, &wasm, parent, "run", &[]).unwrap();
+        let child = results[0].i64().unwrap() as u64;
+        assert_ne!(child, 0);
+        assert_eq!(crate::task::agent_parent(AgentId(child)), Some(parent));
+        crate::task::run_executor();
+        assert!(testing::logged(&format!("[EXEC] Agent {child} finished")));
+    }
+
+    #[test_case]
+    fn spawn_from_file_rejects_paths_outside_the_allowed_prefix() {
+        let runtime = WasmRuntime::new();
+        crate::vfs::register_file(
+            "/agent/untrusted.wasm",
+            testing::yielding_module(1).leak(),
+            0,
+        );
+        let parent =
+            testing::spawn_agent("from-file-denied", alloc::vec![spawn_under("/trusted/")]);
+        let spawn = |path: &[u8]| {
+            let wasm = spawn_from_file_module(path);
+            testing::call(&runtime, &wasm, parent, "run", &[]).unwrap()[0].i64()
+        };
+
+        assert_eq!(spawn(b"/agent/untrusted.wasm"), Some(0));
+        assert!(testing::logged(&format!(
+            "Agent {} denied spawning /agent/untrusted.wasm",
+            parent.0
+        )));
+        assert_eq!(spawn(b"/trusted/../agent/untrusted.wasm"), Some(0));
+        assert_eq!(spawn(b"/trusted/missing.wasm"), Some(0));
+        assert!(crate::task::agent_children(parent).is_empty());
+    }
+
+    #[test_case]
+    fn prefixed_spawn_refuses_raw_module_bytes() {
+        let runtime = WasmRuntime::new();
+        let parent = testing::spawn_agent("from-file-raw", alloc::vec![spawn_under("/trusted/")]);
+
+        let wasm = spawn_with_caps_module(&testing::yielding_module(1), &[]);
+        let results = testing::call(&runtime, &wasm, parent, "run", &[]).unwrap();
+        assert_eq!(results[0].i64(), Some(0));
+        assert!(testing::logged(&format!(
+            "Agent {} denied spawning raw module bytes",
+            parent.0
+        )));
+    }
+
+    #[test_case]
+    fn file_stat_reports_size_mode_and_mtime() {
+        let runtime = WasmRuntime::new();
+        let agent = agent_reader("stat-reader");
+        crate::vfs::register_file("/agent/stat-archived.txt", b"abc", 1_709_214_300);
+        crate::vfs::write_file("/agent/stat-written.txt", b"written", 0);
+        crate::vfs::register_file("/system/stat-secret.txt", b"", 0);
+        let stat = |path: &[u8]| {
+            let wasm = status_module("file_stat", &[0, path.len() as i32, OUT], path);
+            let (status, memory) = run_with_memory(&runtime, &wasm, agent);
+            let out = &memory[OUT as usize..OUT as usize + 16];
+            let field = |at| crate::bytes::read_u32_le(out, at).unwrap();
+            (
+                status,
+                field(0),
+                field(4),
+                crate::bytes::read_u64_le(out, 8).unwrap(),
+            )
+        };
+
+        assert_eq!(stat(b"/agent/stat-archived.txt"), (OK, 3, 1, 1_709_214_300));
+        let (status, size, flags, mtime) = stat(b"/agent/stat-written.txt");
+        assert_eq!((status, size, flags), (OK, 7, 0));
+        assert!(mtime.abs_diff(crate::time::unix_timestamp()) <= 2);
+        assert_eq!(stat(b"/agent/stat-missing.txt").0, ERR_NOT_FOUND);
+        assert_eq!(stat(b"/system/stat-secret.txt").0, ERR_PERMISSION_DENIED);
+    }
+
+    #[test_case]
+    fn file_swap_checks_both_paths() {
+        const B: usize = 64;
+        let runtime = WasmRuntime::new();
+        let agent = agent_writer("swapper");
+        let swap = |a: &[u8], b: &[u8]| {
+            let mut data = a.to_vec();
+            data.resize(B, 0);
+            data.extend_from_slice(b);
+            let args = [0, a.len() as i32, B as i32, b.len() as i32];
+            testing::call_status(
+                &runtime,
+                &status_module("file_swap", &args, &data),
+                agent,
+                "run",
+            )
+        };
+        crate::vfs::write_file("/agent/swap-live.txt", b"v1", 0);
+        crate::vfs::write_file("/agent/swap-next.txt", b"v2", 0);
+        crate::vfs::register_file("/agent/swap-system.txt", b"system", 0);
+        crate::vfs::write_file("/system/swap-secret.txt", b"secret", 0);
+
+        assert_eq!(swap(b"/agent/swap-live.txt", b"/agent/swap-next.txt"), OK);
+        assert_eq!(
+            crate::vfs::open_file("/agent/swap-live.txt"),
+            Some(b"v2".to_vec())
+        );
+        assert_eq!(
+            crate::vfs::open_file("/agent/swap-next.txt"),
+            Some(b"v1".to_vec())
+        );
+
+        assert_eq!(
+            swap(b"/agent/swap-live.txt", b"/agent/swap-system.txt"),
+            ERR_GENERAL
+        );
+        assert_eq!(
+            swap(b"/agent/swap-live.txt", b"/system/swap-secret.txt"),
+            ERR_PERMISSION_DENIED
+        );
+        assert!(testing::logged(&format!(
+            "Agent {} denied file swap: /agent/swap-live.txt <-> /system/swap-secret.txt",
+            agent.0
+        )));
+        assert_eq!(
+            swap(b"/agent/swap-live.txt", b"/agent/swap-missing.txt"),
+            ERR_NOT_FOUND
+        );
+        assert_eq!(
+            crate::vfs::open_file("/agent/swap-live.txt"),
+            Some(b"v2".to_vec())
+        );
+        assert_eq!(
+            crate::vfs::open_file("/system/swap-secret.txt"),
+            Some(b"secret".to_vec())
+        );
+    }
 }